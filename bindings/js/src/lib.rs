@@ -1,12 +1,72 @@
-use moqtail_core::compile as core_compile;
-use napi::Error;
+use moqtail_core::{compile as core_compile, Matcher as CoreMatcher, Message, Payload};
+use napi::{Error, Status};
 use napi_derive::napi;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::Instant;
 
+/// Compile errors are rendered with a caret under the problem, matching the
+/// CLI and the Python bindings.
 #[napi]
 fn compile(query: String) -> Result<String, Error> {
     core_compile(&query)
         .map(|sel| sel.to_string())
-        .map_err(|e| Error::from_reason(e.to_string()))
+        .map_err(|e| Error::new(Status::InvalidArg, e.render(&query)))
+}
+
+/// Runs messages through a compiled selector and its pipeline. Results are
+/// JSON strings in the format of `moqtail sub --json`.
+#[napi]
+pub struct Matcher {
+    inner: CoreMatcher,
+}
+
+#[napi]
+impl Matcher {
+    #[napi(constructor)]
+    pub fn new(query: String) -> Result<Self, Error> {
+        let selector =
+            core_compile(&query).map_err(|e| Error::new(Status::InvalidArg, e.render(&query)))?;
+        Ok(Self {
+            inner: CoreMatcher::new(selector),
+        })
+    }
+
+    /// Processes one message and returns its output, or `null` when the
+    /// message does not match, produces nothing yet or arrives too late for
+    /// event-time windows. Payloads are decoded as JSON when the selector
+    /// reads them; pass buffers as `payload.toString()`.
+    #[napi]
+    pub fn process(
+        &mut self,
+        topic: String,
+        payload: Option<String>,
+        headers: Option<HashMap<String, String>>,
+    ) -> Option<String> {
+        let payload = payload.map_or(Payload::None, |text| Payload::raw(text.into_bytes()));
+        let msg = Message {
+            topic: &topic,
+            headers: headers
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(k, v)| (Cow::Owned(k), Cow::Owned(v)))
+                .collect(),
+            payload,
+        };
+        let output = self.inner.process_output(&msg, Instant::now()).ok()??;
+        Some(output.to_json().to_string())
+    }
+
+    /// Returns the records of the windows that closed since the last call.
+    /// Call it regularly, e.g. once a second.
+    #[napi]
+    pub fn tick(&mut self) -> Vec<String> {
+        self.inner
+            .tick_output(Instant::now())
+            .iter()
+            .map(|output| output.to_json().to_string())
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -17,4 +77,22 @@ mod tests {
     fn compile_returns_string() {
         assert_eq!(compile("/foo".into()).unwrap(), "/foo");
     }
+
+    #[test]
+    fn matcher_returns_json_records() {
+        let mut m = Matcher::new("/s |> window(60s) |> agg(max(json$.t), count())".into()).unwrap();
+        let payload = Some(r#"{"t": 3}"#.to_string());
+        let out: serde_json::Value =
+            serde_json::from_str(&m.process("s".into(), payload, None).unwrap()).unwrap();
+        assert_eq!(out["values"], serde_json::json!({"max": 3.0, "count": 1.0}));
+        assert_eq!(m.process("other".into(), None, None), None);
+    }
+
+    #[test]
+    fn compile_errors_are_rendered() {
+        let err = compile("/foo |> cont()".into()).unwrap_err();
+        assert!(err
+            .reason
+            .starts_with("error: unknown function `cont`, did you mean `count`?"));
+    }
 }
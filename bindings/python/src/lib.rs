@@ -1,16 +1,112 @@
-use moqtail_core::compile as core_compile;
+use moqtail_core::{
+    compile as core_compile, Error, Matcher as CoreMatcher, Message, Payload as CorePayload,
+};
+use pyo3::create_exception;
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::Instant;
+
+create_exception!(
+    moqtail_py,
+    SelectorError,
+    PyValueError,
+    "Selector compile error. The message is rendered with a caret under the \
+     problem; `offset`, `end`, `line`, `column` and `suggestion` locate it."
+);
 
 #[pyfunction]
 fn compile(query: &str) -> PyResult<String> {
     core_compile(query)
         .map(|sel| sel.to_string())
-        .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))
+        .map_err(|e| selector_error(&e, query))
+}
+
+fn selector_error(e: &Error, query: &str) -> PyErr {
+    let err = SelectorError::new_err(e.render(query));
+    Python::with_gil(|py| {
+        let value = err.value_bound(py);
+        value.setattr("offset", e.span.start)?;
+        value.setattr("end", e.span.end)?;
+        value.setattr("line", e.line)?;
+        value.setattr("column", e.column)?;
+        value.setattr("suggestion", e.suggestion.clone())?;
+        Ok::<_, PyErr>(())
+    })
+    .err()
+    .unwrap_or(err)
+}
+
+/// A message payload, as text or raw bytes.
+#[derive(FromPyObject)]
+enum Payload {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+/// Runs messages through a compiled selector and its pipeline. Results are
+/// JSON strings in the format of `moqtail sub --json`.
+#[pyclass]
+struct Matcher {
+    inner: CoreMatcher,
+}
+
+#[pymethods]
+impl Matcher {
+    #[new]
+    fn new(query: &str) -> PyResult<Self> {
+        let selector = core_compile(query).map_err(|e| selector_error(&e, query))?;
+        Ok(Self {
+            inner: CoreMatcher::new(selector),
+        })
+    }
+
+    /// Processes one message and returns its output, or `None` when the
+    /// message does not match, produces nothing yet or arrives too late for
+    /// event-time windows. Payloads are decoded as JSON when the selector
+    /// reads them.
+    #[pyo3(signature = (topic, payload=None, headers=None))]
+    fn process(
+        &mut self,
+        topic: &str,
+        payload: Option<Payload>,
+        headers: Option<HashMap<String, String>>,
+    ) -> Option<String> {
+        let payload = match payload {
+            Some(Payload::Text(text)) => CorePayload::raw(text.into_bytes()),
+            Some(Payload::Bytes(bytes)) => CorePayload::raw(bytes),
+            None => CorePayload::None,
+        };
+        let msg = Message {
+            topic,
+            headers: headers
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(k, v)| (Cow::Owned(k), Cow::Owned(v)))
+                .collect(),
+            payload,
+        };
+        let output = self.inner.process_output(&msg, Instant::now()).ok()??;
+        Some(output.to_json().to_string())
+    }
+
+    /// Returns the records of the windows that closed since the last call.
+    /// Call it regularly, e.g. once a second.
+    fn tick(&mut self) -> Vec<String> {
+        self.inner
+            .tick_output(Instant::now())
+            .iter()
+            .map(|output| output.to_json().to_string())
+            .collect()
+    }
 }
 
 #[pymodule]
 fn moqtail_py(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(compile, m)?)?;
+    m.add_class::<Matcher>()?;
+    m.add("SelectorError", m.py().get_type_bound::<SelectorError>())?;
     Ok(())
 }
 
@@ -22,4 +118,14 @@ mod tests {
     fn compile_returns_string() {
         assert_eq!(compile("/foo").unwrap(), "/foo");
     }
+
+    #[test]
+    fn matcher_returns_json_records() {
+        let mut m = Matcher::new("/s |> window(60s) |> agg(max(json$.t), count())").unwrap();
+        let payload = Some(Payload::Text(r#"{"t": 3}"#.into()));
+        let out: serde_json::Value =
+            serde_json::from_str(&m.process("s", payload, None).unwrap()).unwrap();
+        assert_eq!(out["values"], serde_json::json!({"max": 3.0, "count": 1.0}));
+        assert_eq!(m.process("other", None, None), None);
+    }
 }
@@ -1,11 +1,20 @@
 use clap::{Args, Parser, Subcommand};
-use moqtail_core::compile;
+use moqtail_core::{
+    compile, AggregateRecord, Late, Matcher, Message, Payload, PipelineOutput, Schemas,
+};
+use rumqttc::v5::mqttbytes::v5::{Packet, Publish};
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::{Client, Event, MqttOptions};
 #[cfg(feature = "tls")]
 use rumqttc::Transport;
-use rumqttc::{Client, Event, Incoming, MqttOptions, QoS};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
 use std::process;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[cfg(test)]
@@ -48,10 +57,58 @@ struct SubArgs {
     /// MQTT client ID (auto-generated if omitted)
     #[arg(long)]
     client_id: Option<String>,
+    /// Print one JSON object per message or window result
+    #[arg(long)]
+    json: bool,
     /// Use TLS for the connection
     #[cfg(feature = "tls")]
     #[arg(long)]
     tls: bool,
+    #[command(flatten)]
+    schemas: SchemaArgs,
+}
+
+/// Protobuf message types for `pb$` fields.
+#[derive(Args, Clone, Default)]
+struct SchemaArgs {
+    /// FileDescriptorSet file, as written by `protoc --descriptor_set_out`
+    #[arg(long, value_name = "FILE")]
+    descriptor_set: Vec<PathBuf>,
+    /// `.proto` file to compile
+    #[arg(long, value_name = "FILE")]
+    proto: Vec<PathBuf>,
+    /// Directory to look for imported `.proto` files in
+    #[arg(long, value_name = "DIR")]
+    proto_path: Vec<PathBuf>,
+}
+
+impl SchemaArgs {
+    fn load(&self) -> Result<Option<Schemas>, String> {
+        if self.descriptor_set.is_empty() && self.proto.is_empty() {
+            return Ok(None);
+        }
+        let mut schemas = Schemas::new();
+        for path in &self.descriptor_set {
+            let bytes =
+                fs::read(path).map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+            schemas
+                .add_descriptor_set(&bytes)
+                .map_err(|e| format!("Cannot load {}: {e}", path.display()))?;
+        }
+        if !self.proto.is_empty() {
+            // Imports are also looked for next to the files themselves.
+            let mut includes = self.proto_path.clone();
+            includes.extend(
+                self.proto
+                    .iter()
+                    .filter_map(|p| p.parent().map(PathBuf::from)),
+            );
+            schemas
+                .add_proto_files(&includes, &self.proto)
+                .map_err(|e| format!("Cannot compile .proto files: {e}"))?;
+        }
+        Ok(Some(schemas))
+    }
 }
 
 static CLIENT_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
@@ -73,16 +130,18 @@ fn resolve_client_id(cmd: &SubArgs) -> String {
 
 #[cfg(test)]
 thread_local! {
-    pub static TEST_OPTIONS: RefCell<Option<MqttOptions>> = RefCell::new(None);
+    pub static TEST_OPTIONS: RefCell<Option<MqttOptions>> = const { RefCell::new(None) };
 }
 
 pub(crate) fn run_sub(cmd: SubArgs) -> Result<(), String> {
-    let selector = compile(&cmd.query).map_err(|e| format!("Failed to compile selector: {e}"))?;
+    let selector = compile(&cmd.query)
+        .map_err(|e| format!("Failed to compile selector\n{}", e.render(&cmd.query)))?;
     println!("{selector}");
+    let schemas = cmd.schemas.load()?;
 
     let mut mqttoptions = MqttOptions::new(resolve_client_id(&cmd), cmd.host, cmd.port);
     mqttoptions.set_keep_alive(Duration::from_secs(5));
-    match (cmd.username, cmd.password) {
+    match (cmd.username, cmd.password.clone()) {
         (Some(u), Some(p)) => {
             mqttoptions.set_credentials(u, p);
         }
@@ -106,20 +165,183 @@ pub(crate) fn run_sub(cmd: SubArgs) -> Result<(), String> {
         return Ok(());
     }
 
+    // Brokers only understand `+`/`#`, so subscribe with the covering legacy
+    // filters and leave the precise matching to the client.
+    let filters = selector.to_mqtt_filters();
+    if filters.is_empty() {
+        return Err(format!("Selector `{selector}` cannot match any MQTT topic"));
+    }
+
+    let mut matcher = Matcher::new(selector);
+    if let Some(schemas) = schemas {
+        matcher = matcher.with_schemas(Arc::new(schemas));
+    }
     let (client, mut connection) = Client::new(mqttoptions, 10);
-    if let Err(e) = client.subscribe(selector.to_string(), QoS::AtMostOnce) {
-        return Err(connection_error(e, cmd.password.as_deref()));
+    for filter in filters {
+        if let Err(e) = client.subscribe(filter, QoS::AtMostOnce) {
+            return Err(connection_error(e, cmd.password.as_deref()));
+        }
+    }
+    loop {
+        // Wake up at least once per tick so tumbling and hopping windows are
+        // reported when they close, not when the next message arrives. The
+        // only other error is a closed request channel, which cannot happen
+        // while `client` is alive.
+        if let Ok(event) = connection.recv_timeout(TICK_INTERVAL) {
+            match event {
+                Ok(Event::Incoming(Packet::Publish(p))) => {
+                    if let Some(line) = handle_publish(&mut matcher, &p, Instant::now(), cmd.json) {
+                        println!("{line}");
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => return Err(connection_error(e, cmd.password.as_deref())),
+            }
+        }
+        for output in matcher.tick_output(Instant::now()) {
+            if let PipelineOutput::Aggregate(record) = &output {
+                if let Some(line) = render_record(&output, record, cmd.json) {
+                    println!("{line}");
+                }
+            }
+        }
+    }
+}
+
+/// How often closed windows are checked for while waiting for messages.
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Converts an incoming publish into a [`Message`] for the matcher.
+///
+/// QoS, retain and dup flags become the `qos`, `retained` and `dup` headers.
+/// MQTT v5 user properties are exposed as `prop.<name>`, alongside the
+/// `prop.content-type` and `prop.response-topic` publish properties. The
+/// payload is decoded as JSON when the selector reads it. Returns `None` for
+/// topics that are not valid UTF-8.
+fn to_message(publish: &Publish) -> Option<Message<'_>> {
+    let topic = std::str::from_utf8(&publish.topic).ok()?;
+
+    let mut headers = HashMap::new();
+    headers.insert(
+        Cow::Borrowed("qos"),
+        Cow::Borrowed(match publish.qos {
+            QoS::AtMostOnce => "0",
+            QoS::AtLeastOnce => "1",
+            QoS::ExactlyOnce => "2",
+        }),
+    );
+    headers.insert(
+        Cow::Borrowed("retained"),
+        Cow::Borrowed(if publish.retain { "true" } else { "false" }),
+    );
+    headers.insert(
+        Cow::Borrowed("dup"),
+        Cow::Borrowed(if publish.dup { "true" } else { "false" }),
+    );
+    if let Some(props) = &publish.properties {
+        if let Some(content_type) = &props.content_type {
+            headers.insert(
+                Cow::Borrowed("prop.content-type"),
+                Cow::Borrowed(content_type.as_str()),
+            );
+        }
+        if let Some(response_topic) = &props.response_topic {
+            headers.insert(
+                Cow::Borrowed("prop.response-topic"),
+                Cow::Borrowed(response_topic.as_str()),
+            );
+        }
+        for (key, value) in &props.user_properties {
+            headers.insert(
+                Cow::Owned(format!("prop.{key}")),
+                Cow::Borrowed(value.as_str()),
+            );
+        }
     }
-    for event in connection.iter() {
-        match event {
-            Ok(Event::Incoming(Incoming::Publish(p))) => {
-                println!("{}: {}", p.topic, String::from_utf8_lossy(&p.payload));
+
+    Some(Message {
+        topic,
+        headers,
+        payload: Payload::raw(&publish.payload[..]),
+    })
+}
+
+/// Post-filters a publish delivered by the broker and renders the output line.
+///
+/// Selectors without aggregation stages print `topic: payload` for every
+/// matching message. Selectors with them print the record produced by
+/// [`Matcher::process_output`], if any, prefixed by the topic or, for grouped
+/// pipelines, the `group_by` key. With `json`, every line is the JSON form of
+/// the output instead, with non-JSON payloads as strings. Messages too late
+/// for event-time windows are reported on stderr.
+fn handle_publish(
+    matcher: &mut Matcher,
+    publish: &Publish,
+    now: Instant,
+    json: bool,
+) -> Option<String> {
+    let msg = to_message(publish)?;
+    let output = match matcher.process_output(&msg, now) {
+        Ok(output) => output?,
+        Err(late) => {
+            eprintln!("{}", late_report(msg.topic, &late));
+            return None;
+        }
+    };
+    let raw = String::from_utf8_lossy(&publish.payload);
+    match &output {
+        PipelineOutput::Message { .. } if json => {
+            let mut value = output.to_json();
+            if value["payload"].is_null() && !raw.is_empty() {
+                value["payload"] = raw.into();
             }
-            Ok(_) => {}
-            Err(e) => return Err(connection_error(e, cmd.password.as_deref())),
+            Some(value.to_string())
         }
+        PipelineOutput::Message {
+            topic,
+            payload: Some(payload),
+            ..
+        } if matcher.transforms_payload() => Some(format!("{topic}: {payload}")),
+        PipelineOutput::Message { topic, .. } => Some(format!("{topic}: {raw}")),
+        PipelineOutput::Aggregate(record) => render_record(&output, record, json),
+    }
+}
+
+/// Renders an aggregate record as `prefix: value`, or `prefix: name=value
+/// ...` for several values, where the prefix is the `group_by` key or the
+/// topic and is left out for windows without either. Records whose only
+/// value is undefined are skipped outside JSON output.
+fn render_record(output: &PipelineOutput, record: &AggregateRecord, json: bool) -> Option<String> {
+    if json {
+        return Some(output.to_json().to_string());
     }
-    Ok(())
+    let values = match record.fields.as_slice() {
+        [(_, value)] => (*value)?.to_string(),
+        fields => fields
+            .iter()
+            .map(|(name, value)| match value {
+                Some(value) => format!("{name}={value}"),
+                None => format!("{name}=null"),
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+    };
+    Some(match record.key.as_deref().or(record.topic.as_deref()) {
+        Some(prefix) => format!("{prefix}: {values}"),
+        None => values,
+    })
+}
+
+fn late_report(topic: &str, late: &Late) -> String {
+    let secs = |t: SystemTime| {
+        t.duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64())
+    };
+    format!(
+        "late message on {topic}: event time {}s is behind the watermark {}s",
+        secs(late.event_time),
+        secs(late.watermark)
+    )
 }
 
 fn connection_error(error: impl std::fmt::Display, password: Option<&str>) -> String {
@@ -150,12 +372,35 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rumqttc::v5::mqttbytes::v5::PublishProperties;
 
     fn opts_from(cmd: SubArgs) -> MqttOptions {
         run_sub(cmd).unwrap();
         TEST_OPTIONS.with(|cell| cell.borrow().clone().unwrap())
     }
 
+    #[test]
+    fn compile_errors_are_rendered_with_a_caret() {
+        let cmd = SubArgs {
+            query: "/foo |> sumx(json$.v)".into(),
+            host: "localhost".into(),
+            port: 1883,
+            username: None,
+            password: None,
+            client_id: None,
+            json: false,
+            #[cfg(feature = "tls")]
+            tls: false,
+            schemas: SchemaArgs::default(),
+            dry_run: true,
+        };
+        let err = run_sub(cmd).unwrap_err();
+        assert!(err.starts_with(
+            "Failed to compile selector\nerror: unknown function `sumx`, did you mean `sum`?"
+        ));
+        assert!(err.ends_with("1 | /foo |> sumx(json$.v)\n  |         ^^^^"));
+    }
+
     #[test]
     fn sets_credentials() {
         let cmd = SubArgs {
@@ -166,11 +411,16 @@ mod tests {
             username: Some("user".into()),
             password: Some("pass".into()),
             client_id: None,
+            json: false,
             #[cfg(feature = "tls")]
             tls: false,
+            schemas: SchemaArgs::default(),
         };
         let opts = opts_from(cmd);
-        assert_eq!(opts.credentials(), Some(("user".to_owned(), "pass".to_owned())));
+        assert_eq!(
+            opts.credentials(),
+            Some(("user".to_owned(), "pass".to_owned()))
+        );
     }
 
     #[test]
@@ -183,8 +433,10 @@ mod tests {
             username: Some("user".into()),
             password: None,
             client_id: None,
+            json: false,
             #[cfg(feature = "tls")]
             tls: false,
+            schemas: SchemaArgs::default(),
         };
         let opts = opts_from(cmd);
         assert_eq!(opts.credentials(), Some(("user".to_owned(), "".to_owned())));
@@ -197,8 +449,10 @@ mod tests {
             username: None,
             password: Some("pass".into()),
             client_id: None,
+            json: false,
             #[cfg(feature = "tls")]
             tls: false,
+            schemas: SchemaArgs::default(),
         };
         let opts = opts_from(cmd);
         assert_eq!(opts.credentials(), Some(("".to_owned(), "pass".to_owned())));
@@ -226,7 +480,9 @@ mod tests {
             username: None,
             password: None,
             client_id: None,
+            json: false,
             tls: true,
+            schemas: SchemaArgs::default(),
         };
         let transport = opts_from(cmd).transport();
         assert!(matches!(transport, rumqttc::Transport::Tls(_)));
@@ -243,13 +499,161 @@ mod tests {
             username: None,
             password: None,
             client_id: Some(client_id.into()),
+            json: false,
             #[cfg(feature = "tls")]
             tls: false,
+            schemas: SchemaArgs::default(),
         };
         let opts = opts_from(cmd);
         assert_eq!(opts.client_id(), client_id);
     }
 
+    #[test]
+    fn to_message_maps_flags_and_properties() {
+        let props = PublishProperties {
+            content_type: Some("application/json".into()),
+            user_properties: vec![("site".into(), "lab".into())],
+            ..Default::default()
+        };
+        let mut publish = Publish::new(
+            "sensors/temp",
+            QoS::AtLeastOnce,
+            r#"{"value":42}"#,
+            Some(props),
+        );
+        publish.retain = true;
+
+        let msg = to_message(&publish).unwrap();
+        assert_eq!(msg.topic, "sensors/temp");
+        assert_eq!(msg.headers["qos"], "1");
+        assert_eq!(msg.headers["retained"], "true");
+        assert_eq!(msg.headers["dup"], "false");
+        assert_eq!(msg.headers["prop.content-type"], "application/json");
+        assert_eq!(msg.headers["prop.site"], "lab");
+        assert_eq!(msg.payload.json(), Some(&serde_json::json!({"value": 42})));
+    }
+
+    #[test]
+    fn handle_publish_post_filters_predicates() {
+        let selector = compile("/sensors/+[json$.value>30]").unwrap();
+        let mut matcher = Matcher::new(selector);
+        let now = Instant::now();
+
+        let hot = Publish::new("sensors/temp", QoS::AtMostOnce, r#"{"value":42}"#, None);
+        assert_eq!(
+            handle_publish(&mut matcher, &hot, now, false).as_deref(),
+            Some(r#"sensors/temp: {"value":42}"#)
+        );
+
+        let cold = Publish::new("sensors/temp", QoS::AtMostOnce, r#"{"value":20}"#, None);
+        assert_eq!(handle_publish(&mut matcher, &cold, now, false), None);
+
+        let other = Publish::new("sensors/temp/raw", QoS::AtMostOnce, r#"{"value":42}"#, None);
+        assert_eq!(handle_publish(&mut matcher, &other, now, false), None);
+    }
+
+    #[test]
+    fn handle_publish_runs_pipeline() {
+        let selector = compile("/sensors/+ |> window(60s) |> sum(json$.value)").unwrap();
+        let mut matcher = Matcher::new(selector);
+        let now = Instant::now();
+
+        let first = Publish::new("sensors/a", QoS::AtMostOnce, r#"{"value":1}"#, None);
+        assert_eq!(
+            handle_publish(&mut matcher, &first, now, false).as_deref(),
+            Some("sensors/a: 1")
+        );
+        let second = Publish::new("sensors/b", QoS::AtMostOnce, r#"{"value":2}"#, None);
+        assert_eq!(
+            handle_publish(&mut matcher, &second, now + Duration::from_secs(1), false).as_deref(),
+            Some("sensors/b: 3")
+        );
+    }
+
+    #[test]
+    fn late_report_names_topic_and_times() {
+        let late = Late {
+            event_time: UNIX_EPOCH + Duration::from_secs(90),
+            watermark: UNIX_EPOCH + Duration::from_millis(100_500),
+        };
+        assert_eq!(
+            late_report("sensors/a", &late),
+            "late message on sensors/a: event time 90s is behind the watermark 100.5s"
+        );
+    }
+
+    #[test]
+    fn handle_publish_prefixes_group_key() {
+        let selector = compile("/sensors/+ |> group_by(json$.room) |> count()").unwrap();
+        let mut matcher = Matcher::new(selector);
+        let publish = Publish::new("sensors/a", QoS::AtMostOnce, r#"{"room":"hall"}"#, None);
+        assert_eq!(
+            handle_publish(&mut matcher, &publish, Instant::now(), false).as_deref(),
+            Some("hall: 1")
+        );
+    }
+
+    #[test]
+    fn handle_publish_renders_agg_records() {
+        let selector =
+            compile("/sensors/+ |> window(60s) |> agg(avg(json$.value), count() as n)").unwrap();
+        let mut matcher = Matcher::new(selector);
+        let publish = Publish::new("sensors/a", QoS::AtMostOnce, r#"{"value":4}"#, None);
+        assert_eq!(
+            handle_publish(&mut matcher, &publish, Instant::now(), false).as_deref(),
+            Some("sensors/a: avg=4 n=1")
+        );
+        let line = handle_publish(&mut matcher, &publish, Instant::now(), true).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["topic"], "sensors/a");
+        assert_eq!(json["values"], serde_json::json!({"avg": 4.0, "n": 2.0}));
+        assert!(json["window"]["start"].is_string());
+    }
+
+    #[test]
+    fn handle_publish_json_keeps_raw_payloads() {
+        let mut matcher = Matcher::new(compile("/sensors/+").unwrap());
+        let publish = Publish::new("sensors/a", QoS::AtMostOnce, "on", None);
+        let line = handle_publish(&mut matcher, &publish, Instant::now(), true).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["topic"], "sensors/a");
+        assert_eq!(json["payload"], "on");
+        assert_eq!(json["headers"]["qos"], "0");
+    }
+
+    #[test]
+    fn handle_publish_prints_transformed_payloads() {
+        let selector =
+            compile("/sensors/+ |> map(json$.f = json$.c * 1.8 + 32) |> select(json$.f)");
+        let mut matcher = Matcher::new(selector.unwrap());
+        let publish = Publish::new("sensors/a", QoS::AtMostOnce, r#"{"c": 100}"#, None);
+        assert_eq!(
+            handle_publish(&mut matcher, &publish, Instant::now(), false).as_deref(),
+            Some(r#"sensors/a: {"f":212}"#)
+        );
+    }
+
+    #[test]
+    fn handle_publish_drops_repeated_states() {
+        let mut matcher = Matcher::new(compile("/lamp |> distinct(json$.state)").unwrap());
+        let now = Instant::now();
+        let lines: Vec<_> = [
+            r#"{"state":"on"}"#,
+            r#"{"state":"on"}"#,
+            r#"{"state":"off"}"#,
+        ]
+        .into_iter()
+        .filter_map(|payload| {
+            let publish = Publish::new("lamp", QoS::AtMostOnce, payload, None);
+            handle_publish(&mut matcher, &publish, now, false)
+        })
+        .collect();
+        assert_eq!(
+            lines,
+            [r#"lamp: {"state":"on"}"#, r#"lamp: {"state":"off"}"#]
+        );
+    }
+
     #[test]
     fn generates_default_client_id() {
         let cmd = SubArgs {
@@ -260,8 +664,10 @@ mod tests {
             username: None,
             password: None,
             client_id: None,
+            json: false,
             #[cfg(feature = "tls")]
             tls: false,
+            schemas: SchemaArgs::default(),
         };
         let opts = opts_from(cmd);
         assert!(opts.client_id().starts_with("moqtail-cli-"));
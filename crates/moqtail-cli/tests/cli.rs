@@ -17,6 +17,16 @@ fn sub_errors_on_invalid_selector() {
         .stderr(contains("Failed to compile selector"));
 }
 
+#[test]
+fn sub_points_at_compile_errors() {
+    let mut cmd = Command::cargo_bin("moqtail-cli").unwrap();
+    cmd.arg("sub").arg("/foo[bar contain 1]").arg("--dry-run");
+    cmd.assert()
+        .failure()
+        .stderr(contains("did you mean `contains`?"))
+        .stderr(contains("1 | /foo[bar contain 1]\n  |          ^^^^^^^"));
+}
+
 #[test]
 fn sub_errors_on_connection_failure() {
     let mut cmd = Command::cargo_bin("moqtail-cli").unwrap();
@@ -75,3 +85,48 @@ fn sub_works_with_default_generated_client_id() {
     cmd.arg("sub").arg("/foo").arg("--dry-run");
     cmd.assert().success().stdout(contains("/foo"));
 }
+
+#[test]
+fn sub_loads_protobuf_schemas() {
+    let dir = std::env::temp_dir().join(format!("moqtail-cli-proto-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let proto = dir.join("sensors.proto");
+    std::fs::write(
+        &proto,
+        "syntax = \"proto3\"; package sensors; message Reading { double temperature = 1; }",
+    )
+    .unwrap();
+    let broken = dir.join("broken.proto");
+    std::fs::write(&broken, "message {").unwrap();
+
+    let mut cmd = Command::cargo_bin("moqtail-cli").unwrap();
+    cmd.arg("sub")
+        .arg("/foo[pb$.Reading.temperature > 30]")
+        .arg("--proto")
+        .arg(&proto)
+        .arg("--dry-run");
+    let ok = cmd.assert();
+
+    let mut cmd = Command::cargo_bin("moqtail-cli").unwrap();
+    cmd.arg("sub")
+        .arg("/foo")
+        .arg("--proto")
+        .arg(&broken)
+        .arg("--dry-run");
+    let failed = cmd.assert();
+
+    let mut cmd = Command::cargo_bin("moqtail-cli").unwrap();
+    cmd.arg("sub")
+        .arg("/foo")
+        .arg("--descriptor-set")
+        .arg(dir.join("missing.pb"))
+        .arg("--dry-run");
+    let missing = cmd.assert();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    ok.success().stdout(contains("pb$.Reading.temperature"));
+    failed
+        .failure()
+        .stderr(contains("Cannot compile .proto files"));
+    missing.failure().stderr(contains("Cannot read"));
+}
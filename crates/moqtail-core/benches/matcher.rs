@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use moqtail_core::{compile, Matcher, Message};
+use moqtail_core::{compile, Matcher, Message, Payload, SelectorIndex};
 
 fn long_topic(len: usize) -> String {
     let mut segs = Vec::with_capacity(len + 1);
@@ -21,7 +21,7 @@ fn bench_descendant_long_topic(c: &mut Criterion) {
     let msg = Message {
         topic: &topic,
         headers: HashMap::new(),
-        payload: None,
+        payload: Payload::None,
     };
 
     c.bench_function("descendant_long_topic", |b| {
@@ -29,6 +29,45 @@ fn bench_descendant_long_topic(c: &mut Criterion) {
     });
 }
 
+fn bench_child_selectors(c: &mut Criterion) {
+    let msg = Message {
+        topic: "building/east/floor3/sensor-12",
+        headers: HashMap::new(),
+        payload: Payload::None,
+    };
+    for (name, selector) in [
+        ("literal", "/building/east/floor3/sensor-12"),
+        ("plus", "/building/+/floor3/+"),
+        ("hash", "/building/#"),
+        ("nested_wildcards", "/building/#/floor3/+"),
+        ("descendant", "//floor3/sensor-*"),
+    ] {
+        let matcher = Matcher::new(compile(selector).unwrap());
+        assert!(matcher.matches(&msg));
+        c.bench_function(&format!("matches_{name}"), |b| {
+            b.iter(|| matcher.matches(black_box(&msg)));
+        });
+    }
+}
+
+fn bench_selector_index(c: &mut Criterion) {
+    let mut index = SelectorIndex::new();
+    for i in 0..10_000 {
+        let selector = compile(&format!("/building/b{}/+/sensor-{}", i % 100, i)).unwrap();
+        index.insert_selector(selector);
+    }
+    let msg = Message {
+        topic: "building/b42/floor3/sensor-4242",
+        headers: HashMap::new(),
+        payload: Payload::None,
+    };
+    assert_eq!(index.matches(&msg).len(), 1);
+
+    c.bench_function("index_10k_selectors", |b| {
+        b.iter(|| index.matches(black_box(&msg)));
+    });
+}
+
 fn bench_window_sum(c: &mut Criterion) {
     let selector = compile("/sensor |> window(60s) |> sum(temp)").unwrap();
     let mut matcher = Matcher::new(selector);
@@ -37,7 +76,7 @@ fn bench_window_sum(c: &mut Criterion) {
     let msg = Message {
         topic: "sensor",
         headers,
-        payload: None,
+        payload: Payload::None,
     };
     let mut timestamp = Instant::now();
 
@@ -61,7 +100,7 @@ fn bench_window_avg(c: &mut Criterion) {
     let msg = Message {
         topic: "sensor",
         headers: HashMap::new(),
-        payload: Some(serde_json::json!({"value": 1})),
+        payload: Payload::Json(serde_json::json!({"value": 1})),
     };
     let mut timestamp = Instant::now();
 
@@ -81,6 +120,8 @@ fn bench_window_avg(c: &mut Criterion) {
 criterion_group!(
     benches,
     bench_descendant_long_topic,
+    bench_child_selectors,
+    bench_selector_index,
     bench_window_sum,
     bench_window_avg
 );
@@ -0,0 +1,585 @@
+//! Running state for the aggregation stages of a pipeline.
+
+use crate::ast::Stage;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// State for one aggregation stage, or the aggregations of an `agg(...)`
+/// stage, under the window set by the closest preceding window stage.
+pub(crate) struct Aggregate {
+    /// The aggregations computed over each window.
+    stages: Vec<Stage>,
+    window: Window,
+}
+
+/// The values of an aggregation stage over one window.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Record {
+    /// Start and end of the window as time since the Unix epoch; `None`
+    /// without a window.
+    pub(crate) bounds: Option<(Duration, Duration)>,
+    /// One value per aggregation, `None` when it is undefined for the
+    /// window, such as `rate(f)` over a single sample.
+    pub(crate) values: Vec<Option<f64>>,
+}
+
+enum Window {
+    /// No window: only the current message counts.
+    Current(Metrics),
+    Sliding {
+        duration: Duration,
+        metrics: Metrics,
+    },
+    /// The last `capacity` messages.
+    Count { capacity: usize, metrics: Metrics },
+    /// Messages separated by less than `gap`; the session closes once `gap`
+    /// passes without a message.
+    Session {
+        gap: Duration,
+        /// Times of the oldest and newest message in the open session.
+        seen: Option<(Duration, Duration)>,
+        metrics: Metrics,
+        pending: Vec<Record>,
+    },
+    /// Tumbling (`hop == size`) and hopping windows. Windows start at
+    /// multiples of `hop` since the Unix epoch and are kept in start order.
+    Aligned {
+        size: Duration,
+        hop: Duration,
+        open: VecDeque<(Duration, Metrics)>,
+        /// Windows ending at or before this time have been closed; late
+        /// samples do not reopen them.
+        closed_until: Duration,
+        /// Records of closed windows not yet returned by
+        /// [`Aggregate::close`].
+        pending: Vec<Record>,
+    },
+}
+
+impl Aggregate {
+    pub(crate) fn new(stages: Vec<Stage>, window: Option<&Stage>) -> Self {
+        let metrics = |retain| Metrics::new(&stages, retain);
+        let window = match window {
+            Some(Stage::Window(duration)) => Window::Sliding {
+                duration: *duration,
+                metrics: metrics(true),
+            },
+            Some(Stage::CountWindow(capacity)) => Window::Count {
+                capacity: *capacity,
+                metrics: metrics(true),
+            },
+            Some(Stage::Session(gap)) => Window::Session {
+                gap: *gap,
+                seen: None,
+                metrics: metrics(false),
+                pending: Vec::new(),
+            },
+            Some(Stage::Tumbling(size)) => Self::aligned(*size, *size),
+            Some(Stage::Hopping(size, hop)) => Self::aligned(*size, *hop),
+            _ => Window::Current(metrics(false)),
+        };
+        Self { stages, window }
+    }
+
+    fn aligned(size: Duration, hop: Duration) -> Window {
+        Window::Aligned {
+            size,
+            hop,
+            open: VecDeque::new(),
+            closed_until: Duration::ZERO,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The aggregations computed, in output order.
+    pub(crate) fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    /// Adds the samples of one message, one per aggregation, taken `at` a
+    /// time since the Unix epoch. Returns a record for windows that are
+    /// re-evaluated on every message; aligned windows and sessions only
+    /// report through [`close`](Self::close). Samples for windows that
+    /// already closed are dropped.
+    pub(crate) fn push(&mut self, at: Duration, samples: &[f64]) -> Option<Record> {
+        let stages = &self.stages;
+        match &mut self.window {
+            Window::Current(metrics) => {
+                *metrics = Metrics::new(stages, false);
+                metrics.push(at, samples);
+                Some(metrics.record(stages, None, None))
+            }
+            Window::Sliding { duration, metrics } => {
+                while metrics
+                    .oldest()
+                    .is_some_and(|ts| at.saturating_sub(ts) > *duration)
+                {
+                    metrics.evict();
+                }
+                metrics.push(at, samples);
+                let newest = metrics.newest().unwrap_or(at);
+                let bounds = (newest.saturating_sub(*duration), newest);
+                Some(metrics.record(stages, Some(*duration), Some(bounds)))
+            }
+            Window::Count { capacity, metrics } => {
+                while metrics.len() >= *capacity {
+                    metrics.evict();
+                }
+                metrics.push(at, samples);
+                let bounds = metrics.oldest().zip(metrics.newest());
+                Some(metrics.record(stages, None, bounds))
+            }
+            Window::Session {
+                gap,
+                seen,
+                metrics,
+                pending,
+            } => {
+                if seen.is_some_and(|(_, last)| at >= last + *gap) {
+                    pending.extend(Self::end_session(stages, *gap, seen, metrics));
+                }
+                *seen = Some(match *seen {
+                    Some((first, last)) => (first.min(at), last.max(at)),
+                    None => (at, at),
+                });
+                metrics.push(at, samples);
+                None
+            }
+            Window::Aligned {
+                size,
+                hop,
+                open,
+                closed_until,
+                ..
+            } => {
+                let (size, hop) = (*size, *hop);
+                let mut start = align(at, hop);
+                loop {
+                    if start + size > *closed_until {
+                        let i = open.partition_point(|(s, _)| *s < start);
+                        if open.get(i).is_none_or(|(s, _)| *s != start) {
+                            open.insert(i, (start, Metrics::new(stages, false)));
+                        }
+                        open[i].1.push(at, samples);
+                    }
+                    match start.checked_sub(hop) {
+                        Some(earlier) if earlier + size > at => start = earlier,
+                        _ => break,
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// Closes the aligned windows and sessions that ended at or before
+    /// `watermark` and returns the records of every window closed since the
+    /// last call, oldest first.
+    pub(crate) fn close(&mut self, watermark: Duration) -> Vec<Record> {
+        self.advance(watermark);
+        match &mut self.window {
+            Window::Aligned { pending, .. } | Window::Session { pending, .. } => {
+                std::mem::take(pending)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Closes the aligned windows and sessions that ended at or before
+    /// `watermark`, keeping their records for [`close`](Self::close).
+    pub(crate) fn advance(&mut self, watermark: Duration) {
+        let stages = &self.stages;
+        match &mut self.window {
+            Window::Session {
+                gap,
+                seen,
+                metrics,
+                pending,
+            } if seen.is_some_and(|(_, last)| watermark >= last + *gap) => {
+                pending.extend(Self::end_session(stages, *gap, seen, metrics));
+            }
+            Window::Aligned {
+                size,
+                open,
+                closed_until,
+                pending,
+                ..
+            } => {
+                while let Some((start, _)) = open.front() {
+                    if *start + *size > watermark {
+                        break;
+                    }
+                    if let Some((start, metrics)) = open.pop_front() {
+                        let bounds = (start, start + *size);
+                        pending.push(metrics.record(stages, Some(*size), Some(bounds)));
+                    }
+                }
+                *closed_until = (*closed_until).max(watermark);
+            }
+            _ => {}
+        }
+    }
+
+    /// Resets the open session, returning its record. A session ends `gap`
+    /// after its newest message.
+    fn end_session(
+        stages: &[Stage],
+        gap: Duration,
+        seen: &mut Option<(Duration, Duration)>,
+        metrics: &mut Metrics,
+    ) -> Option<Record> {
+        let (first, last) = seen.take()?;
+        let ended = std::mem::replace(metrics, Metrics::new(stages, false));
+        Some(ended.record(stages, None, Some((first, last + gap))))
+    }
+}
+
+/// Accumulators for the aggregations computed together over one window,
+/// all fed the same messages.
+struct Metrics(Vec<Accumulator>);
+
+impl Metrics {
+    fn new(stages: &[Stage], retain: bool) -> Self {
+        Self(stages.iter().map(|s| Accumulator::new(s, retain)).collect())
+    }
+
+    fn len(&self) -> usize {
+        self.0.first().map_or(0, |acc| acc.len)
+    }
+
+    fn oldest(&self) -> Option<Duration> {
+        self.0.first()?.first.map(|(ts, _)| ts)
+    }
+
+    fn newest(&self) -> Option<Duration> {
+        self.0.first()?.last.map(|(ts, _)| ts)
+    }
+
+    fn push(&mut self, at: Duration, samples: &[f64]) {
+        for (acc, sample) in self.0.iter_mut().zip(samples) {
+            acc.push(at, *sample);
+        }
+    }
+
+    fn evict(&mut self) {
+        for acc in &mut self.0 {
+            acc.evict();
+        }
+    }
+
+    fn record(
+        &self,
+        stages: &[Stage],
+        span: Option<Duration>,
+        bounds: Option<(Duration, Duration)>,
+    ) -> Record {
+        let values = stages
+            .iter()
+            .zip(&self.0)
+            .map(|(stage, acc)| acc.value(stage, span))
+            .collect();
+        Record { bounds, values }
+    }
+}
+
+/// Rounds `at` down to a multiple of `step`.
+fn align(at: Duration, step: Duration) -> Duration {
+    let step = step.as_nanos();
+    let nanos = at.as_nanos() / step * step;
+    Duration::new(
+        (nanos / 1_000_000_000) as u64,
+        (nanos % 1_000_000_000) as u32,
+    )
+}
+
+/// Summary of the samples in one window, letting the stage answer in
+/// amortised `O(1)` instead of rescanning the samples on every message.
+/// Percentiles keep the values sorted, so each sample costs an `O(log n)`
+/// search plus an `O(n)` insert or remove.
+///
+/// Only windows that evict old samples need to retain them; the others keep
+/// `O(1)` state apart from the sorted values a percentile needs.
+struct Accumulator {
+    /// Samples in arrival order, kept only when `retain` is set.
+    samples: VecDeque<(Duration, f64)>,
+    retain: bool,
+    len: usize,
+    first: Option<(Duration, f64)>,
+    last: Option<(Duration, f64)>,
+    /// Sequence number of the next sample, used by [`Summary::Extreme`] to
+    /// recognise evicted samples.
+    next_seq: u64,
+    summary: Summary,
+}
+
+enum Summary {
+    /// Running total for `sum` and `avg`.
+    Sum(f64),
+    /// Monotonic deque for `min` and `max`: sequence numbers and values of
+    /// the samples that can still become the extreme, with the current one
+    /// at the front. Without eviction a sample that does not beat the current
+    /// extreme can never become it, so only the running extreme is kept.
+    Extreme {
+        deque: VecDeque<(u64, f64)>,
+        max: bool,
+    },
+    /// Welford's running mean and sum of squared deviations for `stddev`.
+    Moments { mean: f64, m2: f64 },
+    /// The retained values in ascending order for percentiles.
+    Sorted(Vec<f64>),
+    /// `count`, `first`, `last` and `rate` only need the sample count and
+    /// the oldest and newest samples.
+    Plain,
+}
+
+impl Accumulator {
+    fn new(stage: &Stage, retain: bool) -> Self {
+        let summary = match stage {
+            Stage::Sum(_) | Stage::Avg(_) => Summary::Sum(0.0),
+            Stage::Min(_) => Summary::Extreme {
+                deque: VecDeque::new(),
+                max: false,
+            },
+            Stage::Max(_) => Summary::Extreme {
+                deque: VecDeque::new(),
+                max: true,
+            },
+            Stage::Stddev(_) => Summary::Moments { mean: 0.0, m2: 0.0 },
+            Stage::Percentile(..) => Summary::Sorted(Vec::new()),
+            _ => Summary::Plain,
+        };
+        Self {
+            samples: VecDeque::new(),
+            retain,
+            len: 0,
+            first: None,
+            last: None,
+            next_seq: 0,
+            summary,
+        }
+    }
+
+    fn push(&mut self, at: Duration, value: f64) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.len += 1;
+        let n = self.len as f64;
+        if self.retain {
+            self.samples.push_back((at, value));
+        }
+        self.first.get_or_insert((at, value));
+        self.last = Some((at, value));
+        match &mut self.summary {
+            Summary::Sum(sum) => *sum += value,
+            Summary::Extreme { deque, max } => {
+                while let Some(&(_, back)) = deque.back() {
+                    if (*max && back <= value) || (!*max && back >= value) {
+                        deque.pop_back();
+                    } else {
+                        break;
+                    }
+                }
+                if self.retain || deque.is_empty() {
+                    deque.push_back((seq, value));
+                }
+            }
+            Summary::Moments { mean, m2 } => {
+                let delta = value - *mean;
+                *mean += delta / n;
+                *m2 += delta * (value - *mean);
+            }
+            Summary::Sorted(sorted) => {
+                let at = sorted.partition_point(|v| *v < value);
+                sorted.insert(at, value);
+            }
+            Summary::Plain => {}
+        }
+    }
+
+    /// Removes the oldest sample; a no-op unless samples are retained.
+    fn evict(&mut self) {
+        let Some((_, value)) = self.samples.pop_front() else {
+            return;
+        };
+        self.len -= 1;
+        self.first = self.samples.front().copied();
+        if self.len == 0 {
+            self.last = None;
+        }
+        // The evicted sample had this sequence number.
+        let seq = self.next_seq - self.len as u64 - 1;
+        let n = self.len as f64;
+        match &mut self.summary {
+            Summary::Sum(sum) => {
+                *sum -= value;
+                if self.len == 0 {
+                    *sum = 0.0;
+                }
+            }
+            Summary::Extreme { deque, .. } => {
+                if deque.front().is_some_and(|&(s, _)| s == seq) {
+                    deque.pop_front();
+                }
+            }
+            Summary::Moments { mean, m2 } => {
+                if self.len == 0 {
+                    *mean = 0.0;
+                    *m2 = 0.0;
+                } else {
+                    let old_mean = *mean;
+                    *mean = (old_mean * (n + 1.0) - value) / n;
+                    *m2 = (*m2 - (value - old_mean) * (value - *mean)).max(0.0);
+                }
+            }
+            Summary::Sorted(sorted) => {
+                let at = sorted.partition_point(|v| *v < value);
+                sorted.remove(at);
+            }
+            Summary::Plain => {}
+        }
+    }
+
+    /// The stage's result over the window. `span` is the window length that
+    /// `rate()` divides by; windows without a fixed length use the time
+    /// between their oldest and newest sample.
+    fn value(&self, stage: &Stage, span: Option<Duration>) -> Option<f64> {
+        let n = self.len;
+        let (t0, first) = self.first?;
+        let (t1, last) = self.last?;
+        match (stage, &self.summary) {
+            (Stage::Sum(_), Summary::Sum(sum)) => Some(*sum),
+            (Stage::Avg(_), Summary::Sum(sum)) => Some(*sum / n as f64),
+            (Stage::Count, _) => Some(n as f64),
+            (_, Summary::Extreme { deque, .. }) => deque.front().map(|&(_, v)| v),
+            (Stage::First(_), _) => Some(first),
+            (Stage::Last(_), _) => Some(last),
+            (Stage::Stddev(_), Summary::Moments { m2, .. }) => Some((m2 / n as f64).sqrt()),
+            (Stage::Percentile(_, p), Summary::Sorted(sorted)) => {
+                let rank = p / 100.0 * (n - 1) as f64;
+                let lo = rank.floor() as usize;
+                let hi = rank.ceil() as usize;
+                Some(sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64))
+            }
+            (Stage::Rate(None), _) => match span {
+                Some(span) => {
+                    let secs = span.as_secs_f64();
+                    (secs > 0.0).then(|| n as f64 / secs)
+                }
+                None => {
+                    let secs = t1.saturating_sub(t0).as_secs_f64();
+                    (secs > 0.0).then(|| (n - 1) as f64 / secs)
+                }
+            },
+            (Stage::Rate(Some(_)), _) => {
+                let secs = t1.saturating_sub(t0).as_secs_f64();
+                (secs > 0.0).then(|| (last - first) / secs)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Field;
+
+    fn field() -> Field {
+        Field::Json(vec!["v".into()])
+    }
+
+    fn run(stage: Stage, window: u64, values: &[f64]) -> Vec<Option<f64>> {
+        let window = Stage::Window(Duration::from_secs(window));
+        let mut agg = Aggregate::new(vec![stage], Some(&window));
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| agg.push(secs(i as u64), &[*v]).and_then(|r| r.values[0]))
+            .collect()
+    }
+
+    fn values(records: Vec<Record>) -> Vec<f64> {
+        records.into_iter().filter_map(|r| r.values[0]).collect()
+    }
+
+    #[test]
+    fn extremes_follow_the_window() {
+        let values = [5.0, 1.0, 3.0, 4.0, 2.0, 2.0, 6.0];
+        let mins = run(Stage::Min(field()), 2, &values);
+        let maxs = run(Stage::Max(field()), 2, &values);
+        let expected_min = [5.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0];
+        let expected_max = [5.0, 5.0, 5.0, 4.0, 4.0, 4.0, 6.0];
+        assert_eq!(mins, expected_min.map(Some));
+        assert_eq!(maxs, expected_max.map(Some));
+    }
+
+    #[test]
+    fn extremes_without_eviction_keep_one_value() {
+        let mut acc = Accumulator::new(&Stage::Max(field()), false);
+        for (t, v) in [5.0, 4.0, 3.0, 6.0, 2.0].into_iter().enumerate() {
+            acc.push(secs(t as u64), v);
+        }
+        let Summary::Extreme { deque, .. } = &acc.summary else {
+            unreachable!()
+        };
+        assert_eq!(deque.iter().copied().collect::<Vec<_>>(), [(3, 6.0)]);
+    }
+
+    #[test]
+    fn stddev_survives_eviction() {
+        let out = run(Stage::Stddev(field()), 1, &[100.0, 2.0, 4.0, 4.0]);
+        assert_eq!(out[0], Some(0.0));
+        assert!((out[2].unwrap() - 1.0).abs() < 1e-9);
+        assert!(out[3].unwrap().abs() < 1e-9);
+    }
+
+    #[test]
+    fn percentile_interpolates_between_ranks() {
+        let out = run(Stage::Percentile(field(), 50.0), 10, &[4.0, 1.0, 3.0, 2.0]);
+        assert_eq!(out.last(), Some(&Some(2.5)));
+        let out = run(Stage::Percentile(field(), 95.0), 1, &[9.0, 1.0, 3.0]);
+        assert!((out[2].unwrap() - 2.9).abs() < 1e-9);
+    }
+
+    #[test]
+    fn aligned_windows_close_in_order() {
+        let stage = Stage::Sum(field());
+        let mut agg = Aggregate::new(vec![stage], Some(&Stage::Hopping(secs(10), secs(5))));
+        for t in [1, 6, 12] {
+            agg.advance(secs(t));
+            assert_eq!(agg.push(secs(t), &[t as f64]), None);
+        }
+        // [0, 10) closed when the sample at 12s arrived; [5, 15) closes now.
+        let closed = agg.close(secs(15));
+        let bounds: Vec<_> = closed.iter().map(|r| r.bounds).collect();
+        assert_eq!(
+            bounds,
+            [Some((secs(0), secs(10))), Some((secs(5), secs(15)))]
+        );
+        assert_eq!(values(closed), [7.0, 18.0]);
+        // A late sample for windows that already closed is dropped.
+        agg.push(secs(9), &[100.0]);
+        assert_eq!(values(agg.close(secs(30))), [12.0]);
+        assert!(agg.close(secs(60)).is_empty());
+    }
+
+    #[test]
+    fn metrics_share_a_window() {
+        let stages = vec![Stage::Avg(field()), Stage::Max(field()), Stage::Count];
+        let mut agg = Aggregate::new(stages, Some(&Stage::Session(secs(5))));
+        agg.push(secs(10), &[4.0, 4.0, 1.0]);
+        agg.push(secs(12), &[8.0, 8.0, 1.0]);
+        assert!(agg.close(secs(16)).is_empty());
+        assert_eq!(
+            agg.close(secs(17)),
+            [Record {
+                bounds: Some((secs(10), secs(17))),
+                values: vec![Some(6.0), Some(8.0), Some(2.0)],
+            }]
+        );
+    }
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+}
@@ -1,66 +1,423 @@
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Axis {
     Child,
     Descendant,
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Segment {
     Literal(String),
+    /// Literal level where `*` matches any run of characters, e.g.
+    /// `sensor-*`.
+    Glob(String),
     Plus,
     Hash,
     Message,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Field {
     Header(String),
     Json(Vec<String>),
+    /// `cbor$.path`, a field of a CBOR payload read like a `json$` field.
+    /// Byte strings read as lowercase hex text and tags as the value they
+    /// wrap, so epoch and date-time timestamps are a number and a string.
+    Cbor(Vec<String>),
+    /// `pb$.path`, a field of a Protobuf payload decoded with a registered
+    /// schema. The path starts with the message type unless the message
+    /// names it in its content type.
+    Protobuf(Vec<String>),
+    /// `.`, the topic level matched by the enclosing step.
+    Segment,
+    /// `$name`, the topic level(s) bound by a `{name}` capture.
+    Capture(String),
+    /// `topic[n]`, the nth level of the topic, counting from 1.
+    TopicLevel(usize),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Operator {
     Eq,
+    Ne,
     Lt,
     Gt,
     Le,
     Ge,
+    /// Substring test on strings, membership test on JSON arrays.
+    Contains,
+    /// Set membership; the right-hand side is always a [`Value::List`].
+    In,
+    StartsWith,
+    EndsWith,
+    /// Regular expression search; the right-hand side is always a
+    /// [`Value::Regex`].
+    Matches,
 }
 
-// Value can represent numbers, booleans or strings.  The `Str` variant owns a
+// Value can represent numbers, booleans, strings or lists of those.  The `Str` variant owns a
 // `String`, which cannot implement the `Copy` trait.  Deriving `Copy` for this
 // enum therefore causes compilation to fail.  We only derive `Clone` to allow
 // duplication when needed while keeping the type non-`Copy`.
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Value {
     Number(f64),
     Bool(bool),
     Str(String),
+    List(Vec<Value>),
+    Regex(Pattern),
 }
 
-#[derive(Debug, PartialEq)]
-pub struct Predicate {
+/// Regular expression compiled once, when the selector is compiled.
+#[derive(Debug, Clone)]
+pub struct Pattern(regex::Regex);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        regex::Regex::new(pattern).map(Pattern)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn is_match(&self, haystack: &str) -> bool {
+        self.0.is_match(haystack)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Pattern {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Pattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Pattern::new(&pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Comparison {
     pub field: Field,
     pub op: Operator,
     pub value: Value,
 }
 
+/// Boolean expression inside a predicate bracket.
+///
+/// `not` binds tighter than `and`, which binds tighter than `or`. Binary
+/// operators are left-associative.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Predicate {
+    Compare(Comparison),
+    /// Bare field reference such as `[json$.battery]`, true when the field is
+    /// present.
+    Exists(Field),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+}
+
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Step {
     pub axis: Axis,
     pub segment: Segment,
+    /// Name bound to the level(s) this step consumes, written `{name}` or
+    /// `{name:segment}`.
+    pub capture: Option<String>,
     pub predicates: Vec<Predicate>,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Stage {
+    /// Sliding window over the trailing duration, re-evaluated on every
+    /// message. `sliding(d)` is an alias.
     Window(Duration),
+    /// The last N messages, written `window(100 msgs)`.
+    CountWindow(usize),
+    /// Gap-based windows: a session lasts while messages keep arriving
+    /// within the duration of each other and emits once when it closes.
+    Session(Duration),
+    /// Back-to-back windows aligned to multiples of the duration since the
+    /// Unix epoch, each emitting once when it closes.
+    Tumbling(Duration),
+    /// Windows of the first duration starting every second duration, aligned
+    /// like [`Tumbling`](Stage::Tumbling), so a message can fall into several.
+    Hopping(Duration, Duration),
     Sum(Field),
     Avg(Field),
     Count,
+    Min(Field),
+    Max(Field),
+    First(Field),
+    Last(Field),
+    /// Population standard deviation.
+    Stddev(Field),
+    /// Percentile between 0 and 100, interpolating between the closest
+    /// ranks. `median(f)` and `p95(f)` are shorthands.
+    Percentile(Field, f64),
+    /// Keeps separate pipeline state for each value of the field.
+    GroupBy(Field),
+    /// Places messages in windows by the time in the field instead of their
+    /// arrival, accepting messages up to the duration behind the newest
+    /// timestamp seen.
+    Timestamp(Field, Duration),
+    /// Messages per second over the window, or with a field, its change per
+    /// second between the oldest and newest sample.
+    Rate(Option<Field>),
+    /// Several aggregations over the same window, reported together as one
+    /// record, e.g. `agg(avg(json$.t), max(json$.t) as peak, count())`.
+    Agg(Vec<Metric>),
+    /// Replaces the payload with an object holding only the given JSON
+    /// paths.
+    Select(Vec<Vec<String>>),
+    /// Sets JSON paths to computed values, in order.
+    Map(Vec<Assignment>),
+    /// Moves the value at each first JSON path to the second.
+    Rename(Vec<(Vec<String>, Vec<String>)>),
+    /// Drops messages whose field has the same value as in the last message
+    /// let through.
+    Distinct(Field),
+    /// Lets through at most one message per duration.
+    Throttle(Duration),
+    /// Lets a message through only after the duration passed without any
+    /// message.
+    Debounce(Duration),
+    /// Lets through the first message of each interval of the duration,
+    /// aligned like [`Tumbling`](Stage::Tumbling).
+    Sample(Duration),
+    /// Keeps the records of the last aggregation whose values satisfy the
+    /// condition. Header fields name values: `value` is the last one.
+    Where(Predicate),
+    /// Keeps a record only when the named value crosses the limit, upwards
+    /// or downwards.
+    Threshold(String, f64),
+    /// Keeps a record only when the named value rises above the first limit
+    /// after being below the second, or falls below the second after being
+    /// above the first.
+    Hysteresis(String, f64, f64),
+    /// Keeps a record only when the named value, or with `None` any value,
+    /// differs from the last record kept.
+    Changed(Option<String>),
+}
+
+/// `json$.path = expr` inside a `map(...)` stage.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Assignment {
+    pub target: Vec<String>,
+    pub expr: Expr,
+}
+
+/// An expression computing a JSON value from a message.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Expr {
+    Number(f64),
+    Str(String),
+    Field(Field),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// A call to a function such as `upper` or `round`.
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinaryOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+        }
+    }
+
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Add | BinaryOp::Sub => 1,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 2,
+        }
+    }
+}
+
+/// One aggregation inside an `agg(...)` stage.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Metric {
+    pub stage: Stage,
+    /// Name given with `as`, replacing [`Stage::name`] in the output.
+    pub alias: Option<String>,
+}
+
+impl Metric {
+    /// The field name of the metric in an aggregate record.
+    pub fn name(&self) -> String {
+        self.alias.clone().unwrap_or_else(|| self.stage.name())
+    }
+}
+
+impl Stage {
+    /// The field an aggregation stage reads, if any.
+    pub fn field(&self) -> Option<&Field> {
+        match self {
+            Stage::Sum(f)
+            | Stage::Avg(f)
+            | Stage::Min(f)
+            | Stage::Max(f)
+            | Stage::First(f)
+            | Stage::Last(f)
+            | Stage::Stddev(f)
+            | Stage::Percentile(f, _)
+            | Stage::Rate(Some(f)) => Some(f),
+            Stage::Window(_)
+            | Stage::CountWindow(_)
+            | Stage::Session(_)
+            | Stage::Tumbling(_)
+            | Stage::Hopping(..)
+            | Stage::GroupBy(_)
+            | Stage::Timestamp(..)
+            | Stage::Count
+            | Stage::Rate(None)
+            | Stage::Agg(_)
+            | Stage::Select(_)
+            | Stage::Map(_)
+            | Stage::Rename(_)
+            | Stage::Distinct(_)
+            | Stage::Throttle(_)
+            | Stage::Debounce(_)
+            | Stage::Sample(_)
+            | Stage::Where(_)
+            | Stage::Threshold(..)
+            | Stage::Hysteresis(..)
+            | Stage::Changed(_) => None,
+        }
+    }
+
+    /// Whether the stage computes a value from the messages in its window.
+    pub fn is_aggregation(&self) -> bool {
+        !self.is_window()
+            && !self.is_transform()
+            && !self.is_filter()
+            && !self.is_message_filter()
+            && !matches!(self, Stage::GroupBy(_) | Stage::Timestamp(..))
+    }
+
+    /// Whether the stage rewrites the message payload.
+    pub fn is_transform(&self) -> bool {
+        matches!(self, Stage::Select(_) | Stage::Map(_) | Stage::Rename(_))
+    }
+
+    /// Whether the stage drops messages before they reach the aggregations.
+    pub fn is_message_filter(&self) -> bool {
+        matches!(
+            self,
+            Stage::Distinct(_) | Stage::Throttle(_) | Stage::Debounce(_) | Stage::Sample(_)
+        )
+    }
+
+    /// Whether the stage filters the records of the last aggregation.
+    pub fn is_filter(&self) -> bool {
+        matches!(
+            self,
+            Stage::Where(_) | Stage::Threshold(..) | Stage::Hysteresis(..) | Stage::Changed(_)
+        )
+    }
+
+    /// The function name of the stage, which also names an aggregation's
+    /// value in an aggregate record: `median` and `p95` for those
+    /// percentiles.
+    pub fn name(&self) -> String {
+        match self {
+            Stage::Window(_) | Stage::CountWindow(_) => "window",
+            Stage::Session(_) => "session",
+            Stage::Tumbling(_) => "tumbling",
+            Stage::Hopping(..) => "hopping",
+            Stage::Sum(_) => "sum",
+            Stage::Avg(_) => "avg",
+            Stage::Count => "count",
+            Stage::Min(_) => "min",
+            Stage::Max(_) => "max",
+            Stage::First(_) => "first",
+            Stage::Last(_) => "last",
+            Stage::Stddev(_) => "stddev",
+            Stage::Percentile(_, p) if *p == 50.0 => "median",
+            Stage::Percentile(_, p) if p.fract() == 0.0 && (1.0..=99.0).contains(p) => {
+                return format!("p{p}");
+            }
+            Stage::Percentile(..) => "percentile",
+            Stage::GroupBy(_) => "group_by",
+            Stage::Timestamp(..) => "timestamp",
+            Stage::Rate(_) => "rate",
+            Stage::Agg(_) => "agg",
+            Stage::Select(_) => "select",
+            Stage::Map(_) => "map",
+            Stage::Rename(_) => "rename",
+            Stage::Distinct(_) => "distinct",
+            Stage::Throttle(_) => "throttle",
+            Stage::Debounce(_) => "debounce",
+            Stage::Sample(_) => "sample",
+            Stage::Where(_) => "where",
+            Stage::Threshold(..) => "threshold",
+            Stage::Hysteresis(..) => "hysteresis",
+            Stage::Changed(_) => "changed",
+        }
+        .to_string()
+    }
+
+    /// Whether the stage sets the window for the aggregations after it.
+    pub fn is_window(&self) -> bool {
+        matches!(
+            self,
+            Stage::Window(_)
+                | Stage::CountWindow(_)
+                | Stage::Session(_)
+                | Stage::Tumbling(_)
+                | Stage::Hopping(..)
+        )
+    }
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Selector {
     pub steps: Vec<Step>,
     pub stages: Vec<Stage>,
@@ -77,35 +434,232 @@ impl fmt::Display for Selector {
                 Axis::Descendant => write!(f, "//")?,
             }
 
-            match &step.segment {
-                Segment::Literal(s) => write!(f, "{s}")?,
-                Segment::Plus => write!(f, "+")?,
-                Segment::Hash => write!(f, "#")?,
-                Segment::Message => write!(f, "msg")?,
+            let segment = match &step.segment {
+                Segment::Literal(s) => display_level(s),
+                Segment::Glob(s) => s.clone(),
+                Segment::Plus => "+".to_string(),
+                Segment::Hash => "#".to_string(),
+                Segment::Message => "msg".to_string(),
+            };
+            match (&step.capture, &step.segment) {
+                (Some(name), Segment::Plus) => write!(f, "{{{name}}}")?,
+                (Some(name), _) => write!(f, "{{{name}:{segment}}}")?,
+                (None, _) => write!(f, "{segment}")?,
             }
 
             for pred in &step.predicates {
-                write!(
-                    f,
-                    "[{}{}{}]",
-                    display_field(&pred.field),
-                    display_op(pred.op),
-                    display_value(&pred.value)
-                )?;
+                write!(f, "[{}]", display_predicate(pred))?;
             }
         }
         for stage in &self.stages {
-            match stage {
-                Stage::Window(duration) => write!(f, " |> window({}s)", duration.as_secs())?,
-                Stage::Sum(field) => write!(f, " |> sum({})", display_field(field))?,
-                Stage::Avg(field) => write!(f, " |> avg({})", display_field(field))?,
-                Stage::Count => write!(f, " |> count()")?,
-            }
+            write!(f, " |> {}", display_stage(stage))?;
         }
         Ok(())
     }
 }
 
+fn display_stage(stage: &Stage) -> String {
+    match stage {
+        Stage::Window(duration) => format!("window({})", display_duration(*duration)),
+        Stage::CountWindow(count) => format!("window({count} msgs)"),
+        Stage::Session(gap) => format!("session({})", display_duration(*gap)),
+        Stage::Tumbling(duration) => format!("tumbling({})", display_duration(*duration)),
+        Stage::Hopping(size, hop) => format!(
+            "hopping({}, {})",
+            display_duration(*size),
+            display_duration(*hop)
+        ),
+        Stage::Sum(field) => format!("sum({})", display_field(field)),
+        Stage::Avg(field) => format!("avg({})", display_field(field)),
+        Stage::Count => "count()".to_string(),
+        Stage::Min(field) => format!("min({})", display_field(field)),
+        Stage::Max(field) => format!("max({})", display_field(field)),
+        Stage::First(field) => format!("first({})", display_field(field)),
+        Stage::Last(field) => format!("last({})", display_field(field)),
+        Stage::Stddev(field) => format!("stddev({})", display_field(field)),
+        Stage::Percentile(field, p) => format!("percentile({}, {p})", display_field(field)),
+        Stage::GroupBy(field) => format!("group_by({})", display_field(field)),
+        Stage::Timestamp(field, lateness) if lateness.is_zero() => {
+            format!("timestamp({})", display_field(field))
+        }
+        Stage::Timestamp(field, lateness) => format!(
+            "timestamp({}, {})",
+            display_field(field),
+            display_duration(*lateness)
+        ),
+        Stage::Rate(None) => "rate()".to_string(),
+        Stage::Rate(Some(field)) => format!("rate({})", display_field(field)),
+        Stage::Agg(metrics) => format!(
+            "agg({})",
+            metrics
+                .iter()
+                .map(|m| match &m.alias {
+                    Some(alias) => format!("{} as {alias}", display_stage(&m.stage)),
+                    None => display_stage(&m.stage),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Stage::Select(paths) => format!(
+            "select({})",
+            paths
+                .iter()
+                .map(|p| display_json_path(p))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Stage::Map(assignments) => format!(
+            "map({})",
+            assignments
+                .iter()
+                .map(|a| format!(
+                    "{} = {}",
+                    display_json_path(&a.target),
+                    display_expr(&a.expr)
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Stage::Rename(moves) => format!(
+            "rename({})",
+            moves
+                .iter()
+                .map(|(from, to)| format!(
+                    "{} as {}",
+                    display_json_path(from),
+                    display_json_path(to)
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Stage::Distinct(field) => format!("distinct({})", display_field(field)),
+        Stage::Throttle(interval) => format!("throttle({})", display_duration(*interval)),
+        Stage::Debounce(quiet) => format!("debounce({})", display_duration(*quiet)),
+        Stage::Sample(interval) => format!("sample({})", display_duration(*interval)),
+        Stage::Where(pred) => format!("where({})", display_predicate(pred)),
+        Stage::Threshold(name, limit) if name == "value" => format!("threshold({limit})"),
+        Stage::Threshold(name, limit) => format!("threshold({name}, {limit})"),
+        Stage::Hysteresis(name, high, low) if name == "value" => {
+            format!("hysteresis({high}, {low})")
+        }
+        Stage::Hysteresis(name, high, low) => format!("hysteresis({name}, {high}, {low})"),
+        Stage::Changed(None) => "changed()".to_string(),
+        Stage::Changed(Some(name)) => format!("changed({name})"),
+    }
+}
+
+/// Writes the largest exact units first, as in `1h30m` or `1s500ms`, with
+/// anything finer than a millisecond left as a fraction of the last `ms`;
+/// every form parses back to the same duration.
+fn display_duration(duration: Duration) -> String {
+    let mut secs = duration.as_secs();
+    let nanos = duration.subsec_nanos();
+    let mut out = String::new();
+    for (unit, per_unit) in [("d", 86_400), ("h", 3_600), ("m", 60), ("s", 1)] {
+        if secs >= per_unit {
+            out += &format!("{}{unit}", secs / per_unit);
+            secs %= per_unit;
+        }
+    }
+    let (millis, rest) = (nanos / 1_000_000, nanos % 1_000_000);
+    if rest != 0 {
+        let fraction = format!("{rest:06}");
+        out += &format!("{millis}.{}ms", fraction.trim_end_matches('0'));
+    } else if millis != 0 {
+        out += &format!("{millis}ms");
+    } else if out.is_empty() {
+        out += "0s";
+    }
+    out
+}
+
+fn display_json_path(path: &[String]) -> String {
+    display_field(&Field::Json(path.to_vec()))
+}
+
+/// Renders an expression with the parentheses needed to parse back to the
+/// same tree: operands bind tighter than their operator, and right operands
+/// of equal precedence are parenthesised since operators group left.
+fn display_expr(expr: &Expr) -> String {
+    fn operand(expr: &Expr, min: u8) -> String {
+        match expr {
+            Expr::Binary(op, ..) if op.precedence() < min => format!("({})", display_expr(expr)),
+            _ => display_expr(expr),
+        }
+    }
+    match expr {
+        Expr::Number(n) => n.to_string(),
+        Expr::Str(s) => serde_json::to_string(s).expect("string serialization cannot fail"),
+        Expr::Field(field) => display_field(field),
+        Expr::Neg(inner) => format!("-{}", operand(inner, 3)),
+        Expr::Binary(op, l, r) => format!(
+            "{} {} {}",
+            operand(l, op.precedence()),
+            op.symbol(),
+            operand(r, op.precedence() + 1)
+        ),
+        Expr::Call(name, args) => format!(
+            "{name}({})",
+            args.iter().map(display_expr).collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
+
+/// Writes a literal level bare when it would parse back as the same literal,
+/// and as a quoted string otherwise.
+fn display_level(level: &str) -> String {
+    let name = level.strip_prefix('$').unwrap_or(level);
+    let bare = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        && level != "msg";
+    if bare {
+        level.to_string()
+    } else {
+        serde_json::to_string(level).expect("string serialization cannot fail")
+    }
+}
+
+fn display_predicate(pred: &Predicate) -> String {
+    match pred {
+        Predicate::Compare(cmp) => format!(
+            "{}{}{}",
+            display_field(&cmp.field),
+            display_op(cmp.op),
+            display_value(&cmp.value)
+        ),
+        Predicate::Exists(field) => display_field(field),
+        Predicate::Or(l, r) => format!(
+            "{} or {}",
+            display_predicate(l),
+            display_operand(r, |p| matches!(p, Predicate::Or(..)))
+        ),
+        Predicate::And(l, r) => format!(
+            "{} and {}",
+            display_operand(l, |p| matches!(p, Predicate::Or(..))),
+            display_operand(r, |p| matches!(p, Predicate::Or(..) | Predicate::And(..)))
+        ),
+        Predicate::Not(inner) => format!(
+            "not {}",
+            display_operand(inner, |p| matches!(
+                p,
+                Predicate::Or(..) | Predicate::And(..)
+            ))
+        ),
+    }
+}
+
+/// Renders an operand, parenthesised when `needs_parens` says its precedence
+/// or associativity would otherwise change on reparse.
+fn display_operand(pred: &Predicate, needs_parens: fn(&Predicate) -> bool) -> String {
+    if needs_parens(pred) {
+        format!("({})", display_predicate(pred))
+    } else {
+        display_predicate(pred)
+    }
+}
+
 fn display_field(fld: &Field) -> String {
     match fld {
         Field::Header(s) => s.clone(),
@@ -113,16 +667,33 @@ fn display_field(fld: &Field) -> String {
             "json${}",
             parts.iter().map(|p| format!(".{p}")).collect::<String>()
         ),
+        Field::Cbor(parts) => format!(
+            "cbor${}",
+            parts.iter().map(|p| format!(".{p}")).collect::<String>()
+        ),
+        Field::Protobuf(parts) => format!(
+            "pb${}",
+            parts.iter().map(|p| format!(".{p}")).collect::<String>()
+        ),
+        Field::Segment => ".".to_string(),
+        Field::Capture(name) => format!("${name}"),
+        Field::TopicLevel(n) => format!("topic[{n}]"),
     }
 }
 
 fn display_op(op: Operator) -> &'static str {
     match op {
         Operator::Eq => "=",
+        Operator::Ne => "!=",
         Operator::Lt => "<",
         Operator::Gt => ">",
         Operator::Le => "<=",
         Operator::Ge => ">=",
+        Operator::Contains => " contains ",
+        Operator::In => " in ",
+        Operator::StartsWith => " startsWith ",
+        Operator::EndsWith => " endsWith ",
+        Operator::Matches => "~=",
     }
 }
 
@@ -131,5 +702,16 @@ fn display_value(val: &Value) -> String {
         Value::Number(n) => n.to_string(),
         Value::Bool(b) => b.to_string(),
         Value::Str(s) => serde_json::to_string(s).expect("string serialization cannot fail"),
+        Value::List(items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .map(display_value)
+                .collect::<Vec<_>>()
+                .join(",")
+        ),
+        Value::Regex(p) => {
+            serde_json::to_string(p.as_str()).expect("string serialization cannot fail")
+        }
     }
 }
@@ -0,0 +1,268 @@
+use std::fmt;
+use std::ops::Range;
+use thiserror::Error;
+
+/// What went wrong while compiling a selector.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ErrorKind {
+    /// The selector does not follow the grammar; the message says what was
+    /// expected at that position.
+    #[error("{0}")]
+    Syntax(String),
+    #[error(transparent)]
+    ParseInt(#[from] std::num::ParseIntError),
+    #[error(transparent)]
+    ParseFloat(#[from] std::num::ParseFloatError),
+    #[error("missing selector")]
+    MissingSelector,
+    #[error("missing axis")]
+    MissingAxis,
+    #[error("missing segment")]
+    MissingSegment,
+    #[error("unknown axis {0}")]
+    UnknownAxis(String),
+    #[error("unknown wildcard {0}")]
+    UnknownWildcard(String),
+    #[error("invalid segment")]
+    InvalidSegment,
+    #[error("missing field")]
+    MissingField,
+    #[error("missing operator")]
+    MissingOperator,
+    #[error("unknown operator {0}")]
+    UnknownOperator(String),
+    #[error("missing value")]
+    MissingValue,
+    #[error("invalid value")]
+    InvalidValue,
+    #[error("in requires a list of values")]
+    InRequiresList,
+    #[error("invalid regex {pattern:?}: {message}")]
+    InvalidRegex { pattern: String, message: String },
+    #[error("capture {0} is bound more than once")]
+    DuplicateCapture(String),
+    #[error("unknown capture `${0}`")]
+    UnknownCapture(String),
+    #[error("`.` can only be used in step predicates")]
+    SegmentFieldOutsidePredicate,
+    #[error("missing function")]
+    MissingFunction,
+    #[error("missing function name")]
+    MissingFunctionName,
+    #[error("window requires duration")]
+    WindowRequiresDuration,
+    #[error("{0}")]
+    InvalidWindow(String),
+    #[error("{0}")]
+    InvalidDuration(String),
+    #[error("sum requires field")]
+    SumRequiresField,
+    #[error("avg requires field")]
+    AvgRequiresField,
+    #[error("count takes no arguments")]
+    CountTakesNoArguments,
+    #[error("{0} requires field")]
+    RequiresField(String),
+    #[error("percentile requires a rank between 0 and 100")]
+    InvalidPercentile,
+    #[error("unexpected argument to {0}")]
+    UnexpectedArgument(String),
+    #[error("{0} requires a preceding window")]
+    RequiresWindow(String),
+    #[error("topic levels are numbered from 1")]
+    InvalidTopicLevel,
+    #[error("a pipeline can only be grouped once")]
+    DuplicateGroupBy,
+    #[error("a pipeline can only have one timestamp")]
+    DuplicateTimestamp,
+    #[error("{0} requires duration")]
+    RequiresDuration(String),
+    #[error("agg takes aggregations such as avg(field)")]
+    AggRequiresAggregations,
+    #[error("`{0}` is computed more than once; name one with `as`")]
+    DuplicateName(String),
+    #[error("{0} only works on json$ fields")]
+    RequiresJsonField(String),
+    #[error("map requires assignments such as json$.f = json$.c * 1.8 + 32")]
+    MapRequiresAssignment,
+    #[error("rename requires fields such as json$.from as json$.to")]
+    RenameRequiresFields,
+    #[error("{name} takes {expected}")]
+    ArgumentCount { name: String, expected: String },
+    #[error("{0} must come before aggregations")]
+    StageAfterAggregation(String),
+    #[error("unknown function `{0}`")]
+    UnknownFunction(String),
+    #[error("{0} must follow an aggregation")]
+    RequiresAggregation(String),
+    #[error("{0} must come before where, threshold, hysteresis and changed")]
+    AggregationAfterFilter(String),
+    #[error("unknown value `{0}`; use `value` or a name the last aggregation reports")]
+    UnknownValue(String),
+    #[error("hysteresis takes the high limit first")]
+    InvalidHysteresis,
+    /// A payload field whose format moqtail-core was built without.
+    #[error("{field} fields need the `{feature}` feature of moqtail-core")]
+    FieldNotEnabled { field: String, feature: String },
+}
+
+/// A compile error located in the selector source.
+///
+/// `span` is a byte range into the selector; `line` and `column` are
+/// 1-based, with columns counted in characters. [`render`](Self::render)
+/// formats the error the same way for the CLI and the language bindings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub span: Range<usize>,
+    pub line: usize,
+    pub column: usize,
+    /// Close match for a misspelt name or keyword.
+    pub suggestion: Option<String>,
+}
+
+impl ErrorKind {
+    pub(crate) fn at(self, span: pest::Span) -> Error {
+        let (line, column) = span.start_pos().line_col();
+        Error {
+            kind: self,
+            span: span.start()..span.end(),
+            line,
+            column,
+            suggestion: None,
+        }
+    }
+}
+
+impl Error {
+    pub(crate) fn suggest<'a>(
+        mut self,
+        word: &str,
+        candidates: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        self.suggestion = closest(word, candidates).map(str::to_string);
+        self
+    }
+
+    /// Renders the error with the offending line of `input` and a caret
+    /// under the span:
+    ///
+    /// ```text
+    /// error: unknown function `avgg`, did you mean `avg`?
+    ///  --> 1:9
+    ///   |
+    /// 1 | /foo |> avgg(json$.x)
+    ///   |         ^^^^
+    /// ```
+    pub fn render(&self, input: &str) -> String {
+        // Split as pest does, so `\r\n` ends a line without shifting the
+        // byte offsets that `span` is measured in.
+        let (line_start, line_text) = input
+            .split_inclusive('\n')
+            .scan(0, |start, line| {
+                let line_start = *start;
+                *start += line.len();
+                Some((line_start, line))
+            })
+            .nth(self.line.saturating_sub(1))
+            .map_or((input.len(), ""), |(start, line)| {
+                let line = line.strip_suffix('\n').unwrap_or(line);
+                (start, line.strip_suffix('\r').unwrap_or(line))
+            });
+        let line_end = line_start + line_text.len();
+        let span_start = self.span.start.clamp(line_start, line_end);
+        let span_end = self.span.end.clamp(span_start, line_end);
+        let chars = |range: Range<usize>| input.get(range).map_or(0, |s| s.chars().count());
+        let indent = chars(line_start..span_start);
+        let width = chars(span_start..span_end).max(1);
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        format!(
+            "error: {}\n{gutter}--> {}:{}\n{gutter} |\n{number} | {line_text}\n{gutter} | {}{}",
+            self.message(),
+            self.line,
+            self.column,
+            " ".repeat(indent),
+            "^".repeat(width),
+        )
+    }
+
+    fn message(&self) -> String {
+        match &self.suggestion {
+            Some(s) => format!("{}, did you mean `{s}`?", self.kind),
+            None => self.kind.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.kind)
+    }
+}
+
+/// Picks the candidate closest to `word`, allowing roughly one edit per three
+/// characters so that short words do not match everything.
+fn closest<'a>(word: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (word.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .filter(|c| *c != word)
+        .map(|c| (edit_distance(word, c), c))
+        .filter(|(d, _)| *d <= limit)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+/// Edit distance over characters where swapping two adjacent characters
+/// counts as a single edit (optimal string alignment).
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j - 1] + cost)
+                .min(d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distance_counts_single_edits() {
+        assert_eq!(edit_distance("avg", "avg"), 0);
+        assert_eq!(edit_distance("avgg", "avg"), 1);
+        assert_eq!(edit_distance("adn", "and"), 1);
+        assert_eq!(edit_distance("wign", "wing"), 1);
+        assert_eq!(edit_distance("", "sum"), 3);
+    }
+
+    #[test]
+    fn closest_ignores_distant_candidates() {
+        assert_eq!(closest("avgg", ["sum", "avg", "count"]), Some("avg"));
+        assert_eq!(closest("contain", ["contains", "in"]), Some("contains"));
+        assert_eq!(closest("x", ["sum", "avg"]), None);
+    }
+}
@@ -0,0 +1,318 @@
+//! Running state for the stages that drop messages or records: `distinct`,
+//! `throttle`, `debounce` and `sample` before the aggregations, and `where`,
+//! `threshold`, `hysteresis` and `changed` on the records of the last one.
+
+use crate::ast::{Field, Predicate, Stage, Value};
+use crate::matcher::Matcher;
+use crate::output::AggregateRecord;
+use std::time::Duration;
+
+/// State of a stage that drops messages. Times are since the Unix epoch, in
+/// event time when the pipeline has a `timestamp()` stage.
+pub(crate) enum Gate {
+    Distinct {
+        field: Field,
+        /// The value of the field in the last message let through.
+        last: Option<String>,
+    },
+    Throttle {
+        interval: Duration,
+        /// When the last message was let through.
+        last: Option<Duration>,
+    },
+    Debounce {
+        quiet: Duration,
+        /// When the newest message arrived, let through or not.
+        last: Option<Duration>,
+    },
+    Sample {
+        interval: Duration,
+        /// The interval the last message let through fell in.
+        last: Option<u128>,
+    },
+}
+
+impl Gate {
+    /// The state for a message filter stage, `None` for other stages.
+    pub(crate) fn new(stage: &Stage) -> Option<Self> {
+        Some(match stage {
+            Stage::Distinct(field) => Gate::Distinct {
+                field: field.clone(),
+                last: None,
+            },
+            Stage::Throttle(interval) => Gate::Throttle {
+                interval: *interval,
+                last: None,
+            },
+            Stage::Debounce(quiet) => Gate::Debounce {
+                quiet: *quiet,
+                last: None,
+            },
+            Stage::Sample(interval) => Gate::Sample {
+                interval: *interval,
+                last: None,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Whether a message arriving `at` passes. `value` reads a field of the
+    /// message; messages without the `distinct` field are dropped.
+    pub(crate) fn pass(&mut self, at: Duration, value: &dyn Fn(&Field) -> Option<String>) -> bool {
+        match self {
+            Gate::Distinct { field, last } => match value(field) {
+                Some(v) if last.as_ref() != Some(&v) => {
+                    *last = Some(v);
+                    true
+                }
+                _ => false,
+            },
+            Gate::Throttle { interval, last } => {
+                if last.is_some_and(|last| at < last + *interval) {
+                    return false;
+                }
+                *last = Some(at);
+                true
+            }
+            Gate::Debounce { quiet, last } => {
+                let pass = last.is_none_or(|last| at >= last + *quiet);
+                *last = Some(last.map_or(at, |last| last.max(at)));
+                pass
+            }
+            Gate::Sample { interval, last } => {
+                let bucket = at.as_nanos() / interval.as_nanos();
+                if last.is_some_and(|last| bucket <= last) {
+                    return false;
+                }
+                *last = Some(bucket);
+                true
+            }
+        }
+    }
+}
+
+/// State of a stage that drops records of the last aggregation.
+pub(crate) enum Filter {
+    Where(Predicate),
+    /// `threshold` is a hysteresis whose limits are equal.
+    Crossing {
+        name: String,
+        high: f64,
+        low: f64,
+        /// Whether the value last rose above `high`; values start below.
+        above: bool,
+    },
+    Changed {
+        name: Option<String>,
+        /// The watched values of the last record kept.
+        last: Option<Vec<Option<f64>>>,
+    },
+}
+
+impl Filter {
+    /// The state for a filter stage, `None` for other stages.
+    pub(crate) fn new(stage: &Stage) -> Option<Self> {
+        Some(match stage {
+            Stage::Where(pred) => Filter::Where(pred.clone()),
+            Stage::Threshold(name, limit) => Filter::Crossing {
+                name: name.clone(),
+                high: *limit,
+                low: *limit,
+                above: false,
+            },
+            Stage::Hysteresis(name, high, low) => Filter::Crossing {
+                name: name.clone(),
+                high: *high,
+                low: *low,
+                above: false,
+            },
+            Stage::Changed(name) => Filter::Changed {
+                name: name.clone(),
+                last: None,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Whether `record` passes, updating the state of stateful filters.
+    /// Records whose watched value is undefined never cross a limit.
+    pub(crate) fn keep(&mut self, record: &AggregateRecord) -> bool {
+        match self {
+            Filter::Where(pred) => holds(pred, record),
+            Filter::Crossing {
+                name,
+                high,
+                low,
+                above,
+            } => match value(record, name) {
+                Some(v) if !*above && v > *high => {
+                    *above = true;
+                    true
+                }
+                Some(v) if *above && v < *low => {
+                    *above = false;
+                    true
+                }
+                _ => false,
+            },
+            Filter::Changed { name, last } => {
+                let values = match name {
+                    Some(name) => vec![value(record, name)],
+                    None => record.fields.iter().map(|(_, v)| *v).collect(),
+                };
+                if last.as_ref() == Some(&values) {
+                    return false;
+                }
+                *last = Some(values);
+                true
+            }
+        }
+    }
+}
+
+/// The value called `name`, or the last value for `value` unless the
+/// record has one by that name.
+fn value(record: &AggregateRecord, name: &str) -> Option<f64> {
+    match record.fields.iter().find(|(n, _)| n == name) {
+        Some((_, v)) => *v,
+        None if name == "value" => record.value(),
+        None => None,
+    }
+}
+
+/// Evaluates a `where` condition, whose header fields name record values.
+/// Comparisons with undefined values are false.
+fn holds(pred: &Predicate, record: &AggregateRecord) -> bool {
+    let named = |field: &Field| match field {
+        Field::Header(name) => value(record, name),
+        _ => None,
+    };
+    match pred {
+        Predicate::Compare(cmp) => match named(&cmp.field) {
+            Some(v) => Matcher::compare_values(&Value::Number(v), &cmp.value, cmp.op),
+            None => false,
+        },
+        Predicate::Exists(field) => named(field).is_some(),
+        Predicate::And(l, r) => holds(l, record) && holds(r, record),
+        Predicate::Or(l, r) => holds(l, record) || holds(r, record),
+        Predicate::Not(inner) => !holds(inner, record),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::compile;
+
+    fn gate(selector: &str) -> Gate {
+        let stages = compile(selector).unwrap().stages;
+        stages.iter().find_map(Gate::new).unwrap()
+    }
+
+    fn passed(gate: &mut Gate, millis: &[u64]) -> Vec<u64> {
+        millis
+            .iter()
+            .copied()
+            .filter(|ms| gate.pass(Duration::from_millis(*ms), &|_| None))
+            .collect()
+    }
+
+    #[test]
+    fn distinct_drops_repeated_values() {
+        let mut g = gate("/s |> distinct(state)");
+        let states = ["on", "on", "off", "on", "on"];
+        let passed: Vec<_> = states
+            .iter()
+            .filter(|state| g.pass(Duration::ZERO, &|_| Some(state.to_string())))
+            .collect();
+        assert_eq!(passed, [&"on", &"off", &"on"]);
+        assert!(!g.pass(Duration::ZERO, &|_| None));
+    }
+
+    #[test]
+    fn throttle_debounce_and_sample() {
+        let mut g = gate("/s |> throttle(1s)");
+        assert_eq!(
+            passed(&mut g, &[0, 400, 999, 1000, 1500, 2100]),
+            [0, 1000, 2100]
+        );
+
+        let mut g = gate("/s |> debounce(1s)");
+        assert_eq!(
+            passed(&mut g, &[0, 400, 1300, 2300, 2400, 5000]),
+            [0, 2300, 5000]
+        );
+
+        let mut g = gate("/s |> sample(1s)");
+        assert_eq!(
+            passed(&mut g, &[100, 900, 1000, 1999, 3500, 3000]),
+            [100, 1000, 3500]
+        );
+    }
+
+    fn filter(selector: &str) -> Filter {
+        let stages = compile(selector).unwrap().stages;
+        stages.iter().find_map(Filter::new).unwrap()
+    }
+
+    fn record(fields: &[(&str, Option<f64>)]) -> AggregateRecord {
+        AggregateRecord {
+            key: None,
+            topic: None,
+            window: None,
+            fields: fields.iter().map(|(n, v)| (n.to_string(), *v)).collect(),
+        }
+    }
+
+    fn kept(filter: &mut Filter, values: &[f64]) -> Vec<f64> {
+        values
+            .iter()
+            .copied()
+            .filter(|v| filter.keep(&record(&[("avg", Some(*v))])))
+            .collect()
+    }
+
+    #[test]
+    fn where_reads_named_values() {
+        let mut f = filter(
+            "/s |> agg(avg(json$.v), count() as n, rate(json$.v)) |> where(avg > 30 and n >= 2)",
+        );
+        assert!(f.keep(&record(&[("avg", Some(31.0)), ("n", Some(2.0))])));
+        assert!(!f.keep(&record(&[("avg", Some(31.0)), ("n", Some(1.0))])));
+
+        let mut f = filter("/s |> agg(avg(json$.v), rate(json$.v)) |> where(not rate)");
+        assert!(f.keep(&record(&[("avg", Some(1.0)), ("rate", None)])));
+
+        let mut f = filter("/s |> avg(json$.v) |> where(value in [1, 2])");
+        assert!(f.keep(&record(&[("avg", Some(2.0))])));
+        assert!(!f.keep(&record(&[("avg", None)])));
+    }
+
+    #[test]
+    fn threshold_reports_crossings() {
+        let mut f = filter("/s |> avg(json$.v) |> threshold(30)");
+        assert_eq!(
+            kept(&mut f, &[10.0, 31.0, 35.0, 30.0, 29.0, 28.0, 40.0]),
+            [31.0, 29.0, 40.0]
+        );
+    }
+
+    #[test]
+    fn hysteresis_ignores_the_band() {
+        let mut f = filter("/s |> avg(json$.v) |> hysteresis(avg, 30, 25)");
+        assert_eq!(
+            kept(&mut f, &[20.0, 31.0, 27.0, 29.0, 31.0, 24.0, 26.0, 30.5]),
+            [31.0, 24.0, 30.5]
+        );
+    }
+
+    #[test]
+    fn changed_drops_repeats() {
+        let mut f = filter("/s |> avg(json$.v) |> changed()");
+        assert_eq!(kept(&mut f, &[1.0, 1.0, 2.0, 2.0, 1.0]), [1.0, 2.0, 1.0]);
+
+        let mut f = filter("/s |> agg(max(json$.v), count()) |> changed(max)");
+        assert!(f.keep(&record(&[("max", Some(5.0)), ("count", Some(1.0))])));
+        assert!(!f.keep(&record(&[("max", Some(5.0)), ("count", Some(2.0))])));
+    }
+}
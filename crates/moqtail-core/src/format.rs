@@ -0,0 +1,120 @@
+//! Versioned interchange format for compiled selectors.
+//!
+//! A selector is stored as an envelope `{"version": 1, "selector": {...}}`
+//! where `selector` is the serde representation of [`Selector`]. Decoding
+//! checks the version and that the AST is one [`compile`] could have
+//! produced, by recompiling its `Display` form. This guarantees that
+//! `compile(s)` followed by an encode/decode round trip displays exactly like
+//! `compile(s)`.
+
+use crate::ast::Selector;
+use crate::{compile, Error};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Version written by [`Selector::to_json`] and accepted when decoding.
+pub const FORMAT_VERSION: u64 = 1;
+
+#[derive(Debug, Error)]
+pub enum FormatError {
+    #[error("missing selector format version")]
+    MissingVersion,
+    #[error("unsupported selector format version {0}")]
+    UnsupportedVersion(u64),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "cbor")]
+    #[error("invalid CBOR: {0}")]
+    Cbor(String),
+    /// The decoded AST is not one that `compile` produces from its own
+    /// `Display` output.
+    #[error("selector `{text}` is not valid: {source}")]
+    Invalid { text: String, source: Box<Error> },
+    #[error("selector `{0}` does not round-trip through its text form")]
+    NotCanonical(String),
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u64,
+    selector: &'a Selector,
+}
+
+impl Selector {
+    /// Encodes the selector in the versioned JSON format.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&Envelope {
+            version: FORMAT_VERSION,
+            selector: self,
+        })
+        .expect("selector serialization cannot fail")
+    }
+
+    /// Decodes a selector written by [`to_json`](Self::to_json).
+    pub fn from_json(json: &str) -> Result<Selector, FormatError> {
+        let mut envelope: serde_json::Value = serde_json::from_str(json)?;
+        match envelope.get("version").and_then(serde_json::Value::as_u64) {
+            Some(FORMAT_VERSION) => {}
+            Some(other) => return Err(FormatError::UnsupportedVersion(other)),
+            None => return Err(FormatError::MissingVersion),
+        }
+        let selector = Selector::deserialize(envelope["selector"].take())?;
+        canonical(selector)
+    }
+
+    /// Encodes the selector in the versioned format as CBOR.
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        ciborium::into_writer(
+            &Envelope {
+                version: FORMAT_VERSION,
+                selector: self,
+            },
+            &mut out,
+        )
+        .expect("writing to a Vec cannot fail");
+        out
+    }
+
+    /// Decodes a selector written by [`to_cbor`](Self::to_cbor).
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(bytes: &[u8]) -> Result<Selector, FormatError> {
+        let cbor = |e: &dyn std::fmt::Display| FormatError::Cbor(e.to_string());
+        let envelope: ciborium::Value = ciborium::from_reader(bytes).map_err(|e| cbor(&e))?;
+        let field = |name: &str| {
+            envelope.as_map().and_then(|entries| {
+                entries
+                    .iter()
+                    .find(|(k, _)| k.as_text() == Some(name))
+                    .map(|(_, v)| v)
+            })
+        };
+        let version = field("version")
+            .and_then(|v| v.as_integer())
+            .and_then(|v| u64::try_from(v).ok());
+        match version {
+            Some(FORMAT_VERSION) => {}
+            Some(other) => return Err(FormatError::UnsupportedVersion(other)),
+            None => return Err(FormatError::MissingVersion),
+        }
+        let selector = field("selector")
+            .ok_or_else(|| FormatError::Cbor("missing selector".into()))?
+            .deserialized::<Selector>()
+            .map_err(|e| cbor(&e))?;
+        canonical(selector)
+    }
+}
+
+/// Accepts a decoded selector only if compiling its text form gives it back.
+fn canonical(selector: Selector) -> Result<Selector, FormatError> {
+    let text = selector.to_string();
+    let recompiled = compile(&text).map_err(|source| FormatError::Invalid {
+        text: text.clone(),
+        source: Box::new(source),
+    })?;
+    if recompiled != selector {
+        return Err(FormatError::NotCanonical(text));
+    }
+    Ok(recompiled)
+}
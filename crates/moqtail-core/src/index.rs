@@ -0,0 +1,267 @@
+//! Matching one message against many selectors at once.
+//!
+//! Every selector is filed in a trie under its covering MQTT filter (see
+//! [`Selector::to_mqtt_filters`]). Walking the trie with the levels of a
+//! topic yields the few selectors that can match it, and only those are
+//! searched, so their predicates run and their payload fields are read for
+//! candidates alone.
+
+use crate::ast::Selector;
+use crate::matcher::{Matcher, Message};
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Identifies a selector in a [`SelectorIndex`]. Ids are never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SelectorId(u64);
+
+/// A set of selectors that finds every selector matching a message without
+/// trying each of them.
+#[derive(Default)]
+pub struct SelectorIndex {
+    matchers: HashMap<SelectorId, Entry>,
+    root: Node,
+    next: u64,
+}
+
+struct Entry {
+    matcher: Matcher,
+    /// The filter the selector is filed under; `None` when it can only
+    /// match the empty topic.
+    filter: Option<String>,
+}
+
+/// A level of the trie. A selector whose filter ends with `#` is filed in
+/// the node of the level before it.
+#[derive(Default)]
+struct Node {
+    literals: HashMap<String, Node>,
+    plus: Option<Box<Node>>,
+    /// Selectors whose filter ends at this level.
+    ids: Vec<SelectorId>,
+    /// Selectors whose filter continues with `#`.
+    hash: Vec<SelectorId>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.literals.is_empty()
+            && self.plus.is_none()
+            && self.ids.is_empty()
+            && self.hash.is_empty()
+    }
+
+    fn insert(&mut self, levels: &[&str], id: SelectorId) {
+        match levels {
+            [] => self.ids.push(id),
+            ["#"] => self.hash.push(id),
+            ["+", rest @ ..] => self.plus.get_or_insert_default().insert(rest, id),
+            [level, rest @ ..] => self
+                .literals
+                .entry(level.to_string())
+                .or_default()
+                .insert(rest, id),
+        }
+    }
+
+    /// Removes `id` from the path of `levels`, pruning nodes left empty.
+    fn remove(&mut self, levels: &[&str], id: SelectorId) {
+        match levels {
+            [] => self.ids.retain(|i| *i != id),
+            ["#"] => self.hash.retain(|i| *i != id),
+            ["+", rest @ ..] => {
+                if let Some(plus) = &mut self.plus {
+                    plus.remove(rest, id);
+                    if plus.is_empty() {
+                        self.plus = None;
+                    }
+                }
+            }
+            [level, rest @ ..] => {
+                if let Some(child) = self.literals.get_mut(*level) {
+                    child.remove(rest, id);
+                    if child.is_empty() {
+                        self.literals.remove(*level);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Collects the selectors whose filter matches the remaining `levels`.
+    /// Wildcards in the first level never match topics starting with `$`.
+    fn collect(&self, levels: &[&str], first: bool, out: &mut Vec<SelectorId>) {
+        let reserved = first && levels.first().is_some_and(|l| l.starts_with('$'));
+        if !reserved {
+            out.extend(&self.hash);
+        }
+        let Some((level, rest)) = levels.split_first() else {
+            out.extend(&self.ids);
+            return;
+        };
+        if let Some(child) = self.literals.get(*level) {
+            child.collect(rest, false, out);
+        }
+        if let (Some(plus), false) = (&self.plus, reserved) {
+            plus.collect(rest, false, out);
+        }
+    }
+}
+
+impl SelectorIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a selector, keeping the matcher's pipeline state for
+    /// [`accept`](Self::accept).
+    pub fn insert(&mut self, matcher: Matcher) -> SelectorId {
+        let id = SelectorId(self.next);
+        self.next += 1;
+        let filter = matcher.selector().to_mqtt_filters().pop();
+        match &filter {
+            Some(filter) => self.root.insert(&levels(filter), id),
+            None => self.root.ids.push(id),
+        }
+        self.matchers.insert(id, Entry { matcher, filter });
+        id
+    }
+
+    /// Shorthand for inserting a [`Matcher`] built from `selector`.
+    pub fn insert_selector(&mut self, selector: Selector) -> SelectorId {
+        self.insert(Matcher::new(selector))
+    }
+
+    /// Removes a selector, returning its matcher.
+    pub fn remove(&mut self, id: SelectorId) -> Option<Matcher> {
+        let entry = self.matchers.remove(&id)?;
+        match &entry.filter {
+            Some(filter) => self.root.remove(&levels(filter), id),
+            None => self.root.ids.retain(|i| *i != id),
+        }
+        Some(entry.matcher)
+    }
+
+    pub fn get(&self, id: SelectorId) -> Option<&Matcher> {
+        self.matchers.get(&id).map(|entry| &entry.matcher)
+    }
+
+    pub fn get_mut(&mut self, id: SelectorId) -> Option<&mut Matcher> {
+        self.matchers.get_mut(&id).map(|entry| &mut entry.matcher)
+    }
+
+    pub fn len(&self) -> usize {
+        self.matchers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matchers.is_empty()
+    }
+
+    /// Returns the selectors whose covering filter matches `topic`, in
+    /// ascending order. Every selector matching a message on `topic` is
+    /// among them, so callers may skip decoding the payload when there are
+    /// none.
+    pub fn candidates(&self, topic: &str) -> Vec<SelectorId> {
+        let mut ids = Vec::new();
+        if topic.is_empty() {
+            // The empty topic has no level for a filter to match, but `#`
+            // selectors such as `//sensor` may still match it.
+            ids.extend(&self.root.ids);
+            ids.extend(&self.root.hash);
+        } else {
+            self.root.collect(&levels(topic), true, &mut ids);
+        }
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Returns the selectors matching `msg`, in ascending order.
+    pub fn matches(&self, msg: &Message) -> Vec<SelectorId> {
+        let mut ids = self.candidates(msg.topic);
+        ids.retain(|id| self.matchers[id].matcher.matches(msg));
+        ids
+    }
+
+    /// Returns the selectors accepting `msg`, in ascending order; see
+    /// [`Matcher::accept`]. Every candidate sees the message, so the
+    /// `distinct`, `throttle`, `debounce` and `sample` stages of all
+    /// matching selectors update their state.
+    pub fn accept(&mut self, msg: &Message, timestamp: Instant) -> Vec<SelectorId> {
+        let mut ids = self.candidates(msg.topic);
+        ids.retain(|id| {
+            let entry = self.matchers.get_mut(id).expect("indexed selector");
+            entry.matcher.accept(msg, timestamp)
+        });
+        ids
+    }
+}
+
+fn levels(topic: &str) -> Vec<&str> {
+    topic.split('/').collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+    use crate::payload::Payload;
+
+    fn index(selectors: &[&str]) -> (SelectorIndex, Vec<SelectorId>) {
+        let mut index = SelectorIndex::new();
+        let ids = selectors
+            .iter()
+            .map(|s| index.insert_selector(compile(s).unwrap()))
+            .collect();
+        (index, ids)
+    }
+
+    fn msg(topic: &str) -> Message<'_> {
+        Message {
+            topic,
+            headers: HashMap::new(),
+            payload: Payload::None,
+        }
+    }
+
+    #[test]
+    fn candidates_follow_mqtt_filter_rules() {
+        let (index, ids) = index(&["/a/b", "/a/+", "/a/#", "/+/b", "//b", "/\"$SYS\"/b"]);
+        let pick = |idx: &[usize]| idx.iter().map(|i| ids[*i]).collect::<Vec<_>>();
+        assert_eq!(index.candidates("a/b"), pick(&[0, 1, 2, 3, 4]));
+        assert_eq!(index.candidates("a"), pick(&[2, 4]));
+        assert_eq!(index.candidates("c/b"), pick(&[3, 4]));
+        assert_eq!(index.candidates("a/b/c"), pick(&[2, 4]));
+        assert_eq!(index.candidates("$SYS/b"), pick(&[5]));
+        assert_eq!(index.candidates(""), pick(&[4]));
+    }
+
+    #[test]
+    fn only_candidates_that_match_are_returned() {
+        let (index, ids) = index(&["/a/b", "/a/sensor-*", "//b", "/a/+[json$.on=true]"]);
+        assert_eq!(index.matches(&msg("a/b")), [ids[0], ids[2]]);
+        assert_eq!(index.matches(&msg("a/sensor-1")), [ids[1]]);
+        assert_eq!(index.matches(&msg("x/y/b")), [ids[2]]);
+        assert!(index.matches(&msg("x/y")).is_empty());
+    }
+
+    #[test]
+    fn removed_selectors_no_longer_match() {
+        let (mut index, ids) = index(&["/a/+", "/a/+/c"]);
+        assert!(index.remove(ids[0]).is_some());
+        assert!(index.remove(ids[0]).is_none());
+        assert!(index.matches(&msg("a/b")).is_empty());
+        assert_eq!(index.matches(&msg("a/b/c")), [ids[1]]);
+        assert!(index.remove(ids[1]).is_some());
+        assert!(index.is_empty() && index.root.is_empty());
+    }
+
+    #[test]
+    fn accept_runs_every_candidate_pipeline() {
+        let (mut index, ids) = index(&["/a/+ |> throttle(1s)", "/a/b"]);
+        let now = Instant::now();
+        assert_eq!(index.accept(&msg("a/b"), now), ids);
+        assert_eq!(index.accept(&msg("a/b"), now), [ids[1]]);
+        assert_eq!(index.accept(&msg("a/c"), now), []);
+    }
+}
@@ -1,11 +1,31 @@
 //! Core library for MoQtail
 
+mod aggregate;
 pub mod ast;
+mod error;
+mod filter;
+#[cfg(feature = "serde")]
+pub mod format;
+mod index;
 mod matcher;
+mod output;
 mod parser;
+mod partition;
+mod payload;
+mod planner;
+#[cfg(feature = "pb")]
+mod schema;
+mod timestamp;
+mod transform;
 
-pub use matcher::{Matcher, Message};
-pub use parser::{compile, Error};
+pub use error::{Error, ErrorKind};
+pub use index::{SelectorId, SelectorIndex};
+pub use matcher::{Late, Matcher, Message};
+pub use output::{AggregateRecord, PipelineOutput, WindowBounds};
+pub use parser::compile;
+pub use payload::{Payload, RawPayload};
+#[cfg(feature = "pb")]
+pub use schema::{SchemaError, Schemas};
 
 #[cfg(test)]
 mod tests {
@@ -1,9 +1,23 @@
-use crate::ast::{Axis, Field, Operator, Predicate, Segment, Selector, Stage, Step, Value};
+use crate::aggregate::{Aggregate, Record};
+use crate::ast::{
+    Axis, Comparison, Field, Operator, Predicate, Segment, Selector, Stage, Step, Value,
+};
+use crate::filter::{Filter, Gate};
+use crate::output::{AggregateRecord, PipelineOutput, WindowBounds};
+use crate::partition::Partitions;
+use crate::payload::{Format, Payload, Reads};
+#[cfg(feature = "pb")]
+use crate::schema::Schemas;
+use crate::timestamp;
+use crate::transform;
 use serde_json::Value as JsonValue;
 use std::borrow::Cow;
+use std::cell::RefCell;
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::time::{Duration, Instant};
+use std::collections::HashMap;
+#[cfg(feature = "pb")]
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const ABS_EPS: f64 = 1e-12;
 const REL_EPS: f64 = 1e-9;
@@ -11,371 +25,1317 @@ const REL_EPS: f64 = 1e-9;
 pub struct Message<'a> {
     pub topic: &'a str,
     pub headers: HashMap<Cow<'a, str>, Cow<'a, str>>,
-    pub payload: Option<JsonValue>,
+    pub payload: Payload<'a>,
 }
 
-enum StageState {
-    Window {
-        duration: Option<Duration>,
-        values: VecDeque<(Instant, f64)>,
-        sum: f64,
-    },
-    Counter {
-        duration: Option<Duration>,
-        timestamps: VecDeque<Instant>,
-    },
+/// A message whose event time is behind the watermark, returned by
+/// [`Matcher::process_output`] and [`Matcher::try_process`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Late {
+    pub event_time: SystemTime,
+    pub watermark: SystemTime,
+}
+
+/// How far a message got through a pipeline.
+enum Outcome {
+    /// The message did not match, lacked a field or was dropped by a stage.
+    Dropped,
+    /// The message passed the stages before the aggregations, which it was
+    /// not fed to.
+    Admitted,
+    Output(PipelineOutput),
 }
 
 pub struct Matcher {
     selector: Selector,
-    stage_states: Vec<StageState>,
+    /// Message filters, aggregates and record filters for each `group_by`
+    /// key.
+    partitions: Partitions<State>,
+    /// Records of windows closed early because their key was evicted, handed
+    /// out by the next [`tick_output`](Self::tick_output).
+    evicted: Vec<AggregateRecord>,
+    /// Field names of the records of the last aggregation stage; empty when
+    /// matching messages pass through.
+    fields: Vec<String>,
+    /// Whether `select`, `map` or `rename` rewrite the payload.
+    transforms: bool,
+    /// Whether `distinct`, `throttle`, `debounce` or `sample` drop messages.
+    gates: bool,
+    group_by: Option<Field>,
+    /// The `timestamp()` field and allowed lateness, for event time.
+    event_time: Option<(Field, Duration)>,
+    /// With event time, windows ending at or before this have closed and
+    /// older messages are late. Never moves backwards.
+    watermark: Duration,
+    /// How topics are walked.
+    plan: Plan,
+    /// The payload paths the selector reads.
+    reads: Reads,
+    /// Reads of whole payloads, for messages passed through.
+    whole: Reads,
+    /// An instant and the wall-clock time since the Unix epoch it
+    /// corresponds to, used to align tumbling and hopping windows.
+    clock: (Instant, Duration),
 }
 
-fn json_path<'a>(root: &'a JsonValue, path: &[String]) -> Option<&'a JsonValue> {
-    let mut cur = root;
-    for part in path {
-        cur = cur.get(part)?;
+/// What a predicate can read: the message, the topic level(s) consumed by
+/// the enclosing step and the captures bound so far.
+struct Scope<'s, 'a> {
+    msg: &'s Message<'a>,
+    /// The payload paths the matcher reads, decoded together.
+    reads: &'s Reads,
+    segment: Option<&'s str>,
+    captures: &'s [(&'s str, &'s str)],
+}
+
+impl<'s> Scope<'s, '_> {
+    /// Text of a header, `.` or `$capture` field.
+    fn text(&self, field: &Field) -> Option<&'s str> {
+        match field {
+            Field::Header(name) => self.msg.headers.get(name.as_str()).map(|v| v.as_ref()),
+            Field::Segment => self.segment,
+            Field::Capture(name) => self
+                .captures
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| *v),
+            Field::TopicLevel(n) => self.msg.topic.split('/').nth(n - 1),
+            Field::Json(_) | Field::Cbor(_) | Field::Protobuf(_) => None,
+        }
+    }
+
+    /// Value of a `json$`, `cbor$` or `pb$` field.
+    fn payload(&self, field: &Field) -> Option<&'s JsonValue> {
+        payload_field(self.msg, self.reads, field)
     }
-    Some(cur)
 }
 
-impl Matcher {
-    pub fn new(selector: Selector) -> Self {
-        let mut window_duration = None;
-        let mut stage_states = Vec::new();
-        for stage in &selector.stages {
-            match stage {
-                Stage::Window(duration) => {
-                    window_duration = Some(*duration);
-                }
-                Stage::Sum(_) | Stage::Avg(_) => {
-                    stage_states.push(StageState::Window {
-                        duration: window_duration,
-                        values: VecDeque::new(),
-                        sum: 0.0,
-                    });
-                }
-                Stage::Count => {
-                    stage_states.push(StageState::Counter {
-                        duration: window_duration,
-                        timestamps: VecDeque::new(),
-                    });
+/// How [`Matcher`] walks a topic, chosen once when it is created.
+enum Plan {
+    /// Only child steps, with `#` at most as the last one consuming levels,
+    /// and no predicate reading captures. Every step consumes a known number
+    /// of levels, so one pass over the topic decides without allocating.
+    Linear,
+    /// Descendant axes and `#` followed by more levels run the steps
+    /// compiled into an [`Nfa`].
+    Nfa(Nfa),
+}
+
+impl Plan {
+    fn new(steps: &[Step]) -> Self {
+        let memoize = !steps
+            .iter()
+            .flat_map(|step| &step.predicates)
+            .any(reads_captures);
+        let last_level = steps
+            .iter()
+            .rposition(|step| step.segment != Segment::Message);
+        let linear = steps.iter().enumerate().all(|(idx, step)| {
+            step.axis == Axis::Child && (step.segment != Segment::Hash || Some(idx) == last_level)
+        });
+        if linear && memoize {
+            Plan::Linear
+        } else {
+            Plan::Nfa(Nfa::new(steps, memoize))
+        }
+    }
+
+    fn captures<'s>(
+        &self,
+        steps: &'s [Step],
+        reads: &'s Reads,
+        msg: &'s Message,
+    ) -> Option<Vec<(&'s str, &'s str)>> {
+        match self {
+            Plan::Linear => {
+                let mut captures = Vec::new();
+                linear(steps, reads, msg, Some(&mut captures)).then_some(captures)
+            }
+            Plan::Nfa(nfa) => nfa.captures(steps, reads, msg),
+        }
+    }
+}
+
+/// Matches `msg` against steps planned as [`Plan::Linear`], pushing the
+/// captures onto `captures` when given.
+fn linear<'s>(
+    steps: &'s [Step],
+    reads: &'s Reads,
+    msg: &'s Message,
+    mut captures: Option<&mut Vec<(&'s str, &'s str)>>,
+) -> bool {
+    let topic = msg.topic;
+    // As in `Nfa`, wildcards cannot consume a `$`-prefixed first level.
+    let dollar = topic.starts_with('$');
+    let mut rest = (!topic.is_empty()).then_some(topic);
+    let mut first = true;
+    for step in steps {
+        let segment = match &step.segment {
+            Segment::Message => None,
+            Segment::Hash if dollar && first => return false,
+            Segment::Hash => Some(rest.take().unwrap_or("")),
+            segment => {
+                let Some(text) = rest else {
+                    return false;
+                };
+                let (level, remaining) = match text.split_once('/') {
+                    Some((level, remaining)) => (level, Some(remaining)),
+                    None => (text, None),
+                };
+                let wildcard_ok = !(dollar && first);
+                if !(wildcard_ok || matches!(segment, Segment::Literal(_)))
+                    || !segment_matches(segment, level)
+                {
+                    return false;
                 }
+                rest = remaining;
+                first = false;
+                Some(level)
             }
+        };
+        if let (Some(captures), Some(name), Some(text)) = (&mut captures, &step.capture, segment) {
+            captures.push((name, text));
         }
-        Self {
-            selector,
-            stage_states,
+        let scope = Scope {
+            msg,
+            reads,
+            segment,
+            captures: &[],
+        };
+        if !Matcher::predicates_match(&step.predicates, &scope) {
+            return false;
         }
     }
+    rest.is_none()
+}
 
-    pub fn matches(&self, msg: &Message) -> bool {
-        let segments: Vec<&str> = if msg.topic.is_empty() {
-            Vec::new()
-        } else {
-            msg.topic.split('/').collect()
-        };
-        Self::match_steps(&self.selector.steps, &segments, msg)
+/// A state of an [`Nfa`]. `Level` and `Any` consume a level and move to
+/// their successor on the next one; the others are followed on the same
+/// level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Node {
+    /// Consumes a level matched by the segment of step `step`, binding its
+    /// capture and checking its predicates.
+    Level {
+        step: usize,
+        next: usize,
+    },
+    /// Consumes any level but a `$`-prefixed first one, for descendant axes
+    /// and `#`.
+    Any {
+        next: usize,
+    },
+    /// Follows the first state, then the second: the earlier path wins.
+    Split(usize, usize),
+    /// Notes the level at which a `#` step starts.
+    Mark {
+        next: usize,
+    },
+    /// Binds the capture and checks the predicates of step `step`, either a
+    /// `#` over the levels since the mark or a `msg` step.
+    Check {
+        step: usize,
+        next: usize,
+    },
+    Accept,
+}
+
+/// Steps compiled into a state table, run over the topic levels in one
+/// pass.
+///
+/// A descendant axis becomes a [`Split`](Node::Split) between its step and
+/// an [`Any`](Node::Any) looping back to it, and `#` a loop that checks the
+/// step before consuming each further level. Every level advances all live
+/// threads at once, in the order of the paths they follow, so the first
+/// thread to accept is the path a depth-first walk would find first:
+/// descendant axes and `#` consume as few levels as possible. Threads in the
+/// same state have the same future, so only the first one is kept and
+/// matching takes `O(states * levels)` predicate checks. Predicates of `#`
+/// steps read the levels since the mark, which then becomes part of the
+/// state. When predicates read captures the future depends on them too, and
+/// threads are only merged when all their captures agree.
+///
+/// Without predicates on `#` or `msg` steps, the states a thread reaches
+/// without consuming a level do not depend on the message.
+/// [`matches`](Self::matches) then needs neither threads nor their order:
+/// the live states of a level are a bit set, and each consumed level ORs in
+/// the precomputed closure of the state it moves to.
+struct Nfa {
+    nodes: Vec<Node>,
+    /// Index of each step's capture among the capture slots.
+    captures: Vec<Option<usize>>,
+    /// Whether captures are left out of the state.
+    memoize: bool,
+    /// Whether the mark is part of the state in each node, those inside `#`
+    /// steps with predicates.
+    marked: Vec<bool>,
+    /// Slots per thread binding captures.
+    width: usize,
+    /// For each node, the set of states a thread entering it reaches
+    /// without consuming a level, when that set is fixed.
+    closures: Option<Vec<u64>>,
+}
+
+/// Threads of an [`Nfa`]: a state and its slots each. The first slot is
+/// the byte offset of the mark, the others the bounds of the captures.
+#[derive(Default)]
+struct Threads {
+    nodes: Vec<usize>,
+    slots: Vec<usize>,
+}
+
+impl Threads {
+    fn clear(&mut self) {
+        self.nodes.clear();
+        self.slots.clear();
     }
 
-    /// Runs the post-match processing stages on a message.
-    ///
-    /// Each stage is evaluated sequentially once [`matches`](Self::matches) returns
-    /// `true`. Windowing stages configure the trailing [`Duration`] used by
-    /// subsequent aggregations. Aggregation stages (`sum`, `avg`, `count`) retain
-    /// timestamped samples and evict entries whose age exceeds that duration.
-    /// Without a configured window, aggregations are evaluated from only the
-    /// current message. Missing fields cause processing to short-circuit with
-    /// `None`.
-    ///
-    /// `sum` and `avg` maintain running totals so they execute in `O(1)` time per
-    /// message, while `count` remains proportional to the number of retained
-    /// samples. Expired entries are removed from the front of the deque in
-    /// amortized constant time. Empty topics are handled by
-    /// [`matches`](Self::matches) yielding `false` before processing, so `process`
-    /// only runs on matching topics.
-    pub fn process(&mut self, msg: &Message, timestamp: Instant) -> Option<f64> {
-        if !self.matches(msg) {
-            return None;
+    fn push(&mut self, node: usize, slots: &[usize]) {
+        self.nodes.push(node);
+        self.slots.extend_from_slice(slots);
+    }
+}
+
+/// Buffers reused by [`Nfa`] so that, once they have grown, matching does
+/// not allocate.
+#[derive(Default)]
+struct Scratch {
+    current: Threads,
+    next: Threads,
+    /// Level at which each state was last entered.
+    seen: Vec<usize>,
+    /// States entered on this level that include slots, each a node
+    /// followed by its slots.
+    keys: Vec<usize>,
+    slots: Vec<usize>,
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::default();
+}
+
+/// Calls `f` with the thread's [`Scratch`], or fresh buffers when a
+/// predicate matches another message while they are in use.
+fn with_scratch<T>(f: impl FnOnce(&mut Scratch) -> T) -> T {
+    SCRATCH.with(|scratch| match scratch.try_borrow_mut() {
+        Ok(mut scratch) => f(&mut scratch),
+        Err(_) => f(&mut Scratch::default()),
+    })
+}
+
+impl Nfa {
+    fn new(steps: &[Step], memoize: bool) -> Self {
+        let mut nodes = Vec::new();
+        let mut marked = Vec::new();
+        let mut captures = Vec::new();
+        let mut slot = 0;
+        for (idx, step) in steps.iter().enumerate() {
+            let at = nodes.len();
+            if step.axis == Axis::Descendant {
+                nodes.extend([Node::Split(at + 2, at + 1), Node::Any { next: at }]);
+            }
+            let at = nodes.len();
+            match step.segment {
+                Segment::Message => nodes.push(Node::Check {
+                    step: idx,
+                    next: at + 1,
+                }),
+                Segment::Hash => nodes.extend([
+                    Node::Mark { next: at + 1 },
+                    Node::Split(at + 2, at + 3),
+                    Node::Check {
+                        step: idx,
+                        next: at + 4,
+                    },
+                    Node::Any { next: at + 1 },
+                ]),
+                _ => nodes.push(Node::Level {
+                    step: idx,
+                    next: at + 1,
+                }),
+            }
+            let spans = step.segment == Segment::Hash && !step.predicates.is_empty();
+            marked.resize(at, false);
+            marked.resize(nodes.len(), spans);
+            marked[at] = false;
+            let capture = step.capture.is_some() && step.segment != Segment::Message;
+            captures.push(capture.then(|| {
+                slot += 1;
+                slot - 1
+            }));
         }
-        let mut result = None;
-        let mut state_idx = 0;
-        for stage in &self.selector.stages {
-            match stage {
-                Stage::Window(_) => {}
-                Stage::Sum(field) => {
-                    if let StageState::Window {
-                        duration,
-                        values,
-                        sum,
-                    } = &mut self.stage_states[state_idx]
-                    {
-                        let v = Self::extract_field(field, msg)?;
-                        match duration {
-                            Some(duration) => {
-                                values.push_back((timestamp, v));
-                                *sum += v;
-                                *sum -= Self::prune_values(values, *duration, timestamp);
-                                result = Some(*sum);
-                            }
-                            None => {
-                                values.clear();
-                                values.push_back((timestamp, v));
-                                *sum = v;
-                                result = Some(v);
-                            }
+        nodes.push(Node::Accept);
+        marked.resize(nodes.len(), false);
+        let fixed = memoize
+            && nodes.len() <= u64::BITS as usize
+            && nodes.iter().all(|node| match node {
+                Node::Check { step, .. } => steps[*step].predicates.is_empty(),
+                _ => true,
+            });
+        let closures = fixed.then(|| (0..nodes.len()).map(|idx| closure(&nodes, idx)).collect());
+        Self {
+            nodes,
+            captures,
+            memoize,
+            marked,
+            width: 1 + 2 * slot,
+            closures,
+        }
+    }
+
+    fn matches(&self, steps: &[Step], reads: &Reads, msg: &Message) -> bool {
+        let Some(closures) = &self.closures else {
+            return with_scratch(|scratch| self.run(scratch, steps, reads, msg, false).is_some());
+        };
+        let topic = msg.topic;
+        let dollar = topic.starts_with('$');
+        let mut states = closures[0];
+        let levels = topic.split('/').filter(|_| !topic.is_empty());
+        for (idx, level) in levels.enumerate() {
+            let wildcard_ok = !(dollar && idx == 0);
+            let mut next = 0;
+            let mut live = states;
+            while live != 0 {
+                let node = live.trailing_zeros() as usize;
+                live &= live - 1;
+                match self.nodes[node] {
+                    Node::Level { step, next: to } => {
+                        let scope = Scope {
+                            msg,
+                            reads,
+                            segment: Some(level),
+                            captures: &[],
+                        };
+                        if level_matches(&steps[step], level, wildcard_ok)
+                            && Matcher::predicates_match(&steps[step].predicates, &scope)
+                        {
+                            next |= closures[to];
                         }
                     }
-                    state_idx += 1;
+                    Node::Any { next: to } if wildcard_ok => next |= closures[to],
+                    _ => {}
                 }
-                Stage::Avg(field) => {
-                    if let StageState::Window {
-                        duration,
-                        values,
-                        sum,
-                    } = &mut self.stage_states[state_idx]
-                    {
-                        let v = Self::extract_field(field, msg)?;
-                        match duration {
-                            Some(duration) => {
-                                values.push_back((timestamp, v));
-                                *sum += v;
-                                *sum -= Self::prune_values(values, *duration, timestamp);
-                                let len = values.len();
-                                result = if len == 0 {
-                                    Some(0.0)
-                                } else {
-                                    Some(*sum / len as f64)
-                                };
-                            }
-                            None => {
-                                values.clear();
-                                values.push_back((timestamp, v));
-                                *sum = v;
-                                result = Some(v);
-                            }
+            }
+            if next == 0 {
+                return false;
+            }
+            states = next;
+        }
+        states & 1 << (self.nodes.len() - 1) != 0
+    }
+
+    /// Returns the captures of the first path through the topic that
+    /// satisfies the steps, or `None` when none does.
+    fn captures<'s>(
+        &self,
+        steps: &'s [Step],
+        reads: &'s Reads,
+        msg: &'s Message,
+    ) -> Option<Vec<(&'s str, &'s str)>> {
+        with_scratch(|scratch| {
+            let slots = self.run(scratch, steps, reads, msg, true)?;
+            Some(self.bound(steps, msg.topic, slots, steps.len()))
+        })
+    }
+
+    /// Runs the threads over every level of the topic, returning the slots
+    /// of the first one to accept. Threads only carry the slots they need:
+    /// none when matching without `capture` unless the state includes them.
+    fn run<'r>(
+        &self,
+        scratch: &'r mut Scratch,
+        steps: &[Step],
+        reads: &Reads,
+        msg: &Message,
+        capture: bool,
+    ) -> Option<&'r [usize]> {
+        let Scratch {
+            current,
+            next,
+            seen,
+            keys,
+            slots,
+        } = scratch;
+        let topic = msg.topic;
+        let width = match (capture || !self.memoize, self.marked.contains(&true)) {
+            (true, _) => self.width,
+            (false, true) => 1,
+            (false, false) => 0,
+        };
+        let mut vm = Vm {
+            nfa: self,
+            steps,
+            reads,
+            msg,
+            seen,
+            keys,
+            capture: width > 1,
+            level: 0,
+            at: 0,
+        };
+        vm.seen.clear();
+        vm.seen.resize(self.nodes.len(), usize::MAX);
+        vm.keys.clear();
+        slots.clear();
+        slots.resize(width, 0);
+        current.clear();
+        vm.add(current, 0, slots);
+        // As in MQTT, a `$`-prefixed first level (e.g. `$SYS`) is only
+        // matched by a literal: wildcards, globs and descendant axes cannot
+        // consume it.
+        let dollar = topic.starts_with('$');
+        let levels = topic.split('/').filter(|_| !topic.is_empty());
+        for level in levels {
+            let wildcard_ok = !(dollar && vm.level == 0);
+            let start = vm.at;
+            vm.level += 1;
+            vm.at += level.len() + 1;
+            vm.keys.clear();
+            next.clear();
+            for (idx, &node) in current.nodes.iter().enumerate() {
+                let slots = &mut current.slots[idx * width..(idx + 1) * width];
+                match self.nodes[node] {
+                    Node::Level { step, next: to } => {
+                        if !level_matches(&steps[step], level, wildcard_ok) {
+                            continue;
                         }
-                    }
-                    state_idx += 1;
-                }
-                Stage::Count => {
-                    if let StageState::Counter {
-                        duration,
-                        timestamps,
-                    } = &mut self.stage_states[state_idx]
-                    {
-                        match duration {
-                            Some(duration) => {
-                                timestamps.push_back(timestamp);
-                                Self::prune_timestamps(timestamps, *duration, timestamp);
-                                result = Some(timestamps.len() as f64);
-                            }
-                            None => {
-                                timestamps.clear();
-                                timestamps.push_back(timestamp);
-                                result = Some(1.0);
-                            }
+                        if let Some(capture) = self.captures[step].filter(|_| vm.capture) {
+                            slots[1 + 2 * capture] = start;
+                            slots[2 + 2 * capture] = start + level.len();
+                        }
+                        if vm.check(step, Some(level), slots) {
+                            vm.add(next, to, slots);
                         }
                     }
-                    state_idx += 1;
+                    Node::Any { next: to } if wildcard_ok => vm.add(next, to, slots),
+                    _ => {}
                 }
             }
+            std::mem::swap(current, next);
+            if current.nodes.is_empty() {
+                return None;
+            }
         }
-        result
+        let idx = current
+            .nodes
+            .iter()
+            .position(|&node| self.nodes[node] == Node::Accept)?;
+        Some(&current.slots[idx * width..(idx + 1) * width])
+    }
+
+    /// The captures bound in `slots` by the steps before `end`, in step
+    /// order.
+    fn bound<'s>(
+        &self,
+        steps: &'s [Step],
+        topic: &'s str,
+        slots: &[usize],
+        end: usize,
+    ) -> Vec<(&'s str, &'s str)> {
+        steps[..end]
+            .iter()
+            .zip(&self.captures)
+            .filter_map(|(step, capture)| {
+                let capture = (*capture)?;
+                let text = &topic[slots[1 + 2 * capture]..slots[2 + 2 * capture]];
+                Some((step.capture.as_deref()?, text))
+            })
+            .collect()
+    }
+}
+
+/// The set of consuming and accepting states reached from `node` without
+/// consuming a level, for an [`Nfa`] without predicates on `#` or `msg`
+/// steps.
+fn closure(nodes: &[Node], node: usize) -> u64 {
+    match nodes[node] {
+        Node::Split(first, second) => closure(nodes, first) | closure(nodes, second),
+        Node::Mark { next } | Node::Check { next, .. } => closure(nodes, next),
+        Node::Level { .. } | Node::Any { .. } | Node::Accept => 1 << node,
     }
+}
+
+/// Whether `step` can consume `level`: wildcards and globs cannot consume a
+/// `$`-prefixed first level, so `wildcard_ok` is unset for it.
+fn level_matches(step: &Step, level: &str, wildcard_ok: bool) -> bool {
+    (wildcard_ok || matches!(step.segment, Segment::Literal(_)))
+        && segment_matches(&step.segment, level)
+}
 
-    fn prune_values(
-        values: &mut VecDeque<(Instant, f64)>,
-        duration: Duration,
-        now: Instant,
-    ) -> f64 {
-        let mut removed_sum = 0.0;
-        while let Some((ts, _)) = values.front() {
-            if now.saturating_duration_since(*ts) > duration {
-                if let Some((_, value)) = values.pop_front() {
-                    removed_sum += value;
+/// One run of an [`Nfa`] over a message.
+struct Vm<'s, 'a, 'b> {
+    nfa: &'s Nfa,
+    steps: &'s [Step],
+    reads: &'s Reads,
+    msg: &'s Message<'a>,
+    seen: &'b mut Vec<usize>,
+    keys: &'b mut Vec<usize>,
+    /// Whether threads bind captures.
+    capture: bool,
+    /// Levels consumed so far.
+    level: usize,
+    /// Byte offset of the next level, one past the end of the topic once
+    /// all are consumed.
+    at: usize,
+}
+
+impl<'s> Vm<'s, '_, '_> {
+    /// Adds a thread entering `node` to `threads`, following the states
+    /// that do not consume a level.
+    fn add(&mut self, threads: &mut Threads, node: usize, slots: &mut [usize]) {
+        if !self.enter(node, slots) {
+            return;
+        }
+        match self.nfa.nodes[node] {
+            Node::Split(first, second) => {
+                self.add(threads, first, slots);
+                self.add(threads, second, slots);
+            }
+            Node::Mark { next } if slots.is_empty() => self.add(threads, next, slots),
+            Node::Mark { next } => {
+                let at = self.at.min(self.msg.topic.len());
+                let mark = std::mem::replace(&mut slots[0], at);
+                self.add(threads, next, slots);
+                slots[0] = mark;
+            }
+            Node::Check { step, next } => {
+                // Without slots, the step has no predicates to read the levels.
+                let segment = match self.steps[step].segment {
+                    Segment::Message => None,
+                    _ => slots.first().map(|&mark| self.span(mark)),
+                };
+                let capture = self.nfa.captures[step].filter(|_| self.capture);
+                let capture = capture.map(|capture| {
+                    let bounds = (1 + 2 * capture, 2 + 2 * capture);
+                    let old = (slots[bounds.0], slots[bounds.1]);
+                    let end = self.at.saturating_sub(1).max(slots[0]);
+                    slots[bounds.0] = slots[0];
+                    slots[bounds.1] = end;
+                    (bounds, old)
+                });
+                if self.check(step, segment, slots) {
+                    self.add(threads, next, slots);
+                }
+                if let Some((bounds, old)) = capture {
+                    slots[bounds.0] = old.0;
+                    slots[bounds.1] = old.1;
                 }
-            } else {
-                break;
             }
+            Node::Level { .. } | Node::Any { .. } | Node::Accept => threads.push(node, slots),
         }
-        removed_sum
     }
 
-    fn prune_timestamps(timestamps: &mut VecDeque<Instant>, duration: Duration, now: Instant) {
-        while let Some(ts) = timestamps.front() {
-            if now.saturating_duration_since(*ts) > duration {
-                timestamps.pop_front();
-            } else {
-                break;
+    /// Returns `false` when an earlier thread on this level already entered
+    /// the state of `node` with `slots`.
+    fn enter(&mut self, node: usize, slots: &[usize]) -> bool {
+        let key = match (self.nfa.memoize, self.nfa.marked[node]) {
+            (true, false) => {
+                let fresh = self.seen[node] != self.level;
+                self.seen[node] = self.level;
+                return fresh;
             }
+            (true, true) => &slots[..1],
+            (false, _) => slots,
+        };
+        let stride = 1 + key.len();
+        if self
+            .keys
+            .chunks(stride)
+            .any(|entry| entry[0] == node && entry[1..] == *key)
+        {
+            return false;
+        }
+        self.keys.push(node);
+        self.keys.extend_from_slice(key);
+        true
+    }
+
+    /// The levels since `mark`, joined by `/`.
+    fn span(&self, mark: usize) -> &'s str {
+        if mark >= self.at {
+            return "";
         }
+        &self.msg.topic[mark..self.at - 1]
     }
 
-    /// Iteratively traverses the selector steps against the topic segments.
-    ///
-    /// A manual stack stores pairs of `(step_index, topic_index)` representing
-    /// the traversal state. This avoids recursion and allows exploring multiple
-    /// branches introduced by wildcards and descendant axes. The algorithm
-    /// short-circuits once a full match is found.
-    ///
-    /// Complexity is approximately `O(steps * segments)` in typical cases, but
-    /// nested wildcards (e.g. `#` combined with descendant axes) may lead to a
-    /// combinatorial explosion of states. Empty topics are represented as an
-    /// empty slice and handled naturally by the traversal.
-    fn match_steps(steps: &[Step], topic: &[&str], msg: &Message) -> bool {
-        let mut stack: Vec<(usize, usize)> = vec![(0, 0)];
-        let mut visited: HashSet<(usize, usize)> = HashSet::from([(0, 0)]);
-
-        while let Some((step_idx, topic_idx)) = stack.pop() {
-            if step_idx == steps.len() {
-                if topic_idx == topic.len() {
-                    return true;
-                }
-                continue;
-            }
-            let step = &steps[step_idx];
-            if !Self::predicates_match(&step.predicates, msg) {
-                continue;
-            }
-            match step.axis {
-                Axis::Child => {
-                    Self::match_child(
-                        &mut stack,
-                        step,
-                        step_idx + 1,
-                        topic,
-                        topic_idx,
-                        &mut visited,
-                    );
+    fn check(&self, step: usize, segment: Option<&str>, slots: &[usize]) -> bool {
+        let predicates = &self.steps[step].predicates;
+        if predicates.is_empty() {
+            return true;
+        }
+        let captures = match self.nfa.memoize {
+            true => Vec::new(),
+            false => self.nfa.bound(self.steps, self.msg.topic, slots, step + 1),
+        };
+        let scope = Scope {
+            msg: self.msg,
+            reads: self.reads,
+            segment,
+            captures: &captures,
+        };
+        Matcher::predicates_match(predicates, &scope)
+    }
+}
+
+fn segment_matches(segment: &Segment, level: &str) -> bool {
+    match segment {
+        Segment::Literal(lit) => lit == level,
+        Segment::Glob(pattern) => glob_match(pattern, level),
+        Segment::Plus => true,
+        Segment::Hash | Segment::Message => false,
+    }
+}
+
+/// Matches a level against a pattern where `*` stands for any run of
+/// characters, backtracking only to the most recent `*`.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t) = (pattern.as_bytes(), text.as_bytes());
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if p.get(pi) == Some(&b'*') {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if p.get(pi) == Some(&t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == b'*')
+}
+
+fn reads_captures(pred: &Predicate) -> bool {
+    match pred {
+        Predicate::Compare(cmp) => matches!(cmp.field, Field::Capture(_)),
+        Predicate::Exists(field) => matches!(field, Field::Capture(_)),
+        Predicate::And(l, r) | Predicate::Or(l, r) => reads_captures(l) || reads_captures(r),
+        Predicate::Not(inner) => reads_captures(inner),
+    }
+}
+
+/// Live `group_by` keys per matcher unless changed with
+/// [`Matcher::with_max_partitions`].
+const MAX_PARTITIONS: usize = 10_000;
+
+/// Pipeline state for one `group_by` key.
+struct State {
+    /// The message filter stages, in order.
+    gates: Vec<Gate>,
+    /// One aggregate per stage, `None` for stages that are not aggregations.
+    aggregates: Vec<Option<Aggregate>>,
+    /// The filter stages, in order.
+    filters: Vec<Filter>,
+}
+
+impl State {
+    /// Fresh state for a pipeline: an aggregate for every aggregation stage,
+    /// using the window set by the closest preceding window stage.
+    fn new(stages: &[Stage]) -> Self {
+        let mut window = None;
+        let aggregates = stages
+            .iter()
+            .map(|stage| match stage {
+                stage if stage.is_window() => {
+                    window = Some(stage);
+                    None
                 }
-                Axis::Descendant => {
-                    let mut start = topic_idx;
-                    while start <= topic.len() {
-                        Self::match_child(
-                            &mut stack,
-                            step,
-                            step_idx + 1,
-                            topic,
-                            start,
-                            &mut visited,
-                        );
-                        start += 1;
-                    }
+                Stage::Agg(metrics) => Some(Aggregate::new(
+                    metrics.iter().map(|m| m.stage.clone()).collect(),
+                    window,
+                )),
+                stage if stage.is_aggregation() => {
+                    Some(Aggregate::new(vec![stage.clone()], window))
                 }
-            }
+                _ => None,
+            })
+            .collect();
+        Self {
+            gates: stages.iter().filter_map(Gate::new).collect(),
+            aggregates,
+            filters: stages.iter().filter_map(Filter::new).collect(),
+        }
+    }
+
+    /// Whether a message arriving `at` passes every message filter stage.
+    fn admit(&mut self, at: Duration, value: &dyn Fn(&Field) -> Option<String>) -> bool {
+        self.gates.iter_mut().all(|gate| gate.pass(at, value))
+    }
+
+    /// Whether a record passes every filter stage. Filters after one that
+    /// drops the record do not see it.
+    fn keep(&mut self, record: &AggregateRecord) -> bool {
+        self.filters.iter_mut().all(|filter| filter.keep(record))
+    }
+
+    /// Closes the windows that ended by `watermark`, returning the records
+    /// of the last aggregation that pass the filter stages, oldest first.
+    fn close(
+        &mut self,
+        watermark: Duration,
+        fields: &[String],
+        key: &Option<String>,
+    ) -> Vec<AggregateRecord> {
+        let mut records = Vec::new();
+        for aggregate in self.aggregates.iter_mut().flatten() {
+            records = aggregate.close(watermark);
+        }
+        records
+            .into_iter()
+            .map(|record| named_record(fields, key.clone(), None, record))
+            .filter(|record| self.keep(record))
+            .collect()
+    }
+}
+
+/// Names the values of a record from the last aggregation stage.
+fn named_record(
+    fields: &[String],
+    key: Option<String>,
+    topic: Option<String>,
+    record: Record,
+) -> AggregateRecord {
+    AggregateRecord {
+        key,
+        topic,
+        window: record.bounds.map(|(start, end)| WindowBounds {
+            start: UNIX_EPOCH + start,
+            end: UNIX_EPOCH + end,
+        }),
+        fields: fields.iter().cloned().zip(record.values).collect(),
+    }
+}
+
+/// Names of the values the last aggregation stage reports.
+pub(crate) fn field_names(stages: &[Stage]) -> Vec<String> {
+    match stages.iter().rev().find(|s| s.is_aggregation()) {
+        Some(Stage::Agg(metrics)) => metrics.iter().map(|m| m.name()).collect(),
+        Some(stage) => vec![stage.name()],
+        None => Vec::new(),
+    }
+}
+
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+pub(crate) fn json_path<'a>(root: &'a JsonValue, path: &[String]) -> Option<&'a JsonValue> {
+    let mut cur = root;
+    for part in path {
+        cur = cur.get(part)?;
+    }
+    Some(cur)
+}
+
+/// Value of a `json$`, `cbor$` or `pb$` field of `msg`, decoding the
+/// payload in that format if needed.
+fn payload_field<'m>(msg: &'m Message, reads: &Reads, field: &Field) -> Option<&'m JsonValue> {
+    let (format, path) = match field {
+        Field::Json(path) => (Format::Json, path),
+        Field::Cbor(path) => (Format::Cbor, path),
+        #[cfg(feature = "pb")]
+        Field::Protobuf(path) => {
+            let content_type = msg.headers.get("prop.content-type");
+            let schemas = reads.schemas.as_deref()?;
+            let (message, path) = schemas.resolve(path, content_type.map(|v| v.as_ref()))?;
+            return json_path(msg.payload.protobuf(message)?, path);
+        }
+        _ => return None,
+    };
+    json_path(msg.payload.read(format, reads.paths(format))?, path)
+}
+
+impl Matcher {
+    pub fn new(selector: Selector) -> Self {
+        let group_by = selector.stages.iter().find_map(|stage| match stage {
+            Stage::GroupBy(field) => Some(field.clone()),
+            _ => None,
+        });
+        let event_time = selector.stages.iter().find_map(|stage| match stage {
+            Stage::Timestamp(field, lateness) => Some((field.clone(), *lateness)),
+            _ => None,
+        });
+        let plan = Plan::new(&selector.steps);
+        let reads = Reads::of(&selector);
+        let fields = field_names(&selector.stages);
+        let transforms = selector.stages.iter().any(Stage::is_transform);
+        let gates = selector.stages.iter().any(Stage::is_message_filter);
+        Self {
+            selector,
+            partitions: Partitions::new(MAX_PARTITIONS),
+            evicted: Vec::new(),
+            fields,
+            transforms,
+            gates,
+            group_by,
+            event_time,
+            watermark: Duration::ZERO,
+            plan,
+            reads,
+            whole: Reads::whole(),
+            clock: (Instant::now(), since_epoch(SystemTime::now())),
+        }
+    }
+
+    /// Pins the wall-clock `time` that `instant` corresponds to. Tumbling and
+    /// hopping windows are aligned to the wall clock, which [`new`](Self::new)
+    /// samples when the matcher is created.
+    pub fn with_wall_clock(mut self, instant: Instant, time: SystemTime) -> Self {
+        self.clock = (instant, since_epoch(time));
+        self
+    }
+
+    /// Reads `pb$` fields with the message types registered in `schemas`.
+    /// Without schemas, `pb$` fields are always missing.
+    #[cfg(feature = "pb")]
+    pub fn with_schemas(mut self, schemas: Arc<Schemas>) -> Self {
+        self.reads.schemas = Some(schemas.clone());
+        self.whole.schemas = Some(schemas);
+        self
+    }
+
+    /// Caps the number of `group_by` keys with live state, 10 000 by default.
+    /// When a new key arrives at the cap, the key that least recently saw a
+    /// message is dropped. Its open tumbling, hopping and session windows
+    /// close early, and [`tick`](Self::tick) reports them next.
+    pub fn with_max_partitions(mut self, max: usize) -> Self {
+        let evicted = self.partitions.set_capacity(max);
+        Self::close_evicted(&mut self.evicted, &self.fields, evicted);
+        self
+    }
+
+    pub fn selector(&self) -> &Selector {
+        &self.selector
+    }
+
+    /// Returns `true` when the selector has pipeline stages to run through
+    /// [`process`](Self::process).
+    pub fn has_stages(&self) -> bool {
+        !self.selector.stages.is_empty()
+    }
+
+    /// Returns `true` when `select`, `map` or `rename` stages rewrite the
+    /// payloads passed through by [`process_output`](Self::process_output).
+    pub fn transforms_payload(&self) -> bool {
+        self.transforms
+    }
+
+    pub fn matches(&self, msg: &Message) -> bool {
+        match &self.plan {
+            Plan::Linear => linear(&self.selector.steps, &self.reads, msg, None),
+            Plan::Nfa(nfa) => nfa.matches(&self.selector.steps, &self.reads, msg),
         }
-        false
     }
 
-    /// Expands the traversal stack for a single step at a given topic index.
+    /// Returns the topic levels bound by `{name}` captures, in step order, or
+    /// `None` when the message does not match.
     ///
-    /// Depending on the [`Segment`] variant, it pushes one or more new states:
+    /// When several paths through the topic satisfy the selector, the first
+    /// one found wins: descendant axes and `#` consume as few levels as
+    /// possible.
+    pub fn captures<'s>(&'s self, msg: &'s Message) -> Option<Vec<(&'s str, &'s str)>> {
+        self.plan.captures(&self.selector.steps, &self.reads, msg)
+    }
+
+    /// Runs the post-match processing stages on a message.
     ///
-    /// * `Literal` matches an exact segment.
-    /// * `Plus` consumes exactly one segment.
-    /// * `Hash` explores all remaining suffixes, matching zero or more segments.
-    /// * `Message` does not consume any segment.
+    /// Each stage is evaluated sequentially once [`matches`](Self::matches) returns
+    /// `true`. Window stages set the window used by subsequent aggregations.
+    /// `window`/`sliding` retain timestamped samples, evict entries whose age
+    /// exceeds the duration and produce a value for every message, as do
+    /// `window(N msgs)` windows over the last N messages. Without a window,
+    /// aggregations are evaluated from only the current message. `tumbling`,
+    /// `hopping` and `session` windows only produce values when they close,
+    /// which [`tick`](Self::tick) reports, so `process` returns `None` when the
+    /// last aggregation uses one. Missing or non-numeric fields cause
+    /// processing to short-circuit with `None`. A `group_by` stage gives
+    /// every key its own state; [`process_keyed`](Self::process_keyed) also
+    /// returns the key. An `agg(...)` stage yields its last aggregation
+    /// here; [`process_output`](Self::process_output) returns all of them.
     ///
-    /// In the presence of nested wildcards the number of states can grow
-    /// quickly, which impacts matching complexity.
-    fn match_child(
-        stack: &mut Vec<(usize, usize)>,
-        step: &Step,
-        next_step: usize,
-        topic: &[&str],
-        idx: usize,
-        visited: &mut HashSet<(usize, usize)>,
-    ) {
-        match step.segment {
-            Segment::Literal(ref lit) => {
-                if let Some(seg) = topic.get(idx) {
-                    if lit == seg {
-                        let state = (next_step, idx + 1);
-                        if visited.insert(state) {
-                            stack.push(state);
-                        }
-                    }
-                }
+    /// `sum`, `avg`, `count`, `first`, `last` and `stddev` run in `O(1)` time
+    /// per message and `min`/`max` in amortised `O(1)` using a monotonic
+    /// deque. Percentiles keep the window sorted, so each message costs
+    /// `O(log n)` comparisons plus an `O(n)` insertion. Empty topics are
+    /// handled by [`matches`](Self::matches) yielding `false` before
+    /// processing, so `process` only runs on matching topics.
+    pub fn process(&mut self, msg: &Message, timestamp: Instant) -> Option<f64> {
+        self.process_keyed(msg, timestamp).map(|(_, value)| value)
+    }
+
+    /// Like [`process`](Self::process), but also returns the `group_by` key
+    /// of the partition that produced the value, `None` for ungrouped
+    /// pipelines. Messages without the key are skipped.
+    pub fn process_keyed(
+        &mut self,
+        msg: &Message,
+        timestamp: Instant,
+    ) -> Option<(Option<String>, f64)> {
+        self.try_process(msg, timestamp).ok().flatten()
+    }
+
+    /// Like [`process_keyed`](Self::process_keyed), but reports messages
+    /// that arrive too late for event-time windows instead of silently
+    /// skipping them. See [`process_output`](Self::process_output).
+    pub fn try_process(
+        &mut self,
+        msg: &Message,
+        timestamp: Instant,
+    ) -> Result<Option<(Option<String>, f64)>, Late> {
+        Ok(match self.process_output(msg, timestamp)? {
+            Some(PipelineOutput::Aggregate(record)) => {
+                record.value().map(|value| (record.key, value))
             }
-            Segment::Plus => {
-                if topic.get(idx).is_some() {
-                    let state = (next_step, idx + 1);
-                    if visited.insert(state) {
-                        stack.push(state);
-                    }
+            _ => None,
+        })
+    }
+
+    /// Runs a message through the selector and returns everything it
+    /// produced: the message itself when the selector has no aggregation
+    /// stages, or else the record of the last aggregation stage, with every
+    /// value of an `agg(...)` stage, the `group_by` key and the window
+    /// bounds. Returns `Ok(None)` when the message does not match, lacks a
+    /// field the pipeline reads, or only feeds windows that report when
+    /// they close.
+    ///
+    /// `select`, `map` and `rename` stages run first, in order, and the rest
+    /// of the pipeline reads the payload they produce. `distinct`,
+    /// `throttle`, `debounce` and `sample` stages then drop messages, with
+    /// state kept per `group_by` key, before the aggregations see them or
+    /// they are passed through. `where`, `threshold`, `hysteresis` and
+    /// `changed` stages run last, on the record, and `Ok(None)` is returned
+    /// for records they drop; records of closed windows are filtered the
+    /// same way before [`tick`](Self::tick) reports them.
+    ///
+    /// With a `timestamp()` stage, windows follow the time read from each
+    /// message, and messages without a readable timestamp are skipped. The
+    /// watermark trails the newest timestamp seen by the allowed lateness;
+    /// windows close once it passes their end, and messages older than it
+    /// are late. Without a `timestamp()` stage, nothing is ever late.
+    pub fn process_output(
+        &mut self,
+        msg: &Message,
+        timestamp: Instant,
+    ) -> Result<Option<PipelineOutput>, Late> {
+        Ok(match self.run(msg, timestamp, true)? {
+            Outcome::Output(output) => Some(output),
+            Outcome::Dropped | Outcome::Admitted => None,
+        })
+    }
+
+    /// Returns `true` when a message matches and gets through the
+    /// `distinct`, `throttle`, `debounce` and `sample` stages, whose state
+    /// it updates. Aggregations are not fed. This is what brokers use to
+    /// decide whether to deliver a message to a subscriber; without such
+    /// stages it agrees with [`matches`](Self::matches), except that late
+    /// messages are rejected.
+    pub fn accept(&mut self, msg: &Message, timestamp: Instant) -> bool {
+        matches!(
+            self.run(msg, timestamp, false),
+            Ok(Outcome::Admitted | Outcome::Output(_))
+        )
+    }
+
+    /// Runs a message through the pipeline, stopping before the
+    /// aggregations unless `feed` is set.
+    fn run(&mut self, msg: &Message, timestamp: Instant, feed: bool) -> Result<Outcome, Late> {
+        // Messages passed through carry their whole payload, so decode all
+        // of it at once rather than the paths the selector reads first.
+        let reads = match feed && self.fields.is_empty() {
+            true => &self.whole,
+            false => &self.reads,
+        };
+        let Some(captures) = self.plan.captures(&self.selector.steps, reads, msg) else {
+            return Ok(Outcome::Dropped);
+        };
+        let transformed;
+        let msg = if self.transforms {
+            transformed = Message {
+                topic: msg.topic,
+                headers: msg.headers.clone(),
+                payload: self.transform(msg, &captures).into(),
+            };
+            &transformed
+        } else {
+            msg
+        };
+        let key = match &self.group_by {
+            Some(field) => match Self::group_key(field, reads, msg, &captures) {
+                Some(key) => Some(key),
+                None => return Ok(Outcome::Dropped),
+            },
+            None => None,
+        };
+        let (at, watermark) = match &self.event_time {
+            Some((field, lateness)) => {
+                let Some(at) = Self::event_time(field, reads, msg, &captures) else {
+                    return Ok(Outcome::Dropped);
+                };
+                if at < self.watermark {
+                    return Err(Late {
+                        event_time: UNIX_EPOCH + at,
+                        watermark: UNIX_EPOCH + self.watermark,
+                    });
                 }
+                self.watermark = self.watermark.max(at.saturating_sub(*lateness));
+                (at, self.watermark)
             }
-            Segment::Hash => {
-                let mut i = idx;
-                while i <= topic.len() {
-                    let state = (next_step, i);
-                    if visited.insert(state) {
-                        stack.push(state);
-                    }
-                    i += 1;
-                }
+            None => {
+                let wall = self.wall(timestamp);
+                (wall, wall)
             }
-            Segment::Message => {
-                let state = (next_step, idx);
-                if visited.insert(state) {
-                    stack.push(state);
-                }
+        };
+        let stages = &self.selector.stages;
+        if self.gates {
+            let (state, evicted) = self
+                .partitions
+                .get_or_insert_with(key.clone(), || State::new(stages));
+            Self::close_evicted(&mut self.evicted, &self.fields, evicted);
+            let value = |field: &Field| Self::group_key(field, reads, msg, &captures);
+            if !state.admit(at, &value) {
+                return Ok(Outcome::Dropped);
             }
         }
+        if !feed {
+            return Ok(Outcome::Admitted);
+        }
+        if self.fields.is_empty() {
+            return Ok(Outcome::Output(PipelineOutput::Message {
+                topic: msg.topic.to_string(),
+                headers: msg
+                    .headers
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                payload: msg.payload.json().cloned(),
+            }));
+        }
+        let (state, evicted) = self
+            .partitions
+            .get_or_insert_with(key.clone(), || State::new(stages));
+        Self::close_evicted(&mut self.evicted, &self.fields, evicted);
+        // Every sample is read before any aggregate changes, so a message
+        // missing a later field leaves all of them untouched.
+        let mut samples = Vec::new();
+        for aggregate in state.aggregates.iter().flatten() {
+            let mut values = Vec::with_capacity(aggregate.stages().len());
+            for stage in aggregate.stages() {
+                values.push(match stage.field() {
+                    Some(field) => match Self::extract_field(field, reads, msg, &captures) {
+                        Some(sample) => sample,
+                        None => return Ok(Outcome::Dropped),
+                    },
+                    None => 1.0,
+                });
+            }
+            samples.push(values);
+        }
+        let mut result = None;
+        for (aggregate, values) in state.aggregates.iter_mut().flatten().zip(&samples) {
+            aggregate.advance(watermark);
+            result = aggregate.push(at, values);
+        }
+        let Some(record) = result else {
+            return Ok(Outcome::Dropped);
+        };
+        let record = named_record(&self.fields, key, Some(msg.topic.to_string()), record);
+        if !state.keep(&record) {
+            return Ok(Outcome::Dropped);
+        }
+        Ok(Outcome::Output(PipelineOutput::Aggregate(record)))
+    }
+
+    /// Moves the event-time watermark forward to `time`, closing the windows
+    /// that end by then on the next [`tick`](Self::tick). Useful when a
+    /// source goes quiet, since otherwise only new timestamps move it.
+    pub fn advance_watermark(&mut self, time: SystemTime) {
+        self.watermark = self.watermark.max(since_epoch(time));
+    }
+
+    /// Closes the tumbling, hopping and session windows that ended by `now`
+    /// and returns the results of the last aggregation for every window
+    /// closed since the previous call, oldest first.
+    ///
+    /// Windows also close when a later message reaches
+    /// [`process`](Self::process), but their results are only handed out
+    /// here, so callers should tick regularly, e.g. once a second. Windows
+    /// that received no messages produce nothing.
+    pub fn tick(&mut self, now: Instant) -> Vec<f64> {
+        self.tick_keyed(now)
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// Like [`tick`](Self::tick), but pairs each result with its `group_by`
+    /// key. Results are ordered by key, then oldest first.
+    pub fn tick_keyed(&mut self, now: Instant) -> Vec<(Option<String>, f64)> {
+        self.tick_output(now)
+            .into_iter()
+            .filter_map(|output| match output {
+                PipelineOutput::Aggregate(record) => {
+                    record.value().map(|value| (record.key, value))
+                }
+                PipelineOutput::Message { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Like [`tick_keyed`](Self::tick_keyed), but returns full records with
+    /// window bounds and every value of an `agg(...)` stage.
+    ///
+    /// Windows closed early because the cap set by
+    /// [`with_max_partitions`](Self::with_max_partitions) evicted their key
+    /// come first for that key, with the bounds they would have had.
+    pub fn tick_output(&mut self, now: Instant) -> Vec<PipelineOutput> {
+        let watermark = match self.event_time {
+            Some(_) => self.watermark,
+            None => self.wall(now),
+        };
+        let mut closed = std::mem::take(&mut self.evicted);
+        for (key, state) in self.partitions.iter_mut() {
+            closed.extend(state.close(watermark, &self.fields, key));
+        }
+        closed.sort_by(|a, b| a.key.cmp(&b.key));
+        closed.into_iter().map(PipelineOutput::Aggregate).collect()
+    }
+
+    /// Closes every window of the `evicted` partitions, queueing their
+    /// records in `queue`.
+    fn close_evicted(
+        queue: &mut Vec<AggregateRecord>,
+        fields: &[String],
+        evicted: impl IntoIterator<Item = (Option<String>, State)>,
+    ) {
+        for (key, mut state) in evicted {
+            queue.extend(state.close(Duration::MAX, fields, &key));
+        }
+    }
+
+    /// The payload after the transform stages; `None` if it ends up `null`.
+    fn transform(&self, msg: &Message, captures: &[(&str, &str)]) -> Option<JsonValue> {
+        let scope = Scope {
+            msg,
+            reads: &self.whole,
+            segment: None,
+            captures,
+        };
+        let field = |field: &Field| match field {
+            Field::Cbor(_) | Field::Protobuf(_) => scope.payload(field).cloned(),
+            field => scope.text(field).map(|v| JsonValue::String(v.to_string())),
+        };
+        let payload = msg.payload.json().cloned().unwrap_or(JsonValue::Null);
+        let payload = self
+            .selector
+            .stages
+            .iter()
+            .filter(|stage| stage.is_transform())
+            .fold(payload, |payload, stage| {
+                transform::apply(stage, payload, &field)
+            });
+        Some(payload).filter(|p| !p.is_null())
+    }
+
+    /// Time since the Unix epoch at `instant`, according to `self.clock`.
+    fn wall(&self, instant: Instant) -> Duration {
+        let (anchor, wall) = self.clock;
+        match instant.checked_duration_since(anchor) {
+            Some(ahead) => wall + ahead,
+            None => wall.saturating_sub(anchor.duration_since(instant)),
+        }
     }
 
-    fn predicates_match(preds: &[Predicate], msg: &Message) -> bool {
+    fn predicates_match(preds: &[Predicate], scope: &Scope) -> bool {
         for p in preds {
-            if !Self::predicate_match(p, msg) {
+            if !Self::predicate_match(p, scope) {
                 return false;
             }
         }
         true
     }
 
-    fn predicate_match(pred: &Predicate, msg: &Message) -> bool {
-        let left = match pred.field {
-            Field::Header(ref name) => {
-                let hv = match msg.headers.get(name.as_str()) {
-                    Some(v) => v.as_ref(),
-                    None => return false,
-                };
-                match &pred.value {
-                    Value::Number(_) => match hv.parse::<f64>() {
-                        Ok(num) => Value::Number(num),
-                        Err(_) => return false,
-                    },
-                    Value::Bool(_) => match hv {
-                        "true" => Value::Bool(true),
-                        "false" => Value::Bool(false),
-                        _ => return false,
-                    },
-                    Value::Str(_) => Value::Str(hv.to_string()),
+    /// Evaluates a boolean predicate expression with short-circuiting, so the
+    /// right operand of `and`/`or` is only inspected when it can change the
+    /// outcome.
+    fn predicate_match(pred: &Predicate, scope: &Scope) -> bool {
+        match pred {
+            Predicate::Compare(cmp) => Self::comparison_match(cmp, scope),
+            Predicate::Exists(field) => Self::exists_match(field, scope),
+            Predicate::And(l, r) => {
+                Self::predicate_match(l, scope) && Self::predicate_match(r, scope)
+            }
+            Predicate::Or(l, r) => {
+                Self::predicate_match(l, scope) || Self::predicate_match(r, scope)
+            }
+            Predicate::Not(inner) => !Self::predicate_match(inner, scope),
+        }
+    }
+
+    fn comparison_match(pred: &Comparison, scope: &Scope) -> bool {
+        match pred.field {
+            Field::Json(_) | Field::Cbor(_) | Field::Protobuf(_) => {
+                match scope.payload(&pred.field).and_then(Self::json_value) {
+                    Some(left) => Self::compare_values(&left, &pred.value, pred.op),
+                    None => false,
                 }
             }
-            Field::Json(ref path) => {
-                let cur = match msg.payload.as_ref() {
-                    Some(j) => json_path(j, path),
-                    None => None,
-                };
-                let cur = match cur {
+            ref field => {
+                let hv = match scope.text(field) {
                     Some(v) => v,
                     None => return false,
                 };
-                if let Some(b) = cur.as_bool() {
-                    Value::Bool(b)
-                } else if let Some(n) = cur.as_f64() {
-                    Value::Number(n)
-                } else if let Some(s) = cur.as_str() {
-                    Value::Str(s.to_string())
-                } else {
-                    return false;
+                // Headers, topic levels and captures are text, so they are
+                // coerced to the type of each value they are compared with.
+                match (&pred.value, pred.op) {
+                    (Value::List(items), Operator::In) => items.iter().any(|item| {
+                        Self::compare_values(&Self::coerce_header(hv, item), item, Operator::Eq)
+                    }),
+                    (value, op) => Self::compare_values(&Self::coerce_header(hv, value), value, op),
                 }
             }
-        };
+        }
+    }
 
-        Self::compare_values(&left, &pred.value, pred.op)
+    fn exists_match(field: &Field, scope: &Scope) -> bool {
+        match field {
+            Field::Json(_) | Field::Cbor(_) | Field::Protobuf(_) => scope.payload(field).is_some(),
+            field => scope.text(field).is_some(),
+        }
+    }
+
+    fn coerce_header(hv: &str, target: &Value) -> Value {
+        match target {
+            Value::Number(_) => match hv.parse::<f64>() {
+                Ok(num) => Value::Number(num),
+                Err(_) => Value::Str(hv.to_string()),
+            },
+            Value::Bool(_) => match hv {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ => Value::Str(hv.to_string()),
+            },
+            Value::Str(_) | Value::List(_) | Value::Regex(_) => Value::Str(hv.to_string()),
+        }
+    }
+
+    /// Converts a JSON scalar, or an array of scalars, into a [`Value`].
+    /// Objects and `null` have no comparable value.
+    fn json_value(cur: &JsonValue) -> Option<Value> {
+        if let Some(b) = cur.as_bool() {
+            Some(Value::Bool(b))
+        } else if let Some(n) = cur.as_f64() {
+            Some(Value::Number(n))
+        } else if let Some(s) = cur.as_str() {
+            Some(Value::Str(s.to_string()))
+        } else {
+            cur.as_array()
+                .map(|items| Value::List(items.iter().filter_map(Self::json_value).collect()))
+        }
     }
 
     fn compare_numbers(l: f64, r: f64, op: Operator) -> bool {
@@ -386,10 +1346,12 @@ impl Matcher {
         if l.is_infinite() || r.is_infinite() {
             return match op {
                 Operator::Eq => l == r,
+                Operator::Ne => l != r,
                 Operator::Lt => l < r,
                 Operator::Gt => l > r,
                 Operator::Le => l <= r,
                 Operator::Ge => l >= r,
+                _ => false,
             };
         }
 
@@ -400,33 +1362,103 @@ impl Matcher {
         let ord = l.total_cmp(&r);
         match op {
             Operator::Eq => eq,
+            Operator::Ne => !eq,
             Operator::Lt => ord == Ordering::Less && !eq,
             Operator::Gt => ord == Ordering::Greater && !eq,
             Operator::Le => ord != Ordering::Greater || eq,
             Operator::Ge => ord != Ordering::Less || eq,
+            _ => false,
         }
     }
 
-    fn compare_values(left: &Value, right: &Value, op: Operator) -> bool {
-        match (left, right) {
-            (Value::Number(l), Value::Number(r)) => Self::compare_numbers(*l, *r, op),
-            (Value::Bool(l), Value::Bool(r)) => match op {
-                Operator::Eq => l == r,
-                _ => false,
-            },
-            (Value::Str(l), Value::Str(r)) => match op {
-                Operator::Eq => l == r,
-                _ => false,
-            },
+    /// Compares two values. Values of different types are never equal, so
+    /// they only satisfy `!=`.
+    pub(crate) fn compare_values(left: &Value, right: &Value, op: Operator) -> bool {
+        match (op, left, right) {
+            (Operator::In, _, Value::List(items)) => items
+                .iter()
+                .any(|item| Self::compare_values(left, item, Operator::Eq)),
+            (Operator::Contains, Value::List(items), _) => items
+                .iter()
+                .any(|item| Self::compare_values(item, right, Operator::Eq)),
+            (Operator::Contains, Value::Str(l), Value::Str(r)) => l.contains(r.as_str()),
+            (Operator::StartsWith, Value::Str(l), Value::Str(r)) => l.starts_with(r.as_str()),
+            (Operator::EndsWith, Value::Str(l), Value::Str(r)) => l.ends_with(r.as_str()),
+            (Operator::Matches, Value::Str(l), Value::Regex(re)) => re.is_match(l),
+            (_, Value::Number(l), Value::Number(r)) => Self::compare_numbers(*l, *r, op),
+            (Operator::Eq, Value::Bool(l), Value::Bool(r)) => l == r,
+            (Operator::Ne, Value::Bool(l), Value::Bool(r)) => l != r,
+            (Operator::Eq, Value::Str(l), Value::Str(r)) => l == r,
+            (Operator::Ne, Value::Str(l), Value::Str(r)) => l != r,
+            (Operator::Ne, l, r) => std::mem::discriminant(l) != std::mem::discriminant(r),
             _ => false,
         }
     }
 
-    fn extract_field(field: &Field, msg: &Message) -> Option<f64> {
+    /// The `group_by` key of a message: text fields as they are, JSON
+    /// strings unquoted and other JSON values in their JSON form.
+    fn group_key(
+        field: &Field,
+        reads: &Reads,
+        msg: &Message,
+        captures: &[(&str, &str)],
+    ) -> Option<String> {
+        let scope = Scope {
+            msg,
+            reads,
+            segment: None,
+            captures,
+        };
         match field {
+            Field::Json(_) | Field::Cbor(_) | Field::Protobuf(_) => match scope.payload(field)? {
+                JsonValue::String(s) => Some(s.clone()),
+                other => Some(other.to_string()),
+            },
+            field => scope.text(field).map(str::to_string),
+        }
+    }
+
+    /// The event time of a message, as time since the Unix epoch.
+    fn event_time(
+        field: &Field,
+        reads: &Reads,
+        msg: &Message,
+        captures: &[(&str, &str)],
+    ) -> Option<Duration> {
+        let scope = Scope {
+            msg,
+            reads,
+            segment: None,
+            captures,
+        };
+        match field {
+            Field::Json(_) | Field::Cbor(_) | Field::Protobuf(_) => {
+                timestamp::from_json(scope.payload(field)?)
+            }
+            field => timestamp::from_text(scope.text(field)?),
+        }
+    }
+
+    /// Reads a numeric sample for an aggregation; `NaN` counts as missing so
+    /// it cannot poison running totals or the sorted window.
+    fn extract_field(
+        field: &Field,
+        reads: &Reads,
+        msg: &Message,
+        captures: &[(&str, &str)],
+    ) -> Option<f64> {
+        let value = match field {
             Field::Header(name) => msg.headers.get(name.as_str())?.as_ref().parse::<f64>().ok(),
-            Field::Json(path) => {
-                let v = json_path(msg.payload.as_ref()?, path)?;
+            Field::Capture(name) => captures
+                .iter()
+                .find(|(n, _)| n == name)?
+                .1
+                .parse::<f64>()
+                .ok(),
+            Field::Segment => None,
+            Field::TopicLevel(n) => msg.topic.split('/').nth(n - 1)?.parse::<f64>().ok(),
+            Field::Json(_) | Field::Cbor(_) | Field::Protobuf(_) => {
+                let v = payload_field(msg, reads, field)?;
                 if let Some(f) = v.as_f64() {
                     Some(f)
                 } else if let Some(i) = v.as_i64() {
@@ -435,7 +1467,8 @@ impl Matcher {
                     v.as_u64().map(|u| u as f64)
                 }
             }
-        }
+        };
+        value.filter(|v| !v.is_nan())
     }
 }
 
@@ -452,8 +1485,103 @@ mod tests {
         Message {
             topic,
             headers: HashMap::new(),
-            payload: None,
+            payload: Payload::None,
+        }
+    }
+
+    #[test]
+    fn linear_plan_agrees_with_nfa() {
+        let selectors = [
+            "/foo/bar",
+            "/foo/+/baz",
+            "/foo/#",
+            "/#",
+            "/+",
+            "/foo/sensor-*",
+            "/{room}/+[.=\"t\"]",
+            "/foo/{rest:#}[.=\"a/b\"]",
+            "/foo/#/msg[qos=1]",
+            "/\"$SYS\"/+",
+            "/foo/\"\"",
+        ];
+        let topics = [
+            "",
+            "foo",
+            "foo/",
+            "foo/bar",
+            "foo/baz",
+            "foo/x/baz",
+            "foo/a/b",
+            "foo/sensor-1",
+            "kitchen/t",
+            "$SYS/broker",
+            "$SYS",
+            "a/b/c",
+        ];
+        for selector in selectors {
+            let m = Matcher::new(compile(selector).unwrap());
+            assert!(
+                matches!(m.plan, Plan::Linear),
+                "{selector} should be linear"
+            );
+            for topic in topics {
+                let mut msg = make_msg(topic);
+                msg.headers.insert(Cow::Borrowed("qos"), Cow::Borrowed("1"));
+                let nfa = Nfa::new(&m.selector.steps, true);
+                assert_eq!(
+                    m.captures(&msg),
+                    nfa.captures(&m.selector.steps, &m.reads, &msg),
+                    "{selector} on {topic:?}"
+                );
+            }
+        }
+        for selector in ["//foo", "/#/bar", "/{a}/+[$a = \"q\"]"] {
+            let m = Matcher::new(compile(selector).unwrap());
+            assert!(matches!(m.plan, Plan::Nfa(_)), "{selector}");
+        }
+    }
+
+    #[test]
+    fn nfa_bit_sets_agree_with_threads() {
+        let selectors = [
+            "//sensor",
+            "//{a}/{b}",
+            "/#/b/#",
+            "/building/#/floor3/+",
+            "//\"$SYS\"/+",
+            "/{x:#}/+[.=\"c\"]/{y:#}",
+            "//#/msg",
+        ];
+        let topics = [
+            "",
+            "a",
+            "a/b",
+            "a/b/c",
+            "b/b/b",
+            "a//b",
+            "a/",
+            "$SYS/x",
+            "x/$SYS/y",
+            "x/sensor",
+            "building/e/floor3/s",
+        ];
+        for selector in selectors {
+            let m = Matcher::new(compile(selector).unwrap());
+            let Plan::Nfa(nfa) = &m.plan else {
+                panic!("{selector} should not be linear");
+            };
+            assert!(nfa.closures.is_some(), "{selector}");
+            for topic in topics {
+                let msg = make_msg(topic);
+                let threads = with_scratch(|s| {
+                    nfa.run(s, &m.selector.steps, &m.reads, &msg, false)
+                        .is_some()
+                });
+                assert_eq!(m.matches(&msg), threads, "{selector} on {topic:?}");
+            }
         }
+        let m = Matcher::new(compile("//{x:#}[.=\"a\"]/b").unwrap());
+        assert!(matches!(&m.plan, Plan::Nfa(nfa) if nfa.closures.is_none()));
     }
 
     #[test]
@@ -513,7 +1641,7 @@ mod tests {
     #[test]
     fn values_outside_hybrid_tolerance_are_not_equal() {
         let l = Value::Number(1.0);
-        let r = Value::Number(1.0 + ABS_EPS * 4.0);
+        let r = Value::Number(1.0 + REL_EPS * 4.0);
         assert!(!Matcher::compare_values(&l, &r, Operator::Eq));
 
         let l_large = Value::Number(1_000_000_000.0);
@@ -566,14 +1694,14 @@ mod tests {
         let msg = Message {
             topic: "foo",
             headers: HashMap::new(),
-            payload: Some(json!({"temp": 35})),
+            payload: Payload::Json(json!({"temp": 35})),
         };
         assert!(m.matches(&msg));
 
         let msg = Message {
             topic: "foo",
             headers: HashMap::new(),
-            payload: Some(json!({"temp": 25})),
+            payload: Payload::Json(json!({"temp": 25})),
         };
         assert!(!m.matches(&msg));
     }
@@ -583,10 +1711,13 @@ mod tests {
         let msg = Message {
             topic: "foo",
             headers: HashMap::new(),
-            payload: Some(json!({"temp": 21})),
+            payload: Payload::Json(json!({"temp": 21})),
         };
         let field = Field::Json(vec!["temp".into()]);
-        assert_eq!(Matcher::extract_field(&field, &msg), Some(21.0));
+        assert_eq!(
+            Matcher::extract_field(&field, &Reads::whole(), &msg, &[]),
+            Some(21.0)
+        );
     }
 
     #[test]
@@ -597,7 +1728,7 @@ mod tests {
         let msg1 = Message {
             topic: "sensor",
             headers: HashMap::from([(Cow::Borrowed("temp"), Cow::Borrowed("10"))]),
-            payload: None,
+            payload: Payload::None,
         };
         let start = Instant::now();
         assert_eq!(m.process(&msg1, start), Some(10.0));
@@ -605,7 +1736,7 @@ mod tests {
         let msg2 = Message {
             topic: "sensor",
             headers: HashMap::from([(Cow::Borrowed("temp"), Cow::Borrowed("20"))]),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg2, start + Duration::from_secs(1)), Some(20.0));
     }
@@ -619,14 +1750,14 @@ mod tests {
         let msg1 = Message {
             topic: "sensor",
             headers: HashMap::from([(Cow::Borrowed("temp"), Cow::Borrowed("10"))]),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg1, timestamp), Some(10.0));
 
         let msg2 = Message {
             topic: "sensor",
             headers: HashMap::from([(Cow::Borrowed("temp"), Cow::Borrowed("20"))]),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg2, timestamp), Some(20.0));
     }
@@ -639,7 +1770,7 @@ mod tests {
         let msg1 = Message {
             topic: "sensor",
             headers: HashMap::from([(Cow::Borrowed("temp"), Cow::Borrowed("10"))]),
-            payload: None,
+            payload: Payload::None,
         };
         let start = Instant::now();
         assert_eq!(m.process(&msg1, start), Some(10.0));
@@ -647,14 +1778,14 @@ mod tests {
         let msg2 = Message {
             topic: "sensor",
             headers: HashMap::from([(Cow::Borrowed("temp"), Cow::Borrowed("20"))]),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg2, start + Duration::from_secs(1)), Some(30.0));
 
         let msg3 = Message {
             topic: "sensor",
             headers: HashMap::from([(Cow::Borrowed("temp"), Cow::Borrowed("30"))]),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg3, start + Duration::from_secs(3)), Some(50.0));
 
@@ -671,14 +1802,14 @@ mod tests {
         let msg1 = Message {
             topic: "sensor",
             headers: HashMap::from([(Cow::Borrowed("temp"), Cow::Borrowed("10"))]),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg1, timestamp), Some(10.0));
 
         let msg2 = Message {
             topic: "sensor",
             headers: HashMap::from([(Cow::Borrowed("temp"), Cow::Borrowed("20"))]),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg2, timestamp), Some(30.0));
     }
@@ -691,7 +1822,7 @@ mod tests {
         let msg1 = Message {
             topic: "sensor",
             headers: HashMap::new(),
-            payload: Some(json!({"value": 10})),
+            payload: Payload::Json(json!({"value": 10})),
         };
         let start = Instant::now();
         assert_eq!(m.process(&msg1, start), Some(10.0));
@@ -699,7 +1830,7 @@ mod tests {
         let msg2 = Message {
             topic: "sensor",
             headers: HashMap::new(),
-            payload: Some(json!({"value": 20})),
+            payload: Payload::Json(json!({"value": 20})),
         };
         assert_eq!(m.process(&msg2, start + Duration::from_secs(2)), Some(20.0));
     }
@@ -713,14 +1844,14 @@ mod tests {
         let msg1 = Message {
             topic: "sensor",
             headers: HashMap::from([(Cow::Borrowed("temp"), Cow::Borrowed("10"))]),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg1, timestamp), Some(10.0));
 
         let msg2 = Message {
             topic: "sensor",
             headers: HashMap::from([(Cow::Borrowed("temp"), Cow::Borrowed("20"))]),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg2, timestamp), Some(20.0));
     }
@@ -733,7 +1864,7 @@ mod tests {
         let msg1 = Message {
             topic: "sensor",
             headers: HashMap::new(),
-            payload: Some(json!({"value": 10})),
+            payload: Payload::Json(json!({"value": 10})),
         };
         let start = Instant::now();
         assert_eq!(m.process(&msg1, start), Some(10.0));
@@ -741,14 +1872,14 @@ mod tests {
         let msg2 = Message {
             topic: "sensor",
             headers: HashMap::new(),
-            payload: Some(json!({"value": 20})),
+            payload: Payload::Json(json!({"value": 20})),
         };
         assert_eq!(m.process(&msg2, start + Duration::from_secs(1)), Some(15.0));
 
         let msg3 = Message {
             topic: "sensor",
             headers: HashMap::new(),
-            payload: Some(json!({"value": 30})),
+            payload: Payload::Json(json!({"value": 30})),
         };
         assert_eq!(m.process(&msg3, start + Duration::from_secs(3)), Some(25.0));
 
@@ -763,7 +1894,7 @@ mod tests {
         let msg1 = Message {
             topic: "sensor",
             headers: HashMap::new(),
-            payload: None,
+            payload: Payload::None,
         };
         let start = Instant::now();
         assert_eq!(m.process(&msg1, start), Some(1.0));
@@ -771,7 +1902,7 @@ mod tests {
         let msg2 = Message {
             topic: "sensor",
             headers: HashMap::new(),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg2, start + Duration::from_secs(1)), Some(1.0));
     }
@@ -785,14 +1916,14 @@ mod tests {
         let msg1 = Message {
             topic: "sensor",
             headers: HashMap::new(),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg1, timestamp), Some(1.0));
 
         let msg2 = Message {
             topic: "sensor",
             headers: HashMap::new(),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg2, timestamp), Some(1.0));
     }
@@ -805,7 +1936,7 @@ mod tests {
         let msg1 = Message {
             topic: "sensor",
             headers: HashMap::new(),
-            payload: None,
+            payload: Payload::None,
         };
         let start = Instant::now();
         assert_eq!(m.process(&msg1, start), Some(1.0));
@@ -813,14 +1944,14 @@ mod tests {
         let msg2 = Message {
             topic: "sensor",
             headers: HashMap::new(),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg2, start + Duration::from_secs(1)), Some(2.0));
 
         let msg3 = Message {
             topic: "sensor",
             headers: HashMap::new(),
-            payload: None,
+            payload: Payload::None,
         };
         assert_eq!(m.process(&msg3, start + Duration::from_secs(3)), Some(2.0));
 
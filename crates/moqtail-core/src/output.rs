@@ -0,0 +1,155 @@
+//! What a pipeline produces for a message or a closed window.
+
+use crate::timestamp;
+use serde_json::{json, Map, Value as JsonValue};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The result of running a message, or closing windows, through a selector.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipelineOutput {
+    /// A matching message, passed through by a selector without aggregation
+    /// stages.
+    Message {
+        topic: String,
+        headers: BTreeMap<String, String>,
+        payload: Option<JsonValue>,
+    },
+    /// The values of the last aggregation stage over a window.
+    Aggregate(AggregateRecord),
+}
+
+/// Named values computed over one window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateRecord {
+    /// The `group_by` key, `None` for ungrouped pipelines.
+    pub key: Option<String>,
+    /// Topic of the message that produced the record; `None` for windows
+    /// reported when they close.
+    pub topic: Option<String>,
+    /// Bounds of the window; `None` when aggregating only the current
+    /// message.
+    pub window: Option<WindowBounds>,
+    /// Values by name: the function name, such as `avg`, or the name given
+    /// with `as` inside `agg(...)`. A value is `None` when the window cannot
+    /// define it, e.g. `rate(f)` over a single sample.
+    pub fields: Vec<(String, Option<f64>)>,
+}
+
+/// The time span a record covers. Sliding windows span the window length
+/// up to the newest message, `N msgs` windows their oldest to newest
+/// message, and sessions end one gap after their newest message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowBounds {
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+impl AggregateRecord {
+    /// The value of the field called `name`.
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.fields.iter().find(|(n, _)| n == name)?.1
+    }
+
+    /// The value of the last field, which is the only one unless the
+    /// record comes from `agg(...)`.
+    pub fn value(&self) -> Option<f64> {
+        self.fields.last()?.1
+    }
+}
+
+impl PipelineOutput {
+    /// The output as a JSON object.
+    ///
+    /// Messages become `{"topic", "headers", "payload"}`, with `headers`
+    /// omitted when empty and a `null` payload when it was not JSON.
+    /// Aggregate records become `{"key", "topic", "window", "values"}`,
+    /// where `window` holds RFC 3339 `start` and `end` times, `values` maps
+    /// field names to numbers, and absent parts are omitted. Undefined
+    /// values and non-finite numbers are `null`.
+    pub fn to_json(&self) -> JsonValue {
+        let mut out = Map::new();
+        match self {
+            PipelineOutput::Message {
+                topic,
+                headers,
+                payload,
+            } => {
+                out.insert("topic".into(), topic.clone().into());
+                if !headers.is_empty() {
+                    let headers = headers.iter().map(|(k, v)| (k.clone(), v.clone().into()));
+                    out.insert("headers".into(), Map::from_iter(headers).into());
+                }
+                out.insert("payload".into(), payload.clone().unwrap_or(JsonValue::Null));
+            }
+            PipelineOutput::Aggregate(record) => {
+                if let Some(key) = &record.key {
+                    out.insert("key".into(), key.clone().into());
+                }
+                if let Some(topic) = &record.topic {
+                    out.insert("topic".into(), topic.clone().into());
+                }
+                if let Some(window) = &record.window {
+                    out.insert(
+                        "window".into(),
+                        json!({
+                            "start": rfc3339(window.start),
+                            "end": rfc3339(window.end),
+                        }),
+                    );
+                }
+                let values = record.fields.iter().map(|(name, value)| {
+                    (name.clone(), value.map_or(JsonValue::Null, |v| v.into()))
+                });
+                out.insert("values".into(), Map::from_iter(values).into());
+            }
+        }
+        out.into()
+    }
+}
+
+fn rfc3339(time: SystemTime) -> String {
+    timestamp::to_rfc3339(time.duration_since(UNIX_EPOCH).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn records_serialize_with_window_and_values() {
+        let record = AggregateRecord {
+            key: Some("hall".into()),
+            topic: None,
+            window: Some(WindowBounds {
+                start: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+                end: UNIX_EPOCH + Duration::from_secs(1_700_000_060),
+            }),
+            fields: vec![("avg".into(), Some(21.5)), ("rate".into(), None)],
+        };
+        assert_eq!(record.get("avg"), Some(21.5));
+        assert_eq!(record.value(), None);
+        assert_eq!(
+            PipelineOutput::Aggregate(record).to_json(),
+            json!({
+                "key": "hall",
+                "window": {"start": "2023-11-14T22:13:20Z", "end": "2023-11-14T22:14:20Z"},
+                "values": {"avg": 21.5, "rate": null},
+            })
+        );
+    }
+
+    #[test]
+    fn messages_serialize_with_payload() {
+        let output = PipelineOutput::Message {
+            topic: "sensors/a".into(),
+            headers: BTreeMap::new(),
+            payload: Some(json!({"t": 1})),
+        };
+        assert_eq!(
+            output.to_json(),
+            json!({"topic": "sensors/a", "payload": {"t": 1}})
+        );
+    }
+}
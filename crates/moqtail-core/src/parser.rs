@@ -1,151 +1,192 @@
 use pest::Parser;
 use pest_derive::Parser;
 
-use crate::ast::{Axis, Field, Operator, Predicate, Segment, Selector, Stage, Step, Value};
+use crate::ast::{
+    Assignment, Axis, BinaryOp, Comparison, Expr, Field, Metric, Operator, Pattern, Predicate,
+    Segment, Selector, Stage, Step, Value,
+};
+use crate::error::{Error, ErrorKind};
+use crate::matcher::field_names;
+use crate::transform;
+use pest::error::{ErrorVariant, InputLocation, LineColLocation};
+use pest::iterators::{Pair, Pairs};
+use pest::Span;
 use std::time::Duration;
-use thiserror::Error;
-
-#[derive(Debug, Error)]
-pub enum Error {
-    #[error(transparent)]
-    Pest(#[from] Box<pest::error::Error<Rule>>),
-    #[error(transparent)]
-    ParseInt(#[from] std::num::ParseIntError),
-    #[error(transparent)]
-    ParseFloat(#[from] std::num::ParseFloatError),
-    #[error("missing selector")]
-    MissingSelector,
-    #[error("missing axis")]
-    MissingAxis,
-    #[error("missing segment")]
-    MissingSegment,
-    #[error("unknown axis {0}")]
-    UnknownAxis(String),
-    #[error("unknown wildcard {0}")]
-    UnknownWildcard(String),
-    #[error("invalid segment")]
-    InvalidSegment,
-    #[error("missing field")]
-    MissingField,
-    #[error("missing operator")]
-    MissingOperator,
-    #[error("unknown operator {0}")]
-    UnknownOperator(String),
-    #[error("missing value")]
-    MissingValue,
-    #[error("invalid value")]
-    InvalidValue,
-    #[error("missing function")]
-    MissingFunction,
-    #[error("missing function name")]
-    MissingFunctionName,
-    #[error("window requires duration")]
-    WindowRequiresDuration,
-    #[error("sum requires field")]
-    SumRequiresField,
-    #[error("avg requires field")]
-    AvgRequiresField,
-    #[error("count takes no arguments")]
-    CountTakesNoArguments,
-    #[error("unknown function {0}")]
-    UnknownFunction(String),
-}
-
-impl From<pest::error::Error<Rule>> for Error {
-    fn from(err: pest::error::Error<Rule>) -> Self {
-        Error::Pest(Box::new(err))
-    }
-}
+
+/// Function names accepted in `|>` stages, used for suggestions.
+const FUNCTIONS: &[&str] = &[
+    "window",
+    "session",
+    "sliding",
+    "tumbling",
+    "hopping",
+    "sum",
+    "avg",
+    "count",
+    "min",
+    "max",
+    "first",
+    "last",
+    "stddev",
+    "median",
+    "percentile",
+    "rate",
+    "group_by",
+    "timestamp",
+    "agg",
+    "select",
+    "map",
+    "rename",
+    "distinct",
+    "throttle",
+    "debounce",
+    "sample",
+    "where",
+    "threshold",
+    "hysteresis",
+    "changed",
+];
+
+/// Keywords a misspelt word in a syntax error is compared against.
+const KEYWORDS: &[&str] = &[
+    "and",
+    "or",
+    "not",
+    "contains",
+    "in",
+    "startsWith",
+    "endsWith",
+    "true",
+    "false",
+    "msg",
+    "json",
+];
 
 #[derive(Parser)]
 #[grammar = "selector.pest"]
 struct SelectorParser;
 
 pub fn compile(input: &str) -> Result<Selector, Error> {
-    let mut pairs = SelectorParser::parse(Rule::selector, input)?;
-    let pair = pairs.next().ok_or(Error::MissingSelector)?;
+    let mut pairs =
+        SelectorParser::parse(Rule::selector, input).map_err(|e| syntax_error(e, input))?;
+    let whole = Span::new(input, 0, input.len()).expect("input span is valid");
+    let pair = pairs
+        .next()
+        .ok_or_else(|| ErrorKind::MissingSelector.at(whole))?;
     let mut steps = Vec::new();
     let mut stages = Vec::new();
+    let mut captures: Vec<String> = Vec::new();
 
     for seg in pair.into_inner() {
         match seg.as_rule() {
             Rule::path_segment => {
+                let span = seg.as_span();
                 let mut inner = seg.into_inner();
-                let axis_pair = inner.next().ok_or(Error::MissingAxis)?;
-                let segment_pair = inner.next().ok_or(Error::MissingSegment)?;
+                let axis_pair = inner
+                    .next()
+                    .ok_or_else(|| ErrorKind::MissingAxis.at(span))?;
+                let segment_pair = inner
+                    .next()
+                    .ok_or_else(|| ErrorKind::MissingSegment.at(span))?;
+                let segment_span = segment_pair.as_span();
                 let segment_inner = segment_pair
                     .into_inner()
                     .next()
-                    .ok_or(Error::MissingSegment)?;
+                    .ok_or_else(|| ErrorKind::MissingSegment.at(segment_span))?;
 
                 let axis = match axis_pair.as_str() {
                     "/" => Axis::Child,
                     "//" => Axis::Descendant,
-                    other => return Err(Error::UnknownAxis(other.to_string())),
+                    other => {
+                        return Err(
+                            ErrorKind::UnknownAxis(other.to_string()).at(axis_pair.as_span())
+                        )
+                    }
                 };
 
-                let segment = match segment_inner.as_rule() {
-                    Rule::wildcard => match segment_inner.as_str() {
-                        "+" => Segment::Plus,
-                        "#" => Segment::Hash,
-                        other => return Err(Error::UnknownWildcard(other.to_string())),
-                    },
-                    Rule::ident => {
-                        let s = segment_inner.as_str();
-                        if s == "msg" {
-                            Segment::Message
-                        } else {
-                            Segment::Literal(s.to_string())
-                        }
+                let (segment, capture) = if segment_inner.as_rule() == Rule::capture {
+                    let mut parts = segment_inner.into_inner();
+                    let name_pair = parts
+                        .next()
+                        .ok_or_else(|| ErrorKind::MissingSegment.at(segment_span))?;
+                    let name = name_pair.as_str().to_string();
+                    let segment = match parts.next() {
+                        Some(inner) => parse_segment(inner)?,
+                        None => Segment::Plus,
+                    };
+                    if segment == Segment::Message {
+                        return Err(ErrorKind::InvalidSegment.at(segment_span));
                     }
-                    _ => return Err(Error::InvalidSegment),
+                    if captures.contains(&name) {
+                        return Err(ErrorKind::DuplicateCapture(name).at(name_pair.as_span()));
+                    }
+                    captures.push(name.clone());
+                    (segment, Some(name))
+                } else {
+                    (parse_segment(segment_inner)?, None)
                 };
 
+                let fields = Fields {
+                    captures: &captures,
+                    in_step: true,
+                    values: &[],
+                };
                 let mut predicates = Vec::new();
                 for pred_pair in inner {
                     if pred_pair.as_rule() != Rule::predicate {
                         continue;
                     }
-                    let mut pred_inner = pred_pair.into_inner();
-                    let field_pair = pred_inner.next().ok_or(Error::MissingField)?;
-                    let inner_field = field_pair.into_inner().next().ok_or(Error::MissingField)?;
-                    let field = parse_field(inner_field)?;
-
-                    let op_pair = pred_inner.next().ok_or(Error::MissingOperator)?;
-                    let op = match op_pair.as_str() {
-                        "=" => Operator::Eq,
-                        "<" => Operator::Lt,
-                        ">" => Operator::Gt,
-                        "<=" => Operator::Le,
-                        ">=" => Operator::Ge,
-                        other => return Err(Error::UnknownOperator(other.to_string())),
-                    };
-
-                    let value_pair = pred_inner.next().ok_or(Error::MissingValue)?;
-                    let value_inner = value_pair.into_inner().next().ok_or(Error::MissingValue)?;
-                    let value = match value_inner.as_rule() {
-                        Rule::number => Value::Number(value_inner.as_str().parse::<f64>()?),
-                        Rule::boolean => Value::Bool(value_inner.as_str() == "true"),
-                        Rule::string => {
-                            let s = value_inner.as_str();
-                            let parsed: String =
-                                serde_json::from_str(s).map_err(|_| Error::InvalidValue)?;
-                            Value::Str(parsed)
-                        }
-                        _ => return Err(Error::InvalidValue),
-                    };
-
-                    predicates.push(Predicate { field, op, value });
+                    let pred_span = pred_pair.as_span();
+                    let expr = pred_pair
+                        .into_inner()
+                        .next()
+                        .ok_or_else(|| ErrorKind::MissingField.at(pred_span))?;
+                    predicates.push(parse_predicate(expr, &fields)?);
                 }
 
                 steps.push(Step {
                     axis,
                     segment,
+                    capture,
                     predicates,
                 });
             }
             Rule::stage => {
-                stages.push(parse_stage(seg)?);
+                let values = field_names(&stages);
+                let fields = Fields {
+                    captures: &captures,
+                    in_step: false,
+                    values: &values,
+                };
+                let span = seg.as_span();
+                let stage = parse_stage(seg, &fields)?;
+                if matches!(stage, Stage::GroupBy(_))
+                    && stages.iter().any(|s| matches!(s, Stage::GroupBy(_)))
+                {
+                    return Err(ErrorKind::DuplicateGroupBy.at(span));
+                }
+                if matches!(stage, Stage::Timestamp(..))
+                    && stages.iter().any(|s| matches!(s, Stage::Timestamp(..)))
+                {
+                    return Err(ErrorKind::DuplicateTimestamp.at(span));
+                }
+                if (stage.is_transform() || stage.is_message_filter())
+                    && stages.iter().any(Stage::is_aggregation)
+                {
+                    return Err(ErrorKind::StageAfterAggregation(stage.name()).at(span));
+                }
+                if stage.is_aggregation() && stages.iter().any(Stage::is_filter) {
+                    return Err(ErrorKind::AggregationAfterFilter(stage.name()).at(span));
+                }
+                let windowed = stages.iter().any(Stage::is_window);
+                let counts_rate = |s: &Stage| match s {
+                    Stage::Agg(metrics) => metrics.iter().any(|m| m.stage == Stage::Rate(None)),
+                    s => *s == Stage::Rate(None),
+                };
+                if counts_rate(&stage) && !windowed {
+                    return Err(ErrorKind::RequiresWindow("rate()".into()).at(span));
+                }
+                stages.push(stage);
             }
             _ => {}
         }
@@ -154,89 +195,876 @@ pub fn compile(input: &str) -> Result<Selector, Error> {
     Ok(Selector { steps, stages })
 }
 
-fn parse_field(inner_field: pest::iterators::Pair<Rule>) -> Result<Field, Error> {
-    match inner_field.as_rule() {
-        Rule::ident => Ok(Field::Header(inner_field.as_str().to_string())),
-        Rule::json_field => {
-            let text = inner_field.as_str();
-            // The grammar should provide the prefix, but validate to produce a
-            // dedicated error when it is missing.
-            let without = text.strip_prefix("json$").ok_or(Error::MissingField)?;
-            let parts: Vec<String> = without
-                .split('.')
-                .filter(|p| !p.is_empty())
-                .map(|p| p.to_string())
-                .collect();
-            if parts.is_empty() {
-                Err(Error::MissingField)
+/// Converts a grammar error, naming the expected tokens in user terms and
+/// suggesting a keyword or function when the word at the error position
+/// looks like a misspelling of one.
+fn syntax_error(err: pest::error::Error<Rule>, input: &str) -> Error {
+    let start = match err.location {
+        InputLocation::Pos(pos) => pos,
+        InputLocation::Span((start, _)) => start,
+    };
+    let (line, column) = match err.line_col {
+        LineColLocation::Pos(lc) | LineColLocation::Span(lc, _) => lc,
+    };
+    let message = match &err.variant {
+        ErrorVariant::ParsingError { positives, .. } => {
+            let mut expected: Vec<&str> = Vec::new();
+            for rule in positives {
+                let name = describe_rule(rule);
+                if !expected.contains(&name) {
+                    expected.push(name);
+                }
+            }
+            match expected.split_last() {
+                None => "unexpected input".to_string(),
+                Some((last, [])) => format!("expected {last}"),
+                Some((last, rest)) => format!("expected {} or {last}", rest.join(", ")),
+            }
+        }
+        ErrorVariant::CustomError { message } => message.clone(),
+    };
+    let word = input[start..]
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .next()
+        .unwrap_or("");
+    let error = Error {
+        kind: ErrorKind::Syntax(message),
+        span: start..start + word.len(),
+        line,
+        column,
+        suggestion: None,
+    };
+    if word.is_empty() {
+        error
+    } else {
+        error.suggest(word, KEYWORDS.iter().chain(FUNCTIONS).copied())
+    }
+}
+
+fn describe_rule(rule: &Rule) -> &'static str {
+    match rule {
+        Rule::EOI => "end of input",
+        Rule::selector | Rule::path_segment | Rule::slash => "`/`",
+        Rule::segment | Rule::capture | Rule::level | Rule::glob | Rule::wildcard => "topic level",
+        Rule::predicate => "`[`",
+        Rule::pred_or | Rule::pred_and | Rule::pred_unary | Rule::comparison | Rule::exists => {
+            "predicate"
+        }
+        Rule::or_op => "`or`",
+        Rule::and_op => "`and`",
+        Rule::not_op => "`not`",
+        Rule::field
+        | Rule::header_field
+        | Rule::json_field
+        | Rule::cbor_field
+        | Rule::pb_field
+        | Rule::segment_field
+        | Rule::capture_field
+        | Rule::topic_field => "field",
+        Rule::operator | Rule::word_operator => "operator",
+        Rule::value | Rule::list => "value",
+        Rule::number => "number",
+        Rule::boolean => "boolean",
+        Rule::string => "string",
+        Rule::ident => "name",
+        Rule::stage => "`|>`",
+        Rule::function => "function",
+        Rule::duration => "duration",
+        Rule::unit => "time unit",
+        Rule::message_count => "message count",
+        Rule::count_unit => "`msgs`",
+        Rule::aliased | Rule::as_op => "`as`",
+        Rule::assignment => "assignment",
+        Rule::renamed => "rename",
+        Rule::expr | Rule::product | Rule::negation | Rule::call => "expression",
+        Rule::add_op | Rule::mul_op => "operator",
+        Rule::condition | Rule::where_op => "`where`",
+        _ => "token",
+    }
+}
+
+/// What fields may reference at a given point: the captures bound so far,
+/// whether `.` (the current step's level) exists, and the names of the
+/// values the last aggregation so far reports.
+struct Fields<'a> {
+    captures: &'a [String],
+    in_step: bool,
+    values: &'a [String],
+}
+
+fn parse_segment(pair: Pair<Rule>) -> Result<Segment, Error> {
+    let span = pair.as_span();
+    match pair.as_rule() {
+        Rule::wildcard => match pair.as_str() {
+            "+" => Ok(Segment::Plus),
+            "#" => Ok(Segment::Hash),
+            other => Err(ErrorKind::UnknownWildcard(other.to_string()).at(span)),
+        },
+        Rule::glob => Ok(Segment::Glob(pair.as_str().to_string())),
+        Rule::level => match pair.as_str() {
+            "msg" => Ok(Segment::Message),
+            s => Ok(Segment::Literal(s.to_string())),
+        },
+        Rule::string => {
+            let level: String = serde_json::from_str(pair.as_str())
+                .map_err(|_| ErrorKind::InvalidSegment.at(span))?;
+            // MQTT levels are separated by `/` and may never contain NUL.
+            if level.contains(['/', '\0']) {
+                return Err(ErrorKind::InvalidSegment.at(span));
+            }
+            Ok(Segment::Literal(level))
+        }
+        _ => Err(ErrorKind::InvalidSegment.at(span)),
+    }
+}
+
+fn parse_predicate(pair: Pair<Rule>, fields: &Fields) -> Result<Predicate, Error> {
+    let span = pair.as_span();
+    match pair.as_rule() {
+        Rule::pred_or | Rule::pred_and => {
+            let is_or = pair.as_rule() == Rule::pred_or;
+            let mut operands = pair
+                .into_inner()
+                .filter(|p| !matches!(p.as_rule(), Rule::or_op | Rule::and_op));
+            let first = operands
+                .next()
+                .ok_or_else(|| ErrorKind::MissingField.at(span))?;
+            let mut expr = parse_predicate(first, fields)?;
+            for operand in operands {
+                let rhs = Box::new(parse_predicate(operand, fields)?);
+                expr = if is_or {
+                    Predicate::Or(Box::new(expr), rhs)
+                } else {
+                    Predicate::And(Box::new(expr), rhs)
+                };
+            }
+            Ok(expr)
+        }
+        Rule::pred_unary => {
+            let mut inner = pair.into_inner();
+            let first = inner
+                .next()
+                .ok_or_else(|| ErrorKind::MissingField.at(span))?;
+            if first.as_rule() == Rule::not_op {
+                let operand = inner
+                    .next()
+                    .ok_or_else(|| ErrorKind::MissingField.at(span))?;
+                Ok(Predicate::Not(Box::new(parse_predicate(operand, fields)?)))
             } else {
-                Ok(Field::Json(parts))
+                parse_predicate(first, fields)
+            }
+        }
+        Rule::comparison => Ok(Predicate::Compare(parse_comparison(pair, fields)?)),
+        Rule::exists => {
+            let field_pair = pair
+                .into_inner()
+                .next()
+                .ok_or_else(|| ErrorKind::MissingField.at(span))?;
+            Ok(Predicate::Exists(parse_field(field_pair, fields)?))
+        }
+        _ => Err(ErrorKind::MissingField.at(span)),
+    }
+}
+
+fn parse_comparison(pair: Pair<Rule>, fields: &Fields) -> Result<Comparison, Error> {
+    let span = pair.as_span();
+    let mut pred_inner = pair.into_inner();
+    let field_pair = pred_inner
+        .next()
+        .ok_or_else(|| ErrorKind::MissingField.at(span))?;
+    let field = parse_field(field_pair, fields)?;
+
+    let op_pair = pred_inner
+        .next()
+        .ok_or_else(|| ErrorKind::MissingOperator.at(span))?;
+    let op = match op_pair.as_str() {
+        "=" => Operator::Eq,
+        "!=" => Operator::Ne,
+        "<" => Operator::Lt,
+        ">" => Operator::Gt,
+        "<=" => Operator::Le,
+        ">=" => Operator::Ge,
+        "contains" => Operator::Contains,
+        "in" => Operator::In,
+        "startsWith" => Operator::StartsWith,
+        "endsWith" => Operator::EndsWith,
+        "~=" => Operator::Matches,
+        other => return Err(ErrorKind::UnknownOperator(other.to_string()).at(op_pair.as_span())),
+    };
+
+    let value_pair = pred_inner
+        .next()
+        .ok_or_else(|| ErrorKind::MissingValue.at(span))?;
+    let value_span = value_pair.as_span();
+    let value_inner = value_pair
+        .into_inner()
+        .next()
+        .ok_or_else(|| ErrorKind::MissingValue.at(value_span))?;
+    let value = match value_inner.as_rule() {
+        Rule::list => Value::List(
+            value_inner
+                .into_inner()
+                .map(parse_scalar)
+                .collect::<Result<_, _>>()?,
+        ),
+        _ => parse_scalar(value_inner)?,
+    };
+
+    match (op, &value) {
+        (Operator::In, Value::List(_)) => {}
+        (Operator::In, _) => return Err(ErrorKind::InRequiresList.at(value_span)),
+        (_, Value::List(_)) => return Err(ErrorKind::InvalidValue.at(value_span)),
+        (Operator::Matches, Value::Str(_)) => {}
+        (Operator::Matches, _) => return Err(ErrorKind::InvalidValue.at(value_span)),
+        _ => {}
+    }
+
+    let value = match value {
+        Value::Str(pattern) if op == Operator::Matches => {
+            Value::Regex(compile_pattern(pattern, value_span)?)
+        }
+        other => other,
+    };
+
+    Ok(Comparison { field, op, value })
+}
+
+/// Compiles the pattern of a `~=` comparison. `literal` is the quoted string
+/// as written in the selector; the error span points at the part of it the
+/// regex engine rejected.
+fn compile_pattern(pattern: String, literal: Span) -> Result<Pattern, Error> {
+    let (offset, message) = match regex_syntax::Parser::new().parse(&pattern) {
+        Err(regex_syntax::Error::Parse(err)) => (err.span().start.offset, err.kind().to_string()),
+        Err(regex_syntax::Error::Translate(err)) => {
+            (err.span().start.offset, err.kind().to_string())
+        }
+        Err(err) => (0, err.to_string()),
+        Ok(_) => match Pattern::new(&pattern) {
+            Ok(compiled) => return Ok(compiled),
+            Err(err) => (0, err.to_string()),
+        },
+    };
+    let start = literal.start() + raw_offset(literal.as_str(), offset);
+    let span = literal.get(start - literal.start()..).unwrap_or(literal);
+    Err(ErrorKind::InvalidRegex { pattern, message }.at(span))
+}
+
+/// Maps a byte offset in a decoded string literal to the offset of the same
+/// character in its quoted, escaped source text.
+fn raw_offset(raw: &str, decoded: usize) -> usize {
+    let mut chars = raw.char_indices().skip(1).peekable();
+    let mut seen = 0;
+    while let Some((pos, c)) = chars.next() {
+        if seen >= decoded || c == '"' {
+            return pos;
+        }
+        if c != '\\' {
+            seen += c.len_utf8();
+            continue;
+        }
+        match chars.next() {
+            Some((_, 'u')) => {
+                let hex: String = (0..4)
+                    .filter_map(|_| chars.next())
+                    .map(|(_, h)| h)
+                    .collect();
+                let unit = u32::from_str_radix(&hex, 16).unwrap_or(0);
+                if (0xD800..0xDC00).contains(&unit) {
+                    // High surrogate: the low half follows as another `\uXXXX`.
+                    chars.nth(5);
+                    seen += 4;
+                } else {
+                    seen += char::from_u32(unit).map_or(3, char::len_utf8);
+                }
             }
+            _ => seen += 1,
+        }
+    }
+    raw.len()
+}
+
+fn parse_scalar(pair: Pair<Rule>) -> Result<Value, Error> {
+    let span = pair.as_span();
+    match pair.as_rule() {
+        Rule::number => Ok(Value::Number(
+            pair.as_str()
+                .parse::<f64>()
+                .map_err(|e| ErrorKind::from(e).at(span))?,
+        )),
+        Rule::boolean => Ok(Value::Bool(pair.as_str() == "true")),
+        Rule::string => {
+            let parsed: String = serde_json::from_str(pair.as_str())
+                .map_err(|_| ErrorKind::InvalidValue.at(span))?;
+            Ok(Value::Str(parsed))
+        }
+        _ => Err(ErrorKind::InvalidValue.at(span)),
+    }
+}
+
+/// Parses a `field` pair, checking capture references and `.` against what
+/// is available at this point of the selector.
+fn parse_field(field_pair: Pair<Rule>, fields: &Fields) -> Result<Field, Error> {
+    let span = field_pair.as_span();
+    let inner_field = field_pair
+        .into_inner()
+        .next()
+        .ok_or_else(|| ErrorKind::MissingField.at(span))?;
+    match inner_field.as_rule() {
+        Rule::header_field => Ok(Field::Header(inner_field.as_str().to_string())),
+        Rule::topic_field => {
+            let digits = inner_field.as_str()["topic[".len()..].trim_end_matches(']');
+            let level = digits
+                .parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| ErrorKind::InvalidTopicLevel.at(span))?;
+            Ok(Field::TopicLevel(level))
+        }
+        Rule::segment_field if fields.in_step => Ok(Field::Segment),
+        Rule::segment_field => Err(ErrorKind::SegmentFieldOutsidePredicate.at(span)),
+        Rule::capture_field => {
+            let name = &inner_field.as_str()[1..];
+            if !fields.captures.iter().any(|c| c == name) {
+                return Err(ErrorKind::UnknownCapture(name.to_string())
+                    .at(span)
+                    .suggest(name, fields.captures.iter().map(String::as_str)));
+            }
+            Ok(Field::Capture(name.to_string()))
+        }
+        Rule::json_field => field_path(inner_field.as_str(), "json$")
+            .map(Field::Json)
+            .ok_or_else(|| ErrorKind::MissingField.at(span)),
+        Rule::cbor_field if cfg!(feature = "cbor") => field_path(inner_field.as_str(), "cbor$")
+            .map(Field::Cbor)
+            .ok_or_else(|| ErrorKind::MissingField.at(span)),
+        Rule::cbor_field => Err(ErrorKind::FieldNotEnabled {
+            field: "cbor$".into(),
+            feature: "cbor".into(),
+        }
+        .at(span)),
+        Rule::pb_field if cfg!(feature = "pb") => field_path(inner_field.as_str(), "pb$")
+            .map(Field::Protobuf)
+            .ok_or_else(|| ErrorKind::MissingField.at(span)),
+        Rule::pb_field => Err(ErrorKind::FieldNotEnabled {
+            field: "pb$".into(),
+            feature: "pb".into(),
         }
-        _ => unreachable!(),
+        .at(span)),
+        _ => Err(ErrorKind::MissingField.at(span)),
     }
 }
 
-fn parse_stage(pair: pest::iterators::Pair<Rule>) -> Result<Stage, Error> {
-    let mut inner = pair.into_inner();
-    let func_pair = inner.next().ok_or(Error::MissingFunction)?;
+/// Splits a payload field such as `json$.a.b` into its path after `prefix`.
+/// The grammar should provide the prefix, but it is validated so that a
+/// missing prefix or an empty path yields a dedicated error.
+fn field_path(text: &str, prefix: &str) -> Option<Vec<String>> {
+    let parts: Vec<String> = text
+        .strip_prefix(prefix)?
+        .split('.')
+        .filter(|p| !p.is_empty())
+        .map(|p| p.to_string())
+        .collect();
+    (!parts.is_empty()).then_some(parts)
+}
+
+fn parse_stage(pair: Pair<Rule>, fields: &Fields) -> Result<Stage, Error> {
+    let span = pair.as_span();
+    let func_pair = pair
+        .into_inner()
+        .next()
+        .ok_or_else(|| ErrorKind::MissingFunction.at(span))?;
+    match func_pair.as_rule() {
+        Rule::condition => parse_condition(func_pair, fields),
+        _ => parse_function(func_pair, fields),
+    }
+}
+
+fn parse_function(func_pair: Pair<Rule>, fields: &Fields) -> Result<Stage, Error> {
+    let func_span = func_pair.as_span();
     let mut func_inner = func_pair.into_inner();
-    let name = func_inner
+    let name_pair = func_inner
         .next()
-        .ok_or(Error::MissingFunctionName)?
-        .as_str();
+        .ok_or_else(|| ErrorKind::MissingFunctionName.at(func_span))?;
+    let name = name_pair.as_str();
+    match name {
+        "agg" => return parse_agg(func_inner, func_span, fields),
+        "select" | "map" | "rename" => return parse_transform(name, func_inner, func_span, fields),
+        "threshold" | "hysteresis" | "changed" => {
+            return parse_filter(name, func_inner, func_span, fields)
+        }
+        _ => {}
+    }
     let arg = func_inner.next();
+    let second = func_inner.next();
+    if let Some(extra) = func_inner.next().or(second
+        .clone()
+        .filter(|_| !matches!(name, "percentile" | "hopping" | "timestamp")))
+    {
+        return Err(ErrorKind::UnexpectedArgument(name.to_string()).at(extra.as_span()));
+    }
+    // Errors about a missing argument point at the whole call, others at the
+    // offending argument.
+    let arg_span = arg.as_ref().map_or(func_span, |a| a.as_span());
+    let field_arg = |kind: ErrorKind| match arg.clone() {
+        Some(a) if a.as_rule() == Rule::field => parse_field(a, fields),
+        _ => Err(kind.at(arg_span)),
+    };
+    let requires_field = || ErrorKind::RequiresField(name.to_string());
     match name {
-        "window" => {
-            let a = arg.ok_or(Error::WindowRequiresDuration)?;
-            if a.as_rule() != Rule::duration {
-                return Err(Error::WindowRequiresDuration);
-            }
-            let mut duration_parts = a.into_inner();
-            let value = duration_parts.next().ok_or(Error::WindowRequiresDuration)?;
-            let unit = duration_parts.next().ok_or(Error::WindowRequiresDuration)?;
-            if value.as_rule() != Rule::number || unit.as_rule() != Rule::unit {
-                return Err(Error::WindowRequiresDuration);
-            }
-            if duration_parts.next().is_some() {
-                return Err(Error::WindowRequiresDuration);
-            }
-
-            let amount = value.as_str().parse::<u64>()?;
-            let seconds = match unit.as_str() {
-                "s" => amount,
-                "m" => amount
-                    .checked_mul(60)
-                    .ok_or(Error::WindowRequiresDuration)?,
-                "h" => amount
-                    .checked_mul(3600)
-                    .ok_or(Error::WindowRequiresDuration)?,
-                _ => return Err(Error::WindowRequiresDuration),
-            };
-            Ok(Stage::Window(Duration::from_secs(seconds)))
+        "window" if arg.as_ref().map(Pair::as_rule) == Some(Rule::message_count) => {
+            let count = arg.and_then(|a| a.into_inner().next());
+            let capacity = count
+                .as_ref()
+                .and_then(|c| c.as_str().parse::<usize>().ok())
+                .filter(|c| *c > 0)
+                .ok_or_else(|| {
+                    ErrorKind::InvalidWindow("message count must be a positive integer".into())
+                        .at(arg_span)
+                })?;
+            Ok(Stage::CountWindow(capacity))
         }
-        "sum" => {
-            let a = arg.ok_or(Error::SumRequiresField)?;
-            if a.as_rule() != Rule::field {
-                return Err(Error::SumRequiresField);
+        "window" | "sliding" => Ok(Stage::Window(parse_duration(
+            arg,
+            arg_span,
+            ErrorKind::WindowRequiresDuration,
+        )?)),
+        "session" => {
+            let gap = parse_duration(arg, arg_span, ErrorKind::WindowRequiresDuration)?;
+            if gap.is_zero() {
+                return Err(
+                    ErrorKind::InvalidWindow("session gap must be positive".into()).at(arg_span),
+                );
             }
-            let field_inner = a.into_inner().next().ok_or(Error::SumRequiresField)?;
-            Ok(Stage::Sum(parse_field(field_inner)?))
+            Ok(Stage::Session(gap))
         }
-        "avg" => {
-            let a = arg.ok_or(Error::AvgRequiresField)?;
-            if a.as_rule() != Rule::field {
-                return Err(Error::AvgRequiresField);
+        "tumbling" => {
+            let size = parse_duration(arg, arg_span, ErrorKind::WindowRequiresDuration)?;
+            if size.is_zero() {
+                return Err(
+                    ErrorKind::InvalidWindow("window size must be positive".into()).at(arg_span),
+                );
             }
-            let field_inner = a.into_inner().next().ok_or(Error::AvgRequiresField)?;
-            Ok(Stage::Avg(parse_field(field_inner)?))
+            Ok(Stage::Tumbling(size))
         }
+        "hopping" => {
+            let size = parse_duration(arg, arg_span, ErrorKind::WindowRequiresDuration)?;
+            let hop_span = second.as_ref().map_or(func_span, |a| a.as_span());
+            let hop = parse_duration(second, hop_span, ErrorKind::WindowRequiresDuration)?;
+            if size.is_zero() {
+                return Err(
+                    ErrorKind::InvalidWindow("window size must be positive".into()).at(arg_span),
+                );
+            }
+            if hop.is_zero() || hop > size {
+                return Err(ErrorKind::InvalidWindow(
+                    "hop must be positive and no longer than the window".into(),
+                )
+                .at(hop_span));
+            }
+            if size.as_nanos().div_ceil(hop.as_nanos()) > MAX_HOPS_PER_WINDOW {
+                return Err(ErrorKind::InvalidWindow(format!(
+                    "a window may span at most {} hops",
+                    MAX_HOPS_PER_WINDOW
+                ))
+                .at(hop_span));
+            }
+            Ok(Stage::Hopping(size, hop))
+        }
+        "sum" => Ok(Stage::Sum(field_arg(ErrorKind::SumRequiresField)?)),
+        "avg" => Ok(Stage::Avg(field_arg(ErrorKind::AvgRequiresField)?)),
         "count" => {
             if arg.is_some() {
-                return Err(Error::CountTakesNoArguments);
+                return Err(ErrorKind::CountTakesNoArguments.at(arg_span));
             }
             Ok(Stage::Count)
         }
-        _ => Err(Error::UnknownFunction(name.to_string())),
+        "min" => Ok(Stage::Min(field_arg(requires_field())?)),
+        "max" => Ok(Stage::Max(field_arg(requires_field())?)),
+        "first" => Ok(Stage::First(field_arg(requires_field())?)),
+        "last" => Ok(Stage::Last(field_arg(requires_field())?)),
+        "stddev" => Ok(Stage::Stddev(field_arg(requires_field())?)),
+        "median" => Ok(Stage::Percentile(field_arg(requires_field())?, 50.0)),
+        "percentile" => {
+            let field = field_arg(requires_field())?;
+            let rank = second.ok_or_else(|| ErrorKind::InvalidPercentile.at(func_span))?;
+            let p = match rank.as_rule() {
+                Rule::number => rank.as_str().parse::<f64>().ok(),
+                _ => None,
+            }
+            .filter(|p| (0.0..=100.0).contains(p))
+            .ok_or_else(|| ErrorKind::InvalidPercentile.at(rank.as_span()))?;
+            Ok(Stage::Percentile(field, p))
+        }
+        "group_by" => Ok(Stage::GroupBy(field_arg(requires_field())?)),
+        "distinct" => Ok(Stage::Distinct(field_arg(requires_field())?)),
+        "throttle" | "debounce" | "sample" => {
+            let interval = parse_duration(arg, arg_span, ErrorKind::RequiresDuration(name.into()))?;
+            if interval.is_zero() {
+                return Err(ErrorKind::RequiresDuration(name.into()).at(arg_span));
+            }
+            Ok(match name {
+                "throttle" => Stage::Throttle(interval),
+                "debounce" => Stage::Debounce(interval),
+                _ => Stage::Sample(interval),
+            })
+        }
+        "timestamp" => {
+            let field = field_arg(requires_field())?;
+            let lateness = match second {
+                Some(lateness) => {
+                    let span = lateness.as_span();
+                    parse_duration(
+                        Some(lateness),
+                        span,
+                        ErrorKind::RequiresDuration("allowed lateness".into()),
+                    )?
+                }
+                None => Duration::ZERO,
+            };
+            Ok(Stage::Timestamp(field, lateness))
+        }
+        "rate" => match arg {
+            None => Ok(Stage::Rate(None)),
+            Some(_) => Ok(Stage::Rate(Some(field_arg(requires_field())?))),
+        },
+        _ => match percentile_shorthand(name) {
+            Some(p) => Ok(Stage::Percentile(field_arg(requires_field())?, p)),
+            None => Err(ErrorKind::UnknownFunction(name.to_string())
+                .at(name_pair.as_span())
+                .suggest(name, FUNCTIONS.iter().copied())),
+        },
+    }
+}
+
+/// Parses the arguments of `agg(...)`: aggregations, each optionally named
+/// with `as`, whose names must differ.
+fn parse_agg(args: Pairs<Rule>, span: Span, fields: &Fields) -> Result<Stage, Error> {
+    let mut metrics: Vec<Metric> = Vec::new();
+    for arg in args {
+        let arg_span = arg.as_span();
+        let (function, alias) = match arg.as_rule() {
+            Rule::function => (arg, None),
+            Rule::aliased => {
+                let mut inner = arg.into_inner();
+                let function = inner
+                    .next()
+                    .ok_or_else(|| ErrorKind::MissingFunction.at(arg_span))?;
+                let alias = inner.find(|p| p.as_rule() == Rule::ident);
+                (function, alias.map(|a| a.as_str().to_string()))
+            }
+            _ => return Err(ErrorKind::AggRequiresAggregations.at(arg_span)),
+        };
+        let stage = parse_function(function, fields)?;
+        if !stage.is_aggregation() || matches!(stage, Stage::Agg(_)) {
+            return Err(ErrorKind::AggRequiresAggregations.at(arg_span));
+        }
+        let metric = Metric { stage, alias };
+        let name = metric.name();
+        if metrics.iter().any(|m| m.name() == name) {
+            return Err(ErrorKind::DuplicateName(name).at(arg_span));
+        }
+        metrics.push(metric);
+    }
+    if metrics.is_empty() {
+        return Err(ErrorKind::AggRequiresAggregations.at(span));
+    }
+    Ok(Stage::Agg(metrics))
+}
+
+/// Parses the arguments of `select`, `map` and `rename`, which read and
+/// write JSON paths only.
+fn parse_transform(
+    name: &str,
+    args: Pairs<Rule>,
+    span: Span,
+    fields: &Fields,
+) -> Result<Stage, Error> {
+    let json_path = |pair: Pair<Rule>| {
+        let span = pair.as_span();
+        match parse_field(pair, fields)? {
+            Field::Json(path) => Ok(path),
+            _ => Err(ErrorKind::RequiresJsonField(name.to_string()).at(span)),
+        }
+    };
+    let expected = |span| match name {
+        "select" => ErrorKind::RequiresJsonField(name.to_string()).at(span),
+        "map" => ErrorKind::MapRequiresAssignment.at(span),
+        _ => ErrorKind::RenameRequiresFields.at(span),
+    };
+    let mut stage = match name {
+        "select" => Stage::Select(Vec::new()),
+        "map" => Stage::Map(Vec::new()),
+        _ => Stage::Rename(Vec::new()),
+    };
+    for arg in args {
+        let arg_span = arg.as_span();
+        match (&mut stage, arg.as_rule()) {
+            (Stage::Select(paths), Rule::field) => paths.push(json_path(arg)?),
+            (Stage::Map(assignments), Rule::assignment) => {
+                let mut inner = arg.into_inner();
+                let (Some(target), Some(expr)) = (inner.next(), inner.next()) else {
+                    return Err(expected(arg_span));
+                };
+                assignments.push(Assignment {
+                    target: json_path(target)?,
+                    expr: parse_expr(expr, fields)?,
+                });
+            }
+            (Stage::Rename(moves), Rule::renamed) => {
+                let mut inner = arg.into_inner().filter(|p| p.as_rule() == Rule::field);
+                let (Some(from), Some(to)) = (inner.next(), inner.next()) else {
+                    return Err(expected(arg_span));
+                };
+                moves.push((json_path(from)?, json_path(to)?));
+            }
+            _ => return Err(expected(arg_span)),
+        }
+    }
+    let empty = match &stage {
+        Stage::Select(paths) => paths.is_empty(),
+        Stage::Map(assignments) => assignments.is_empty(),
+        Stage::Rename(moves) => moves.is_empty(),
+        _ => false,
+    };
+    if empty {
+        return Err(expected(span));
+    }
+    Ok(stage)
+}
+
+/// Parses `where(...)`, whose fields must name values of the last
+/// aggregation.
+fn parse_condition(pair: Pair<Rule>, fields: &Fields) -> Result<Stage, Error> {
+    let span = pair.as_span();
+    if fields.values.is_empty() {
+        return Err(ErrorKind::RequiresAggregation("where".into()).at(span));
+    }
+    let pred = pair
+        .into_inner()
+        .find(|p| p.as_rule() == Rule::pred_or)
+        .ok_or_else(|| ErrorKind::MissingField.at(span))?;
+    for field in pred.clone().into_inner().flatten() {
+        if field.as_rule() == Rule::field {
+            value_name(field, fields)?;
+        }
+    }
+    Ok(Stage::Where(parse_predicate(pred, fields)?))
+}
+
+/// Parses `threshold`, `hysteresis` and `changed`, whose optional first
+/// argument names the value they watch.
+fn parse_filter(
+    name: &str,
+    args: Pairs<Rule>,
+    span: Span,
+    fields: &Fields,
+) -> Result<Stage, Error> {
+    if fields.values.is_empty() {
+        return Err(ErrorKind::RequiresAggregation(name.to_string()).at(span));
+    }
+    let mut args = args.peekable();
+    let value = match args.next_if(|arg| arg.as_rule() == Rule::field) {
+        Some(arg) => Some(value_name(arg, fields)?),
+        None => None,
+    };
+    let mut limits = Vec::new();
+    for arg in args {
+        let arg_span = arg.as_span();
+        if arg.as_rule() != Rule::number {
+            return Err(ErrorKind::UnexpectedArgument(name.to_string()).at(arg_span));
+        }
+        limits.push(
+            arg.as_str()
+                .parse::<f64>()
+                .map_err(|e| ErrorKind::from(e).at(arg_span))?,
+        );
+    }
+    let watched = || value.clone().unwrap_or_else(|| "value".to_string());
+    match (name, limits.as_slice()) {
+        ("changed", []) => Ok(Stage::Changed(value)),
+        ("threshold", [limit]) => Ok(Stage::Threshold(watched(), *limit)),
+        ("hysteresis", [high, low]) if high >= low => Ok(Stage::Hysteresis(watched(), *high, *low)),
+        ("hysteresis", [_, _]) => Err(ErrorKind::InvalidHysteresis.at(span)),
+        _ => Err(ErrorKind::ArgumentCount {
+            name: name.to_string(),
+            expected: match name {
+                "changed" => "at most a value name",
+                "threshold" => "a limit",
+                _ => "a high and a low limit",
+            }
+            .to_string(),
+        }
+        .at(span)),
+    }
+}
+
+/// Resolves a field naming a value of the last aggregation: `value` for its
+/// last value, or a name it reports, such as `avg` or an `agg(...)` alias.
+fn value_name(pair: Pair<Rule>, fields: &Fields) -> Result<String, Error> {
+    let span = pair.as_span();
+    let name = pair.as_str();
+    let bare = pair.into_inner().next().map(|p| p.as_rule()) == Some(Rule::header_field);
+    if bare && (name == "value" || fields.values.iter().any(|v| v == name)) {
+        return Ok(name.to_string());
+    }
+    let candidates = std::iter::once("value").chain(fields.values.iter().map(String::as_str));
+    Err(ErrorKind::UnknownValue(name.to_string())
+        .at(span)
+        .suggest(name, candidates))
+}
+
+/// Parses an `expr`, `product`, `negation` or operand into an [`Expr`],
+/// grouping operators of equal precedence from the left.
+fn parse_expr(pair: Pair<Rule>, fields: &Fields) -> Result<Expr, Error> {
+    let span = pair.as_span();
+    match pair.as_rule() {
+        Rule::expr | Rule::product => {
+            let mut inner = pair.into_inner();
+            let first = inner
+                .next()
+                .ok_or_else(|| ErrorKind::MissingValue.at(span))?;
+            let mut expr = parse_expr(first, fields)?;
+            while let (Some(op), Some(rhs)) = (inner.next(), inner.next()) {
+                let op = match op.as_str() {
+                    "+" => BinaryOp::Add,
+                    "-" => BinaryOp::Sub,
+                    "*" => BinaryOp::Mul,
+                    "/" => BinaryOp::Div,
+                    _ => BinaryOp::Rem,
+                };
+                expr = Expr::Binary(op, Box::new(expr), Box::new(parse_expr(rhs, fields)?));
+            }
+            Ok(expr)
+        }
+        Rule::negation => {
+            let inner = pair
+                .into_inner()
+                .next()
+                .ok_or_else(|| ErrorKind::MissingValue.at(span))?;
+            Ok(match parse_expr(inner, fields)? {
+                Expr::Number(n) => Expr::Number(-n),
+                expr => Expr::Neg(Box::new(expr)),
+            })
+        }
+        Rule::number => pair
+            .as_str()
+            .parse()
+            .map(Expr::Number)
+            .map_err(|e: std::num::ParseFloatError| ErrorKind::from(e).at(span)),
+        Rule::string => serde_json::from_str(pair.as_str())
+            .map(Expr::Str)
+            .map_err(|_| ErrorKind::InvalidValue.at(span)),
+        Rule::field => Ok(Expr::Field(parse_field(pair, fields)?)),
+        Rule::call => {
+            let mut inner = pair.into_inner();
+            let name_pair = inner
+                .next()
+                .ok_or_else(|| ErrorKind::MissingFunctionName.at(span))?;
+            let name = name_pair.as_str();
+            let Some(&(_, min, max)) = transform::FUNCTIONS.iter().find(|(n, ..)| *n == name)
+            else {
+                return Err(ErrorKind::UnknownFunction(name.to_string())
+                    .at(name_pair.as_span())
+                    .suggest(name, transform::FUNCTIONS.iter().map(|(n, ..)| *n)));
+            };
+            let args = inner
+                .map(|arg| parse_expr(arg, fields))
+                .collect::<Result<Vec<_>, _>>()?;
+            if args.len() < min || args.len() > max {
+                let expected = match (min, max) {
+                    (1, 1) => "1 argument".to_string(),
+                    (min, usize::MAX) => format!("at least {min} argument"),
+                    (min, max) if min == max => format!("{min} arguments"),
+                    (min, max) => format!("{min} to {max} arguments"),
+                };
+                return Err(ErrorKind::ArgumentCount {
+                    name: name.to_string(),
+                    expected,
+                }
+                .at(span));
+            }
+            Ok(Expr::Call(name.to_string(), args))
+        }
+        _ => Err(ErrorKind::MissingValue.at(span)),
+    }
+}
+
+/// Parses a duration such as `60s`; `span` locates the error, of the given
+/// kind, when the argument is missing or is not a duration.
+fn parse_duration(arg: Option<Pair<Rule>>, span: Span, kind: ErrorKind) -> Result<Duration, Error> {
+    let err = || kind.clone().at(span);
+    let a = arg.ok_or_else(err)?;
+    if a.as_rule() != Rule::duration {
+        return Err(err());
+    }
+    let duration_span = a.as_span();
+    let mut total: u128 = 0;
+    let mut parts = a.into_inner();
+    while let Some(value) = parts.next() {
+        let unit = parts.next().ok_or_else(err)?;
+        if value.as_rule() != Rule::number || unit.as_rule() != Rule::unit {
+            return Err(err());
+        }
+        let nanos = duration_nanos(value.as_str(), unit.as_str())
+            .map_err(|message| ErrorKind::InvalidDuration(message.into()).at(value.as_span()))?;
+        total = total.checked_add(nanos).ok_or_else(|| {
+            ErrorKind::InvalidDuration("duration is too long".into()).at(duration_span)
+        })?;
+    }
+    let secs = u64::try_from(total / NANOS_PER_SEC)
+        .map_err(|_| ErrorKind::InvalidDuration("duration is too long".into()).at(duration_span))?;
+    Ok(Duration::new(secs, (total % NANOS_PER_SEC) as u32))
+}
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Overlapping windows of `hopping(size, hop)`, each of which receives every
+/// message.
+const MAX_HOPS_PER_WINDOW: u128 = 1_000;
+
+/// The exact number of nanoseconds in `amount` of `unit`, computed from the
+/// decimal digits so that fractions such as `1.5s` lose no precision.
+fn duration_nanos(amount: &str, unit: &str) -> Result<u128, &'static str> {
+    let per_unit = match unit {
+        "ms" => 1_000_000,
+        "s" => NANOS_PER_SEC,
+        "m" => 60 * NANOS_PER_SEC,
+        "h" => 3_600 * NANOS_PER_SEC,
+        _ => 86_400 * NANOS_PER_SEC,
+    };
+    if amount.starts_with('-') {
+        return Err("durations cannot be negative");
+    }
+    let (whole, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    let fraction = fraction.trim_end_matches('0');
+    let too_long = "duration is too long";
+    let whole = whole
+        .parse::<u128>()
+        .ok()
+        .and_then(|w| w.checked_mul(per_unit))
+        .ok_or(too_long)?;
+    if fraction.is_empty() {
+        return Ok(whole);
+    }
+    // No unit has exact fractions beyond 16 digits, so longer ones are
+    // rejected before they can overflow.
+    if fraction.len() > 16 {
+        return Err("durations are limited to nanosecond precision");
+    }
+    let scale = 10u128.pow(fraction.len() as u32);
+    let scaled = fraction.parse::<u128>().map_err(|_| too_long)? * per_unit;
+    if scaled % scale != 0 {
+        return Err("durations are limited to nanosecond precision");
+    }
+    whole.checked_add(scaled / scale).ok_or(too_long)
+}
+
+/// `p1` to `p99`, e.g. `p95(json$.latency)`.
+fn percentile_shorthand(name: &str) -> Option<f64> {
+    let digits = name.strip_prefix('p')?;
+    if digits.is_empty() || digits.len() > 2 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
+    let p: u8 = digits.parse().ok()?;
+    (1..=99).contains(&p).then_some(f64::from(p))
 }
@@ -0,0 +1,120 @@
+//! Per-key pipeline state for `group_by`.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Pipeline state keyed by the `group_by` value, `None` when the pipeline is
+/// not grouped. Holds at most `capacity` partitions and evicts the least
+/// recently used one to make room for a new key, handing it back so that its
+/// open windows can be closed.
+pub(crate) struct Partitions<T> {
+    entries: HashMap<Option<String>, (u64, T)>,
+    /// Keys by the use counter they were last touched at, oldest first.
+    recency: BTreeMap<u64, Option<String>>,
+    next_use: u64,
+    capacity: usize,
+}
+
+impl<T> Partitions<T> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            next_use: 0,
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Changes the cap, returning the partitions evicted to fit it, oldest
+    /// first.
+    pub(crate) fn set_capacity(&mut self, capacity: usize) -> Vec<(Option<String>, T)> {
+        self.capacity = capacity.max(1);
+        let mut evicted = Vec::new();
+        while self.entries.len() > self.capacity {
+            evicted.extend(self.evict_oldest());
+        }
+        evicted
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the state for `key`, creating it with `init`, along with the
+    /// least recently used partition if it was evicted because the cap is
+    /// reached.
+    pub(crate) fn get_or_insert_with(
+        &mut self,
+        key: Option<String>,
+        init: impl FnOnce() -> T,
+    ) -> (&mut T, Option<(Option<String>, T)>) {
+        let used = self.next_use;
+        self.next_use += 1;
+        let mut evicted = None;
+        if let Some((last_use, _)) = self.entries.get(&key) {
+            self.recency.remove(last_use);
+        } else if self.entries.len() >= self.capacity {
+            evicted = self.evict_oldest();
+        }
+        self.recency.insert(used, key.clone());
+        let entry = self.entries.entry(key).or_insert_with(|| (used, init()));
+        entry.0 = used;
+        (&mut entry.1, evicted)
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = (&Option<String>, &mut T)> {
+        self.entries
+            .iter_mut()
+            .map(|(key, (_, state))| (key, state))
+    }
+
+    fn evict_oldest(&mut self) -> Option<(Option<String>, T)> {
+        let (_, key) = self.recency.pop_first()?;
+        let (_, state) = self.entries.remove(&key)?;
+        Some((key, state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(k: &str) -> Option<String> {
+        Some(k.to_string())
+    }
+
+    #[test]
+    fn evicts_least_recently_used_partition() {
+        let mut partitions = Partitions::new(2);
+        let mut bump = |k: &str| {
+            let (count, evicted) = partitions.get_or_insert_with(key(k), || 0);
+            *count += 1;
+            evicted
+        };
+        assert_eq!(bump("a"), None);
+        assert_eq!(bump("b"), None);
+        assert_eq!(bump("a"), None);
+        assert_eq!(bump("c"), Some((key("b"), 1)));
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(*partitions.get_or_insert_with(key("a"), || 0).0, 2);
+        assert_eq!(*partitions.get_or_insert_with(key("b"), || 0).0, 0);
+    }
+
+    #[test]
+    fn shrinking_the_cap_evicts_immediately() {
+        let mut partitions = Partitions::new(3);
+        for k in ["a", "b", "c"] {
+            partitions.get_or_insert_with(key(k), || ());
+        }
+        let evicted: Vec<_> = partitions
+            .set_capacity(1)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(evicted, [key("a"), key("b")]);
+        assert_eq!(partitions.len(), 1);
+        let mut keys: Vec<_> = partitions.iter_mut().map(|(k, _)| k.clone()).collect();
+        keys.sort();
+        assert_eq!(keys, [key("c")]);
+    }
+}
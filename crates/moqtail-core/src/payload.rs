@@ -0,0 +1,615 @@
+//! Message payloads decoded on demand.
+//!
+//! A matcher knows which `json$` and `cbor$` paths its selector reads. The
+//! first of them to need the payload of a raw message decodes just those
+//! paths: JSON objects are scanned key by key and values nobody reads are
+//! skipped without being built, while CBOR payloads, small by design, are
+//! decoded in one go and only the paths read are converted. Later reads of
+//! paths the decoded tree already covers reuse it; any other read decodes
+//! the whole payload, once. Protobuf payloads are decoded whole, once for
+//! each message type `pb$` fields read them as.
+
+use crate::ast::{Expr, Field, Predicate, Selector, Stage};
+#[cfg(feature = "pb")]
+use crate::schema::{self, Schemas};
+#[cfg(feature = "cbor")]
+use ciborium::Value as CborValue;
+#[cfg(feature = "pb")]
+use protobuf::reflect::MessageDescriptor;
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use serde_json::{Map, Value as JsonValue};
+use std::borrow::Cow;
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::fmt;
+#[cfg(feature = "pb")]
+use std::sync::Arc;
+
+/// The payload of a [`Message`](crate::Message).
+pub enum Payload<'a> {
+    /// The message has no payload.
+    None,
+    /// A JSON payload the caller already decoded.
+    Json(JsonValue),
+    /// Raw bytes, decoded as JSON, CBOR or Protobuf only when a selector
+    /// reads them.
+    /// Payloads that do not decode read as missing.
+    Raw(RawPayload<'a>),
+}
+
+/// Raw payload bytes with what was decoded from them so far.
+pub struct RawPayload<'a> {
+    bytes: Cow<'a, [u8]>,
+    json: Decoded,
+    /// Boxed so payloads never read as CBOR stay small.
+    cbor: OnceCell<Box<Decoded>>,
+    #[cfg(feature = "pb")]
+    protobuf: OnceCell<Box<Message>>,
+}
+
+/// A payload decoded as a Protobuf message type, followed by the decodings
+/// as other types.
+#[cfg(feature = "pb")]
+struct Message {
+    type_name: String,
+    tree: Option<JsonValue>,
+    next: OnceCell<Box<Message>>,
+}
+
+/// The trees decoded from a payload in one format.
+#[derive(Default)]
+struct Decoded {
+    /// The paths decoded first and the tree holding them.
+    partial: OnceCell<(Paths, Option<JsonValue>)>,
+    whole: OnceCell<Option<JsonValue>>,
+}
+
+impl Decoded {
+    fn read(&self, bytes: &[u8], format: Format, paths: &Paths) -> Option<&JsonValue> {
+        if let Some(tree) = self.whole.get() {
+            return tree.as_ref();
+        }
+        if !paths.whole {
+            let (covered, tree) = self
+                .partial
+                .get_or_init(|| (paths.clone(), decode(bytes, format, paths)));
+            if covered.covers(paths) {
+                return tree.as_ref();
+            }
+        }
+        self.whole
+            .get_or_init(|| decode(bytes, format, &ALL))
+            .as_ref()
+    }
+}
+
+/// An encoding a payload field can be read in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Json,
+    Cbor,
+}
+
+impl<'a> Payload<'a> {
+    pub fn raw(bytes: impl Into<Cow<'a, [u8]>>) -> Self {
+        Payload::Raw(RawPayload {
+            bytes: bytes.into(),
+            json: Decoded::default(),
+            cbor: OnceCell::new(),
+            #[cfg(feature = "pb")]
+            protobuf: OnceCell::new(),
+        })
+    }
+
+    /// The whole payload as JSON, decoding raw bytes if needed. `None` when
+    /// there is no payload or it is not JSON.
+    pub fn json(&self) -> Option<&JsonValue> {
+        self.read(Format::Json, &ALL)
+    }
+
+    /// The payload decoded from `format`, holding at least `paths`. Only raw
+    /// payloads can be read as CBOR.
+    pub(crate) fn read(&self, format: Format, paths: &Paths) -> Option<&JsonValue> {
+        match (self, format) {
+            (Payload::Raw(raw), Format::Json) => raw.json.read(&raw.bytes, format, paths),
+            (Payload::Raw(raw), Format::Cbor) => {
+                let cbor = raw.cbor.get_or_init(Box::default);
+                cbor.read(&raw.bytes, format, paths)
+            }
+            (Payload::Json(json), Format::Json) => Some(json),
+            _ => None,
+        }
+    }
+
+    /// The payload decoded as a message of type `message`. Only raw
+    /// payloads can be read as Protobuf.
+    #[cfg(feature = "pb")]
+    pub(crate) fn protobuf(&self, message: &MessageDescriptor) -> Option<&JsonValue> {
+        let Payload::Raw(raw) = self else {
+            return None;
+        };
+        let mut cell = &raw.protobuf;
+        loop {
+            let decoded = cell.get_or_init(|| {
+                Box::new(Message {
+                    type_name: message.full_name().to_string(),
+                    tree: schema::decode(message, &raw.bytes),
+                    next: OnceCell::new(),
+                })
+            });
+            if decoded.type_name == message.full_name() {
+                return decoded.tree.as_ref();
+            }
+            cell = &decoded.next;
+        }
+    }
+}
+
+impl From<JsonValue> for Payload<'_> {
+    fn from(json: JsonValue) -> Self {
+        Payload::Json(json)
+    }
+}
+
+impl From<Option<JsonValue>> for Payload<'_> {
+    fn from(json: Option<JsonValue>) -> Self {
+        json.map_or(Payload::None, Payload::Json)
+    }
+}
+
+/// The payload paths a selector reads in each format, and the schemas it
+/// reads Protobuf payloads with.
+#[derive(Default, Debug)]
+pub(crate) struct Reads {
+    json: Paths,
+    cbor: Paths,
+    #[cfg(feature = "pb")]
+    pub(crate) schemas: Option<Arc<Schemas>>,
+}
+
+impl Reads {
+    /// The paths a selector reads. Transforms pass the rest of a JSON
+    /// payload on, so they read all of it.
+    pub(crate) fn of(selector: &Selector) -> Self {
+        let mut reads = Reads::default();
+        for pred in selector.steps.iter().flat_map(|step| &step.predicates) {
+            reads.add_predicate(pred);
+        }
+        for stage in &selector.stages {
+            reads.add_stage(stage);
+        }
+        reads
+    }
+
+    /// Reads of whole payloads.
+    pub(crate) fn whole() -> Self {
+        Reads {
+            json: Paths::WHOLE,
+            cbor: Paths::WHOLE,
+            #[cfg(feature = "pb")]
+            schemas: None,
+        }
+    }
+
+    pub(crate) fn paths(&self, format: Format) -> &Paths {
+        match format {
+            Format::Json => &self.json,
+            Format::Cbor => &self.cbor,
+        }
+    }
+
+    fn add_stage(&mut self, stage: &Stage) {
+        match stage {
+            Stage::Select(_) | Stage::Rename(_) => self.json.whole = true,
+            Stage::Map(assignments) => {
+                self.json.whole = true;
+                for assignment in assignments {
+                    self.add_expr(&assignment.expr);
+                }
+            }
+            Stage::Agg(metrics) => {
+                for metric in metrics {
+                    self.add_stage(&metric.stage);
+                }
+            }
+            Stage::GroupBy(field) | Stage::Timestamp(field, _) | Stage::Distinct(field) => {
+                self.add_field(field)
+            }
+            stage => {
+                if let Some(field) = stage.field() {
+                    self.add_field(field);
+                }
+            }
+        }
+    }
+
+    fn add_predicate(&mut self, pred: &Predicate) {
+        match pred {
+            Predicate::Compare(cmp) => self.add_field(&cmp.field),
+            Predicate::Exists(field) => self.add_field(field),
+            Predicate::And(l, r) | Predicate::Or(l, r) => {
+                self.add_predicate(l);
+                self.add_predicate(r);
+            }
+            Predicate::Not(inner) => self.add_predicate(inner),
+        }
+    }
+
+    fn add_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Field(field) => self.add_field(field),
+            Expr::Neg(inner) => self.add_expr(inner),
+            Expr::Binary(_, l, r) => {
+                self.add_expr(l);
+                self.add_expr(r);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|arg| self.add_expr(arg)),
+            Expr::Number(_) | Expr::Str(_) => {}
+        }
+    }
+
+    fn add_field(&mut self, field: &Field) {
+        match field {
+            Field::Json(path) => self.json.add(path),
+            Field::Cbor(path) => self.cbor.add(path),
+            _ => {}
+        }
+    }
+}
+
+/// Paths read from a payload, as a tree of object keys.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub(crate) struct Paths {
+    /// Whether everything below this point is read.
+    whole: bool,
+    children: BTreeMap<String, Paths>,
+}
+
+/// Paths reading everything, to borrow.
+static ALL: Paths = Paths::WHOLE;
+
+impl Paths {
+    const WHOLE: Paths = Paths {
+        whole: true,
+        children: BTreeMap::new(),
+    };
+
+    fn add(&mut self, path: &[String]) {
+        let mut node = self;
+        for key in path {
+            if node.whole {
+                return;
+            }
+            node = node.children.entry(key.clone()).or_default();
+        }
+        node.whole = true;
+        node.children.clear();
+    }
+
+    /// Whether a tree decoded for `self` holds everything `other` reads.
+    fn covers(&self, other: &Paths) -> bool {
+        self.whole
+            || (!other.whole
+                && other.children.iter().all(|(key, child)| {
+                    self.children
+                        .get(key)
+                        .is_some_and(|mine| mine.covers(child))
+                }))
+    }
+
+    #[cfg(feature = "cbor")]
+    fn child(&self, key: &str) -> Option<&Paths> {
+        match self.whole {
+            true => Some(&ALL),
+            false => self.children.get(key),
+        }
+    }
+}
+
+/// Decodes `paths` of a payload, `None` when it is not in `format`.
+fn decode(bytes: &[u8], format: Format, paths: &Paths) -> Option<JsonValue> {
+    match format {
+        Format::Json => decode_json(bytes, paths),
+        #[cfg(feature = "cbor")]
+        Format::Cbor => {
+            let value: CborValue = ciborium::from_reader(bytes).ok()?;
+            Some(from_cbor(value, paths))
+        }
+        // `cbor$` fields do not compile without the feature.
+        #[cfg(not(feature = "cbor"))]
+        Format::Cbor => None,
+    }
+}
+
+/// Converts the parts of a CBOR value under `paths`, leaving out like
+/// [`decode_json`] does the map entries that are not read and the items of
+/// arrays read only in part. Map keys become text, byte strings lowercase
+/// hex and tags the value they wrap, except bignums, which become numbers.
+#[cfg(feature = "cbor")]
+fn from_cbor(value: CborValue, paths: &Paths) -> JsonValue {
+    match value {
+        CborValue::Integer(n) => integer(i128::from(n)),
+        CborValue::Float(f) => f.into(),
+        CborValue::Text(s) => s.into(),
+        CborValue::Bool(b) => b.into(),
+        CborValue::Null => JsonValue::Null,
+        CborValue::Bytes(bytes) => hex(&bytes).into(),
+        CborValue::Tag(tag @ (2 | 3), inner) => match *inner {
+            CborValue::Bytes(bytes) => bignum(tag == 3, &bytes),
+            inner => from_cbor(inner, paths),
+        },
+        CborValue::Tag(_, inner) => from_cbor(*inner, paths),
+        CborValue::Array(items) if paths.whole => items
+            .into_iter()
+            .map(|item| from_cbor(item, &ALL))
+            .collect(),
+        CborValue::Array(_) => JsonValue::Array(Vec::new()),
+        CborValue::Map(entries) => {
+            let mut out = Map::new();
+            for (key, value) in entries {
+                let Some(key) = cbor_key(key) else {
+                    continue;
+                };
+                if let Some(child) = paths.child(&key) {
+                    out.insert(key, from_cbor(value, child));
+                }
+            }
+            JsonValue::Object(out)
+        }
+        _ => JsonValue::Null,
+    }
+}
+
+/// Map keys that paths can name: text, integers, booleans and byte strings.
+#[cfg(feature = "cbor")]
+fn cbor_key(key: CborValue) -> Option<String> {
+    match key {
+        CborValue::Text(s) => Some(s),
+        CborValue::Integer(n) => Some(i128::from(n).to_string()),
+        CborValue::Bool(b) => Some(b.to_string()),
+        CborValue::Bytes(bytes) => Some(hex(&bytes)),
+        CborValue::Tag(_, inner) => cbor_key(*inner),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "cbor")]
+fn integer(n: i128) -> JsonValue {
+    if let Ok(n) = i64::try_from(n) {
+        n.into()
+    } else if let Ok(n) = u64::try_from(n) {
+        n.into()
+    } else {
+        (n as f64).into()
+    }
+}
+
+/// The number a bignum tag holds: `n` for tag 2, `-1 - n` for tag 3. Ones
+/// beyond the range of JSON integers lose precision as floats.
+#[cfg(feature = "cbor")]
+fn bignum(negative: bool, bytes: &[u8]) -> JsonValue {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    let n = &bytes[start..];
+    if n.len() < 16 {
+        let n = n.iter().fold(0i128, |n, b| n << 8 | i128::from(*b));
+        integer(if negative { -1 - n } else { n })
+    } else {
+        let n = n.iter().fold(0f64, |n, b| n * 256.0 + f64::from(*b));
+        (if negative { -1.0 - n } else { n }).into()
+    }
+}
+
+#[cfg(feature = "cbor")]
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decodes `paths` of a JSON document, `None` when it is not JSON.
+fn decode_json(bytes: &[u8], paths: &Paths) -> Option<JsonValue> {
+    let mut de = serde_json::Deserializer::from_slice(bytes);
+    let json = paths.deserialize(&mut de).ok()?;
+    de.end().ok()?;
+    Some(json)
+}
+
+impl<'de> DeserializeSeed<'de> for &Paths {
+    type Value = JsonValue;
+
+    fn deserialize<D: Deserializer<'de>>(self, de: D) -> Result<JsonValue, D::Error> {
+        if self.whole {
+            JsonValue::deserialize(de)
+        } else {
+            de.deserialize_any(self)
+        }
+    }
+}
+
+/// Builds the parts of a value under the paths, leaving out object members
+/// that are not read and the items of arrays, which paths cannot enter.
+impl<'de> Visitor<'de> for &Paths {
+    type Value = JsonValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<JsonValue, E> {
+        Ok(JsonValue::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<JsonValue, E> {
+        Ok(v.into())
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<JsonValue, E> {
+        Ok(v.into())
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<JsonValue, E> {
+        Ok(v.into())
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<JsonValue, E> {
+        Ok(v.into())
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<JsonValue, E> {
+        Ok(v.into())
+    }
+
+    fn visit_unit<E>(self) -> Result<JsonValue, E> {
+        Ok(JsonValue::Null)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<JsonValue, A::Error> {
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(JsonValue::Array(Vec::new()))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<JsonValue, A::Error> {
+        let mut out = Map::new();
+        while let Some(entry) = map.next_key_seed(Key(self))? {
+            match entry {
+                Some((key, child)) => {
+                    out.insert(key.clone(), map.next_value_seed(child)?);
+                }
+                None => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(JsonValue::Object(out))
+    }
+}
+
+/// Looks an object key up among the children of a node without copying it.
+struct Key<'p>(&'p Paths);
+
+impl<'de, 'p> DeserializeSeed<'de> for Key<'p> {
+    type Value = Option<(&'p String, &'p Paths)>;
+
+    fn deserialize<D: Deserializer<'de>>(self, de: D) -> Result<Self::Value, D::Error> {
+        de.deserialize_str(self)
+    }
+}
+
+impl<'de, 'p> Visitor<'de> for Key<'p> {
+    type Value = Option<(&'p String, &'p Paths)>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an object key")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(self.0.children.get_key_value(v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::compile;
+    use serde_json::json;
+
+    fn reads(selector: &str) -> Reads {
+        Reads::of(&compile(selector).unwrap())
+    }
+
+    #[cfg(feature = "cbor")]
+    fn cbor(value: CborValue) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&value, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn selectors_list_the_paths_they_read() {
+        let r = reads("/s[json$.a.b > 1 or json$.c] |> group_by(json$.d)");
+        let mut expected = Reads::default();
+        for path in [&["a", "b"][..], &["c"], &["d"]] {
+            expected.add_field(&Field::Json(path.iter().map(|k| k.to_string()).collect()));
+        }
+        assert_eq!((&r.json, &r.cbor), (&expected.json, &Paths::default()));
+        assert!(reads("/s |> select(json$.a)").json.whole);
+        if cfg!(feature = "cbor") {
+            let r = reads("/s[json$.a.b > 1 or json$.c] |> group_by(json$.d) |> avg(cbor$.a.e)");
+            expected.add_field(&Field::Cbor(vec!["a".into(), "e".into()]));
+            assert_eq!((r.json, r.cbor), (expected.json, expected.cbor));
+            let r = reads("/s |> map(json$.x = cbor$.x * 2)");
+            assert!(r.json.whole && !r.cbor.whole && r.cbor.children.contains_key("x"));
+        }
+        if cfg!(feature = "pb") {
+            let r = reads("/s[qos=1] |> avg(pb$.Reading.v)");
+            assert_eq!((r.json, r.cbor), (Paths::default(), Paths::default()));
+        }
+    }
+
+    #[test]
+    fn only_read_paths_are_decoded() {
+        let bytes = br#"{"a": {"b": 1, "x": [1, 2]}, "c": [3], "d": "skip", "e": null}"#;
+        let r = reads("/s[json$.a.b = 1 and json$.c and json$.e]");
+        assert_eq!(
+            decode(bytes, Format::Json, &r.json),
+            Some(json!({"a": {"b": 1}, "c": [3], "e": null}))
+        );
+        assert_eq!(decode(b"{\"a\": 1} trailing", Format::Json, &r.json), None);
+        assert_eq!(decode(b"not json", Format::Json, &r.json), None);
+    }
+
+    #[test]
+    fn raw_payloads_decode_once_for_covered_paths() {
+        let payload = Payload::raw(&br#"{"a": 1, "b": 2}"#[..]);
+        let a = reads("/s[json$.a]");
+        assert_eq!(payload.read(Format::Json, &a.json), Some(&json!({"a": 1})));
+        assert_eq!(
+            payload.read(Format::Json, &Paths::default()),
+            Some(&json!({"a": 1}))
+        );
+        let Payload::Raw(raw) = &payload else {
+            unreachable!()
+        };
+        assert!(raw.json.whole.get().is_none());
+        assert_eq!(
+            payload.read(Format::Json, &reads("/s[json$.b]").json),
+            Some(&json!({"a": 1, "b": 2}))
+        );
+        assert_eq!(payload.json(), Some(&json!({"a": 1, "b": 2})));
+        assert_eq!(payload.read(Format::Cbor, &ALL), None);
+        assert_eq!(Payload::raw(&b"on"[..]).json(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "cbor")]
+    fn cbor_values_map_onto_json() {
+        let bytes = cbor(CborValue::Map(vec![
+            (
+                "t".into(),
+                CborValue::Tag(1, Box::new(1_700_000_000.into())),
+            ),
+            ("id".into(), CborValue::Bytes(vec![0xca, 0xfe])),
+            (
+                "big".into(),
+                CborValue::Tag(2, Box::new(CborValue::Bytes(vec![1, 0]))),
+            ),
+            (
+                "neg".into(),
+                CborValue::Tag(3, Box::new(CborValue::Bytes(vec![9]))),
+            ),
+            (7.into(), CborValue::Array(vec![1.into(), 2.5.into()])),
+            ("skip".into(), "x".into()),
+        ]));
+        let r = reads("/s[cbor$.t and cbor$.id and cbor$.big and cbor$.neg and cbor$.7]");
+        assert_eq!(
+            decode(&bytes, Format::Cbor, &r.cbor),
+            Some(json!({"t": 1_700_000_000, "id": "cafe", "big": 256, "neg": -10, "7": [1, 2.5]}))
+        );
+        let nested = reads("/s[cbor$.7.x]");
+        assert_eq!(
+            decode(&bytes, Format::Cbor, &nested.cbor),
+            Some(json!({"7": []}))
+        );
+        assert_eq!(decode(b"\xff\xff", Format::Cbor, &ALL), None);
+        assert_eq!(Payload::Json(json!({})).read(Format::Cbor, &r.cbor), None);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::ast::{Axis, Segment, Selector};
+
+impl Selector {
+    /// Computes the standard MQTT topic filters covering this selector.
+    ///
+    /// Brokers only understand `+` and `#`, so the returned filters are a
+    /// coarse over-approximation: every topic the selector can match is
+    /// matched by at least one filter, and [`Matcher`](crate::Matcher) is
+    /// expected to post-filter the delivered messages.
+    ///
+    /// Literal and `+` child steps map one-to-one onto filter levels, and glob
+    /// levels such as `sensor-*` widen to `+`. The first
+    /// step that can consume a variable number of levels (a `#` segment or a
+    /// descendant axis) ends the filter with `#`, since MQTT only allows the
+    /// multi-level wildcard in the last position. `msg` steps consume no level
+    /// and are skipped.
+    ///
+    /// An empty vector is returned when the selector can only match the empty
+    /// topic, which no broker will ever deliver.
+    pub fn to_mqtt_filters(&self) -> Vec<String> {
+        let mut levels: Vec<&str> = Vec::new();
+        let mut open_ended = false;
+
+        for step in &self.steps {
+            if step.axis == Axis::Descendant {
+                open_ended = true;
+                break;
+            }
+            match &step.segment {
+                // Topic names cannot contain wildcards, but a quoted literal
+                // can; widen it rather than emit a wildcard by accident.
+                Segment::Literal(s) if s.contains(['+', '#']) => levels.push("+"),
+                Segment::Literal(s) => levels.push(s),
+                Segment::Plus | Segment::Glob(_) => levels.push("+"),
+                Segment::Hash => {
+                    open_ended = true;
+                    break;
+                }
+                Segment::Message => {}
+            }
+        }
+
+        if open_ended {
+            levels.push("#");
+        }
+        if levels.is_empty() {
+            return Vec::new();
+        }
+        vec![levels.join("/")]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile;
+
+    fn filters(input: &str) -> Vec<String> {
+        compile(input).unwrap().to_mqtt_filters()
+    }
+
+    #[test]
+    fn literal_and_plus_levels_are_preserved() {
+        assert_eq!(filters("/foo/bar"), vec!["foo/bar"]);
+        assert_eq!(filters("/foo/+/baz"), vec!["foo/+/baz"]);
+    }
+
+    #[test]
+    fn globs_and_captures_widen_to_plus() {
+        assert_eq!(filters("/building/sensor-*"), vec!["building/+"]);
+        assert_eq!(filters("/building/{wing}/{rest:#}"), vec!["building/+/#"]);
+        assert_eq!(filters("/{id:dev}/x"), vec!["dev/x"]);
+    }
+
+    #[test]
+    fn quoted_levels_are_kept_verbatim() {
+        assert_eq!(filters("/\"$SYS\"/broker"), vec!["$SYS/broker"]);
+        assert_eq!(filters("/a/\"\"/\"room 1.2\""), vec!["a//room 1.2"]);
+        assert_eq!(filters("/\"a+b\"/c"), vec!["+/c"]);
+    }
+
+    #[test]
+    fn hash_truncates_filter() {
+        assert_eq!(filters("/foo/#"), vec!["foo/#"]);
+        assert_eq!(filters("/foo/#/bar"), vec!["foo/#"]);
+    }
+
+    #[test]
+    fn descendant_axis_becomes_hash() {
+        assert_eq!(filters("//sensor"), vec!["#"]);
+        assert_eq!(filters("/building//sensor/+"), vec!["building/#"]);
+    }
+
+    #[test]
+    fn message_steps_are_skipped() {
+        assert_eq!(filters("/msg[qos<=1]/foo"), vec!["foo"]);
+        assert_eq!(filters("/msg[qos<=1]//sensor"), vec!["#"]);
+        assert_eq!(filters("/foo/msg[retained=true]/bar"), vec!["foo/bar"]);
+    }
+
+    #[test]
+    fn empty_topic_only_selector_has_no_filter() {
+        assert!(filters("/msg[retained=true]").is_empty());
+    }
+
+    #[test]
+    fn stages_do_not_affect_filters() {
+        assert_eq!(
+            filters("/sensor/+ |> window(60s) |> avg(json$.value)"),
+            vec!["sensor/+"]
+        );
+    }
+}
@@ -0,0 +1,426 @@
+//! Protobuf schemas for reading `pb$` fields.
+//!
+//! Protobuf payloads do not name their fields, so they are read with the
+//! message descriptors registered in a [`Schemas`]. A `pb$` path starts with
+//! the message type, by its full or package-relative name, and goes on with
+//! field names: `pb$.Reading.temperature`. A path that does not start with a
+//! registered type reads the type named by the `proto` or `messageType`
+//! parameter of the message's `prop.content-type` header instead.
+
+use protobuf::descriptor::{FileDescriptorProto, FileDescriptorSet};
+use protobuf::reflect::{
+    FieldDescriptor, FileDescriptor, MessageDescriptor, ReflectFieldRef, ReflectValueRef,
+    RuntimeFieldType, RuntimeType, Syntax,
+};
+use protobuf::{Message as _, MessageDyn};
+use serde_json::{Map, Value as JsonValue};
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Why descriptors could not be registered.
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    #[error("invalid descriptors: {0}")]
+    Descriptor(#[from] protobuf::Error),
+    /// The `.proto` files could not be read or do not compile.
+    #[cfg(feature = "proto")]
+    #[error("{0}")]
+    Proto(String),
+}
+
+/// Protobuf message types that `pb$` fields can read.
+///
+/// The well-known types under `google/protobuf/` are registered from the
+/// start, so descriptors may import them.
+#[derive(Debug, Clone)]
+pub struct Schemas {
+    files: Vec<FileDescriptor>,
+    /// Message types by full name and, unless another type has it as its
+    /// full name, by name relative to their package.
+    messages: HashMap<String, MessageDescriptor>,
+}
+
+impl Default for Schemas {
+    fn default() -> Self {
+        use protobuf::well_known_types::*;
+        let mut schemas = Schemas {
+            files: Vec::new(),
+            messages: HashMap::new(),
+        };
+        for file in [
+            any::file_descriptor(),
+            api::file_descriptor(),
+            duration::file_descriptor(),
+            empty::file_descriptor(),
+            field_mask::file_descriptor(),
+            source_context::file_descriptor(),
+            struct_::file_descriptor(),
+            timestamp::file_descriptor(),
+            type_::file_descriptor(),
+            wrappers::file_descriptor(),
+        ] {
+            schemas.register(file.clone());
+        }
+        schemas
+    }
+}
+
+impl Schemas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the files of an encoded `FileDescriptorSet`, such as
+    /// `protoc --include_imports --descriptor_set_out` writes. Files may
+    /// import ones registered before; files registered already are skipped.
+    pub fn add_descriptor_set(&mut self, bytes: &[u8]) -> Result<(), SchemaError> {
+        let set = FileDescriptorSet::parse_from_bytes(bytes)?;
+        self.add_files(set.file)
+    }
+
+    /// Compiles `.proto` files, looking for the files they import under
+    /// `includes`, and registers them.
+    #[cfg(feature = "proto")]
+    pub fn add_proto_files<P: AsRef<std::path::Path>>(
+        &mut self,
+        includes: &[P],
+        files: &[P],
+    ) -> Result<(), SchemaError> {
+        let set = protobuf_parse::Parser::new()
+            .pure()
+            .includes(includes)
+            .inputs(files)
+            .file_descriptor_set()
+            .map_err(|e| SchemaError::Proto(format!("{e:#}")))?;
+        self.add_files(set.file)
+    }
+
+    fn add_files(&mut self, protos: Vec<FileDescriptorProto>) -> Result<(), SchemaError> {
+        let protos = protos
+            .into_iter()
+            .filter(|proto| !self.files.iter().any(|f| f.proto().name() == proto.name()))
+            .collect();
+        for file in FileDescriptor::new_dynamic_fds(protos, &self.files)? {
+            self.register(file);
+        }
+        Ok(())
+    }
+
+    fn register(&mut self, file: FileDescriptor) {
+        let mut pending: Vec<_> = file.messages().collect();
+        while let Some(message) = pending.pop() {
+            pending.extend(message.nested_messages().filter(|m| !m.is_map_entry()));
+            self.messages
+                .entry(message.name_to_package().to_string())
+                .or_insert_with(|| message.clone());
+            self.messages
+                .insert(message.full_name().to_string(), message);
+        }
+        self.files.push(file);
+    }
+
+    /// The message type registered under `name`.
+    pub fn message(&self, name: &str) -> Option<&MessageDescriptor> {
+        self.messages.get(name)
+    }
+
+    /// The message type a `pb$` path reads and the field path within it.
+    /// `None` when neither the path nor the content type names a registered
+    /// type, or when they name different ones.
+    pub(crate) fn resolve<'p>(
+        &self,
+        path: &'p [String],
+        content_type: Option<&str>,
+    ) -> Option<(&MessageDescriptor, &'p [String])> {
+        let labelled = match content_type.and_then(message_type) {
+            Some(name) => Some(self.message(name)?),
+            None => None,
+        };
+        let mut name = String::new();
+        for (i, part) in path.iter().enumerate() {
+            if i > 0 {
+                name.push('.');
+            }
+            name.push_str(part);
+            if let Some(message) = self.message(&name) {
+                return match labelled {
+                    Some(labelled) if labelled != message => None,
+                    _ => Some((message, &path[i + 1..])),
+                };
+            }
+        }
+        Some((labelled?, path))
+    }
+}
+
+/// The `proto` or `messageType` parameter of a content type such as
+/// `application/x-protobuf; messageType=sensors.Reading`.
+fn message_type(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        let key = key.trim();
+        (key.eq_ignore_ascii_case("proto") || key.eq_ignore_ascii_case("messageType"))
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+/// Decodes a payload of type `message` into the JSON its `pb$` fields read,
+/// `None` when it is not one. Fields are named as in the `.proto` file and
+/// unknown fields are left out.
+pub(crate) fn decode(message: &MessageDescriptor, bytes: &[u8]) -> Option<JsonValue> {
+    Some(message_json(&*message.parse_from_bytes(bytes).ok()?))
+}
+
+/// Fields that are not set read as missing when Protobuf tracks their
+/// presence and as their default value otherwise. Timestamps and durations
+/// read as seconds and wrapper types as the value they wrap.
+fn message_json(message: &dyn MessageDyn) -> JsonValue {
+    let descriptor = message.descriptor_dyn();
+    // Scalars of the well-known types have no presence.
+    let scalar = |name: &str| {
+        let field = descriptor.field_by_name(name)?;
+        Some(field.get_singular_field_or_default(message))
+    };
+    match descriptor.full_name() {
+        "google.protobuf.Timestamp" | "google.protobuf.Duration" => {
+            let seconds = scalar("seconds").and_then(|v| v.to_i64()).unwrap_or(0);
+            let nanos = scalar("nanos").and_then(|v| v.to_i32()).unwrap_or(0);
+            return (seconds as f64 + f64::from(nanos) / 1e9).into();
+        }
+        name if name.starts_with("google.protobuf.") && name.ends_with("Value") => {
+            if let Some(value) = scalar("value") {
+                return value_json(value);
+            }
+        }
+        _ => {}
+    }
+    let mut out = Map::new();
+    for field in descriptor.fields() {
+        let value = match field.get_reflect(message) {
+            ReflectFieldRef::Optional(value) => match value.value() {
+                Some(value) => value_json(value),
+                None if has_presence(&field) => continue,
+                None => value_json(field.get_singular_field_or_default(message)),
+            },
+            ReflectFieldRef::Repeated(items) => items.into_iter().map(value_json).collect(),
+            ReflectFieldRef::Map(entries) => JsonValue::Object(
+                (&entries)
+                    .into_iter()
+                    .map(|(key, value)| (key_text(key), value_json(value)))
+                    .collect(),
+            ),
+        };
+        out.insert(field.name().to_string(), value);
+    }
+    JsonValue::Object(out)
+}
+
+fn has_presence(field: &FieldDescriptor) -> bool {
+    matches!(
+        field.runtime_field_type(),
+        RuntimeFieldType::Singular(RuntimeType::Message(_))
+    ) || field.containing_oneof_including_synthetic().is_some()
+        || field.containing_message().file_descriptor().syntax() == Syntax::Proto2
+}
+
+/// Scalars as JSON scalars, bytes as lowercase hex like CBOR byte strings
+/// and enums by the name of their value.
+fn value_json(value: ReflectValueRef) -> JsonValue {
+    match value {
+        ReflectValueRef::U32(n) => n.into(),
+        ReflectValueRef::U64(n) => n.into(),
+        ReflectValueRef::I32(n) => n.into(),
+        ReflectValueRef::I64(n) => n.into(),
+        ReflectValueRef::F32(n) => f64::from(n).into(),
+        ReflectValueRef::F64(n) => n.into(),
+        ReflectValueRef::Bool(b) => b.into(),
+        ReflectValueRef::String(s) => s.into(),
+        ReflectValueRef::Bytes(bytes) => bytes
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
+            .into(),
+        ReflectValueRef::Enum(descriptor, n) => match descriptor.value_by_number(n) {
+            Some(value) => value.name().into(),
+            None => n.into(),
+        },
+        ReflectValueRef::Message(message) => message_json(&*message),
+    }
+}
+
+fn key_text(key: ReflectValueRef) -> String {
+    match key {
+        ReflectValueRef::String(s) => s.to_string(),
+        key => key.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::text_format;
+
+    const SENSORS: &str = r#"
+        file {
+            name: "sensors.proto" package: "sensors.v1" syntax: "proto3"
+            dependency: "google/protobuf/timestamp.proto"
+            message_type {
+                name: "Reading"
+                field { name: "temperature" number: 1 label: LABEL_OPTIONAL type: TYPE_DOUBLE }
+                field { name: "id" number: 2 label: LABEL_OPTIONAL type: TYPE_STRING }
+                field {
+                    name: "battery" number: 3 label: LABEL_OPTIONAL type: TYPE_INT32
+                    oneof_index: 0 proto3_optional: true
+                }
+                field {
+                    name: "status" number: 4 label: LABEL_OPTIONAL type: TYPE_ENUM
+                    type_name: ".sensors.v1.Status"
+                }
+                field { name: "serial" number: 5 label: LABEL_OPTIONAL type: TYPE_BYTES }
+                field { name: "samples" number: 6 label: LABEL_REPEATED type: TYPE_INT32 }
+                field {
+                    name: "labels" number: 7 label: LABEL_REPEATED type: TYPE_MESSAGE
+                    type_name: ".sensors.v1.Reading.LabelsEntry"
+                }
+                field {
+                    name: "at" number: 8 label: LABEL_OPTIONAL type: TYPE_MESSAGE
+                    type_name: ".google.protobuf.Timestamp"
+                }
+                nested_type {
+                    name: "LabelsEntry" options { map_entry: true }
+                    field { name: "key" number: 1 label: LABEL_OPTIONAL type: TYPE_STRING }
+                    field { name: "value" number: 2 label: LABEL_OPTIONAL type: TYPE_STRING }
+                }
+                oneof_decl { name: "_battery" }
+            }
+            message_type {
+                name: "Status"
+                field { name: "ok" number: 1 label: LABEL_OPTIONAL type: TYPE_BOOL }
+            }
+            enum_type {
+                name: "Status"
+                value { name: "UNKNOWN" number: 0 }
+                value { name: "OK" number: 1 }
+            }
+        }
+    "#;
+
+    fn schemas() -> Schemas {
+        let set: FileDescriptorSet = text_format::parse_from_str(SENSORS).unwrap();
+        let mut schemas = Schemas::new();
+        schemas
+            .add_descriptor_set(&set.write_to_bytes().unwrap())
+            .unwrap();
+        schemas
+    }
+
+    fn encode(schemas: &Schemas, message: &str, text: &str) -> Vec<u8> {
+        let mut m = schemas.message(message).unwrap().new_instance();
+        text_format::merge_from_str(&mut *m, text).unwrap();
+        m.write_to_bytes_dyn().unwrap()
+    }
+
+    fn path(path: &str) -> Vec<String> {
+        path.split('.').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn types_come_from_the_path_or_the_content_type() {
+        let schemas = schemas();
+        let full = |m: Option<(&MessageDescriptor, &[String])>| {
+            m.map(|(m, rest)| (m.full_name().to_string(), rest.join(".")))
+        };
+        let reading = Some(("sensors.v1.Reading".to_string(), "temperature".to_string()));
+        for p in ["Reading.temperature", "sensors.v1.Reading.temperature"] {
+            assert_eq!(full(schemas.resolve(&path(p), None)), reading);
+        }
+        let labelled = "application/x-protobuf; messageType=sensors.v1.Reading";
+        assert_eq!(
+            full(schemas.resolve(&path("temperature"), Some(labelled))),
+            reading
+        );
+        let labelled = "application/protobuf;proto=\"Reading\"";
+        assert_eq!(
+            full(schemas.resolve(&path("Reading.temperature"), Some(labelled))),
+            reading
+        );
+        assert_eq!(full(schemas.resolve(&path("temperature"), None)), None);
+        assert_eq!(
+            full(schemas.resolve(&path("temperature"), Some("application/x-protobuf"))),
+            None
+        );
+        let other = "application/x-protobuf; messageType=google.protobuf.Timestamp";
+        assert_eq!(
+            full(schemas.resolve(&path("Reading.temperature"), Some(other))),
+            None
+        );
+        let unknown = "application/x-protobuf; messageType=Missing";
+        assert_eq!(
+            full(schemas.resolve(&path("Reading.temperature"), Some(unknown))),
+            None
+        );
+    }
+
+    #[test]
+    fn messages_decode_into_json() {
+        let schemas = schemas();
+        let reading = schemas.message("Reading").unwrap();
+        let bytes = encode(
+            &schemas,
+            "Reading",
+            r#"temperature: 35.5 status: OK serial: "\xca\xfe" samples: 1 samples: 2
+               labels { key: "room" value: "lab" } at { seconds: 1700000000 nanos: 500000000 }"#,
+        );
+        assert_eq!(
+            decode(reading, &bytes),
+            Some(serde_json::json!({
+                "temperature": 35.5,
+                "id": "",
+                "status": "OK",
+                "serial": "cafe",
+                "samples": [1, 2],
+                "labels": {"room": "lab"},
+                "at": 1_700_000_000.5,
+            }))
+        );
+        // `battery: 0`, which the encoder would leave out.
+        assert_eq!(decode(reading, b"\x18\x00").unwrap()["battery"], 0);
+        assert_eq!(decode(reading, b"\xff\xff\xff"), None);
+    }
+
+    #[test]
+    fn unknown_fields_are_left_out() {
+        let schemas = schemas();
+        let status = schemas.message("sensors.v1.Status").unwrap();
+        // Field 1 of a `Status` is a bool, so a double there does not decode.
+        let bytes = encode(&schemas, "Reading", "temperature: 1 id: \"x\"");
+        assert_eq!(decode(status, &bytes), None);
+        let bytes = encode(&schemas, "Reading", "samples: 7");
+        assert_eq!(
+            decode(status, &bytes),
+            Some(serde_json::json!({"ok": false}))
+        );
+    }
+
+    #[cfg(feature = "proto")]
+    #[test]
+    fn proto_files_compile() {
+        let dir = std::env::temp_dir().join(format!("moqtail-proto-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("plant.proto");
+        std::fs::write(
+            &file,
+            r#"syntax = "proto3";
+            package plant;
+            import "google/protobuf/timestamp.proto";
+            message Pump { double flow = 1; google.protobuf.Timestamp at = 2; }"#,
+        )
+        .unwrap();
+        let mut schemas = Schemas::new();
+        let added = schemas.add_proto_files(std::slice::from_ref(&dir), &[file]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        added.unwrap();
+        assert!(schemas.message("plant.Pump").is_some());
+        assert!(schemas.message("Pump").is_some());
+    }
+}
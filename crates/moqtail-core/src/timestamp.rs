@@ -0,0 +1,228 @@
+//! Event timestamps read from messages by the `timestamp()` stage, and the
+//! RFC 3339 form window bounds are reported in.
+
+use serde_json::Value as JsonValue;
+use std::time::Duration;
+
+/// Epoch numbers at or above this are taken as milliseconds; as seconds it
+/// would be the year 5138.
+const MILLIS_THRESHOLD: f64 = 1e11;
+
+/// Time since the Unix epoch of a JSON timestamp: a number of seconds or
+/// milliseconds, or a string holding either or an RFC 3339 date-time.
+pub(crate) fn from_json(value: &JsonValue) -> Option<Duration> {
+    match value {
+        JsonValue::Number(n) => from_epoch(n.as_f64()?),
+        JsonValue::String(s) => from_text(s),
+        _ => None,
+    }
+}
+
+/// Like [`from_json`] for header and topic text.
+pub(crate) fn from_text(text: &str) -> Option<Duration> {
+    match text.trim().parse::<f64>() {
+        Ok(n) => from_epoch(n),
+        Err(_) => from_rfc3339(text.trim()),
+    }
+}
+
+fn from_epoch(n: f64) -> Option<Duration> {
+    let secs = if n.abs() >= MILLIS_THRESHOLD {
+        n / 1000.0
+    } else {
+        n
+    };
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// Parses `YYYY-MM-DDTHH:MM:SS[.frac](Z|±HH:MM)`. Times before the epoch are
+/// not supported.
+fn from_rfc3339(s: &str) -> Option<Duration> {
+    let b = s.as_bytes();
+    let digits = |range: std::ops::Range<usize>| -> Option<i64> {
+        let part = b.get(range)?;
+        if !part.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        std::str::from_utf8(part).ok()?.parse().ok()
+    };
+    if b.len() < 20
+        || b[4] != b'-'
+        || b[7] != b'-'
+        || !matches!(b[10], b'T' | b't' | b' ')
+        || b[13] != b':'
+        || b[16] != b':'
+    {
+        return None;
+    }
+    let (year, month, day) = (digits(0..4)?, digits(5..7)?, digits(8..10)?);
+    let (hour, minute, second) = (digits(11..13)?, digits(14..16)?, digits(17..19)?);
+    if !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    let mut nanos = 0u32;
+    if let Some(frac) = rest.strip_prefix('.') {
+        let len = frac.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return None;
+        }
+        let padded = format!("{:0<9}", &frac[..len.min(9)]);
+        nanos = padded.parse().ok()?;
+        rest = &frac[len..];
+    }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes().first()? {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let o = rest.as_bytes();
+            if o.len() != 6 || o[3] != b':' {
+                return None;
+            }
+            let hours: i64 = rest[1..3].parse().ok()?;
+            let minutes: i64 = rest[4..6].parse().ok()?;
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+
+    let secs =
+        days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second - offset;
+    Some(Duration::new(u64::try_from(secs).ok()?, nanos))
+}
+
+/// Formats a time since the Unix epoch as an RFC 3339 UTC date-time, with
+/// milliseconds when the time has any.
+pub(crate) fn to_rfc3339(time: Duration) -> String {
+    let secs = time.as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let of_day = secs.rem_euclid(86_400);
+    let (hour, minute, second) = (of_day / 3600, of_day / 60 % 60, of_day % 60);
+    let millis = time.subsec_millis();
+    let frac = if millis == 0 {
+        String::new()
+    } else {
+        format!(".{millis:03}")
+    };
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}{frac}Z")
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days from 1970-01-01 to the given proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The proleptic Gregorian date `days` after 1970-01-01; the inverse of
+/// [`days_from_civil`].
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_rfc3339() {
+        assert_eq!(
+            from_text("2023-11-14T22:13:20Z"),
+            Some(Duration::from_secs(1_700_000_000))
+        );
+        assert_eq!(
+            from_text("2023-11-15T00:13:20.250+02:00"),
+            Some(Duration::from_millis(1_700_000_000_250))
+        );
+        assert_eq!(
+            from_text("2024-02-29 12:00:00z"),
+            Some(Duration::from_secs(1_709_208_000))
+        );
+        assert_eq!(from_text("1970-01-01T00:00:00Z"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn formats_rfc3339() {
+        assert_eq!(
+            to_rfc3339(Duration::from_secs(1_700_000_000)),
+            "2023-11-14T22:13:20Z"
+        );
+        assert_eq!(
+            to_rfc3339(Duration::from_millis(1_709_208_000_250)),
+            "2024-02-29T12:00:00.250Z"
+        );
+        assert_eq!(to_rfc3339(Duration::ZERO), "1970-01-01T00:00:00Z");
+        for text in ["2000-03-01T00:00:00Z", "2100-12-31T23:59:59.999Z"] {
+            assert_eq!(to_rfc3339(from_text(text).unwrap()), text);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_dates() {
+        for text in [
+            "2023-02-29T00:00:00Z",
+            "2023-11-14T24:00:00Z",
+            "2023-11-14T22:13:20",
+            "2023-11-14T22:13:20.Z",
+            "2023-11-14T22:13:20+0200",
+            "1969-12-31T23:59:59Z",
+            "yesterday",
+        ] {
+            assert_eq!(from_text(text), None, "{text}");
+        }
+    }
+
+    #[test]
+    fn epoch_numbers_are_seconds_or_milliseconds() {
+        assert_eq!(
+            from_json(&json!(1_700_000_000)),
+            Some(Duration::from_secs(1_700_000_000))
+        );
+        assert_eq!(
+            from_json(&json!(1_700_000_000_500u64)),
+            Some(Duration::from_millis(1_700_000_000_500))
+        );
+        assert_eq!(
+            from_json(&json!("1700000000.5")),
+            Some(Duration::from_millis(1_700_000_000_500))
+        );
+        assert_eq!(from_json(&json!(-5)), None);
+        assert_eq!(from_json(&json!(true)), None);
+    }
+}
@@ -0,0 +1,278 @@
+//! Payload rewriting for the `select`, `map` and `rename` stages, and the
+//! expressions `map` computes.
+
+use crate::ast::{BinaryOp, Expr, Field, Stage};
+use crate::matcher::json_path;
+use serde_json::{Map, Value as JsonValue};
+
+/// Functions callable in expressions, with their minimum and maximum number
+/// of arguments.
+pub(crate) const FUNCTIONS: &[(&str, usize, usize)] = &[
+    ("upper", 1, 1),
+    ("lower", 1, 1),
+    ("trim", 1, 1),
+    ("len", 1, 1),
+    ("concat", 1, usize::MAX),
+    ("replace", 3, 3),
+    ("str", 1, 1),
+    ("num", 1, 1),
+    ("abs", 1, 1),
+    ("floor", 1, 1),
+    ("ceil", 1, 1),
+    ("round", 1, 2),
+];
+
+/// Integral results below this magnitude are written as JSON integers.
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// Rewrites `payload` for a transform stage. `field` reads the header,
+/// capture, topic, `cbor$` and `pb$` fields of the message. A payload that is
+/// not a JSON object is replaced by one as soon as a path is written.
+pub(crate) fn apply(
+    stage: &Stage,
+    payload: JsonValue,
+    field: &dyn Fn(&Field) -> Option<JsonValue>,
+) -> JsonValue {
+    match stage {
+        Stage::Select(paths) => {
+            let mut out = JsonValue::Object(Map::new());
+            for path in paths {
+                if let Some(value) = json_path(&payload, path) {
+                    set_path(&mut out, path, value.clone());
+                }
+            }
+            out
+        }
+        Stage::Map(assignments) => {
+            let mut out = payload;
+            for assignment in assignments {
+                if let Some(value) = eval(&assignment.expr, &out, field) {
+                    set_path(&mut out, &assignment.target, value);
+                }
+            }
+            out
+        }
+        Stage::Rename(moves) => {
+            let mut out = payload;
+            for (from, to) in moves {
+                if let Some(value) = take_path(&mut out, from) {
+                    set_path(&mut out, to, value);
+                }
+            }
+            out
+        }
+        _ => payload,
+    }
+}
+
+/// Writes `value` at `path`, creating objects along the way and replacing
+/// anything that is not one.
+fn set_path(root: &mut JsonValue, path: &[String], value: JsonValue) {
+    let Some((last, parents)) = path.split_last() else {
+        *root = value;
+        return;
+    };
+    let mut cur = root;
+    for part in parents {
+        cur = object(cur).entry(part.clone()).or_insert(JsonValue::Null);
+    }
+    object(cur).insert(last.clone(), value);
+}
+
+fn object(value: &mut JsonValue) -> &mut Map<String, JsonValue> {
+    if !value.is_object() {
+        *value = JsonValue::Object(Map::new());
+    }
+    match value {
+        JsonValue::Object(map) => map,
+        _ => unreachable!("replaced by an object above"),
+    }
+}
+
+/// Removes and returns the value at `path`.
+fn take_path(root: &mut JsonValue, path: &[String]) -> Option<JsonValue> {
+    let (last, parents) = path.split_last()?;
+    let mut cur = root;
+    for part in parents {
+        cur = cur.get_mut(part.as_str())?;
+    }
+    cur.as_object_mut()?.remove(last.as_str())
+}
+
+/// Evaluates an expression against the payload as rewritten so far.
+/// Returns `None` when a field is missing, an operand has the wrong type or
+/// the result is not a finite number.
+pub(crate) fn eval(
+    expr: &Expr,
+    payload: &JsonValue,
+    field: &dyn Fn(&Field) -> Option<JsonValue>,
+) -> Option<JsonValue> {
+    match expr {
+        Expr::Number(n) => number(*n),
+        Expr::Str(s) => Some(JsonValue::String(s.clone())),
+        Expr::Field(Field::Json(path)) => json_path(payload, path).cloned(),
+        Expr::Field(f) => field(f),
+        Expr::Neg(inner) => number(-as_number(&eval(inner, payload, field)?)?),
+        Expr::Binary(op, l, r) => {
+            let l = as_number(&eval(l, payload, field)?)?;
+            let r = as_number(&eval(r, payload, field)?)?;
+            number(match op {
+                BinaryOp::Add => l + r,
+                BinaryOp::Sub => l - r,
+                BinaryOp::Mul => l * r,
+                BinaryOp::Div => l / r,
+                BinaryOp::Rem => l % r,
+            })
+        }
+        Expr::Call(name, args) => {
+            let args = args
+                .iter()
+                .map(|arg| eval(arg, payload, field))
+                .collect::<Option<Vec<_>>>()?;
+            call(name, &args)
+        }
+    }
+}
+
+fn call(name: &str, args: &[JsonValue]) -> Option<JsonValue> {
+    let text = |i: usize| as_text(&args[i]);
+    let num = |i: usize| as_number(&args[i]);
+    match name {
+        "upper" => Some(text(0)?.to_uppercase().into()),
+        "lower" => Some(text(0)?.to_lowercase().into()),
+        "trim" => Some(text(0)?.trim().into()),
+        "len" => match &args[0] {
+            JsonValue::Array(items) => number(items.len() as f64),
+            value => number(as_text(value)?.chars().count() as f64),
+        },
+        "concat" => Some(
+            (0..args.len())
+                .map(text)
+                .collect::<Option<String>>()?
+                .into(),
+        ),
+        "replace" => Some(text(0)?.replace(&text(1)?, &text(2)?).into()),
+        "str" => Some(text(0)?.into()),
+        "num" => number(num(0)?),
+        "abs" => number(num(0)?.abs()),
+        "floor" => number(num(0)?.floor()),
+        "ceil" => number(num(0)?.ceil()),
+        "round" => {
+            let scale = match args.get(1) {
+                Some(digits) => 10f64.powi(as_number(digits)? as i32),
+                None => 1.0,
+            };
+            number((num(0)? * scale).round() / scale)
+        }
+        _ => None,
+    }
+}
+
+/// Numbers, and strings holding one, since header and topic fields are text.
+fn as_number(value: &JsonValue) -> Option<f64> {
+    match value {
+        JsonValue::Number(n) => n.as_f64(),
+        JsonValue::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Strings as they are and other scalars in their JSON form.
+fn as_text(value: &JsonValue) -> Option<String> {
+    match value {
+        JsonValue::String(s) => Some(s.clone()),
+        JsonValue::Number(_) | JsonValue::Bool(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// A JSON number, written as an integer when it is one.
+fn number(n: f64) -> Option<JsonValue> {
+    if n.fract() == 0.0 && n.abs() < MAX_EXACT_INTEGER {
+        Some((n as i64).into())
+    } else {
+        serde_json::Number::from_f64(n).map(JsonValue::Number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::compile;
+    use serde_json::json;
+
+    fn run(selector: &str, payload: JsonValue) -> JsonValue {
+        let stages = compile(selector).unwrap().stages;
+        let field = |field: &Field| match field {
+            Field::Header(name) if name == "unit" => Some(json!("C")),
+            _ => None,
+        };
+        stages
+            .iter()
+            .fold(payload, |payload, stage| apply(stage, payload, &field))
+    }
+
+    #[test]
+    fn select_keeps_only_listed_paths() {
+        let out = run(
+            "/s |> select(json$.b, json$.n.x, json$.missing)",
+            json!({"a": 1, "b": 2, "n": {"x": 3, "y": 4}}),
+        );
+        assert_eq!(out, json!({"b": 2, "n": {"x": 3}}));
+        assert_eq!(run("/s |> select(json$.a)", json!(5)), json!({}));
+    }
+
+    #[test]
+    fn map_assigns_in_order() {
+        let out = run(
+            "/s |> map(json$.f = json$.c * 1.8 + 32, json$.g = json$.f - 2, json$.u = lower(unit))",
+            json!({"c": 20}),
+        );
+        assert_eq!(out, json!({"c": 20, "f": 68, "g": 66, "u": "c"}));
+    }
+
+    #[test]
+    fn failed_assignments_leave_the_payload_alone() {
+        let out = run(
+            "/s |> map(json$.a = json$.missing + 1, json$.b = json$.s * 2, json$.c = 1 / 0)",
+            json!({"s": "text"}),
+        );
+        assert_eq!(out, json!({"s": "text"}));
+    }
+
+    #[test]
+    fn rename_moves_values() {
+        let out = run(
+            "/s |> rename(json$.t as json$.temp, json$.n.v as json$.v, json$.none as json$.x)",
+            json!({"t": 1, "n": {"v": 2}}),
+        );
+        assert_eq!(out, json!({"n": {}, "temp": 1, "v": 2}));
+    }
+
+    #[test]
+    fn functions() {
+        let eval = |expr: &str| {
+            let sel = compile(&format!("/s |> map(json$.r = {expr})")).unwrap();
+            let Stage::Map(assignments) = &sel.stages[0] else {
+                unreachable!()
+            };
+            eval(
+                &assignments[0].expr,
+                &json!({"s": " Hi ", "n": -2.456, "l": [1, 2]}),
+                &|_| None,
+            )
+        };
+        assert_eq!(eval("upper(trim(json$.s))"), Some(json!("HI")));
+        assert_eq!(
+            eval("concat(\"n=\", json$.n, \"!\")"),
+            Some(json!("n=-2.456!"))
+        );
+        assert_eq!(eval("replace(json$.s, \"i\", \"o\")"), Some(json!(" Ho ")));
+        assert_eq!(eval("len(json$.s) + len(json$.l)"), Some(json!(6)));
+        assert_eq!(eval("round(json$.n, 2)"), Some(json!(-2.46)));
+        assert_eq!(eval("abs(floor(json$.n))"), Some(json!(3)));
+        assert_eq!(eval("num(\"4\") % 3 - -(2)"), Some(json!(3)));
+        assert_eq!(eval("(1 + 2) * 3"), Some(json!(9)));
+        assert_eq!(eval("upper(json$.l)"), None);
+    }
+}
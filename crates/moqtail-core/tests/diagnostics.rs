@@ -0,0 +1,117 @@
+use moqtail_core::{compile, ErrorKind};
+
+fn spanned(input: &str) -> (ErrorKind, &str) {
+    let err = compile(input).unwrap_err();
+    (err.kind, &input[err.span])
+}
+
+#[test]
+fn semantic_errors_point_at_the_offending_text() {
+    assert_eq!(
+        spanned("/foo[bar=[1,2]]"),
+        (ErrorKind::InvalidValue, "[1,2]")
+    );
+    assert_eq!(
+        spanned("/foo |> sum(5s)"),
+        (ErrorKind::SumRequiresField, "5s")
+    );
+    assert_eq!(
+        spanned("/foo |> window()"),
+        (ErrorKind::WindowRequiresDuration, "window()")
+    );
+    assert_eq!(
+        spanned("/foo |> avgg(json$.x)"),
+        (ErrorKind::UnknownFunction("avgg".into()), "avgg")
+    );
+    assert_eq!(
+        spanned("/{a}/{a}"),
+        (ErrorKind::DuplicateCapture("a".into()), "a")
+    );
+}
+
+#[test]
+fn line_and_column_are_one_based() {
+    let err = compile("/foo\n  |> count(json$.x)").unwrap_err();
+    assert_eq!(err.kind, ErrorKind::CountTakesNoArguments);
+    assert_eq!((err.line, err.column), (2, 12));
+
+    let err = compile("/foo[").unwrap_err();
+    assert!(matches!(err.kind, ErrorKind::Syntax(_)));
+    assert_eq!((err.line, err.column), (1, 6));
+}
+
+#[test]
+fn syntax_errors_name_what_was_expected() {
+    let err = compile("/foo |> count() bar").unwrap_err();
+    assert_eq!(
+        err.kind,
+        ErrorKind::Syntax("expected end of input or `|>`".into())
+    );
+}
+
+#[test]
+fn misspellings_get_suggestions() {
+    let err = compile("/foo |> avgg(json$.x)").unwrap_err();
+    assert_eq!(err.suggestion.as_deref(), Some("avg"));
+    assert_eq!(
+        err.to_string(),
+        "1:9: unknown function `avgg`, did you mean `avg`?"
+    );
+
+    let err = compile("/foo[name contain \"x\"]").unwrap_err();
+    assert_eq!(err.suggestion.as_deref(), Some("contains"));
+
+    let err = compile("/{wing}/+[$wign = \"E\"]").unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnknownCapture("wign".into()));
+    assert_eq!(err.suggestion.as_deref(), Some("wing"));
+
+    let err = compile("/foo |> frobnicate()").unwrap_err();
+    assert_eq!(err.suggestion, None);
+}
+
+#[test]
+fn render_underlines_the_span() {
+    let input = "/foo |> avgg(json$.x)";
+    let rendered = compile(input).unwrap_err().render(input);
+    assert_eq!(
+        rendered,
+        "error: unknown function `avgg`, did you mean `avg`?\n\
+         \x20--> 1:9\n\
+         \x20 |\n\
+         1 | /foo |> avgg(json$.x)\n\
+         \x20 |         ^^^^"
+    );
+}
+
+#[test]
+fn render_shows_only_the_failing_line() {
+    let input = "/foo\n  |> sum(1s)";
+    let rendered = compile(input).unwrap_err().render(input);
+    assert!(
+        rendered.ends_with("2 |   |> sum(1s)\n  |          ^^"),
+        "{rendered}"
+    );
+}
+
+#[test]
+fn render_handles_crlf_line_endings() {
+    let input = "/foo\r\n  |> window(5s)\r\n  |> sum(1s)";
+    let rendered = compile(input).unwrap_err().render(input);
+    assert!(
+        rendered.ends_with("3 |   |> sum(1s)\n  |          ^^"),
+        "{rendered}"
+    );
+}
+
+#[test]
+fn render_tolerates_a_zero_line() {
+    let input = "/foo |> avgg(json$.x)";
+    let mut err = compile(input).unwrap_err();
+    err.line = 0;
+    assert!(
+        err.render(input)
+            .ends_with("| /foo |> avgg(json$.x)\n  |         ^^^^"),
+        "{}",
+        err.render(input)
+    );
+}
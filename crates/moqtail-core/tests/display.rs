@@ -1,4 +1,6 @@
-use moqtail_core::ast::{Axis, Field, Operator, Predicate, Segment, Selector, Step, Value};
+use moqtail_core::ast::{
+    Axis, Comparison, Field, Operator, Predicate, Segment, Selector, Step, Value,
+};
 use moqtail_core::compile;
 
 #[test]
@@ -20,11 +22,12 @@ fn selector_display_with_quoted_string() {
         steps: vec![Step {
             axis: Axis::Child,
             segment: Segment::Literal("foo".into()),
-            predicates: vec![Predicate {
+            capture: None,
+            predicates: vec![Predicate::Compare(Comparison {
                 field: Field::Header("bar".into()),
                 op: Operator::Eq,
                 value: Value::Str("qu\"ote".into()),
-            }],
+            })],
         }],
         stages: vec![],
     };
@@ -39,11 +42,12 @@ fn selector_display_with_backslash() {
         steps: vec![Step {
             axis: Axis::Child,
             segment: Segment::Literal("foo".into()),
-            predicates: vec![Predicate {
+            capture: None,
+            predicates: vec![Predicate::Compare(Comparison {
                 field: Field::Header("bar".into()),
                 op: Operator::Eq,
                 value: Value::Str("a\\b".into()),
-            }],
+            })],
         }],
         stages: vec![],
     };
@@ -57,3 +61,58 @@ fn compile_errors_on_unclosed_predicate() {
     let result = compile("/foo[bar=1");
     assert!(result.is_err());
 }
+
+#[test]
+fn selector_display_boolean_predicates_roundtrip() {
+    for input in [
+        "/foo[json$.level=\"warn\" or json$.level=\"error\"]",
+        "/msg[not retained=true]",
+        "/foo[(a=1 or b=2) and c=3]",
+        "/foo[a=1 and (b=2 and c=3)]",
+        "/foo[a=1 or (b=2 or c=3)]",
+        "/foo[not (a=1 and b=2)]",
+        "/foo[not not a=1]",
+    ] {
+        let selector = compile(input).unwrap();
+        assert_eq!(selector.to_string(), input);
+        assert_eq!(compile(&selector.to_string()).unwrap(), selector);
+    }
+}
+
+#[test]
+fn selector_display_drops_redundant_parentheses() {
+    let selector = compile("/foo[(a=1 and b=2) or (not c=3)]").unwrap();
+    assert_eq!(selector.to_string(), "/foo[a=1 and b=2 or not c=3]");
+}
+
+#[test]
+fn selector_display_operators_roundtrip() {
+    for input in [
+        "/foo[bar!=1]",
+        "/foo[json$.name contains \"ab\"]",
+        "/foo[json$.state in [\"on\",\"off\"]]",
+        "/foo[json$.empty in []]",
+        "/foo[json$.serial startsWith \"AB\"]",
+        "/foo[json$.file endsWith \".csv\"]",
+        "/foo[json$.battery]",
+        "/foo[not json$.battery and qos in [0,1]]",
+        "/building/sensor-*",
+        "/$SYS/broker/\"uptime (s)\"",
+        "/a/\"\"/b",
+        "/\"msg\"/\"sensor-*\"/\"café\"",
+        "/{room:\"room 1.2\"}/\"x\\\"y\"",
+        "/building/{wing}/{room:room-*}/{rest:#}",
+        "/floor/+[.>3]",
+        "/{id}[$id startsWith \"dev\"] |> sum($id)",
+        "/foo[json$.serial~=\"^AB[0-9]{6}$\"]",
+        "/msg[prop.content-type~=\"^application/(json|cbor)\"]",
+        "/sensor |> window(1m) |> min(json$.t) |> max(prop.rssi)",
+        "/sensor |> first(json$.t) |> last(json$.t) |> stddev(json$.t)",
+        "/sensor |> window(5m) |> percentile(json$.latency, 99.5)",
+        "/sensor |> window(1m) |> rate() |> rate(json$.energy)",
+    ] {
+        let selector = compile(input).unwrap();
+        assert_eq!(selector.to_string(), input);
+        assert_eq!(compile(&selector.to_string()).unwrap(), selector);
+    }
+}
@@ -0,0 +1,89 @@
+#![cfg(feature = "serde")]
+
+use moqtail_core::ast::Selector;
+use moqtail_core::compile;
+use moqtail_core::format::FormatError;
+
+const SELECTORS: &[&str] = &[
+    "/foo/bar",
+    "//sensor/+/#",
+    "/msg[qos<=1][retained=true]//sensor",
+    "/foo[json$.a.b>-1.5 or not (x=true and y!=\"z\")]",
+    "/foo[json$.state in [\"on\",\"off\"]][json$.serial~=\"^AB[0-9]{6}$\"]",
+    "/building/{wing}/{room:room-*}[$wing=\"E\" and .>3]",
+    "/$SYS/\"room 1.2\"/\"\"",
+    "/sensor |> window(60s) |> avg(json$.value)",
+    "/meter/{reading} |> sum($reading) |> count()",
+];
+
+#[test]
+fn json_round_trip_preserves_display() {
+    for input in SELECTORS {
+        let selector = compile(input).unwrap();
+        let decoded = Selector::from_json(&selector.to_json()).unwrap();
+        assert_eq!(decoded.to_string(), selector.to_string());
+        assert_eq!(decoded, selector);
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn cbor_round_trip_preserves_display() {
+    for input in SELECTORS {
+        let selector = compile(input).unwrap();
+        let decoded = Selector::from_cbor(&selector.to_cbor()).unwrap();
+        assert_eq!(decoded.to_string(), selector.to_string());
+    }
+}
+
+#[test]
+fn json_shape_is_stable() {
+    let selector = compile("/foo[qos<=1] |> count()").unwrap();
+    assert_eq!(
+        selector.to_json(),
+        r#"{"version":1,"selector":{"steps":[{"axis":"child","segment":{"literal":"foo"},"capture":null,"predicates":[{"compare":{"field":{"header":"qos"},"op":"le","value":{"number":1.0}}}]}],"stages":["count"]}}"#
+    );
+}
+
+#[test]
+fn unknown_versions_are_rejected() {
+    assert!(matches!(
+        Selector::from_json(r#"{"version":2,"selector":{}}"#),
+        Err(FormatError::UnsupportedVersion(2))
+    ));
+    assert!(matches!(
+        Selector::from_json(r#"{"selector":{}}"#),
+        Err(FormatError::MissingVersion)
+    ));
+}
+
+#[test]
+fn asts_compile_cannot_produce_are_rejected() {
+    // `in` with a scalar operand.
+    let json = r#"{"version":1,"selector":{"steps":[{"axis":"child","segment":{"literal":"a"},"capture":null,"predicates":[{"compare":{"field":{"header":"x"},"op":"in","value":{"number":1.0}}}]}],"stages":[]}}"#;
+    assert!(matches!(
+        Selector::from_json(json),
+        Err(FormatError::Invalid { .. })
+    ));
+
+    // A negated literal reads back as a negative number.
+    let json = r#"{"version":1,"selector":{"steps":[{"axis":"child","segment":{"literal":"a"},"capture":null,"predicates":[]}],"stages":[{"map":[{"target":["x"],"expr":{"neg":{"number":2.0}}}]}]}}"#;
+    assert!(matches!(
+        Selector::from_json(json),
+        Err(FormatError::NotCanonical(_))
+    ));
+
+    // Sub-second windows have a text form.
+    let json = r#"{"version":1,"selector":{"steps":[{"axis":"child","segment":{"literal":"a"},"capture":null,"predicates":[]}],"stages":[{"window":{"secs":1,"nanos":5}}]}}"#;
+    assert_eq!(
+        Selector::from_json(json).unwrap().to_string(),
+        "/a |> window(1s0.000005ms)"
+    );
+
+    // Regexes are compiled while decoding.
+    let json = r#"{"version":1,"selector":{"steps":[{"axis":"child","segment":{"literal":"a"},"capture":null,"predicates":[{"compare":{"field":{"header":"x"},"op":"matches","value":{"regex":"("}}}]}],"stages":[]}}"#;
+    assert!(matches!(
+        Selector::from_json(json),
+        Err(FormatError::Json(_))
+    ));
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use moqtail_core::{compile, Matcher, Message};
+use moqtail_core::{compile, Matcher, Message, Payload};
 
 fn build_topic(len: usize, last: &str) -> String {
     let mut segs = Vec::with_capacity(len + 1);
@@ -19,7 +19,7 @@ fn matches_descendant_in_long_topic() {
     let msg = Message {
         topic: &topic,
         headers: HashMap::new(),
-        payload: None,
+        payload: Payload::None,
     };
     assert!(
         matcher.matches(&msg),
@@ -36,7 +36,7 @@ fn no_match_in_long_topic() {
     let msg = Message {
         topic: &topic,
         headers: HashMap::new(),
-        payload: None,
+        payload: Payload::None,
     };
     assert!(!matcher.matches(&msg));
 }
@@ -49,7 +49,7 @@ fn matches_descendant_in_very_long_topic() {
     let msg = Message {
         topic: &topic,
         headers: HashMap::new(),
-        payload: None,
+        payload: Payload::None,
     };
     assert!(
         matcher.matches(&msg),
@@ -66,7 +66,7 @@ fn no_match_in_very_long_topic() {
     let msg = Message {
         topic: &topic,
         headers: HashMap::new(),
-        payload: None,
+        payload: Payload::None,
     };
     assert!(!matcher.matches(&msg));
 }
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use moqtail_core::{compile, Matcher, Message};
+use moqtail_core::{compile, Matcher, Message, Payload};
 
 #[test]
 fn trailing_empty_segment_requires_wildcard() {
@@ -10,7 +10,7 @@ fn trailing_empty_segment_requires_wildcard() {
     let msg = Message {
         topic: "foo/bar/",
         headers: HashMap::new(),
-        payload: None,
+        payload: Payload::None,
     };
 
     assert!(!matcher.matches(&msg));
@@ -24,7 +24,7 @@ fn literal_does_not_skip_empty_segments() {
     let msg = Message {
         topic: "foo//bar",
         headers: HashMap::new(),
-        payload: None,
+        payload: Payload::None,
     };
 
     assert!(!matcher.matches(&msg));
@@ -38,7 +38,7 @@ fn plus_matches_empty_segment() {
     let msg = Message {
         topic: "foo/",
         headers: HashMap::new(),
-        payload: None,
+        payload: Payload::None,
     };
 
     assert!(matcher.matches(&msg));
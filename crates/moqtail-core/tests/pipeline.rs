@@ -1,7 +1,12 @@
-use moqtail_core::{ast::Stage, compile, Error, Matcher, Message};
+#[cfg(feature = "cbor")]
+use ciborium::{cbor, Value};
+use moqtail_core::{
+    ast::Stage, compile, AggregateRecord, Error, ErrorKind, Late, Matcher, Message, Payload,
+    PipelineOutput, WindowBounds,
+};
 use serde_json::json;
 use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 // Pipeline stages now operate on time-based windows. These tests exercise the
 // eviction behaviour by advancing the synthetic timestamps passed to
@@ -17,14 +22,14 @@ fn avg_pipeline() {
     let msg1 = Message {
         topic: "sensor",
         headers: headers.clone(),
-        payload: Some(json!({"value": 10})),
+        payload: Payload::Json(json!({"value": 10})),
     };
     assert_eq!(m.process(&msg1, start), Some(10.0));
 
     let msg2 = Message {
         topic: "sensor",
         headers: headers.clone(),
-        payload: Some(json!({"value": 20})),
+        payload: Payload::Json(json!({"value": 20})),
     };
     assert_eq!(
         m.process(&msg2, start + Duration::from_secs(30)),
@@ -34,7 +39,7 @@ fn avg_pipeline() {
     let msg3 = Message {
         topic: "sensor",
         headers,
-        payload: Some(json!({"value": 30})),
+        payload: Payload::Json(json!({"value": 30})),
     };
     // The first reading is now older than the 60s window, so it should be dropped.
     assert_eq!(
@@ -54,14 +59,14 @@ fn sum_pipeline() {
     let msg1 = Message {
         topic: "sensor",
         headers: headers.clone(),
-        payload: Some(json!({"value": 10})),
+        payload: Payload::Json(json!({"value": 10})),
     };
     assert_eq!(m.process(&msg1, start), Some(10.0));
 
     let msg2 = Message {
         topic: "sensor",
         headers: headers.clone(),
-        payload: Some(json!({"value": 20})),
+        payload: Payload::Json(json!({"value": 20})),
     };
     assert_eq!(
         m.process(&msg2, start + Duration::from_secs(30)),
@@ -71,7 +76,7 @@ fn sum_pipeline() {
     let msg3 = Message {
         topic: "sensor",
         headers,
-        payload: Some(json!({"value": 40})),
+        payload: Payload::Json(json!({"value": 40})),
     };
     assert_eq!(
         m.process(&msg3, start + Duration::from_secs(75)),
@@ -89,7 +94,7 @@ fn sum_pipeline_large_unsigned() {
     let msg = Message {
         topic: "sensor",
         headers,
-        payload: Some(json!({"value": u64::MAX})),
+        payload: Payload::Json(json!({"value": u64::MAX})),
     };
     assert_eq!(m.process(&msg, Instant::now()), Some(u64::MAX as f64));
 }
@@ -105,21 +110,21 @@ fn count_pipeline() {
     let msg1 = Message {
         topic: "sensor",
         headers: headers.clone(),
-        payload: None,
+        payload: Payload::None,
     };
     assert_eq!(m.process(&msg1, start), Some(1.0));
 
     let msg2 = Message {
         topic: "sensor",
         headers: headers.clone(),
-        payload: None,
+        payload: Payload::None,
     };
     assert_eq!(m.process(&msg2, start + Duration::from_secs(30)), Some(2.0));
 
     let msg3 = Message {
         topic: "sensor",
         headers,
-        payload: None,
+        payload: Payload::None,
     };
     // Only the two most recent events fall in the trailing 60s window.
     assert_eq!(m.process(&msg3, start + Duration::from_secs(90)), Some(2.0));
@@ -129,11 +134,17 @@ fn count_pipeline() {
 fn count_rejects_arguments() {
     assert!(matches!(
         compile("/sensor |> count(json$.value)"),
-        Err(Error::CountTakesNoArguments)
+        Err(Error {
+            kind: ErrorKind::CountTakesNoArguments,
+            ..
+        })
     ));
     assert!(matches!(
         compile("/sensor |> count(window(5s))"),
-        Err(Error::CountTakesNoArguments)
+        Err(Error {
+            kind: ErrorKind::CountTakesNoArguments,
+            ..
+        })
     ));
 }
 
@@ -145,7 +156,7 @@ fn sum_missing_field() {
     let msg = Message {
         topic: "sensor",
         headers: HashMap::new(),
-        payload: Some(json!({"other": 10})),
+        payload: Payload::Json(json!({"other": 10})),
     };
     assert_eq!(m.process(&msg, Instant::now()), None);
 }
@@ -171,12 +182,65 @@ fn window_parses_seconds_minutes_and_hours() {
     );
 }
 
+#[test]
+fn sub_second_fractional_and_compound_durations() {
+    for (input, duration, display) in [
+        ("500ms", Duration::from_millis(500), "500ms"),
+        ("1.5s", Duration::from_millis(1500), "1s500ms"),
+        ("1d", Duration::from_secs(86_400), "1d"),
+        ("1h30m", Duration::from_secs(5400), "1h30m"),
+        ("1m0.25s", Duration::from_millis(60_250), "1m250ms"),
+        ("0.1ms", Duration::from_nanos(100_000), "0.1ms"),
+        ("2.000000001s", Duration::new(2, 1), "2s0.000001ms"),
+        ("0.5d", Duration::from_secs(43_200), "12h"),
+        (
+            "90061.001s",
+            Duration::from_millis(90_061_001),
+            "1d1h1m1s1ms",
+        ),
+        ("0s", Duration::ZERO, "0s"),
+    ] {
+        let sel = compile(&format!("/sensor |> window({input})")).unwrap();
+        assert_eq!(sel.stages, [Stage::Window(duration)], "{input}");
+        let shown = sel.to_string();
+        assert_eq!(shown, format!("/sensor |> window({display})"));
+        assert_eq!(compile(&shown).unwrap().stages, sel.stages);
+    }
+    assert_eq!(
+        compile("/s |> hopping(1.5s, 500ms) |> count()")
+            .unwrap()
+            .to_string(),
+        "/s |> hopping(1s500ms, 500ms) |> count()"
+    );
+    for (input, message) in [
+        ("window(-5s)", "durations cannot be negative"),
+        (
+            "window(0.0000000001s)",
+            "durations are limited to nanosecond precision",
+        ),
+        ("window(99999999999999999999d)", "duration is too long"),
+    ] {
+        let err = compile(&format!("/sensor |> {input}")).unwrap_err();
+        assert_eq!(
+            err.kind,
+            ErrorKind::InvalidDuration(message.into()),
+            "{input}"
+        );
+    }
+    assert!(compile("/sensor |> window(1h30)").is_err());
+    assert!(compile("/sensor |> window(1 h 30 m)").is_err());
+    assert!(compile("/sensor |> window(1h 30m)").is_err());
+}
+
 #[test]
 fn window_rejects_malformed_unit_and_missing_argument() {
     assert!(compile("/sensor |> window(5x)").is_err());
     assert!(matches!(
         compile("/sensor |> window()"),
-        Err(moqtail_core::Error::WindowRequiresDuration)
+        Err(Error {
+            kind: ErrorKind::WindowRequiresDuration,
+            ..
+        })
     ));
 }
 
@@ -186,7 +250,7 @@ fn window_minutes_and_hours_pipeline() {
     let msg = Message {
         topic: "sensor",
         headers: headers.clone(),
-        payload: None,
+        payload: Payload::None,
     };
 
     let minutes_sel = compile("/sensor |> window(5m) |> count()").unwrap();
@@ -220,7 +284,10 @@ fn window_minutes_and_hours_pipeline() {
 fn sum_requires_field_argument() {
     assert!(matches!(
         compile("/foo |> sum(1s)"),
-        Err(moqtail_core::Error::SumRequiresField)
+        Err(Error {
+            kind: ErrorKind::SumRequiresField,
+            ..
+        })
     ));
 }
 
@@ -228,6 +295,926 @@ fn sum_requires_field_argument() {
 fn avg_requires_field_argument() {
     assert!(matches!(
         compile("/foo |> avg(window(5s))"),
-        Err(moqtail_core::Error::AvgRequiresField)
+        Err(Error {
+            kind: ErrorKind::AvgRequiresField,
+            ..
+        })
     ));
 }
+
+fn reading(value: f64) -> Message<'static> {
+    Message {
+        topic: "sensor",
+        headers: HashMap::new(),
+        payload: Payload::Json(json!({ "value": value })),
+    }
+}
+
+/// Feeds one reading per second and collects the pipeline output.
+fn run(selector: &str, values: &[f64]) -> Vec<Option<f64>> {
+    let mut m = Matcher::new(compile(selector).unwrap());
+    let start = Instant::now();
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| m.process(&reading(*v), start + Duration::from_secs(i as u64)))
+        .collect()
+}
+
+#[test]
+fn min_and_max_pipeline() {
+    let values = [3.0, 1.0, 4.0, 1.0, 5.0, 9.0, 2.0];
+    assert_eq!(
+        run("/sensor |> window(2s) |> min(json$.value)", &values),
+        [3.0, 1.0, 1.0, 1.0, 1.0, 1.0, 2.0].map(Some)
+    );
+    assert_eq!(
+        run("/sensor |> window(2s) |> max(json$.value)", &values),
+        [3.0, 3.0, 4.0, 4.0, 5.0, 9.0, 9.0].map(Some)
+    );
+}
+
+#[test]
+fn first_and_last_pipeline() {
+    let values = [3.0, 1.0, 4.0, 1.0];
+    assert_eq!(
+        run("/sensor |> window(1s) |> first(json$.value)", &values),
+        [3.0, 3.0, 1.0, 4.0].map(Some)
+    );
+    assert_eq!(
+        run("/sensor |> window(1s) |> last(json$.value)", &values),
+        values.map(Some)
+    );
+}
+
+#[test]
+fn stddev_pipeline() {
+    let out = run(
+        "/sensor |> window(60s) |> stddev(json$.value)",
+        &[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0],
+    );
+    assert_eq!(out[0], Some(0.0));
+    assert!((out[7].unwrap() - 2.0).abs() < 1e-9);
+}
+
+#[test]
+fn percentile_pipeline_and_shorthands() {
+    let values = [10.0, 40.0, 20.0, 30.0];
+    assert_eq!(
+        run("/sensor |> window(60s) |> median(json$.value)", &values)[3],
+        Some(25.0)
+    );
+    assert_eq!(
+        run(
+            "/sensor |> window(60s) |> percentile(json$.value, 0)",
+            &values
+        )[3],
+        Some(10.0)
+    );
+    let p95 = run("/sensor |> window(60s) |> p95(json$.value)", &values)[3].unwrap();
+    assert!((p95 - 38.5).abs() < 1e-9);
+
+    let sel = compile("/sensor |> p95(json$.value) |> median(json$.value)").unwrap();
+    assert_eq!(
+        sel.to_string(),
+        "/sensor |> percentile(json$.value, 95) |> percentile(json$.value, 50)"
+    );
+}
+
+#[test]
+fn rate_pipeline() {
+    let counts = run("/sensor |> window(10s) |> rate()", &[0.0; 3]);
+    assert_eq!(counts, [0.1, 0.2, 0.3].map(Some));
+
+    let deltas = run(
+        "/sensor |> window(10s) |> rate(json$.value)",
+        &[100.0, 104.0, 110.0],
+    );
+    assert_eq!(deltas, [None, Some(4.0), Some(5.0)]);
+}
+
+#[test]
+fn new_stages_reject_bad_arguments() {
+    for (input, kind) in [
+        ("/foo |> min()", ErrorKind::RequiresField("min".into())),
+        ("/foo |> p95(5s)", ErrorKind::RequiresField("p95".into())),
+        ("/foo |> percentile(json$.x)", ErrorKind::InvalidPercentile),
+        (
+            "/foo |> percentile(json$.x, 101)",
+            ErrorKind::InvalidPercentile,
+        ),
+        (
+            "/foo |> max(json$.x, 1)",
+            ErrorKind::UnexpectedArgument("max".into()),
+        ),
+        ("/foo |> rate()", ErrorKind::RequiresWindow("rate()".into())),
+    ] {
+        match compile(input) {
+            Err(e) => assert_eq!(e.kind, kind, "{input}"),
+            Ok(sel) => panic!("{input} compiled to {sel}"),
+        }
+    }
+    assert!(matches!(
+        compile("/foo |> p100(json$.x)"),
+        Err(Error {
+            kind: ErrorKind::UnknownFunction(_),
+            ..
+        })
+    ));
+}
+
+/// A matcher whose `start` instant falls exactly on a wall-clock minute.
+fn aligned_matcher(selector: &str, start: Instant) -> Matcher {
+    let minute = UNIX_EPOCH + Duration::from_secs(1_700_000_040);
+    Matcher::new(compile(selector).unwrap()).with_wall_clock(start, minute)
+}
+
+#[test]
+fn tumbling_window_emits_once_per_closed_window() {
+    let start = Instant::now();
+    let at = |s: u64| start + Duration::from_secs(s);
+    let mut m = aligned_matcher("/sensor |> tumbling(60s) |> avg(json$.value)", start);
+
+    assert_eq!(m.process(&reading(10.0), at(5)), None);
+    assert_eq!(m.process(&reading(20.0), at(59)), None);
+    assert!(m.tick(at(59)).is_empty());
+    assert_eq!(m.tick(at(60)), [15.0]);
+    assert!(m.tick(at(61)).is_empty());
+
+    // A message after the boundary closes the previous window; the result is
+    // handed out by the next tick.
+    m.process(&reading(30.0), at(70));
+    m.process(&reading(40.0), at(130));
+    assert_eq!(m.tick(at(130)), [30.0]);
+    assert_eq!(m.tick(at(300)), [40.0]);
+}
+
+#[test]
+fn hopping_window_assigns_messages_to_overlapping_windows() {
+    let start = Instant::now();
+    let at = |s: u64| start + Duration::from_secs(s);
+    let mut m = aligned_matcher("/sensor |> hopping(60s, 30s) |> count()", start);
+
+    for s in [10, 40, 50, 80] {
+        assert_eq!(m.process(&reading(0.0), at(s)), None);
+    }
+    // Windows [-30, 30), [0, 60), [30, 90) and [60, 120) relative to start.
+    assert_eq!(m.tick(at(60)), [1.0, 3.0]);
+    assert_eq!(m.tick(at(120)), [3.0, 1.0]);
+}
+
+#[test]
+fn sliding_is_an_alias_for_window() {
+    let sel = compile("/sensor |> sliding(60s) |> count()").unwrap();
+    assert_eq!(sel, compile("/sensor |> window(60s) |> count()").unwrap());
+    let sel = compile("/sensor |> hopping(1m, 15s) |> tumbling(1h)").unwrap();
+    assert_eq!(
+        sel.stages,
+        [
+            Stage::Hopping(Duration::from_secs(60), Duration::from_secs(15)),
+            Stage::Tumbling(Duration::from_secs(3600)),
+        ]
+    );
+    assert_eq!(
+        sel.to_string(),
+        "/sensor |> hopping(1m, 15s) |> tumbling(1h)"
+    );
+}
+
+#[test]
+fn aligned_windows_reject_bad_durations() {
+    for input in [
+        "/foo |> tumbling(0s)",
+        "/foo |> hopping(10s, 20s)",
+        "/foo |> hopping(10s, 0s)",
+        "/foo |> hopping(1h, 1s)",
+    ] {
+        assert!(
+            matches!(
+                compile(input),
+                Err(Error {
+                    kind: ErrorKind::InvalidWindow(_),
+                    ..
+                })
+            ),
+            "{input}"
+        );
+    }
+    assert!(matches!(
+        compile("/foo |> hopping(10s)"),
+        Err(Error {
+            kind: ErrorKind::WindowRequiresDuration,
+            ..
+        })
+    ));
+    assert!(compile("/foo |> tumbling(10s) |> rate()").is_ok());
+    assert!(compile("/foo |> hopping(1000s, 1s)").is_ok());
+}
+
+#[test]
+fn count_window_keeps_the_last_messages() {
+    assert_eq!(
+        run(
+            "/sensor |> window(3 msgs) |> sum(json$.value)",
+            &[1.0, 2.0, 3.0, 4.0, 5.0]
+        ),
+        [1.0, 3.0, 6.0, 9.0, 12.0].map(Some)
+    );
+    assert_eq!(
+        run(
+            "/sensor |> window(2 msgs) |> min(json$.value)",
+            &[1.0, 5.0, 3.0, 4.0]
+        ),
+        [1.0, 1.0, 3.0, 3.0].map(Some)
+    );
+    assert_eq!(
+        run("/sensor |> window(1 msg) |> rate()", &[0.0, 0.0]),
+        [None, None]
+    );
+    assert_eq!(
+        run("/sensor |> window(5 msgs) |> rate()", &[0.0, 0.0, 0.0]),
+        [None, Some(1.0), Some(1.0)]
+    );
+}
+
+#[test]
+fn session_window_closes_after_inactivity() {
+    let start = Instant::now();
+    let at = |s: u64| start + Duration::from_secs(s);
+    let mut m = Matcher::new(compile("/sensor |> session(30s) |> sum(json$.value)").unwrap());
+
+    for (s, v) in [(0, 1.0), (20, 2.0), (45, 3.0)] {
+        assert_eq!(m.process(&reading(v), at(s)), None);
+    }
+    assert!(m.tick(at(74)).is_empty());
+    assert_eq!(m.tick(at(75)), [6.0]);
+
+    // A message after the gap starts a new session and reports the old one
+    // on the next tick.
+    m.process(&reading(10.0), at(100));
+    m.process(&reading(20.0), at(200));
+    assert_eq!(m.tick(at(200)), [10.0]);
+    assert_eq!(m.tick(at(230)), [20.0]);
+}
+
+#[test]
+fn count_and_session_windows_parse_and_display() {
+    for input in [
+        "/sensor |> window(100 msgs) |> avg(json$.value)",
+        "/sensor |> session(30s) |> count()",
+    ] {
+        assert_eq!(compile(input).unwrap().to_string(), input);
+    }
+    assert_eq!(
+        compile("/sensor |> window(1 msg)").unwrap().stages,
+        [Stage::CountWindow(1)]
+    );
+    for input in [
+        "/foo |> window(0 msgs)",
+        "/foo |> window(1.5 msgs)",
+        "/foo |> session(0s)",
+    ] {
+        assert!(
+            matches!(
+                compile(input),
+                Err(Error {
+                    kind: ErrorKind::InvalidWindow(_),
+                    ..
+                })
+            ),
+            "{input}"
+        );
+    }
+    assert!(compile("/foo |> session(5 msgs)").is_err());
+    assert!(compile("/foo |> window(5 msgsx)").is_err());
+}
+
+fn device_reading(topic: &'static str, value: f64) -> Message<'static> {
+    Message {
+        topic,
+        headers: HashMap::new(),
+        payload: Payload::Json(json!({ "device": topic.split('/').nth(1), "value": value })),
+    }
+}
+
+#[test]
+fn group_by_keeps_state_per_key() {
+    let start = Instant::now();
+    for selector in [
+        "//sensor |> group_by(topic[2]) |> window(60s) |> avg(json$.value)",
+        "//sensor |> window(60s) |> group_by(json$.device) |> avg(json$.value)",
+        "/plant/{device}/sensor |> group_by($device) |> window(60s) |> avg(json$.value)",
+    ] {
+        let mut m = Matcher::new(compile(selector).unwrap());
+        let key = |k: &str| Some(k.to_string());
+        assert_eq!(
+            m.process_keyed(&device_reading("plant/a/sensor", 10.0), start),
+            Some((key("a"), 10.0))
+        );
+        assert_eq!(
+            m.process_keyed(&device_reading("plant/b/sensor", 100.0), start),
+            Some((key("b"), 100.0))
+        );
+        assert_eq!(
+            m.process_keyed(&device_reading("plant/a/sensor", 20.0), start),
+            Some((key("a"), 15.0)),
+            "{selector}"
+        );
+    }
+}
+
+#[test]
+fn group_by_skips_messages_without_a_key() {
+    let mut m = Matcher::new(compile("//sensor |> group_by(json$.site) |> count()").unwrap());
+    assert_eq!(m.process(&reading(1.0), Instant::now()), None);
+
+    let mut m = Matcher::new(compile("/sensor |> count()").unwrap());
+    assert_eq!(
+        m.process_keyed(&reading(1.0), Instant::now()),
+        Some((None, 1.0))
+    );
+}
+
+#[test]
+fn grouped_windows_close_per_key() {
+    let start = Instant::now();
+    let at = |s: u64| start + Duration::from_secs(s);
+    let mut m = aligned_matcher(
+        "//sensor |> group_by(topic[2]) |> tumbling(60s) |> sum(json$.value)",
+        start,
+    );
+    m.process(&device_reading("plant/b/sensor", 1.0), at(1));
+    m.process(&device_reading("plant/a/sensor", 2.0), at(2));
+    m.process(&device_reading("plant/b/sensor", 3.0), at(3));
+    assert_eq!(
+        m.tick_keyed(at(60)),
+        [(Some("a".to_string()), 2.0), (Some("b".to_string()), 4.0)]
+    );
+}
+
+#[test]
+fn group_by_evicts_least_recently_used_keys() {
+    let start = Instant::now();
+    let mut m =
+        Matcher::new(compile("//sensor |> group_by(topic[2]) |> window(60s) |> count()").unwrap())
+            .with_max_partitions(2);
+    for topic in ["plant/a/sensor", "plant/b/sensor", "plant/a/sensor"] {
+        m.process(&device_reading(topic, 0.0), start);
+    }
+    // `c` evicts `b`, the key that went longest without a message.
+    m.process(&device_reading("plant/c/sensor", 0.0), start);
+    assert_eq!(
+        m.process(&device_reading("plant/a/sensor", 0.0), start),
+        Some(3.0)
+    );
+    assert_eq!(
+        m.process(&device_reading("plant/b/sensor", 0.0), start),
+        Some(1.0)
+    );
+}
+
+#[test]
+fn evicted_keys_hand_out_their_open_windows() {
+    let start = Instant::now();
+    let at = |s: u64| start + Duration::from_secs(s);
+    let mut m = aligned_matcher(
+        "//sensor |> group_by(topic[2]) |> tumbling(60s) |> sum(json$.value)",
+        start,
+    )
+    .with_max_partitions(1);
+    m.process(&device_reading("plant/a/sensor", 1.0), at(1));
+    m.process(&device_reading("plant/a/sensor", 2.0), at(2));
+    // `b` evicts `a` while its window is still open.
+    m.process(&device_reading("plant/b/sensor", 5.0), at(3));
+    let closed = m.tick_output(at(4));
+    assert_eq!(closed.len(), 1);
+    assert_eq!(
+        closed[0].to_json(),
+        json!({
+            "key": "a",
+            "window": {"start": "2023-11-14T22:14:00Z", "end": "2023-11-14T22:15:00Z"},
+            "values": {"sum": 3.0},
+        })
+    );
+    assert!(m.tick_output(at(5)).is_empty());
+    assert_eq!(m.tick_keyed(at(60)), [(Some("b".to_string()), 5.0)]);
+}
+
+#[test]
+fn group_by_parses_and_displays() {
+    let input = "//sensor |> group_by(topic[2]) |> window(1m) |> avg(json$.value)";
+    assert_eq!(compile(input).unwrap().to_string(), input);
+    assert!(compile("/{id}/x[topic[1] = \"a\"] |> group_by($id)").is_ok());
+    for (input, kind) in [
+        (
+            "//sensor |> group_by()",
+            ErrorKind::RequiresField("group_by".into()),
+        ),
+        (
+            "//sensor |> group_by(topic[0])",
+            ErrorKind::InvalidTopicLevel,
+        ),
+        (
+            "//sensor |> group_by(topic[1]) |> group_by(qos)",
+            ErrorKind::DuplicateGroupBy,
+        ),
+    ] {
+        match compile(input) {
+            Err(e) => assert_eq!(e.kind, kind, "{input}"),
+            Ok(sel) => panic!("{input} compiled to {sel}"),
+        }
+    }
+}
+
+fn stamped(ts: serde_json::Value, value: f64) -> Message<'static> {
+    Message {
+        topic: "sensor",
+        headers: HashMap::new(),
+        payload: Payload::Json(json!({ "ts": ts, "value": value })),
+    }
+}
+
+#[test]
+fn event_time_windows_follow_payload_timestamps() {
+    let mut m = Matcher::new(
+        compile("/sensor |> timestamp(json$.ts, 30s) |> tumbling(60s) |> sum(json$.value)")
+            .unwrap(),
+    );
+    // Arrival time is irrelevant once the pipeline has a timestamp.
+    let now = Instant::now();
+    assert_eq!(
+        m.try_process(&stamped(json!(1_700_000_040), 1.0), now),
+        Ok(None)
+    );
+    assert_eq!(
+        m.try_process(&stamped(json!("2023-11-14T22:14:30Z"), 2.0), now),
+        Ok(None)
+    );
+    // Out of order but within the allowed lateness.
+    assert_eq!(
+        m.try_process(&stamped(json!(1_700_000_050_000u64), 4.0), now),
+        Ok(None)
+    );
+    assert!(m.tick(now).is_empty());
+
+    // A timestamp of 22:15:30 moves the watermark to the end of the first
+    // minute.
+    m.try_process(&stamped(json!(1_700_000_130), 8.0), now)
+        .unwrap();
+    assert_eq!(m.tick(now), [7.0]);
+
+    let late = m.try_process(&stamped(json!(1_700_000_090), 16.0), now);
+    assert_eq!(
+        late,
+        Err(Late {
+            event_time: UNIX_EPOCH + Duration::from_secs(1_700_000_090),
+            watermark: UNIX_EPOCH + Duration::from_secs(1_700_000_100),
+        })
+    );
+    assert_eq!(m.process(&stamped(json!(1_700_000_090), 16.0), now), None);
+
+    m.advance_watermark(UNIX_EPOCH + Duration::from_secs(1_700_000_160));
+    assert_eq!(m.tick(now), [8.0]);
+}
+
+#[test]
+fn event_time_drives_sliding_windows_and_rates() {
+    let mut m = Matcher::new(
+        compile("/sensor |> timestamp(json$.ts) |> window(10s) |> rate(json$.value)").unwrap(),
+    );
+    let now = Instant::now();
+    assert_eq!(m.process(&stamped(json!(100), 0.0), now), None);
+    assert_eq!(m.process(&stamped(json!(104), 8.0), now), Some(2.0));
+    assert_eq!(m.process(&stamped(json!(120), 48.0), now), None);
+    // Messages without a timestamp are skipped rather than reported late.
+    assert_eq!(m.try_process(&reading(1.0), now), Ok(None));
+}
+
+#[test]
+fn timestamp_stage_parses_and_displays() {
+    for input in [
+        "/sensor |> timestamp(json$.ts) |> tumbling(1m) |> count()",
+        "/sensor |> timestamp(prop.sent-at, 30s) |> tumbling(1m) |> count()",
+    ] {
+        assert_eq!(compile(input).unwrap().to_string(), input);
+    }
+    for (input, kind) in [
+        (
+            "/s |> timestamp(json$.ts, 5)",
+            ErrorKind::RequiresDuration("allowed lateness".into()),
+        ),
+        (
+            "/s |> timestamp()",
+            ErrorKind::RequiresField("timestamp".into()),
+        ),
+        (
+            "/s |> timestamp(json$.a) |> timestamp(json$.b)",
+            ErrorKind::DuplicateTimestamp,
+        ),
+    ] {
+        match compile(input) {
+            Err(e) => assert_eq!(e.kind, kind, "{input}"),
+            Ok(sel) => panic!("{input} compiled to {sel}"),
+        }
+    }
+}
+
+#[test]
+fn agg_reports_several_aggregates_per_window() {
+    let start = Instant::now();
+    let at = |s: u64| start + Duration::from_secs(s);
+    let mut m = aligned_matcher(
+        "/sensor |> window(60s) |> agg(avg(json$.value), max(json$.value) as peak, count())",
+        start,
+    );
+    m.process(&reading(10.0), at(0));
+    let output = m.process_output(&reading(30.0), at(20)).unwrap();
+    let minute = |s: u64| UNIX_EPOCH + Duration::from_secs(1_700_000_040 + s);
+    assert_eq!(
+        output,
+        Some(PipelineOutput::Aggregate(AggregateRecord {
+            key: None,
+            topic: Some("sensor".into()),
+            window: Some(WindowBounds {
+                start: minute(20) - Duration::from_secs(60),
+                end: minute(20),
+            }),
+            fields: vec![
+                ("avg".into(), Some(20.0)),
+                ("peak".into(), Some(30.0)),
+                ("count".into(), Some(2.0)),
+            ],
+        }))
+    );
+    // The single-value API reports the last aggregation.
+    assert_eq!(m.process(&reading(20.0), at(30)), Some(3.0));
+}
+
+#[test]
+fn closed_windows_report_bounds_and_keys() {
+    let start = Instant::now();
+    let at = |s: u64| start + Duration::from_secs(s);
+    let mut m = aligned_matcher(
+        "//sensor |> group_by(topic[2]) |> tumbling(60s) |> agg(min(json$.value), p90(json$.value))",
+        start,
+    );
+    m.process(&device_reading("plant/a/sensor", 2.0), at(1));
+    m.process(&device_reading("plant/a/sensor", 4.0), at(2));
+    let closed = m.tick_output(at(60));
+    assert_eq!(closed.len(), 1);
+    assert_eq!(
+        closed[0].to_json(),
+        json!({
+            "key": "a",
+            "window": {"start": "2023-11-14T22:14:00Z", "end": "2023-11-14T22:15:00Z"},
+            "values": {"min": 2.0, "p90": 3.8},
+        })
+    );
+}
+
+#[test]
+fn selectors_without_aggregations_pass_messages_through() {
+    let mut m = Matcher::new(compile("/sensor |> group_by(json$.value)").unwrap());
+    let output = m.process_output(&reading(1.0), Instant::now()).unwrap();
+    assert_eq!(
+        output.map(|o| o.to_json()),
+        Some(json!({"topic": "sensor", "payload": {"value": 1.0}}))
+    );
+    assert_eq!(m.process(&reading(1.0), Instant::now()), None);
+}
+
+#[test]
+fn agg_parses_and_displays() {
+    let input =
+        "/sensor |> window(1m) |> agg(avg(json$.t), percentile(json$.t, 99) as worst, count())";
+    assert_eq!(compile(input).unwrap().to_string(), input);
+    assert!(compile("/sensor |> window(5s) |> agg(rate(), rate(json$.v) as slope)").is_ok());
+    for (input, kind) in [
+        ("/s |> agg()", ErrorKind::AggRequiresAggregations),
+        ("/s |> agg(json$.t)", ErrorKind::AggRequiresAggregations),
+        ("/s |> agg(window(5s))", ErrorKind::AggRequiresAggregations),
+        (
+            "/s |> agg(agg(count()))",
+            ErrorKind::AggRequiresAggregations,
+        ),
+        (
+            "/s |> agg(avg(json$.a), avg(json$.b))",
+            ErrorKind::DuplicateName("avg".into()),
+        ),
+        (
+            "/s |> agg(max(json$.a) as x, min(json$.a) as x)",
+            ErrorKind::DuplicateName("x".into()),
+        ),
+        (
+            "/s |> agg(rate())",
+            ErrorKind::RequiresWindow("rate()".into()),
+        ),
+    ] {
+        match compile(input) {
+            Err(e) => assert_eq!(e.kind, kind, "{input}"),
+            Ok(sel) => panic!("{input} compiled to {sel}"),
+        }
+    }
+}
+
+#[test]
+fn transforms_rewrite_passed_through_payloads() {
+    let mut m = Matcher::new(
+        compile(
+            "/plant/{device}/sensor \
+             |> map(json$.temp_f = json$.temp_c * 1.8 + 32, json$.device = upper($device)) \
+             |> rename(json$.temp_c as json$.celsius) \
+             |> select(json$.device, json$.celsius, json$.temp_f, json$.qos)",
+        )
+        .unwrap(),
+    );
+    let msg = Message {
+        topic: "plant/a1/sensor",
+        headers: HashMap::new(),
+        payload: Payload::Json(json!({"temp_c": 25, "raw": [1, 2, 3]})),
+    };
+    match m.process_output(&msg, Instant::now()) {
+        Ok(Some(PipelineOutput::Message { payload, .. })) => assert_eq!(
+            payload,
+            Some(json!({"device": "A1", "celsius": 25, "temp_f": 77}))
+        ),
+        other => panic!("unexpected output {other:?}"),
+    }
+}
+
+#[test]
+fn aggregations_read_transformed_payloads() {
+    let mut m = Matcher::new(
+        compile("/sensor |> map(json$.f = json$.value * 1.8 + 32) |> window(60s) |> max(json$.f)")
+            .unwrap(),
+    );
+    let start = Instant::now();
+    assert_eq!(m.process(&reading(100.0), start), Some(212.0));
+    assert_eq!(m.process(&reading(0.0), start), Some(212.0));
+}
+
+#[test]
+fn transforms_parse_and_display() {
+    for input in [
+        "/s |> select(json$.a, json$.b.c)",
+        "/s |> map(json$.x = (json$.a + 1) * -json$.b - (2 - json$.c) / 4 % 3)",
+        "/s |> map(json$.n = concat(\"id-\", lower(trim(json$.id))), json$.r = round(json$.v, 2))",
+        "/s |> rename(json$.t as json$.temp, json$.u as json$.meta.unit) |> count()",
+    ] {
+        assert_eq!(compile(input).unwrap().to_string(), input);
+    }
+    for (input, kind) in [
+        (
+            "/s |> select(qos)",
+            ErrorKind::RequiresJsonField("select".into()),
+        ),
+        (
+            "/s |> select()",
+            ErrorKind::RequiresJsonField("select".into()),
+        ),
+        ("/s |> map(json$.a)", ErrorKind::MapRequiresAssignment),
+        (
+            "/s |> map(qos = 1)",
+            ErrorKind::RequiresJsonField("map".into()),
+        ),
+        ("/s |> rename(json$.a)", ErrorKind::RenameRequiresFields),
+        (
+            "/s |> map(json$.a = round())",
+            ErrorKind::ArgumentCount {
+                name: "round".into(),
+                expected: "1 to 2 arguments".into(),
+            },
+        ),
+        (
+            "/s |> count() |> select(json$.a)",
+            ErrorKind::StageAfterAggregation("select".into()),
+        ),
+    ] {
+        match compile(input) {
+            Err(e) => assert_eq!(e.kind, kind, "{input}"),
+            Ok(sel) => panic!("{input} compiled to {sel}"),
+        }
+    }
+    let err = compile("/s |> map(json$.a = uper(json$.b))").unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnknownFunction("uper".into()));
+    assert_eq!(err.suggestion.as_deref(), Some("upper"));
+}
+
+#[test]
+fn where_filters_aggregate_results() {
+    let mut m = Matcher::new(
+        compile("/sensor |> window(60s) |> avg(json$.value) |> where(value > 30)").unwrap(),
+    );
+    let start = Instant::now();
+    assert_eq!(m.process(&reading(20.0), start), None);
+    assert_eq!(m.process(&reading(60.0), start), Some(40.0));
+    assert_eq!(m.process(&reading(10.0), start), None);
+}
+
+#[test]
+fn threshold_alerts_per_key_when_windows_close() {
+    let start = Instant::now();
+    let at = |s: u64| start + Duration::from_secs(s);
+    let mut m = aligned_matcher(
+        "//sensor |> group_by(topic[2]) |> tumbling(60s) |> max(json$.value) |> threshold(30)",
+        start,
+    );
+    let mut alerts = Vec::new();
+    for (minute, a, b) in [(0, 35.0, 10.0), (1, 40.0, 31.0), (2, 20.0, 32.0)] {
+        m.process(&device_reading("plant/a/sensor", a), at(minute * 60 + 1));
+        m.process(&device_reading("plant/b/sensor", b), at(minute * 60 + 1));
+        alerts.extend(m.tick_keyed(at(minute * 60 + 60)));
+    }
+    assert_eq!(
+        alerts,
+        [
+            (Some("a".into()), 35.0),
+            (Some("b".into()), 31.0),
+            (Some("a".into()), 20.0),
+        ]
+    );
+}
+
+#[test]
+fn filters_parse_and_display() {
+    for input in [
+        "/s |> avg(json$.v) |> where(value>30 and not value>=40)",
+        "/s |> agg(avg(json$.v), count() as n) |> where(avg>30 or n<2) |> changed(avg)",
+        "/s |> window(10s) |> max(json$.v) |> threshold(-2.5)",
+        "/s |> max(json$.v) |> hysteresis(max, 30, 25) |> changed()",
+    ] {
+        assert_eq!(compile(input).unwrap().to_string(), input);
+    }
+    for (input, kind) in [
+        (
+            "/s |> where(value > 1)",
+            ErrorKind::RequiresAggregation("where".into()),
+        ),
+        (
+            "/s |> changed() |> avg(json$.v)",
+            ErrorKind::RequiresAggregation("changed".into()),
+        ),
+        (
+            "/s |> avg(json$.v) |> changed() |> count()",
+            ErrorKind::AggregationAfterFilter("count".into()),
+        ),
+        (
+            "/s |> avg(json$.v) |> where(json$.v > 1)",
+            ErrorKind::UnknownValue("json$.v".into()),
+        ),
+        (
+            "/s |> avg(json$.v) |> hysteresis(25, 30)",
+            ErrorKind::InvalidHysteresis,
+        ),
+        (
+            "/s |> avg(json$.v) |> threshold()",
+            ErrorKind::ArgumentCount {
+                name: "threshold".into(),
+                expected: "a limit".into(),
+            },
+        ),
+    ] {
+        match compile(input) {
+            Err(e) => assert_eq!(e.kind, kind, "{input}"),
+            Ok(sel) => panic!("{input} compiled to {sel}"),
+        }
+    }
+    let err = compile("/s |> agg(avg(json$.v) as mean) |> where(maen > 1)").unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnknownValue("maen".into()));
+    assert_eq!(err.suggestion.as_deref(), Some("mean"));
+}
+
+#[test]
+fn distinct_keeps_state_per_key() {
+    let mut m =
+        Matcher::new(compile("//sensor |> group_by(topic[2]) |> distinct(json$.value)").unwrap());
+    let now = Instant::now();
+    let passed: Vec<_> = [
+        ("plant/a/sensor", 1.0),
+        ("plant/b/sensor", 1.0),
+        ("plant/a/sensor", 1.0),
+        ("plant/a/sensor", 2.0),
+        ("plant/b/sensor", 1.0),
+    ]
+    .into_iter()
+    .filter_map(
+        |(topic, value)| match m.process_output(&device_reading(topic, value), now) {
+            Ok(Some(PipelineOutput::Message { topic, .. })) => Some((topic, value)),
+            _ => None,
+        },
+    )
+    .collect();
+    assert_eq!(
+        passed,
+        [
+            ("plant/a/sensor".into(), 1.0),
+            ("plant/b/sensor".into(), 1.0),
+            ("plant/a/sensor".into(), 2.0),
+        ]
+    );
+}
+
+#[test]
+fn throttled_messages_do_not_reach_aggregations() {
+    let mut m =
+        Matcher::new(compile("/sensor |> throttle(10s) |> window(60s) |> count()").unwrap());
+    let start = Instant::now();
+    let at = |s: u64| start + Duration::from_secs(s);
+    assert_eq!(m.process(&reading(1.0), at(0)), Some(1.0));
+    assert_eq!(m.process(&reading(1.0), at(5)), None);
+    assert_eq!(m.process(&reading(1.0), at(10)), Some(2.0));
+    assert!(!m.accept(&reading(1.0), at(15)));
+    assert!(m.accept(&reading(1.0), at(20)));
+    assert_eq!(m.process(&reading(1.0), at(21)), None);
+}
+
+#[test]
+fn message_filters_parse_and_display() {
+    for input in [
+        "/s |> distinct(json$.state)",
+        "/s |> map(json$.on = json$.v % 2) |> distinct(json$.on) |> debounce(2s)",
+        "/s |> sample(10s) |> throttle(1m) |> count()",
+    ] {
+        assert_eq!(compile(input).unwrap().to_string(), input);
+    }
+    for (input, kind) in [
+        (
+            "/s |> distinct()",
+            ErrorKind::RequiresField("distinct".into()),
+        ),
+        (
+            "/s |> throttle()",
+            ErrorKind::RequiresDuration("throttle".into()),
+        ),
+        (
+            "/s |> sample(0s)",
+            ErrorKind::RequiresDuration("sample".into()),
+        ),
+        (
+            "/s |> count() |> debounce(1s)",
+            ErrorKind::StageAfterAggregation("debounce".into()),
+        ),
+    ] {
+        match compile(input) {
+            Err(e) => assert_eq!(e.kind, kind, "{input}"),
+            Ok(sel) => panic!("{input} compiled to {sel}"),
+        }
+    }
+}
+
+#[test]
+fn raw_payloads_pass_through_whole() {
+    let mut m = Matcher::new(compile("/s[json$.on = true]").unwrap());
+    let msg = Message {
+        topic: "s",
+        headers: HashMap::new(),
+        payload: Payload::raw(&br#"{"on": true, "level": 3}"#[..]),
+    };
+    match m.process_output(&msg, Instant::now()) {
+        Ok(Some(PipelineOutput::Message { payload, .. })) => {
+            assert_eq!(payload, Some(json!({"on": true, "level": 3})))
+        }
+        other => panic!("unexpected output {other:?}"),
+    }
+
+    let mut m = Matcher::new(compile("/s |> group_by(json$.id) |> avg(json$.v)").unwrap());
+    let msg = Message {
+        topic: "s",
+        headers: HashMap::new(),
+        payload: Payload::raw(&br#"{"id": "a", "v": 2, "extra": {"x": 1}}"#[..]),
+    };
+    assert_eq!(
+        m.process_keyed(&msg, Instant::now()),
+        Some((Some("a".into()), 2.0))
+    );
+}
+
+#[test]
+#[cfg(feature = "cbor")]
+fn cbor_fields_feed_aggregations() {
+    let mut m = Matcher::new(
+        compile("/s |> timestamp(cbor$.at) |> group_by(cbor$.id) |> tumbling(60s) |> sum(cbor$.v)")
+            .unwrap(),
+    );
+    let reading = |at: u64, v: i64| {
+        // Tag 1 marks an epoch timestamp.
+        let value = cbor!({
+            "at" => Value::Tag(1, Box::new(at.into())),
+            "id" => Value::Bytes(vec![0x0a]),
+            "v" => v,
+        })
+        .unwrap();
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&value, &mut bytes).unwrap();
+        Message {
+            topic: "s",
+            headers: HashMap::new(),
+            payload: Payload::raw(bytes),
+        }
+    };
+    let now = Instant::now();
+    assert_eq!(m.try_process(&reading(1_700_000_040, 1), now), Ok(None));
+    assert_eq!(m.try_process(&reading(1_700_000_050, 2), now), Ok(None));
+    m.try_process(&reading(1_700_000_130, 4), now).unwrap();
+    assert_eq!(m.tick_keyed(now), [(Some("0a".into()), 3.0)]);
+}
@@ -1,7 +1,13 @@
-use moqtail_core::{compile, Error, Matcher, Message};
+#[cfg(feature = "cbor")]
+use ciborium::{cbor, Value};
+#[cfg(feature = "pb")]
+use moqtail_core::Schemas;
+use moqtail_core::{compile, Error, ErrorKind, Matcher, Message, Payload};
 use serde_json::json;
 use std::borrow::Cow;
 use std::collections::HashMap;
+#[cfg(feature = "pb")]
+use std::sync::Arc;
 
 #[test]
 fn header_predicate_match() {
@@ -9,7 +15,7 @@ fn header_predicate_match() {
     let msg = Message {
         topic: "",
         headers: HashMap::from([(Cow::Borrowed("qos"), Cow::Borrowed("0"))]),
-        payload: None,
+        payload: Payload::None,
     };
     let m = Matcher::new(sel);
     assert!(m.matches(&msg));
@@ -22,12 +28,166 @@ fn json_predicate_match() {
     let msg = Message {
         topic: "foo",
         headers: HashMap::new(),
-        payload: Some(payload),
+        payload: Payload::Json(payload),
     };
     let m = Matcher::new(sel);
     assert!(m.matches(&msg));
 }
 
+#[test]
+fn raw_payloads_are_decoded_on_demand() {
+    let m = Matcher::new(compile("/foo[json$.temp>30]").unwrap());
+    let raw = |bytes: &'static [u8]| Message {
+        topic: "foo",
+        headers: HashMap::new(),
+        payload: Payload::raw(bytes),
+    };
+    assert!(m.matches(&raw(br#"{"temp": 35, "log": [1, {"deep": true}]}"#)));
+    assert!(!m.matches(&raw(br#"{"temp": 25}"#)));
+    assert!(!m.matches(&raw(b"35")));
+    assert!(!m.matches(&raw(b"not json")));
+
+    let m = Matcher::new(compile("/foo").unwrap());
+    assert!(m.matches(&raw(b"not json")));
+}
+
+#[test]
+#[cfg(feature = "cbor")]
+fn cbor_predicates_compare_like_json() {
+    let m = Matcher::new(
+        compile(r#"/foo[cbor$.temp > 30 and cbor$.id = "cafe" and cbor$.unit in ["C", "F"]]"#)
+            .unwrap(),
+    );
+    let message = |value: Value| {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&value, &mut bytes).unwrap();
+        Message {
+            topic: "foo",
+            headers: HashMap::new(),
+            payload: Payload::raw(bytes),
+        }
+    };
+    let reading = |temp: f64, unit: &str| {
+        message(
+            cbor!({
+                "temp" => temp,
+                "id" => Value::Bytes(vec![0xca, 0xfe]),
+                "unit" => unit,
+            })
+            .unwrap(),
+        )
+    };
+    assert!(m.matches(&reading(35.5, "C")));
+    assert!(!m.matches(&reading(25.0, "C")));
+    assert!(!m.matches(&reading(35.5, "K")));
+    assert!(!m.matches(&message(Value::Integer(35.into()))));
+
+    // A JSON payload has no CBOR fields, and the other way round.
+    let m = Matcher::new(compile("/foo[cbor$.temp]").unwrap());
+    let json = Message {
+        topic: "foo",
+        headers: HashMap::new(),
+        payload: Payload::Json(json!({"temp": 35})),
+    };
+    assert!(!m.matches(&json));
+    let m = Matcher::new(compile("/foo[json$.temp]").unwrap());
+    assert!(!m.matches(&reading(35.5, "C")));
+}
+
+/// Schemas with a proto3 `sensors.Reading { double temperature = 1; }`.
+#[cfg(feature = "pb")]
+fn sensor_schemas() -> Arc<Schemas> {
+    let set: protobuf::descriptor::FileDescriptorSet = protobuf::text_format::parse_from_str(
+        r#"file {
+            name: "sensors.proto" package: "sensors" syntax: "proto3"
+            message_type {
+                name: "Reading"
+                field { name: "temperature" number: 1 label: LABEL_OPTIONAL type: TYPE_DOUBLE }
+            }
+        }"#,
+    )
+    .unwrap();
+    let mut schemas = Schemas::new();
+    schemas
+        .add_descriptor_set(&protobuf::Message::write_to_bytes(&set).unwrap())
+        .unwrap();
+    Arc::new(schemas)
+}
+
+#[test]
+#[cfg(feature = "pb")]
+fn protobuf_predicates_read_registered_types() {
+    let schemas = sensor_schemas();
+    let matcher =
+        |selector: &str| Matcher::new(compile(selector).unwrap()).with_schemas(schemas.clone());
+    let message = |bytes: Vec<u8>, content_type: Option<&'static str>| Message {
+        topic: "foo",
+        headers: content_type
+            .map(|ct| (Cow::Borrowed("prop.content-type"), Cow::Borrowed(ct)))
+            .into_iter()
+            .collect(),
+        payload: Payload::raw(bytes),
+    };
+    // Field 1, a double.
+    let reading = |t: f64| [&[0x09][..], &t.to_le_bytes()].concat();
+
+    let m = matcher("/foo[pb$.Reading.temperature > 30]");
+    assert!(m.matches(&message(reading(35.5), None)));
+    assert!(!m.matches(&message(reading(25.0), None)));
+    assert!(!m.matches(&message(b"\xff\xff".to_vec(), None)));
+
+    // Payloads that do not decode and unknown types read like a missing
+    // JSON path.
+    let m = matcher("/foo[not pb$.Reading.temperature > 30]");
+    assert!(m.matches(&message(b"\xff\xff".to_vec(), None)));
+    let m = matcher("/foo[pb$.Missing.temperature]");
+    assert!(!m.matches(&message(reading(35.5), None)));
+
+    let labelled = Some("application/x-protobuf; messageType=sensors.Reading");
+    let m = matcher("/foo[pb$.temperature > 30]");
+    assert!(m.matches(&message(reading(35.5), labelled)));
+    assert!(!m.matches(&message(reading(35.5), None)));
+    let m = matcher("/foo[pb$.Reading.temperature > 30]");
+    assert!(m.matches(&message(reading(35.5), labelled)));
+    let other = Some("application/x-protobuf; messageType=google.protobuf.Timestamp");
+    assert!(!m.matches(&message(reading(35.5), other)));
+
+    let m = Matcher::new(compile("/foo[pb$.Reading.temperature]").unwrap());
+    assert!(!m.matches(&message(reading(35.5), None)));
+}
+
+#[test]
+#[cfg(not(feature = "cbor"))]
+fn cbor_fields_need_the_cbor_feature() {
+    let err = compile("/foo[cbor$.temp > 30]").unwrap_err();
+    assert_eq!(
+        err.kind,
+        ErrorKind::FieldNotEnabled {
+            field: "cbor$".into(),
+            feature: "cbor".into(),
+        }
+    );
+    assert_eq!(err.span, 5..15);
+}
+
+#[test]
+#[cfg(not(feature = "pb"))]
+fn protobuf_fields_need_the_pb_feature() {
+    let err = compile("/foo[pb$.Reading.temperature > 30]").unwrap_err();
+    assert_eq!(
+        err.kind,
+        ErrorKind::FieldNotEnabled {
+            field: "pb$".into(),
+            feature: "pb".into(),
+        }
+    );
+    assert_eq!(err.span, 5..28);
+    assert_eq!(
+        err.to_string(),
+        "1:6: pb$ fields need the `pb` feature of moqtail-core"
+    );
+}
+
 #[test]
 
 fn header_predicate_negative_fractional() {
@@ -35,7 +195,7 @@ fn header_predicate_negative_fractional() {
     let msg = Message {
         topic: "",
         headers: HashMap::from([(Cow::Borrowed("temp"), Cow::Borrowed("-1.5"))]),
-        payload: None,
+        payload: Payload::None,
     };
     let m = Matcher::new(sel);
     assert!(m.matches(&msg));
@@ -48,7 +208,7 @@ fn json_predicate_fractional() {
     let msg = Message {
         topic: "foo",
         headers: HashMap::new(),
-        payload: Some(payload),
+        payload: Payload::Json(payload),
     };
     let m = Matcher::new(sel);
     assert!(m.matches(&msg));
@@ -57,7 +217,13 @@ fn json_predicate_fractional() {
 #[test]
 fn json_predicate_missing_field() {
     let err = compile("/foo[json$>1]").unwrap_err();
-    assert!(matches!(err, Error::MissingField));
+    assert!(matches!(
+        err,
+        Error {
+            kind: ErrorKind::MissingField,
+            ..
+        }
+    ));
 }
 
 #[test]
@@ -66,7 +232,7 @@ fn header_string_predicate_matches_numeric_text() {
     let msg = Message {
         topic: "",
         headers: HashMap::from([(Cow::Borrowed("tag"), Cow::Borrowed("123"))]),
-        payload: None,
+        payload: Payload::None,
     };
     let matcher = Matcher::new(sel);
     assert!(matcher.matches(&msg));
@@ -78,8 +244,164 @@ fn header_string_predicate_matches_boolean_text() {
     let msg = Message {
         topic: "",
         headers: HashMap::from([(Cow::Borrowed("flag"), Cow::Borrowed("true"))]),
-        payload: None,
+        payload: Payload::None,
+    };
+    let matcher = Matcher::new(sel);
+    assert!(matcher.matches(&msg));
+}
+
+#[test]
+fn dotted_header_predicate_matches_property() {
+    let sel = compile("/msg[prop.content-type=\"application/json\"]").unwrap();
+    let msg = Message {
+        topic: "",
+        headers: HashMap::from([(
+            Cow::Borrowed("prop.content-type"),
+            Cow::Borrowed("application/json"),
+        )]),
+        payload: Payload::None,
     };
     let matcher = Matcher::new(sel);
     assert!(matcher.matches(&msg));
 }
+
+#[test]
+fn or_predicate_matches_either_side() {
+    let sel = compile("/log[json$.level=\"warn\" or json$.level=\"error\"]").unwrap();
+    let matcher = Matcher::new(sel);
+    for (level, expected) in [("warn", true), ("error", true), ("info", false)] {
+        let msg = Message {
+            topic: "log",
+            headers: HashMap::new(),
+            payload: Payload::Json(json!({ "level": level })),
+        };
+        assert_eq!(matcher.matches(&msg), expected, "level {level}");
+    }
+}
+
+#[test]
+fn not_predicate_negates_header() {
+    let sel = compile("/msg[not retained=true]").unwrap();
+    let matcher = Matcher::new(sel);
+    let msg = |retained: &'static str| Message {
+        topic: "",
+        headers: HashMap::from([(Cow::Borrowed("retained"), Cow::Borrowed(retained))]),
+        payload: Payload::None,
+    };
+    assert!(matcher.matches(&msg("false")));
+    assert!(!matcher.matches(&msg("true")));
+}
+
+#[test]
+fn not_predicate_matches_missing_field() {
+    let sel = compile("/foo[not json$.battery<10]").unwrap();
+    let msg = Message {
+        topic: "foo",
+        headers: HashMap::new(),
+        payload: Payload::Json(json!({})),
+    };
+    assert!(Matcher::new(sel).matches(&msg));
+}
+
+#[test]
+fn parenthesised_predicate_groups() {
+    let sel = compile("/foo[(json$.a=1 or json$.b=1) and json$.c=1]").unwrap();
+    let matcher = Matcher::new(sel);
+    let msg = |payload| Message {
+        topic: "foo",
+        headers: HashMap::new(),
+        payload: Payload::Json(payload),
+    };
+    assert!(matcher.matches(&msg(json!({"b": 1, "c": 1}))));
+    assert!(!matcher.matches(&msg(json!({"a": 1, "b": 1}))));
+}
+
+fn json_msg(payload: serde_json::Value) -> Message<'static> {
+    Message {
+        topic: "foo",
+        headers: HashMap::new(),
+        payload: Payload::Json(payload),
+    }
+}
+
+#[test]
+fn not_equal_predicate() {
+    let matcher = Matcher::new(compile("/foo[json$.state!=\"off\"]").unwrap());
+    assert!(matcher.matches(&json_msg(json!({"state": "on"}))));
+    assert!(!matcher.matches(&json_msg(json!({"state": "off"}))));
+    // Values of a different type are never equal.
+    assert!(matcher.matches(&json_msg(json!({"state": 0}))));
+    // A missing field still fails the predicate.
+    assert!(!matcher.matches(&json_msg(json!({}))));
+}
+
+#[test]
+fn contains_predicate_on_strings_and_arrays() {
+    let matcher = Matcher::new(compile("/foo[json$.tags contains \"hot\"]").unwrap());
+    assert!(matcher.matches(&json_msg(json!({"tags": ["cold", "hot"]}))));
+    assert!(matcher.matches(&json_msg(json!({"tags": "too hot"}))));
+    assert!(!matcher.matches(&json_msg(json!({"tags": ["cold"]}))));
+
+    let matcher = Matcher::new(compile("/foo[json$.codes contains 3]").unwrap());
+    assert!(matcher.matches(&json_msg(json!({"codes": [1, 3]}))));
+    assert!(!matcher.matches(&json_msg(json!({"codes": "3"}))));
+}
+
+#[test]
+fn in_predicate_checks_membership() {
+    let matcher = Matcher::new(compile("/foo[json$.level in [\"warn\",\"error\"]]").unwrap());
+    assert!(matcher.matches(&json_msg(json!({"level": "error"}))));
+    assert!(!matcher.matches(&json_msg(json!({"level": "info"}))));
+
+    let matcher = Matcher::new(compile("/msg[qos in [0,1]]").unwrap());
+    let msg = |qos: &'static str| Message {
+        topic: "",
+        headers: HashMap::from([(Cow::Borrowed("qos"), Cow::Borrowed(qos))]),
+        payload: Payload::None,
+    };
+    assert!(matcher.matches(&msg("1")));
+    assert!(!matcher.matches(&msg("2")));
+}
+
+#[test]
+fn prefix_and_suffix_predicates() {
+    let matcher = Matcher::new(
+        compile("/foo[json$.serial startsWith \"AB\" and json$.serial endsWith \"9\"]").unwrap(),
+    );
+    assert!(matcher.matches(&json_msg(json!({"serial": "AB123459"}))));
+    assert!(!matcher.matches(&json_msg(json!({"serial": "XAB123459"}))));
+    assert!(!matcher.matches(&json_msg(json!({"serial": 12}))));
+}
+
+#[test]
+fn existence_predicate() {
+    let matcher = Matcher::new(compile("/foo[json$.battery]").unwrap());
+    assert!(matcher.matches(&json_msg(json!({"battery": 80}))));
+    assert!(matcher.matches(&json_msg(json!({"battery": null}))));
+    assert!(!matcher.matches(&json_msg(json!({"voltage": 3.3}))));
+
+    let matcher = Matcher::new(compile("/msg[not dup]").unwrap());
+    assert!(matcher.matches(&Message {
+        topic: "",
+        headers: HashMap::new(),
+        payload: Payload::None,
+    }));
+}
+
+#[test]
+fn regex_predicate() {
+    let matcher = Matcher::new(compile("/foo[json$.serial ~= \"^AB[0-9]{6}$\"]").unwrap());
+    assert!(matcher.matches(&json_msg(json!({"serial": "AB123456"}))));
+    assert!(!matcher.matches(&json_msg(json!({"serial": "AB12345"}))));
+    assert!(!matcher.matches(&json_msg(json!({"serial": 123456}))));
+
+    let matcher =
+        Matcher::new(compile("/msg[prop.content-type ~= \"^application/(json|cbor)\"]").unwrap());
+    let msg = |ct: &'static str| Message {
+        topic: "",
+        headers: HashMap::from([(Cow::Borrowed("prop.content-type"), Cow::Borrowed(ct))]),
+        payload: Payload::None,
+    };
+    assert!(matcher.matches(&msg("application/json; charset=utf-8")));
+    assert!(!matcher.matches(&msg("text/plain")));
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use moqtail_core::{compile, Error, ErrorKind, Matcher, Message, Payload};
+
+fn msg(topic: &str) -> Message<'_> {
+    Message {
+        topic,
+        headers: HashMap::new(),
+        payload: Payload::None,
+    }
+}
+
+#[test]
+fn glob_matches_within_one_level() {
+    let matcher = Matcher::new(compile("/building/sensor-*").unwrap());
+    assert!(matcher.matches(&msg("building/sensor-1")));
+    assert!(matcher.matches(&msg("building/sensor-")));
+    assert!(!matcher.matches(&msg("building/actuator-1")));
+    assert!(!matcher.matches(&msg("building/sensor-1/temp")));
+
+    let matcher = Matcher::new(compile("/*-temp-*/value").unwrap());
+    assert!(matcher.matches(&msg("room-temp-3/value")));
+    assert!(!matcher.matches(&msg("room-humidity-3/value")));
+}
+
+#[test]
+fn dot_compares_the_matched_level() {
+    let matcher = Matcher::new(compile("/building/+/floor/+[. > 3]").unwrap());
+    assert!(matcher.matches(&msg("building/E/floor/4")));
+    assert!(!matcher.matches(&msg("building/E/floor/2")));
+    assert!(!matcher.matches(&msg("building/E/floor/roof")));
+
+    let matcher = Matcher::new(compile("//+[. in [\"temp\",\"humidity\"]]").unwrap());
+    assert!(matcher.matches(&msg("a/b/humidity")));
+    assert!(!matcher.matches(&msg("a/b/pressure")));
+}
+
+#[test]
+fn dot_on_hash_sees_remaining_levels() {
+    let matcher = Matcher::new(compile("/logs/#[. startsWith \"app/\"]").unwrap());
+    assert!(matcher.matches(&msg("logs/app/error")));
+    assert!(!matcher.matches(&msg("logs/db/error")));
+}
+
+#[test]
+fn captures_are_returned_in_step_order() {
+    let matcher = Matcher::new(compile("/building/{wing}/{room:room-*}//{kind}").unwrap());
+    assert_eq!(
+        matcher.captures(&msg("building/E/room-12/sensors/temp")),
+        Some(vec![("wing", "E"), ("room", "room-12"), ("kind", "temp")])
+    );
+    assert_eq!(matcher.captures(&msg("building/E/hall/temp")), None);
+}
+
+#[test]
+fn hash_capture_spans_levels() {
+    let matcher = Matcher::new(compile("/logs/{path:#}").unwrap());
+    assert_eq!(
+        matcher.captures(&msg("logs/app/error")),
+        Some(vec![("path", "app/error")])
+    );
+    assert_eq!(matcher.captures(&msg("logs")), Some(vec![("path", "")]));
+}
+
+#[test]
+fn predicates_can_reference_earlier_captures() {
+    let matcher =
+        Matcher::new(compile("/building/{wing}/floor/+[$wing = \"E\" and . > 3]").unwrap());
+    assert!(matcher.matches(&msg("building/E/floor/4")));
+    assert!(!matcher.matches(&msg("building/W/floor/4")));
+}
+
+#[test]
+fn capture_references_backtrack_through_descendants() {
+    // `{a}` first binds `p`, which fails at the last step; binding `q`
+    // reaches that same step and level again and must be re-evaluated.
+    let matcher = Matcher::new(compile("//{a}//x/+[$a = \"q\"]").unwrap());
+    assert!(matcher.matches(&msg("p/q/x/z")));
+    assert!(!matcher.matches(&msg("p/r/x/z")));
+}
+
+#[test]
+fn stages_can_aggregate_captures() {
+    let mut matcher = Matcher::new(compile("/meter/{reading} |> sum($reading)").unwrap());
+    let now = Instant::now();
+    assert_eq!(matcher.process(&msg("meter/42"), now), Some(42.0));
+    assert_eq!(matcher.process(&msg("meter/abc"), now), None);
+}
+
+#[test]
+fn capture_errors() {
+    assert!(matches!(
+        compile("/{a}/{a}").unwrap_err(),
+        Error { kind: ErrorKind::DuplicateCapture(name), .. } if name == "a"
+    ));
+    assert!(matches!(
+        compile("/foo[$a = 1]/{a}").unwrap_err(),
+        Error { kind: ErrorKind::UnknownCapture(name), .. } if name == "a"
+    ));
+    assert!(matches!(
+        compile("/{a} |> sum($b)").unwrap_err(),
+        Error { kind: ErrorKind::UnknownCapture(name), .. } if name == "b"
+    ));
+    assert!(matches!(
+        compile("/foo |> sum(.)").unwrap_err(),
+        Error {
+            kind: ErrorKind::SegmentFieldOutsidePredicate,
+            ..
+        }
+    ));
+    assert!(matches!(
+        compile("/{a:msg}").unwrap_err(),
+        Error {
+            kind: ErrorKind::InvalidSegment,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn quoted_levels_match_any_topic_characters() {
+    let matcher = Matcher::new(compile("/\"room 1.2\"/\"temp:°C\"").unwrap());
+    assert!(matcher.matches(&msg("room 1.2/temp:°C")));
+
+    let matcher = Matcher::new(compile("/a/\"\"/b").unwrap());
+    assert!(matcher.matches(&msg("a//b")));
+    assert!(!matcher.matches(&msg("a/b")));
+
+    // Quoted `msg` and `*` are plain literals.
+    let matcher = Matcher::new(compile("/\"msg\"/\"*\"").unwrap());
+    assert!(matcher.matches(&msg("msg/*")));
+    assert!(!matcher.matches(&msg("msg/x")));
+}
+
+#[test]
+fn dollar_levels_need_a_literal() {
+    let msg_sys = msg("$SYS/broker/uptime");
+    assert!(Matcher::new(compile("/$SYS/broker/uptime").unwrap()).matches(&msg_sys));
+    assert!(Matcher::new(compile("/\"$SYS\"/#").unwrap()).matches(&msg_sys));
+    assert!(Matcher::new(compile("/{root:$SYS}/+/+").unwrap()).matches(&msg_sys));
+    for selector in ["/+/broker/uptime", "/#", "//uptime", "/*/broker/uptime"] {
+        assert!(
+            !Matcher::new(compile(selector).unwrap()).matches(&msg_sys),
+            "{selector} must not match a $-prefixed topic"
+        );
+    }
+    // Only the first level is reserved.
+    assert!(Matcher::new(compile("/a/+").unwrap()).matches(&msg("a/$b")));
+}
+
+#[test]
+fn quoted_level_errors() {
+    assert!(matches!(
+        compile("/\"a/b\"").unwrap_err(),
+        Error {
+            kind: ErrorKind::InvalidSegment,
+            ..
+        }
+    ));
+    assert!(matches!(
+        compile("/\"a\\u0000\"").unwrap_err(),
+        Error {
+            kind: ErrorKind::InvalidSegment,
+            ..
+        }
+    ));
+}
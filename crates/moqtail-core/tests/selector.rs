@@ -1,6 +1,6 @@
 use moqtail_core::{
-    ast::{Axis, Field, Operator, Predicate, Segment, Selector, Stage, Step, Value},
-    compile, Error,
+    ast::{Axis, Comparison, Field, Operator, Predicate, Segment, Selector, Stage, Step, Value},
+    compile, Error, ErrorKind,
 };
 
 #[test]
@@ -12,11 +12,12 @@ fn parse_selector_with_predicate() {
             steps: vec![Step {
                 axis: Axis::Child,
                 segment: Segment::Literal("foo".into()),
-                predicates: vec![Predicate {
+                capture: None,
+                predicates: vec![Predicate::Compare(Comparison {
                     field: Field::Header("bar".into()),
                     op: Operator::Eq,
                     value: Value::Number(1.0)
-                }],
+                })],
             }],
             stages: vec![],
         }
@@ -33,16 +34,19 @@ fn parse_selector_with_wildcards() {
                 Step {
                     axis: Axis::Child,
                     segment: Segment::Literal("foo".into()),
+                    capture: None,
                     predicates: vec![],
                 },
                 Step {
                     axis: Axis::Child,
                     segment: Segment::Plus,
+                    capture: None,
                     predicates: vec![],
                 },
                 Step {
                     axis: Axis::Child,
                     segment: Segment::Hash,
+                    capture: None,
                     predicates: vec![],
                 },
             ],
@@ -61,11 +65,13 @@ fn parse_selector_descendant() {
                 Step {
                     axis: Axis::Descendant,
                     segment: Segment::Literal("sensor".into()),
+                    capture: None,
                     predicates: vec![],
                 },
                 Step {
                     axis: Axis::Child,
                     segment: Segment::Hash,
+                    capture: None,
                     predicates: vec![],
                 },
             ],
@@ -94,15 +100,17 @@ fn parse_header_axis() {
                 Step {
                     axis: Axis::Child,
                     segment: Segment::Message,
-                    predicates: vec![Predicate {
+                    capture: None,
+                    predicates: vec![Predicate::Compare(Comparison {
                         field: Field::Header("qos".into()),
                         op: Operator::Le,
                         value: Value::Number(1.0)
-                    }],
+                    })],
                 },
                 Step {
                     axis: Axis::Child,
                     segment: Segment::Literal("foo".into()),
+                    capture: None,
                     predicates: vec![],
                 }
             ],
@@ -120,11 +128,12 @@ fn parse_json_predicate() {
             steps: vec![Step {
                 axis: Axis::Child,
                 segment: Segment::Literal("foo".into()),
-                predicates: vec![Predicate {
+                capture: None,
+                predicates: vec![Predicate::Compare(Comparison {
                     field: Field::Json(vec!["temp".into()]),
                     op: Operator::Gt,
                     value: Value::Number(30.0)
-                }],
+                })],
             }],
             stages: vec![],
         }
@@ -140,11 +149,12 @@ fn parse_negative_fractional_number() {
             steps: vec![Step {
                 axis: Axis::Child,
                 segment: Segment::Literal("foo".into()),
-                predicates: vec![Predicate {
+                capture: None,
+                predicates: vec![Predicate::Compare(Comparison {
                     field: Field::Header("bar".into()),
                     op: Operator::Eq,
                     value: Value::Number(-1.5)
-                }],
+                })],
             }],
             stages: vec![],
         }
@@ -154,7 +164,13 @@ fn parse_negative_fractional_number() {
 #[test]
 fn error_on_malformed_json_prefix() {
     let err = compile("/foo[json.temp>30]").unwrap_err();
-    assert!(matches!(err, Error::MissingField));
+    assert!(matches!(
+        err,
+        Error {
+            kind: ErrorKind::MissingField,
+            ..
+        }
+    ));
 }
 
 #[test]
@@ -163,6 +179,7 @@ fn selector_display_keeps_count_parentheses() {
         steps: vec![Step {
             axis: Axis::Child,
             segment: Segment::Literal("sensor".into()),
+            capture: None,
             predicates: vec![],
         }],
         stages: vec![Stage::Count],
@@ -170,3 +187,122 @@ fn selector_display_keeps_count_parentheses() {
 
     assert_eq!(sel.to_string(), "/sensor |> count()");
 }
+
+#[test]
+fn parse_boolean_predicate_precedence() {
+    let sel = compile("/foo[not a=1 or b=2 and c=3]").unwrap();
+    let cmp = |name: &str, n: f64| {
+        Box::new(Predicate::Compare(Comparison {
+            field: Field::Header(name.into()),
+            op: Operator::Eq,
+            value: Value::Number(n),
+        }))
+    };
+    assert_eq!(
+        sel.steps[0].predicates,
+        vec![Predicate::Or(
+            Box::new(Predicate::Not(cmp("a", 1.0))),
+            Box::new(Predicate::And(cmp("b", 2.0), cmp("c", 3.0))),
+        )]
+    );
+}
+
+#[test]
+fn keywords_are_allowed_as_header_names() {
+    let sel = compile("/foo[not=1][order=2 or android=3]").unwrap();
+    assert_eq!(sel.to_string(), "/foo[not=1][order=2 or android=3]");
+}
+
+#[test]
+fn error_on_dangling_boolean_operator() {
+    assert!(compile("/foo[a=1 or]").is_err());
+    assert!(compile("/foo[(a=1]").is_err());
+    assert!(compile("/foo[not (]").is_err());
+}
+
+#[test]
+fn parse_in_operator_with_list() {
+    let sel = compile("/foo[json$.state in [\"on\", 1, true]]").unwrap();
+    assert_eq!(
+        sel.steps[0].predicates,
+        vec![Predicate::Compare(Comparison {
+            field: Field::Json(vec!["state".into()]),
+            op: Operator::In,
+            value: Value::List(vec![
+                Value::Str("on".into()),
+                Value::Number(1.0),
+                Value::Bool(true),
+            ]),
+        })]
+    );
+}
+
+#[test]
+fn parse_existence_predicate() {
+    let sel = compile("/foo[json$.battery][not retained]").unwrap();
+    assert_eq!(
+        sel.steps[0].predicates,
+        vec![
+            Predicate::Exists(Field::Json(vec!["battery".into()])),
+            Predicate::Not(Box::new(Predicate::Exists(Field::Header(
+                "retained".into()
+            )))),
+        ]
+    );
+}
+
+#[test]
+fn error_on_misplaced_list() {
+    assert!(matches!(
+        compile("/foo[bar in \"a\"]").unwrap_err(),
+        Error {
+            kind: ErrorKind::InRequiresList,
+            ..
+        }
+    ));
+    assert!(matches!(
+        compile("/foo[bar=[1]]").unwrap_err(),
+        Error {
+            kind: ErrorKind::InvalidValue,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn regex_is_compiled_with_the_selector() {
+    let sel = compile("/foo[json$.serial ~= \"^AB[0-9]{6}$\"]").unwrap();
+    let Predicate::Compare(cmp) = &sel.steps[0].predicates[0] else {
+        panic!("expected comparison");
+    };
+    assert_eq!(cmp.op, Operator::Matches);
+    match &cmp.value {
+        Value::Regex(re) => assert_eq!(re.as_str(), "^AB[0-9]{6}$"),
+        other => panic!("expected regex, got {other:?}"),
+    }
+}
+
+#[test]
+fn error_on_invalid_regex() {
+    let input = "/foo[json$.serial ~= \"^AB[0-9\"]";
+    let err = compile(input).unwrap_err();
+    match &err.kind {
+        ErrorKind::InvalidRegex { pattern, .. } => assert_eq!(pattern, "^AB[0-9"),
+        other => panic!("unexpected error {other:?}"),
+    }
+    assert_eq!(&input[err.span.start..], "[0-9\"]");
+
+    // Offsets account for escapes in the string literal.
+    let input = r#"/foo[bar ~= "\\d\"(ab"]"#;
+    let err = compile(input).unwrap_err();
+    assert!(matches!(err.kind, ErrorKind::InvalidRegex { .. }));
+    assert_eq!(&input[err.span.start..], "(ab\"]");
+
+    assert!(matches!(
+        compile("/foo[bar ~= 1]").unwrap_err(),
+        Error {
+            kind: ErrorKind::InvalidValue,
+            ..
+        }
+    ));
+}
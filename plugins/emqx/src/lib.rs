@@ -1,6 +1,12 @@
 //! Minimal EMQX plugin using the extension API.
-
-use moqtail_core::{compile, Matcher, Message};
+//!
+//! The publish hook sees only the topic of a message and decides for every
+//! subscriber at once, so the plugin supports stateless topic selectors only:
+//! selectors with pipeline stages, or with predicates on the payload or the
+//! headers, are rejected when the plugin is loaded.
+
+use moqtail_core::ast::{Field, Predicate, Selector};
+use moqtail_core::{compile, Message, Payload, SelectorIndex};
 use std::{
     collections::HashMap,
     ffi::CStr,
@@ -32,7 +38,33 @@ extern "C" {
 const MESSAGE_HOOK: &[u8] = b"message_publish\0";
 
 pub struct PluginContext {
-    matchers: Vec<Matcher>,
+    /// Only read once loaded, so EMQX may run the hook on several threads.
+    selectors: SelectorIndex,
+}
+
+/// Whether `selector` only reads the topic and keeps no state between
+/// messages.
+fn is_topic_selector(selector: &Selector) -> bool {
+    fn reads_topic(predicate: &Predicate) -> bool {
+        match predicate {
+            Predicate::Compare(c) => is_topic_field(&c.field),
+            Predicate::Exists(field) => is_topic_field(field),
+            Predicate::And(l, r) | Predicate::Or(l, r) => reads_topic(l) && reads_topic(r),
+            Predicate::Not(p) => reads_topic(p),
+        }
+    }
+    fn is_topic_field(field: &Field) -> bool {
+        matches!(
+            field,
+            Field::Segment | Field::Capture(_) | Field::TopicLevel(_)
+        )
+    }
+    selector.stages.is_empty()
+        && selector
+            .steps
+            .iter()
+            .flat_map(|step| &step.predicates)
+            .all(reads_topic)
 }
 
 extern "C" fn on_message(msg: *mut EmqxMessage, userdata: *mut c_void) -> c_int {
@@ -41,7 +73,7 @@ extern "C" fn on_message(msg: *mut EmqxMessage, userdata: *mut c_void) -> c_int
     }
 
     unsafe {
-        let ctx = &*(userdata as *mut PluginContext);
+        let ctx = &*(userdata as *const PluginContext);
         if msg.is_null() || (*msg).topic.is_null() {
             return 0;
         }
@@ -52,18 +84,17 @@ extern "C" fn on_message(msg: *mut EmqxMessage, userdata: *mut c_void) -> c_int
         let m = Message {
             topic,
             headers: HashMap::new(),
-            payload: None,
+            payload: Payload::None,
         };
-        for matcher in &ctx.matchers {
-            if matcher.matches(&m) {
-                return 0;
-            }
+        if !ctx.selectors.matches(&m).is_empty() {
+            return 0;
         }
     }
     1
 }
 
-/// Called by EMQX when the plugin is loaded.
+/// Called by EMQX when the plugin is loaded. Selectors that do not compile
+/// or are not stateless topic selectors are reported and skipped.
 #[no_mangle]
 pub unsafe extern "C" fn moqtail_init(
     selectors: *const *const c_char,
@@ -77,7 +108,7 @@ pub unsafe extern "C" fn moqtail_init(
         }
         std::slice::from_raw_parts(selectors, count)
     };
-    let mut matchers = Vec::new();
+    let mut selectors = SelectorIndex::new();
     for &ptr in slice {
         if ptr.is_null() {
             continue;
@@ -87,12 +118,19 @@ pub unsafe extern "C" fn moqtail_init(
             Err(_) => continue,
         };
         match compile(sel) {
-            Ok(s) => matchers.push(Matcher::new(s)),
+            Ok(s) if is_topic_selector(&s) => {
+                selectors.insert_selector(s);
+            }
+            Ok(s) => eprintln!(
+                "[MoQTail] selector {} needs the payload, headers or per-subscriber \
+                 state, which EMQX does not provide",
+                s
+            ),
             Err(e) => eprintln!("[MoQTail] selector error: {}", e),
         }
     }
 
-    let ctx = Box::new(PluginContext { matchers });
+    let ctx = Box::new(PluginContext { selectors });
     let ctx_ptr = Box::into_raw(ctx) as *mut c_void;
     emqx_extension_register_hook(
         MESSAGE_HOOK.as_ptr() as *const c_char,
@@ -146,6 +184,23 @@ mod tests {
         0
     }
 
+    #[test]
+    fn only_stateless_topic_selectors_are_supported() {
+        for (selector, supported) in [
+            ("/foo/+", true),
+            ("//sensor-*/{room}[. startsWith \"r\"]", true),
+            ("/foo[topic[1]=\"foo\" and not(.=\"bar\")]", true),
+            ("/foo[json$.temp>30]", false),
+            ("/foo/msg[qos<=1]", false),
+            ("/foo[. = \"foo\" or json$.on]", false),
+            ("/foo |> distinct(json$.state)", false),
+            ("/foo |> throttle(1s)", false),
+        ] {
+            let selector = compile(selector).unwrap();
+            assert_eq!(is_topic_selector(&selector), supported, "{selector}");
+        }
+    }
+
     #[test]
     fn on_message_rejects_null_userdata() {
         let topic = CString::new("foo/bar").unwrap();
@@ -1,4 +1,13 @@
-use std::{env, fs, path::PathBuf};
+use std::{
+    env, fs,
+    mem::{offset_of, size_of},
+    path::{Path, PathBuf},
+};
+
+#[allow(dead_code)]
+mod bindings {
+    include!("src/bindings.rs");
+}
 
 fn main() {
     println!("cargo:rerun-if-changed=src/dummy.c");
@@ -9,4 +18,183 @@ fn main() {
     println!("cargo:rerun-if-changed=src/bindings.rs");
     let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
     fs::copy("src/bindings.rs", out_path.join("bindings.rs")).expect("could not copy bindings");
+    check_layouts(&out_path);
+}
+
+/// Appends assertions that the event numbers match the broker header.
+macro_rules! constants {
+    ($c:ident, $($name:ident),*) => {
+        $(
+            $c += &format!(
+                "_Static_assert({0} == {1}, \"value of {0}\");\n",
+                stringify!($name),
+                bindings::$name,
+            );
+        )*
+    };
+}
+
+/// Appends assertions that `$ty` has the size and field offsets of the
+/// struct of the same name in the broker header.
+macro_rules! layout {
+    ($c:ident, $ty:ident { $($field:ident),* }) => {
+        $c += &format!(
+            "_Static_assert(sizeof(struct {0}) == {1}, \"size of {0}\");\n",
+            stringify!($ty),
+            size_of::<bindings::$ty>(),
+        );
+        $(
+            $c += &format!(
+                "_Static_assert(offsetof(struct {0}, {1}) == {2}, \"offset of {0}.{1}\");\n",
+                stringify!($ty),
+                stringify!($field),
+                offset_of!(bindings::$ty, $field),
+            );
+        )*
+    };
+}
+
+/// Checks the hand-written bindings and event numbers against the Mosquitto
+/// 2.1 header. The plugin frees and overwrites `topic_filter` through them, so a layout that
+/// differs from the broker's must fail the build rather than corrupt memory.
+///
+/// The header is looked up in `MOSQUITTO_INCLUDE_DIR`, which makes the check
+/// mandatory, or else on the compiler's default include path.
+fn check_layouts(out_path: &Path) {
+    println!("cargo:rerun-if-env-changed=MOSQUITTO_INCLUDE_DIR");
+    let include = env::var_os("MOSQUITTO_INCLUDE_DIR").map(PathBuf::from);
+    if let Some(dir) = &include {
+        println!(
+            "cargo:rerun-if-changed={}",
+            dir.join("mosquitto/broker.h").display()
+        );
+    }
+    // The sizes below are those of the build host.
+    if env::var("HOST").unwrap() != env::var("TARGET").unwrap() {
+        println!("cargo:warning=cross-compiling: Mosquitto event layouts are not checked");
+        return;
+    }
+
+    // Compiler output is only forwarded for the check itself, which names
+    // the failed assertion.
+    let build = |file: &Path, warnings: bool| {
+        let mut build = cc::Build::new();
+        build
+            .file(file)
+            .cargo_metadata(false)
+            .cargo_warnings(warnings);
+        if let Some(dir) = &include {
+            build.include(dir);
+        }
+        build.try_compile_intermediates()
+    };
+
+    let header = "#include <stddef.h>\n#include <mosquitto/broker.h>\n";
+    let probe = out_path.join("layout_probe.c");
+    fs::write(&probe, header).expect("could not write layout probe");
+    if include.is_none() && build(&probe, false).is_err() {
+        println!(
+            "cargo:warning=mosquitto/broker.h not found: Mosquitto event layouts are not checked \
+             (set MOSQUITTO_INCLUDE_DIR to check them)"
+        );
+        return;
+    }
+
+    let mut c = header.to_string();
+    constants!(
+        c,
+        MOSQ_EVT_ACL_CHECK,
+        MOSQ_EVT_DISCONNECT,
+        MOSQ_EVT_SUBSCRIBE,
+        MOSQ_EVT_UNSUBSCRIBE,
+        MOSQ_EVT_PERSIST_CLIENT_ADD,
+        MOSQ_EVT_PERSIST_CLIENT_DELETE,
+        MOSQ_EVT_PERSIST_CLIENT_UPDATE
+    );
+    layout!(c, mosquitto_opt { key, value });
+    layout!(
+        c,
+        mosquitto_evt_acl_check {
+            future,
+            client,
+            topic,
+            payload,
+            properties,
+            access,
+            payloadlen,
+            qos,
+            retain,
+            future2
+        }
+    );
+    layout!(
+        c,
+        mosquitto_subscription {
+            clientid,
+            topic_filter,
+            properties,
+            identifier,
+            options,
+            padding,
+            future2
+        }
+    );
+    layout!(
+        c,
+        mosquitto_evt_subscribe {
+            future,
+            client,
+            data,
+            future2
+        }
+    );
+    layout!(
+        c,
+        mosquitto_evt_unsubscribe {
+            future,
+            client,
+            data,
+            future2
+        }
+    );
+    layout!(
+        c,
+        mosquitto_evt_disconnect {
+            future,
+            client,
+            reason,
+            future2
+        }
+    );
+    layout!(
+        c,
+        mosquitto_client {
+            clientid,
+            username,
+            auth_method,
+            will,
+            will_delay_time,
+            session_expiry_time,
+            will_delay_interval,
+            session_expiry_interval,
+            max_packet_size,
+            listener_port,
+            max_qos,
+            retain_available,
+            future2
+        }
+    );
+    layout!(
+        c,
+        mosquitto_evt_persist_client {
+            future,
+            data,
+            future2
+        }
+    );
+    let check = out_path.join("layout.c");
+    fs::write(&check, c).expect("could not write layout check");
+    if let Err(e) = build(&check, true) {
+        panic!("bindings do not match the Mosquitto 2.1 header: {e}");
+    }
 }
@@ -1,3 +1,11 @@
+pub const MOSQ_EVT_ACL_CHECK: ::std::os::raw::c_int = 2;
+pub const MOSQ_EVT_DISCONNECT: ::std::os::raw::c_int = 10;
+pub const MOSQ_EVT_SUBSCRIBE: ::std::os::raw::c_int = 12;
+pub const MOSQ_EVT_UNSUBSCRIBE: ::std::os::raw::c_int = 13;
+pub const MOSQ_EVT_PERSIST_CLIENT_ADD: ::std::os::raw::c_int = 19;
+pub const MOSQ_EVT_PERSIST_CLIENT_DELETE: ::std::os::raw::c_int = 20;
+pub const MOSQ_EVT_PERSIST_CLIENT_UPDATE: ::std::os::raw::c_int = 21;
+
 #[allow(non_camel_case_types)]
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -9,20 +17,90 @@ pub struct mosquitto_opt {
 #[allow(non_camel_case_types)]
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
-pub struct mosquitto_evt_message {
+pub struct mosquitto_evt_acl_check {
     pub future: *mut ::std::os::raw::c_void,
     pub client: *mut ::std::os::raw::c_void,
-    pub topic: *mut ::std::os::raw::c_char,
-    pub payload: *mut ::std::os::raw::c_void,
+    pub topic: *const ::std::os::raw::c_char,
+    pub payload: *const ::std::os::raw::c_void,
     pub properties: *mut ::std::os::raw::c_void,
-    pub reason_string: *mut ::std::os::raw::c_char,
+    pub access: ::std::os::raw::c_int,
     pub payloadlen: u32,
-    pub qos: ::std::os::raw::c_int,
-    pub reason_code: ::std::os::raw::c_int,
+    pub qos: u8,
     pub retain: bool,
     pub future2: [*mut ::std::os::raw::c_void; 4],
 }
 
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct mosquitto_subscription {
+    pub clientid: *mut ::std::os::raw::c_char,
+    pub topic_filter: *mut ::std::os::raw::c_char,
+    pub properties: *mut ::std::os::raw::c_void,
+    pub identifier: u32,
+    pub options: u8,
+    pub padding: [u8; 3],
+    pub future2: [*mut ::std::os::raw::c_void; 8],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct mosquitto_evt_subscribe {
+    pub future: *mut ::std::os::raw::c_void,
+    pub client: *mut ::std::os::raw::c_void,
+    pub data: mosquitto_subscription,
+    pub future2: [*mut ::std::os::raw::c_void; 8],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct mosquitto_evt_unsubscribe {
+    pub future: *mut ::std::os::raw::c_void,
+    pub client: *mut ::std::os::raw::c_void,
+    pub data: mosquitto_subscription,
+    pub future2: [*mut ::std::os::raw::c_void; 8],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct mosquitto_evt_disconnect {
+    pub future: *mut ::std::os::raw::c_void,
+    pub client: *mut ::std::os::raw::c_void,
+    pub reason: ::std::os::raw::c_int,
+    pub future2: [*mut ::std::os::raw::c_void; 4],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct mosquitto_client {
+    pub clientid: *mut ::std::os::raw::c_char,
+    pub username: *mut ::std::os::raw::c_char,
+    pub auth_method: *mut ::std::os::raw::c_char,
+    pub will: *mut ::std::os::raw::c_void,
+    pub will_delay_time: ::std::os::raw::c_long,
+    pub session_expiry_time: ::std::os::raw::c_long,
+    pub will_delay_interval: u32,
+    pub session_expiry_interval: u32,
+    pub max_packet_size: u32,
+    pub listener_port: u16,
+    pub max_qos: u8,
+    pub retain_available: bool,
+    pub future2: [*mut ::std::os::raw::c_void; 8],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct mosquitto_evt_persist_client {
+    pub future: *mut ::std::os::raw::c_void,
+    pub data: mosquitto_client,
+    pub future2: [*mut ::std::os::raw::c_void; 8],
+}
+
 #[allow(non_camel_case_types)]
 pub enum mosquitto_plugin_id_t {}
 
@@ -53,4 +131,20 @@ extern "C" {
         >,
         event_data: *const ::std::os::raw::c_void,
     ) -> ::std::os::raw::c_int;
+
+    pub fn mosquitto_client_id(
+        client: *const ::std::os::raw::c_void,
+    ) -> *const ::std::os::raw::c_char;
+
+    pub fn mosquitto_strdup(s: *const ::std::os::raw::c_char) -> *mut ::std::os::raw::c_char;
+
+    pub fn mosquitto_free(mem: *mut ::std::os::raw::c_void);
+
+    pub fn mosquitto_property_read_string_pair(
+        proplist: *const ::std::os::raw::c_void,
+        identifier: ::std::os::raw::c_int,
+        name: *mut *mut ::std::os::raw::c_char,
+        value: *mut *mut ::std::os::raw::c_char,
+        skip_first: bool,
+    ) -> *const ::std::os::raw::c_void;
 }
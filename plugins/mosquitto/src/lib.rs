@@ -1,86 +1,486 @@
 //! Mosquitto plugin entry points.
 //!
-//! The plugin parses `plugin_opt_selector` entries from the broker
-//! configuration, compiles them using `moqtail-core`, and registers a publish
-//! callback.  Incoming messages are filtered before they reach the broker
-//! clients.
-
-use moqtail_core::{compile, Matcher, Message};
-use serde_json::Value as JsonValue;
+//! Clients opt into MoQTail filtering per subscription, either by attaching
+//! the selector to an MQTT v5 SUBSCRIBE as a `moqtail-selector` user property
+//! or by subscribing to `$moqtail/<selector>`. The broker validates topic
+//! filters before plugins see them, so only the first form can carry
+//! predicates on wildcard levels. The plugin compiles the selector with
+//! `moqtail-core`, routes it through the covering legacy `+`/`#` filter and
+//! remembers the selector for that client. Deliveries are then checked through
+//! the broker's ACL hook: a message reaching a client only through MoQTail
+//! subscriptions is withheld unless one of their selectors accepts it: it
+//! matches and gets through stages such as `distinct` and `throttle`, which
+//! keep their state per subscription. Every other check is allowed, so plain
+//! subscriptions keep normal MQTT semantics.
+
+use moqtail_core::{compile, Matcher, Message, Payload, SelectorId, SelectorIndex};
 use std::{
     borrow::Cow,
     collections::HashMap,
-    ffi::CStr,
-    os::raw::{c_int, c_void},
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int, c_void},
     slice,
+    sync::Mutex,
+    time::Instant,
 };
 
 // Bindings generated in build.rs
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
-const MOSQ_EVT_MESSAGE: c_int = 7;
 const MOSQ_ERR_SUCCESS: c_int = 0;
+const MOSQ_ERR_NOMEM: c_int = 1;
+const MOSQ_ERR_ACL_DENIED: c_int = 12;
 const MOSQ_ERR_PLUGIN_DEFER: c_int = 17;
+const MOSQ_ACL_READ: c_int = 0x01;
+const MQTT_PROP_USER_PROPERTY: c_int = 38;
+
+/// Topic filter prefix marking a MoQTail-annotated subscription.
+pub const SELECTOR_PREFIX: &str = "$moqtail/";
+
+/// Name of the SUBSCRIBE user property carrying a selector.
+pub const SELECTOR_PROPERTY: &str = "moqtail-selector";
+
+type Callback = extern "C" fn(c_int, *mut c_void, *mut c_void) -> c_int;
+
+const CALLBACKS: [(c_int, Callback); 7] = [
+    (MOSQ_EVT_SUBSCRIBE, on_subscribe),
+    (MOSQ_EVT_UNSUBSCRIBE, on_unsubscribe),
+    (MOSQ_EVT_ACL_CHECK, on_acl_check),
+    (MOSQ_EVT_DISCONNECT, on_disconnect),
+    (MOSQ_EVT_PERSIST_CLIENT_ADD, on_persist_client),
+    (MOSQ_EVT_PERSIST_CLIENT_UPDATE, on_persist_client),
+    (MOSQ_EVT_PERSIST_CLIENT_DELETE, on_persist_client),
+];
+
+/// Subscriptions of a single client.
+#[derive(Default)]
+struct ClientSubscriptions {
+    /// Selectors keyed by the filter the broker sees. An empty list marks a
+    /// plain MQTT subscription. Several MoQTail selectors that map to the
+    /// same legacy filter share one broker subscription and are combined
+    /// disjunctively.
+    filters: HashMap<String, Vec<SelectorId>>,
+    selectors: SelectorIndex,
+    /// Whether the broker keeps the session, and with it the subscriptions,
+    /// after the client disconnects.
+    persistent: bool,
+}
 
-// Use generated types `mosquitto_evt_message` and `mosquitto_opt`
+impl ClientSubscriptions {
+    /// Files `matchers` under the subscription on `filter`. With `replace`,
+    /// selectors filed there before and not repeated are dropped, as the
+    /// subscription replaces the previous one; otherwise they are kept.
+    ///
+    /// A selector that is already filed under `filter` with the same
+    /// canonical text keeps its matcher rather than gaining a copy. Clients
+    /// with persistent sessions resubscribe on every reconnect, and the broker
+    /// keeps the flow of publications going, so stages such as `distinct`
+    /// keep their state too.
+    fn subscribe(&mut self, filter: String, matchers: Vec<Matcher>, replace: bool) {
+        let mut previous: Vec<(String, SelectorId)> = self
+            .filters
+            .remove(&filter)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| Some((self.selectors.get(id)?.selector().to_string(), id)))
+            .collect();
+        let mut ids = Vec::with_capacity(matchers.len());
+        for matcher in matchers {
+            let text = matcher.selector().to_string();
+            match previous.iter().position(|(t, _)| *t == text) {
+                Some(i) => ids.push(previous.swap_remove(i).1),
+                None => ids.push(self.selectors.insert(matcher)),
+            }
+        }
+        for (_, id) in previous {
+            if replace {
+                self.selectors.remove(id);
+            } else {
+                ids.push(id);
+            }
+        }
+        self.filters.insert(filter, ids);
+    }
 
+    /// Drops the subscription on `filter` along with its selectors.
+    fn remove(&mut self, filter: &str) {
+        for id in self.filters.remove(filter).unwrap_or_default() {
+            self.selectors.remove(id);
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct PluginContext {
-    matchers: Vec<Matcher>,
+    clients: Mutex<HashMap<String, ClientSubscriptions>>,
+}
+
+/// Matches a topic name against a standard MQTT topic filter.
+///
+/// Wildcards in the first level never match topics starting with `$`, as
+/// required by the MQTT specification.
+fn topic_matches_filter(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match level {
+            "#" => return true,
+            "+" => {
+                if topic_levels.next().is_none() {
+                    return false;
+                }
+            }
+            lit => {
+                if topic_levels.next() != Some(lit) {
+                    return false;
+                }
+            }
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+unsafe fn client_id(client: *mut c_void) -> Option<String> {
+    if client.is_null() {
+        return None;
+    }
+    let id = mosquitto_client_id(client);
+    if id.is_null() {
+        return None;
+    }
+    Some(CStr::from_ptr(id).to_string_lossy().into_owned())
+}
+
+/// Replaces a broker-owned topic filter string with `filter`.
+unsafe fn rewrite_filter(slot: &mut *mut c_char, filter: &str) -> Result<(), c_int> {
+    let filter = CString::new(filter).map_err(|_| MOSQ_ERR_ACL_DENIED)?;
+    let dup = mosquitto_strdup(filter.as_ptr());
+    if dup.is_null() {
+        return Err(MOSQ_ERR_NOMEM);
+    }
+    mosquitto_free(*slot as *mut c_void);
+    *slot = dup;
+    Ok(())
+}
+
+/// Compiles the selector of a `$moqtail/` subscription and computes the
+/// legacy filter it is routed through.
+fn plan_selector(query: &str) -> Option<(String, Matcher)> {
+    let selector = match compile(query) {
+        Ok(sel) => sel,
+        Err(e) => {
+            eprintln!("[MoQTail] selector error: {}", e);
+            return None;
+        }
+    };
+    match selector.to_mqtt_filters().as_slice() {
+        [filter] => Some((filter.clone(), Matcher::new(selector))),
+        _ => {
+            eprintln!(
+                "[MoQTail] selector {} cannot be routed through a single topic filter",
+                selector
+            );
+            None
+        }
+    }
 }
 
-extern "C" fn on_message(_: c_int, event_data: *mut c_void, userdata: *mut c_void) -> c_int {
+/// Reads the selectors attached to a SUBSCRIBE as `moqtail-selector` user
+/// properties.
+unsafe fn property_selectors(properties: *const c_void) -> Vec<String> {
+    let mut selectors = Vec::new();
+    let mut prop = properties;
+    let mut skip_first = false;
+    while !prop.is_null() {
+        let mut name: *mut c_char = std::ptr::null_mut();
+        let mut value: *mut c_char = std::ptr::null_mut();
+        prop = mosquitto_property_read_string_pair(
+            prop,
+            MQTT_PROP_USER_PROPERTY,
+            &mut name,
+            &mut value,
+            skip_first,
+        );
+        if !prop.is_null() && CStr::from_ptr(name).to_bytes() == SELECTOR_PROPERTY.as_bytes() {
+            selectors.push(CStr::from_ptr(value).to_string_lossy().into_owned());
+        }
+        mosquitto_free(name as *mut c_void);
+        mosquitto_free(value as *mut c_void);
+        skip_first = true;
+    }
+    selectors
+}
+
+/// Compiles the selectors a subscription on `filter` carries as user
+/// properties. Each must be routed through `filter` itself, since the broker
+/// keeps the filter as sent.
+fn plan_property_selectors(filter: &str, queries: Vec<String>) -> Option<Vec<Matcher>> {
+    queries
+        .into_iter()
+        .map(|query| {
+            let (legacy, matcher) = plan_selector(&query)?;
+            if legacy != filter {
+                eprintln!(
+                    "[MoQTail] selector {} is routed through {}, not {}",
+                    query, legacy, filter
+                );
+                return None;
+            }
+            Some(matcher)
+        })
+        .collect()
+}
+
+extern "C" fn on_subscribe(_: c_int, event_data: *mut c_void, userdata: *mut c_void) -> c_int {
     if userdata.is_null() || event_data.is_null() {
         return MOSQ_ERR_PLUGIN_DEFER;
     }
 
     unsafe {
         let ctx = &*(userdata as *mut PluginContext);
-        let msg = &*(event_data as *mut mosquitto_evt_message);
-        if msg.topic.is_null() {
-            return MOSQ_ERR_SUCCESS;
+        let evt = &mut *(event_data as *mut mosquitto_evt_subscribe);
+        let Some(id) = client_id(evt.client) else {
+            return MOSQ_ERR_PLUGIN_DEFER;
+        };
+        if evt.data.topic_filter.is_null() {
+            return MOSQ_ERR_PLUGIN_DEFER;
         }
-        let topic = match CStr::from_ptr(msg.topic).to_str() {
-            Ok(t) => t,
+        let filter = match CStr::from_ptr(evt.data.topic_filter).to_str() {
+            Ok(f) => f.to_string(),
             Err(_) => return MOSQ_ERR_PLUGIN_DEFER,
         };
 
-        let mut headers = HashMap::new();
-        headers.insert(Cow::Borrowed("qos"), Cow::Owned(msg.qos.to_string()));
-        headers.insert(
-            Cow::Borrowed("retained"),
-            Cow::Borrowed(if msg.retain { "true" } else { "false" }),
-        );
+        match filter.strip_prefix(SELECTOR_PREFIX) {
+            None => {
+                let queries = property_selectors(evt.data.properties);
+                let Some(matchers) = plan_property_selectors(&filter, queries) else {
+                    return MOSQ_ERR_ACL_DENIED;
+                };
+                // The subscription replaces any selectors on the same filter,
+                // just as the broker replaces the subscription itself. Without
+                // selectors it is a plain subscription.
+                let mut clients = ctx.clients.lock().unwrap_or_else(|e| e.into_inner());
+                clients
+                    .entry(id)
+                    .or_default()
+                    .subscribe(filter, matchers, true);
+            }
+            Some(query) => {
+                let Some((legacy, matcher)) = plan_selector(query) else {
+                    return MOSQ_ERR_ACL_DENIED;
+                };
+                if let Err(rc) = rewrite_filter(&mut evt.data.topic_filter, &legacy) {
+                    return rc;
+                }
+                let mut clients = ctx.clients.lock().unwrap_or_else(|e| e.into_inner());
+                clients
+                    .entry(id)
+                    .or_default()
+                    .subscribe(legacy, vec![matcher], false);
+            }
+        }
+    }
+    MOSQ_ERR_SUCCESS
+}
+
+extern "C" fn on_unsubscribe(_: c_int, event_data: *mut c_void, userdata: *mut c_void) -> c_int {
+    if userdata.is_null() || event_data.is_null() {
+        return MOSQ_ERR_PLUGIN_DEFER;
+    }
 
-        let payload = if !msg.payload.is_null() && msg.payloadlen > 0 {
-            let bytes = slice::from_raw_parts(msg.payload as *const u8, msg.payloadlen as usize);
-            match serde_json::from_slice::<JsonValue>(bytes) {
-                Ok(j) => Some(j),
-                Err(e) => {
-                    eprintln!("[MoQTail] payload JSON parse error: {}", e);
-                    None
+    unsafe {
+        let ctx = &*(userdata as *mut PluginContext);
+        let evt = &mut *(event_data as *mut mosquitto_evt_unsubscribe);
+        let Some(id) = client_id(evt.client) else {
+            return MOSQ_ERR_PLUGIN_DEFER;
+        };
+        if evt.data.topic_filter.is_null() {
+            return MOSQ_ERR_PLUGIN_DEFER;
+        }
+        let filter = match CStr::from_ptr(evt.data.topic_filter).to_str() {
+            Ok(f) => f.to_string(),
+            Err(_) => return MOSQ_ERR_PLUGIN_DEFER,
+        };
+
+        let filter = match filter.strip_prefix(SELECTOR_PREFIX) {
+            None => filter,
+            Some(query) => {
+                let Some((legacy, _)) = plan_selector(query) else {
+                    return MOSQ_ERR_PLUGIN_DEFER;
+                };
+                if let Err(rc) = rewrite_filter(&mut evt.data.topic_filter, &legacy) {
+                    return rc;
                 }
+                legacy
             }
-        } else {
-            None
         };
 
-        let m = Message {
-            topic,
-            headers,
-            payload,
+        let mut clients = ctx.clients.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(subs) = clients.get_mut(&id) {
+            subs.remove(&filter);
+            if subs.filters.is_empty() && !subs.persistent {
+                clients.remove(&id);
+            }
+        }
+    }
+    MOSQ_ERR_SUCCESS
+}
+
+unsafe fn build_message<'a>(evt: &mosquitto_evt_acl_check, topic: &'a str) -> Message<'a> {
+    let mut headers = HashMap::new();
+    headers.insert(Cow::Borrowed("qos"), Cow::Owned(evt.qos.to_string()));
+    headers.insert(
+        Cow::Borrowed("retained"),
+        Cow::Borrowed(if evt.retain { "true" } else { "false" }),
+    );
+
+    // Decoded only if a selector reads the payload; non-JSON payloads are
+    // common on MQTT and simply fail `json$` predicates.
+    let payload = if !evt.payload.is_null() && evt.payloadlen > 0 {
+        Payload::raw(slice::from_raw_parts(
+            evt.payload as *const u8,
+            evt.payloadlen as usize,
+        ))
+    } else {
+        Payload::None
+    };
+
+    Message {
+        topic,
+        headers,
+        payload,
+    }
+}
+
+extern "C" fn on_acl_check(_: c_int, event_data: *mut c_void, userdata: *mut c_void) -> c_int {
+    if userdata.is_null() || event_data.is_null() {
+        return MOSQ_ERR_PLUGIN_DEFER;
+    }
+
+    unsafe {
+        let ctx = &*(userdata as *mut PluginContext);
+        let evt = &*(event_data as *mut mosquitto_evt_acl_check);
+        if evt.topic.is_null() {
+            return MOSQ_ERR_PLUGIN_DEFER;
+        }
+        // Only deliveries are filtered; the broker denies any check no plugin
+        // answers, so everything else is explicitly allowed.
+        if evt.access != MOSQ_ACL_READ {
+            return MOSQ_ERR_SUCCESS;
+        }
+        let Some(id) = client_id(evt.client) else {
+            return MOSQ_ERR_SUCCESS;
+        };
+        let topic = match CStr::from_ptr(evt.topic).to_str() {
+            Ok(t) => t,
+            Err(_) => return MOSQ_ERR_SUCCESS,
+        };
+
+        let mut clients = ctx.clients.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(subs) = clients.get_mut(&id) else {
+            return MOSQ_ERR_SUCCESS;
         };
-        for matcher in &ctx.matchers {
-            if matcher.matches(&m) {
+
+        // The message is only withheld when every subscription routing it to
+        // this client is a MoQTail subscription whose selectors all reject it.
+        // Selectors are filed under their filter, so those routing the topic
+        // are the candidates of the index.
+        let routed = subs
+            .filters
+            .iter()
+            .filter(|(f, _)| topic_matches_filter(f, topic));
+        let mut filtered = false;
+        for (_, ids) in routed {
+            if ids.is_empty() {
                 return MOSQ_ERR_SUCCESS;
             }
+            filtered = true;
+        }
+        if !filtered {
+            return MOSQ_ERR_SUCCESS;
+        }
+        let msg = build_message(evt, topic);
+        if subs.selectors.accept(&msg, Instant::now()).is_empty() {
+            MOSQ_ERR_ACL_DENIED
+        } else {
+            MOSQ_ERR_SUCCESS
+        }
+    }
+}
+
+extern "C" fn on_disconnect(_: c_int, event_data: *mut c_void, userdata: *mut c_void) -> c_int {
+    if userdata.is_null() || event_data.is_null() {
+        return MOSQ_ERR_PLUGIN_DEFER;
+    }
+
+    unsafe {
+        let ctx = &*(userdata as *mut PluginContext);
+        let evt = &*(event_data as *mut mosquitto_evt_disconnect);
+        // Sessions the broker keeps hold on to their subscriptions, so their
+        // selectors stay until the session itself ends.
+        if let Some(id) = client_id(evt.client) {
+            let mut clients = ctx.clients.lock().unwrap_or_else(|e| e.into_inner());
+            if clients.get(&id).is_some_and(|subs| !subs.persistent) {
+                clients.remove(&id);
+            }
         }
     }
-    MOSQ_ERR_PLUGIN_DEFER
+    MOSQ_ERR_SUCCESS
 }
 
-// Generated bindings provide `mosquitto_opt`
+/// Tracks which sessions outlive their connection. The broker reports a
+/// session with a positive expiry interval when it is created or updated,
+/// which covers MQTT v5 clients regardless of clean start as well as MQTT
+/// 3.1.1 clients without clean session, and deletes it when it ends.
+extern "C" fn on_persist_client(
+    event: c_int,
+    event_data: *mut c_void,
+    userdata: *mut c_void,
+) -> c_int {
+    if userdata.is_null() || event_data.is_null() {
+        return MOSQ_ERR_PLUGIN_DEFER;
+    }
+
+    unsafe {
+        let ctx = &*(userdata as *mut PluginContext);
+        let evt = &*(event_data as *mut mosquitto_evt_persist_client);
+        if evt.data.clientid.is_null() {
+            return MOSQ_ERR_SUCCESS;
+        }
+        let id = CStr::from_ptr(evt.data.clientid)
+            .to_string_lossy()
+            .into_owned();
+        let mut clients = ctx.clients.lock().unwrap_or_else(|e| e.into_inner());
+        if event == MOSQ_EVT_PERSIST_CLIENT_DELETE {
+            clients.remove(&id);
+        } else if evt.data.session_expiry_interval > 0 {
+            clients.entry(id).or_default().persistent = true;
+        } else if let Some(subs) = clients.get_mut(&id) {
+            subs.persistent = false;
+        }
+    }
+    MOSQ_ERR_SUCCESS
+}
+
+unsafe fn unregister_callbacks(identifier: *mut c_void, callbacks: &[(c_int, Callback)]) {
+    for &(event, cb) in callbacks {
+        let _ = mosquitto_callback_unregister(
+            identifier as *mut mosquitto_plugin_id_t,
+            event,
+            Some(cb),
+            std::ptr::null(),
+        );
+    }
+}
 
 /// Called when the plugin is loaded.
+///
+/// # Safety
+///
+/// `userdata` must be valid for writes and `options` must point to
+/// `option_count` entries, as guaranteed by the broker.
 #[no_mangle]
 pub unsafe extern "C" fn mosquitto_plugin_init(
     identifier: *mut c_void,
@@ -91,58 +491,38 @@ pub unsafe extern "C" fn mosquitto_plugin_init(
     if userdata.is_null() || option_count < 0 {
         return MOSQ_ERR_PLUGIN_DEFER;
     }
-
-    let slice = if option_count == 0 {
-        &[]
-    } else {
-        if options.is_null() {
-            return MOSQ_ERR_PLUGIN_DEFER;
-        }
-        std::slice::from_raw_parts(options, option_count as usize)
-    };
-    let mut matchers = Vec::new();
-
-    for opt in slice.iter() {
-        if opt.key.is_null() || opt.value.is_null() {
-            continue;
-        }
-        let key = CStr::from_ptr(opt.key).to_string_lossy();
-        if key == "selector" || key == "plugin_opt_selector" {
-            let val = CStr::from_ptr(opt.value).to_string_lossy();
-            match compile(&val) {
-                Ok(sel) => matchers.push(Matcher::new(sel)),
-                Err(e) => eprintln!("[MoQTail] selector error: {}", e),
-            }
-        }
+    if option_count > 0 && options.is_null() {
+        return MOSQ_ERR_PLUGIN_DEFER;
     }
 
-    let ctx = Box::new(PluginContext { matchers });
+    let ctx = Box::<PluginContext>::default();
     let ctx_ptr = Box::into_raw(ctx) as *mut c_void;
 
-    let rc = mosquitto_callback_register(
-        identifier as *mut mosquitto_plugin_id_t,
-        MOSQ_EVT_MESSAGE,
-        Some(on_message),
-        std::ptr::null(),
-        ctx_ptr,
-    );
-
-    if rc != MOSQ_ERR_SUCCESS {
-        drop(Box::from_raw(ctx_ptr as *mut PluginContext));
-        if !userdata.is_null() {
+    for (registered, &(event, cb)) in CALLBACKS.iter().enumerate() {
+        let rc = mosquitto_callback_register(
+            identifier as *mut mosquitto_plugin_id_t,
+            event,
+            Some(cb),
+            std::ptr::null(),
+            ctx_ptr,
+        );
+        if rc != MOSQ_ERR_SUCCESS {
+            unregister_callbacks(identifier, &CALLBACKS[..registered]);
+            drop(Box::from_raw(ctx_ptr as *mut PluginContext));
             *userdata = std::ptr::null_mut();
+            return rc;
         }
-        return rc;
     }
 
-    if !userdata.is_null() {
-        *userdata = ctx_ptr;
-    }
-
-    rc
+    *userdata = ctx_ptr;
+    MOSQ_ERR_SUCCESS
 }
 
 /// Called when the plugin is unloaded.
+///
+/// # Safety
+///
+/// `userdata` must be the pointer produced by [`mosquitto_plugin_init`].
 #[no_mangle]
 pub unsafe extern "C" fn mosquitto_plugin_cleanup(
     identifier: *mut c_void,
@@ -150,12 +530,7 @@ pub unsafe extern "C" fn mosquitto_plugin_cleanup(
     _options: *mut mosquitto_opt,
     _option_count: c_int,
 ) -> c_int {
-    let _ = mosquitto_callback_unregister(
-        identifier as *mut mosquitto_plugin_id_t,
-        MOSQ_EVT_MESSAGE,
-        Some(on_message),
-        std::ptr::null(),
-    );
+    unregister_callbacks(identifier, &CALLBACKS);
     if !userdata.is_null() {
         drop(Box::from_raw(userdata as *mut PluginContext));
     }
@@ -165,34 +540,27 @@ pub unsafe extern "C" fn mosquitto_plugin_cleanup(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::ffi::CString;
-    use std::os::raw::c_char;
     use std::sync::atomic::{AtomicI32, Ordering};
-    use std::sync::Mutex;
 
     static TEST_LOCK: Mutex<()> = Mutex::new(());
     static REGISTER_RESULT: AtomicI32 = AtomicI32::new(MOSQ_ERR_SUCCESS);
+    static REGISTERED: Mutex<Vec<c_int>> = Mutex::new(Vec::new());
 
-    #[no_mangle]
-    pub static mut REGISTERED: Option<(
-        extern "C" fn(c_int, *mut c_void, *mut c_void) -> c_int,
-        *mut c_void,
-    )> = None;
-    pub static mut REGISTER_RC: c_int = MOSQ_ERR_SUCCESS;
+    fn lock() -> std::sync::MutexGuard<'static, ()> {
+        TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
 
     #[no_mangle]
     unsafe extern "C" fn mosquitto_callback_register(
         _identifier: *mut c_void,
-        _event: c_int,
-        cb_func: Option<extern "C" fn(c_int, *mut c_void, *mut c_void) -> c_int>,
+        event: c_int,
+        _cb_func: Option<Callback>,
         _event_data: *const c_void,
-        userdata: *mut c_void,
+        _userdata: *mut c_void,
     ) -> c_int {
         let rc = REGISTER_RESULT.load(Ordering::SeqCst);
         if rc == MOSQ_ERR_SUCCESS {
-            if let Some(f) = cb_func {
-                REGISTERED = Some((f, userdata));
-            }
+            REGISTERED.lock().unwrap().push(event);
         }
         rc
     }
@@ -200,67 +568,102 @@ mod tests {
     #[no_mangle]
     unsafe extern "C" fn mosquitto_callback_unregister(
         _identifier: *mut c_void,
-        _event: c_int,
-        _cb_func: Option<extern "C" fn(c_int, *mut c_void, *mut c_void) -> c_int>,
+        event: c_int,
+        _cb_func: Option<Callback>,
         _event_data: *const c_void,
     ) -> c_int {
-        REGISTERED = None;
+        REGISTERED.lock().unwrap().retain(|&e| e != event);
         MOSQ_ERR_SUCCESS
     }
 
+    #[no_mangle]
+    unsafe extern "C" fn mosquitto_client_id(client: *const c_void) -> *const c_char {
+        client as *const c_char
+    }
+
+    #[no_mangle]
+    unsafe extern "C" fn mosquitto_strdup(s: *const c_char) -> *mut c_char {
+        CStr::from_ptr(s).to_owned().into_raw()
+    }
+
+    #[no_mangle]
+    unsafe extern "C" fn mosquitto_free(mem: *mut c_void) {
+        if !mem.is_null() {
+            drop(CString::from_raw(mem as *mut c_char));
+        }
+    }
+
+    #[no_mangle]
+    unsafe extern "C" fn mosquitto_property_read_string_pair(
+        _proplist: *const c_void,
+        _identifier: c_int,
+        _name: *mut *mut c_char,
+        _value: *mut *mut c_char,
+        _skip_first: bool,
+    ) -> *const c_void {
+        std::ptr::null()
+    }
+
+    #[test]
+    fn topic_filter_matching() {
+        assert!(topic_matches_filter("foo/+", "foo/bar"));
+        assert!(topic_matches_filter("foo/+", "foo/"));
+        assert!(!topic_matches_filter("foo/+", "foo/bar/baz"));
+        assert!(topic_matches_filter("foo/#", "foo"));
+        assert!(topic_matches_filter("foo/#", "foo/bar/baz"));
+        assert!(topic_matches_filter("#", "a/b"));
+        assert!(!topic_matches_filter("#", "$SYS/uptime"));
+        assert!(!topic_matches_filter("+/uptime", "$SYS/uptime"));
+        assert!(topic_matches_filter("$SYS/#", "$SYS/uptime"));
+        assert!(!topic_matches_filter("foo/bar", "foo//bar"));
+    }
+
     #[test]
     fn init_clears_userdata_when_callback_registration_fails() {
-        let _guard = TEST_LOCK.lock().unwrap();
+        let _guard = lock();
         REGISTER_RESULT.store(MOSQ_ERR_PLUGIN_DEFER, Ordering::SeqCst);
+        REGISTERED.lock().unwrap().clear();
         unsafe {
-            REGISTERED = None;
-            let key = CString::new("selector").unwrap();
-            let val = CString::new("/foo/+").unwrap();
-            let mut opt = mosquitto_opt {
-                key: key.as_ptr() as *mut c_char,
-                value: val.as_ptr() as *mut c_char,
-            };
             let mut userdata = std::ptr::dangling_mut::<c_void>();
-
             assert_eq!(
-                mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, &mut opt, 1),
+                mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, std::ptr::null_mut(), 0),
                 MOSQ_ERR_PLUGIN_DEFER
             );
             assert!(userdata.is_null());
-            assert!(matches!(REGISTERED, None));
         }
+        assert!(REGISTERED.lock().unwrap().is_empty());
         REGISTER_RESULT.store(MOSQ_ERR_SUCCESS, Ordering::SeqCst);
-    fn on_message_rejects_null_callback_data() {
+    }
+
+    #[test]
+    fn callbacks_reject_null_callback_data() {
+        let mut ctx = PluginContext::default();
+        let ctx_ptr = &mut ctx as *mut _ as *mut c_void;
+        for (event, cb) in CALLBACKS {
+            assert_eq!(
+                cb(event, std::ptr::null_mut(), ctx_ptr),
+                MOSQ_ERR_PLUGIN_DEFER
+            );
+        }
+
+        let client = CString::new("client").unwrap();
         let topic = CString::new("foo/bar").unwrap();
-        let mut msg = mosquitto_evt_message {
+        let mut evt = mosquitto_evt_acl_check {
             future: std::ptr::null_mut(),
-            client: std::ptr::null_mut(),
-            topic: topic.as_ptr() as *mut c_char,
-            payload: std::ptr::null_mut(),
+            client: client.as_ptr() as *mut c_void,
+            topic: topic.as_ptr(),
+            payload: std::ptr::null(),
             properties: std::ptr::null_mut(),
-            reason_string: std::ptr::null_mut(),
+            access: MOSQ_ACL_READ,
             payloadlen: 0,
             qos: 0,
-            reason_code: 0,
             retain: false,
             future2: [std::ptr::null_mut(); 4],
         };
-        let mut ctx = PluginContext {
-            matchers: Vec::new(),
-        };
-
         assert_eq!(
-            on_message(
-                MOSQ_EVT_MESSAGE,
-                std::ptr::null_mut(),
-                &mut ctx as *mut _ as *mut c_void
-            ),
-            MOSQ_ERR_PLUGIN_DEFER
-        );
-        assert_eq!(
-            on_message(
-                MOSQ_EVT_MESSAGE,
-                &mut msg as *mut _ as *mut c_void,
+            on_acl_check(
+                MOSQ_EVT_ACL_CHECK,
+                &mut evt as *mut _ as *mut c_void,
                 std::ptr::null_mut(),
             ),
             MOSQ_ERR_PLUGIN_DEFER
@@ -269,19 +672,20 @@ mod tests {
 
     #[test]
     fn init_accepts_zero_options_and_rejects_invalid_inputs() {
+        let _guard = lock();
+        REGISTER_RESULT.store(MOSQ_ERR_SUCCESS, Ordering::SeqCst);
+        REGISTERED.lock().unwrap().clear();
         unsafe {
-            REGISTERED = None;
-            REGISTER_RC = MOSQ_ERR_SUCCESS;
             let mut userdata: *mut c_void = std::ptr::null_mut();
 
             assert_eq!(
-                mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, std::ptr::null_mut(), 0,),
+                mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, std::ptr::null_mut(), 0),
                 MOSQ_ERR_SUCCESS
             );
             assert!(!userdata.is_null());
-            let registered = REGISTERED;
-            assert!(registered.is_some());
+            assert_eq!(REGISTERED.lock().unwrap().len(), CALLBACKS.len());
             mosquitto_plugin_cleanup(std::ptr::null_mut(), userdata, std::ptr::null_mut(), 0);
+            assert!(REGISTERED.lock().unwrap().is_empty());
 
             assert_eq!(
                 mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, std::ptr::null_mut(), 1),
@@ -309,84 +713,54 @@ mod tests {
     }
 
     #[test]
-    fn init_does_not_write_userdata_when_registration_fails() {
-        unsafe {
-            REGISTERED = None;
-            REGISTER_RC = MOSQ_ERR_PLUGIN_DEFER;
-            let mut userdata: *mut c_void = std::ptr::null_mut();
-
-            assert_eq!(
-                mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, std::ptr::null_mut(), 0),
-                MOSQ_ERR_PLUGIN_DEFER
-            );
-            assert!(userdata.is_null());
-            let registered = REGISTERED;
-            assert!(registered.is_none());
-
-            REGISTER_RC = MOSQ_ERR_SUCCESS;
-        }
-    }
-
-    #[test]
-    fn filter_logic() {
-        let _guard = TEST_LOCK.lock().unwrap();
-        REGISTER_RESULT.store(MOSQ_ERR_SUCCESS, Ordering::SeqCst);
-        unsafe {
-            REGISTER_RC = MOSQ_ERR_SUCCESS;
-            let key = CString::new("selector").unwrap();
-            let val = CString::new("/foo/+").unwrap();
-            let mut opt = mosquitto_opt {
-                key: key.as_ptr() as *mut c_char,
-                value: val.as_ptr() as *mut c_char,
-            };
-            let mut userdata: *mut c_void = std::ptr::null_mut();
+    fn plain_subscription_replaces_selectors_on_same_filter() {
+        let ctx = PluginContext::default();
+        let ctx_ptr = &ctx as *const _ as *mut c_void;
+        let client = CString::new("client").unwrap();
 
-            assert_eq!(
-                mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, &mut opt, 1),
-                MOSQ_ERR_SUCCESS
-            );
-            let (cb, ctx) = REGISTERED.expect("callback registered");
-
-            let topic1 = CString::new("foo/bar").unwrap();
-            let payload1 = CString::new("{\"temp\":1}").unwrap();
-            let mut msg = mosquitto_evt_message {
+        let subscribe = |filter: &str| {
+            let mut evt = mosquitto_evt_subscribe {
                 future: std::ptr::null_mut(),
-                client: std::ptr::null_mut(),
-                topic: topic1.as_ptr() as *mut c_char,
-                payload: payload1.as_ptr() as *mut c_void,
-                properties: std::ptr::null_mut(),
-                reason_string: std::ptr::null_mut(),
-                payloadlen: payload1.as_bytes().len() as u32,
-                qos: 0,
-                reason_code: 0,
-                retain: false,
-                future2: [std::ptr::null_mut(); 4],
+                client: client.as_ptr() as *mut c_void,
+                data: mosquitto_subscription {
+                    clientid: std::ptr::null_mut(),
+                    topic_filter: CString::new(filter).unwrap().into_raw(),
+                    properties: std::ptr::null_mut(),
+                    identifier: 0,
+                    options: 0,
+                    padding: [0; 3],
+                    future2: [std::ptr::null_mut(); 8],
+                },
+                future2: [std::ptr::null_mut(); 8],
             };
-
-            assert_eq!(
-                cb(MOSQ_EVT_MESSAGE, &mut msg as *mut _ as *mut c_void, ctx),
-                MOSQ_ERR_SUCCESS
+            let rc = on_subscribe(
+                MOSQ_EVT_SUBSCRIBE,
+                &mut evt as *mut _ as *mut c_void,
+                ctx_ptr,
             );
+            let filter = unsafe { CString::from_raw(evt.data.topic_filter) };
+            (rc, filter.into_string().unwrap())
+        };
 
-            let topic2 = CString::new("baz/qux").unwrap();
-            msg.topic = topic2.as_ptr() as *mut c_char;
-            assert_eq!(
-                cb(MOSQ_EVT_MESSAGE, &mut msg as *mut _ as *mut c_void, ctx),
-                MOSQ_ERR_PLUGIN_DEFER
-            );
+        assert_eq!(
+            subscribe("$moqtail//foo/+"),
+            (MOSQ_ERR_SUCCESS, "foo/+".to_string())
+        );
+        assert_eq!(
+            ctx.clients.lock().unwrap()["client"].filters["foo/+"].len(),
+            1,
+            "selector should be tracked under its legacy filter"
+        );
 
-            let topic3 = CString::new("foo/baz").unwrap();
-            let bad_payload = CString::new("{temp:}").unwrap();
-            msg.topic = topic3.as_ptr() as *mut c_char;
-            msg.payload = bad_payload.as_ptr() as *mut c_void;
-            msg.payloadlen = bad_payload.as_bytes().len() as u32;
-            assert_eq!(
-                cb(MOSQ_EVT_MESSAGE, &mut msg as *mut _ as *mut c_void, ctx),
-                MOSQ_ERR_SUCCESS
-            );
+        assert_eq!(subscribe("foo/+"), (MOSQ_ERR_SUCCESS, "foo/+".to_string()));
+        let clients = ctx.clients.lock().unwrap();
+        assert!(clients["client"].filters["foo/+"].is_empty());
+        assert!(clients["client"].selectors.is_empty());
+        drop(clients);
 
-            mosquitto_plugin_cleanup(std::ptr::null_mut(), userdata, std::ptr::null_mut(), 0);
-            assert!(matches!(REGISTERED, None));
-        }
+        assert_eq!(
+            subscribe("$moqtail/foo[bar"),
+            (MOSQ_ERR_ACL_DENIED, "$moqtail/foo[bar".to_string())
+        );
     }
 }
@@ -0,0 +1,288 @@
+//! Broker function stand-ins shared by the plugin integration tests.
+
+#![allow(dead_code)]
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::{Mutex, MutexGuard};
+
+use moqtail_mosquitto::{
+    mosquitto_client, mosquitto_evt_acl_check, mosquitto_evt_disconnect,
+    mosquitto_evt_persist_client, mosquitto_evt_subscribe, mosquitto_evt_unsubscribe,
+    mosquitto_plugin_cleanup, mosquitto_plugin_init, mosquitto_subscription,
+};
+
+pub const MOSQ_EVT_ACL_CHECK: c_int = 2;
+pub const MOSQ_EVT_DISCONNECT: c_int = 10;
+pub const MOSQ_EVT_SUBSCRIBE: c_int = 12;
+pub const MOSQ_EVT_UNSUBSCRIBE: c_int = 13;
+pub const MOSQ_EVT_PERSIST_CLIENT_ADD: c_int = 19;
+pub const MOSQ_EVT_PERSIST_CLIENT_DELETE: c_int = 20;
+pub const MOSQ_EVT_PERSIST_CLIENT_UPDATE: c_int = 21;
+pub const MOSQ_ERR_SUCCESS: c_int = 0;
+pub const MOSQ_ERR_ACL_DENIED: c_int = 12;
+pub const MOSQ_ERR_PLUGIN_DEFER: c_int = 17;
+pub const MOSQ_ACL_READ: c_int = 0x01;
+pub const MOSQ_ACL_WRITE: c_int = 0x02;
+pub const MQTT_PROP_USER_PROPERTY: c_int = 38;
+
+type Callback = extern "C" fn(c_int, *mut c_void, *mut c_void) -> c_int;
+
+static TEST_MUTEX: Mutex<()> = Mutex::new(());
+static REGISTERED: Mutex<Vec<(c_int, Callback, usize)>> = Mutex::new(Vec::new());
+
+pub fn test_lock() -> MutexGuard<'static, ()> {
+    TEST_MUTEX
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[no_mangle]
+unsafe extern "C" fn mosquitto_callback_register(
+    _identifier: *mut c_void,
+    event: c_int,
+    cb_func: Option<Callback>,
+    _event_data: *const c_void,
+    userdata: *mut c_void,
+) -> c_int {
+    if let Some(f) = cb_func {
+        REGISTERED
+            .lock()
+            .unwrap()
+            .push((event, f, userdata as usize));
+    }
+    0
+}
+
+#[no_mangle]
+unsafe extern "C" fn mosquitto_callback_unregister(
+    _identifier: *mut c_void,
+    event: c_int,
+    _cb_func: Option<Callback>,
+    _event_data: *const c_void,
+) -> c_int {
+    REGISTERED.lock().unwrap().retain(|(e, _, _)| *e != event);
+    0
+}
+
+#[no_mangle]
+unsafe extern "C" fn mosquitto_client_id(client: *const c_void) -> *const c_char {
+    client as *const c_char
+}
+
+#[no_mangle]
+unsafe extern "C" fn mosquitto_strdup(s: *const c_char) -> *mut c_char {
+    CStr::from_ptr(s).to_owned().into_raw()
+}
+
+#[no_mangle]
+unsafe extern "C" fn mosquitto_free(mem: *mut c_void) {
+    if !mem.is_null() {
+        drop(CString::from_raw(mem as *mut c_char));
+    }
+}
+
+/// A user property in the broker's linked property list.
+pub struct UserProperty {
+    name: CString,
+    value: CString,
+    next: *const UserProperty,
+}
+
+#[no_mangle]
+unsafe extern "C" fn mosquitto_property_read_string_pair(
+    proplist: *const c_void,
+    identifier: c_int,
+    name: *mut *mut c_char,
+    value: *mut *mut c_char,
+    skip_first: bool,
+) -> *const c_void {
+    assert_eq!(identifier, MQTT_PROP_USER_PROPERTY);
+    let mut prop = proplist as *const UserProperty;
+    if skip_first && !prop.is_null() {
+        prop = (*prop).next;
+    }
+    if let Some(p) = prop.as_ref() {
+        *name = mosquitto_strdup(p.name.as_ptr());
+        *value = mosquitto_strdup(p.value.as_ptr());
+    }
+    prop as *const c_void
+}
+
+/// A loaded plugin instance driven through its registered callbacks.
+pub struct Plugin {
+    userdata: *mut c_void,
+}
+
+impl Plugin {
+    pub fn load() -> Self {
+        let mut userdata: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, std::ptr::null_mut(), 0),
+                MOSQ_ERR_SUCCESS
+            );
+        }
+        Plugin { userdata }
+    }
+
+    fn dispatch(&self, event: c_int, event_data: *mut c_void) -> c_int {
+        let (_, cb, userdata) = *REGISTERED
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(e, _, _)| *e == event)
+            .expect("callback registered");
+        cb(event, event_data, userdata as *mut c_void)
+    }
+
+    /// Subscribes `client` to `filter`, returning the result code and the
+    /// topic filter the broker would store.
+    pub fn subscribe(&self, client: &CStr, filter: &str) -> (c_int, String) {
+        self.subscribe_with_properties(client, filter, &[])
+    }
+
+    /// Subscribes like [`Plugin::subscribe`], attaching MQTT v5 user
+    /// properties to the SUBSCRIBE.
+    pub fn subscribe_with_properties(
+        &self,
+        client: &CStr,
+        filter: &str,
+        properties: &[(&str, &str)],
+    ) -> (c_int, String) {
+        let mut list: Vec<Box<UserProperty>> = Vec::new();
+        for (name, value) in properties.iter().rev() {
+            let next = list.last().map_or(std::ptr::null(), |p| &**p as *const _);
+            list.push(Box::new(UserProperty {
+                name: CString::new(*name).unwrap(),
+                value: CString::new(*value).unwrap(),
+                next,
+            }));
+        }
+        let mut data = subscription(filter);
+        if let Some(head) = list.last() {
+            data.properties = &**head as *const UserProperty as *mut c_void;
+        }
+        let mut evt = mosquitto_evt_subscribe {
+            future: std::ptr::null_mut(),
+            client: client.as_ptr() as *mut c_void,
+            data,
+            future2: [std::ptr::null_mut(); 8],
+        };
+        let rc = self.dispatch(MOSQ_EVT_SUBSCRIBE, &mut evt as *mut _ as *mut c_void);
+        (rc, take_filter(evt.data))
+    }
+
+    pub fn unsubscribe(&self, client: &CStr, filter: &str) -> (c_int, String) {
+        let mut evt = mosquitto_evt_unsubscribe {
+            future: std::ptr::null_mut(),
+            client: client.as_ptr() as *mut c_void,
+            data: subscription(filter),
+            future2: [std::ptr::null_mut(); 8],
+        };
+        let rc = self.dispatch(MOSQ_EVT_UNSUBSCRIBE, &mut evt as *mut _ as *mut c_void);
+        (rc, take_filter(evt.data))
+    }
+
+    pub fn disconnect(&self, client: &CStr) -> c_int {
+        let mut evt = mosquitto_evt_disconnect {
+            future: std::ptr::null_mut(),
+            client: client.as_ptr() as *mut c_void,
+            reason: 0,
+            future2: [std::ptr::null_mut(); 4],
+        };
+        self.dispatch(MOSQ_EVT_DISCONNECT, &mut evt as *mut _ as *mut c_void)
+    }
+
+    /// Reports a session change for `client`, with the session expiry
+    /// interval the broker keeps it for after a disconnect.
+    pub fn persist_client(&self, client: &CStr, event: c_int, session_expiry: u32) -> c_int {
+        let mut evt = mosquitto_evt_persist_client {
+            future: std::ptr::null_mut(),
+            data: mosquitto_client {
+                clientid: client.as_ptr() as *mut c_char,
+                username: std::ptr::null_mut(),
+                auth_method: std::ptr::null_mut(),
+                will: std::ptr::null_mut(),
+                will_delay_time: 0,
+                session_expiry_time: 0,
+                will_delay_interval: 0,
+                session_expiry_interval: session_expiry,
+                max_packet_size: 0,
+                listener_port: 1883,
+                max_qos: 2,
+                retain_available: true,
+                future2: [std::ptr::null_mut(); 8],
+            },
+            future2: [std::ptr::null_mut(); 8],
+        };
+        self.dispatch(event, &mut evt as *mut _ as *mut c_void)
+    }
+
+    /// Runs the delivery ACL check for a message sent to `client`.
+    pub fn deliver(&self, client: &CStr, delivery: Delivery<'_>) -> c_int {
+        let topic = CString::new(delivery.topic).unwrap();
+        let mut evt = mosquitto_evt_acl_check {
+            future: std::ptr::null_mut(),
+            client: client.as_ptr() as *mut c_void,
+            topic: topic.as_ptr(),
+            payload: delivery.payload.as_ptr() as *const c_void,
+            properties: std::ptr::null_mut(),
+            access: delivery.access,
+            payloadlen: delivery.payload.len() as u32,
+            qos: delivery.qos,
+            retain: delivery.retain,
+            future2: [std::ptr::null_mut(); 4],
+        };
+        self.dispatch(MOSQ_EVT_ACL_CHECK, &mut evt as *mut _ as *mut c_void)
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        unsafe {
+            mosquitto_plugin_cleanup(std::ptr::null_mut(), self.userdata, std::ptr::null_mut(), 0);
+        }
+        assert!(REGISTERED.lock().unwrap().is_empty());
+    }
+}
+
+/// Message fields checked when the broker delivers to a subscriber.
+#[derive(Clone, Copy)]
+pub struct Delivery<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: u8,
+    pub retain: bool,
+    pub access: c_int,
+}
+
+impl<'a> Delivery<'a> {
+    pub fn new(topic: &'a str) -> Self {
+        Delivery {
+            topic,
+            payload: &[],
+            qos: 0,
+            retain: false,
+            access: MOSQ_ACL_READ,
+        }
+    }
+}
+
+fn subscription(filter: &str) -> mosquitto_subscription {
+    mosquitto_subscription {
+        clientid: std::ptr::null_mut(),
+        topic_filter: CString::new(filter).unwrap().into_raw(),
+        properties: std::ptr::null_mut(),
+        identifier: 0,
+        options: 0,
+        padding: [0; 3],
+        future2: [std::ptr::null_mut(); 8],
+    }
+}
+
+fn take_filter(data: mosquitto_subscription) -> String {
+    unsafe { CString::from_raw(data.topic_filter) }
+        .into_string()
+        .unwrap()
+}
@@ -1,293 +1,356 @@
 use std::ffi::CString;
-use std::os::raw::{c_char, c_int, c_void};
-use std::sync::{Mutex, MutexGuard};
-extern crate moqtail_mosquitto;
-use moqtail_mosquitto::{
-    mosquitto_evt_message, mosquitto_opt, mosquitto_plugin_cleanup, mosquitto_plugin_init,
-};
 
-const MOSQ_ERR_PLUGIN_DEFER: c_int = 17;
+mod common;
+use common::*;
 
-static TEST_MUTEX: Mutex<()> = Mutex::new(());
+#[test]
+fn filter_integration() {
+    let _guard = test_lock();
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+
+    assert_eq!(
+        plugin.subscribe(&client, "$moqtail//foo/+"),
+        (MOSQ_ERR_SUCCESS, "foo/+".to_string())
+    );
+
+    assert_eq!(
+        plugin.deliver(&client, Delivery::new("foo/bar")),
+        MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        plugin.deliver(&client, Delivery::new("foo/")),
+        MOSQ_ERR_SUCCESS
+    );
+    // Not routed through the legacy filter, so the selector does not apply.
+    assert_eq!(
+        plugin.deliver(&client, Delivery::new("baz/qux")),
+        MOSQ_ERR_SUCCESS
+    );
+}
+
+#[test]
+fn header_filter() {
+    let _guard = test_lock();
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+
+    assert_eq!(
+        plugin.subscribe(&client, "$moqtail//msg[qos<=1]/foo"),
+        (MOSQ_ERR_SUCCESS, "foo".to_string())
+    );
+
+    let mut delivery = Delivery::new("foo");
+    delivery.payload = b"not json";
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
 
-fn test_lock() -> MutexGuard<'static, ()> {
-    TEST_MUTEX
-        .lock()
-        .unwrap_or_else(|poisoned| poisoned.into_inner())
+    delivery.qos = 2;
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_ACL_DENIED);
 }
 
-static mut REGISTERED: Option<(
-    extern "C" fn(c_int, *mut c_void, *mut c_void) -> c_int,
-    *mut c_void,
-)> = None;
-
-#[no_mangle]
-unsafe extern "C" fn mosquitto_callback_register(
-    _identifier: *mut c_void,
-    _event: c_int,
-    cb_func: Option<extern "C" fn(c_int, *mut c_void, *mut c_void) -> c_int>,
-    _event_data: *const c_void,
-    userdata: *mut c_void,
-) -> c_int {
-    if let Some(f) = cb_func {
-        REGISTERED = Some((f, userdata));
-    }
-    0
+#[test]
+fn retained_header_filter_true() {
+    let _guard = test_lock();
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+    plugin.subscribe(&client, "$moqtail//msg[retained=true]/foo");
+
+    let mut delivery = Delivery::new("foo");
+    delivery.retain = true;
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
+
+    delivery.retain = false;
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_ACL_DENIED);
 }
 
-#[no_mangle]
-unsafe extern "C" fn mosquitto_callback_unregister(
-    _identifier: *mut c_void,
-    _event: c_int,
-    _cb_func: Option<extern "C" fn(c_int, *mut c_void, *mut c_void) -> c_int>,
-    _event_data: *const c_void,
-) -> c_int {
-    REGISTERED = None;
-    0
+#[test]
+fn retained_header_filter_false() {
+    let _guard = test_lock();
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+    plugin.subscribe(&client, "$moqtail//msg[retained=false]/foo");
+
+    let mut delivery = Delivery::new("foo");
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
+
+    delivery.retain = true;
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_ACL_DENIED);
 }
 
 #[test]
-fn filter_integration() {
+fn payload_filter() {
     let _guard = test_lock();
-    unsafe {
-        let key = CString::new("selector").unwrap();
-        let val = CString::new("/foo/+").unwrap();
-        let mut opt = mosquitto_opt {
-            key: key.as_ptr() as *mut c_char,
-            value: val.as_ptr() as *mut c_char,
-        };
-        let mut userdata: *mut c_void = std::ptr::null_mut();
-
-        assert_eq!(
-            mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, &mut opt, 1),
-            0
-        );
-        let (cb, ctx) = REGISTERED.expect("callback registered");
-
-        let topic1 = CString::new("foo/bar").unwrap();
-        let mut msg = mosquitto_evt_message {
-            future: std::ptr::null_mut(),
-            client: std::ptr::null_mut(),
-            topic: topic1.as_ptr() as *mut c_char,
-            payload: std::ptr::null_mut(),
-            properties: std::ptr::null_mut(),
-            reason_string: std::ptr::null_mut(),
-            payloadlen: 0,
-            qos: 0,
-            reason_code: 0,
-            retain: false,
-            future2: [std::ptr::null_mut(); 4],
-        };
-
-        assert_eq!(cb(7, &mut msg as *mut _ as *mut c_void, ctx), 0);
-
-        let topic2 = CString::new("foo/").unwrap();
-        msg.topic = topic2.as_ptr() as *mut c_char;
-        assert_eq!(cb(7, &mut msg as *mut _ as *mut c_void, ctx), 0);
-
-        let topic3 = CString::new("foo//bar").unwrap();
-        msg.topic = topic3.as_ptr() as *mut c_char;
-        assert_eq!(
-            cb(7, &mut msg as *mut _ as *mut c_void, ctx),
-            MOSQ_ERR_PLUGIN_DEFER
-        );
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+    plugin.subscribe(&client, "$moqtail//foo[json$.temp>30]");
 
-        let topic4 = CString::new("baz/qux").unwrap();
-        msg.topic = topic4.as_ptr() as *mut c_char;
-        assert_eq!(
-            cb(7, &mut msg as *mut _ as *mut c_void, ctx),
-            MOSQ_ERR_PLUGIN_DEFER
-        );
+    let mut delivery = Delivery::new("foo");
+    delivery.payload = b"{\"temp\":35}";
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
+
+    delivery.payload = b"{\"temp\":25}";
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_ACL_DENIED);
+}
 
-        mosquitto_plugin_cleanup(std::ptr::null_mut(), userdata, std::ptr::null_mut(), 0);
-        assert!(REGISTERED.is_none());
+#[test]
+fn distinct_and_throttle_stages_withhold_deliveries() {
+    let _guard = test_lock();
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+    plugin.subscribe(&client, "$moqtail//foo |> distinct(json$.state)");
+
+    let mut delivery = Delivery::new("foo");
+    for (payload, expected) in [
+        (&b"{\"state\":\"on\"}"[..], MOSQ_ERR_SUCCESS),
+        (b"{\"state\":\"on\"}", MOSQ_ERR_ACL_DENIED),
+        (b"{\"state\":\"off\"}", MOSQ_ERR_SUCCESS),
+    ] {
+        delivery.payload = payload;
+        assert_eq!(plugin.deliver(&client, delivery), expected);
     }
+
+    plugin.subscribe(&client, "$moqtail//bar |> throttle(1h)");
+    assert_eq!(
+        plugin.deliver(&client, Delivery::new("bar")),
+        MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        plugin.deliver(&client, Delivery::new("bar")),
+        MOSQ_ERR_ACL_DENIED
+    );
 }
 
 #[test]
-fn header_filter() {
+fn filtering_is_per_client() {
     let _guard = test_lock();
-    unsafe {
-        let key = CString::new("selector").unwrap();
-        let val = CString::new("/msg[qos<=1]").unwrap();
-        let mut opt = mosquitto_opt {
-            key: key.as_ptr() as *mut c_char,
-            value: val.as_ptr() as *mut c_char,
-        };
-        let mut userdata: *mut c_void = std::ptr::null_mut();
-
-        assert_eq!(
-            mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, &mut opt, 1),
-            0
-        );
-        let (cb, ctx) = REGISTERED.expect("callback registered");
-
-        let topic = CString::new("").unwrap();
-        let bad_payload = CString::new("not json").unwrap();
-        let mut msg = mosquitto_evt_message {
-            future: std::ptr::null_mut(),
-            client: std::ptr::null_mut(),
-            topic: topic.as_ptr() as *mut c_char,
-            payload: bad_payload.as_ptr() as *mut c_void,
-            properties: std::ptr::null_mut(),
-            reason_string: std::ptr::null_mut(),
-            payloadlen: bad_payload.as_bytes().len() as u32,
-            qos: 0,
-            reason_code: 0,
-            retain: false,
-            future2: [std::ptr::null_mut(); 4],
-        };
-
-        assert_eq!(cb(7, &mut msg as *mut _ as *mut c_void, ctx), 0);
-
-        msg.qos = 2;
-        assert_eq!(
-            cb(7, &mut msg as *mut _ as *mut c_void, ctx),
-            MOSQ_ERR_PLUGIN_DEFER
-        );
+    let plugin = Plugin::load();
+    let moqtail = CString::new("moqtail-client").unwrap();
+    let legacy = CString::new("legacy-client").unwrap();
+
+    plugin.subscribe(&moqtail, "$moqtail//sensors/+[json$.value>30]");
+    assert_eq!(
+        plugin.subscribe(&legacy, "sensors/+"),
+        (MOSQ_ERR_SUCCESS, "sensors/+".to_string())
+    );
+
+    let mut delivery = Delivery::new("sensors/temp");
+    delivery.payload = b"{\"value\":20}";
+    assert_eq!(plugin.deliver(&moqtail, delivery), MOSQ_ERR_ACL_DENIED);
+    assert_eq!(plugin.deliver(&legacy, delivery), MOSQ_ERR_SUCCESS);
+}
 
-        mosquitto_plugin_cleanup(std::ptr::null_mut(), userdata, std::ptr::null_mut(), 0);
-        assert!(REGISTERED.is_none());
-    }
+#[test]
+fn plain_subscription_of_same_client_keeps_delivery() {
+    let _guard = test_lock();
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+
+    plugin.subscribe(&client, "$moqtail//sensors/temp[json$.value>30]");
+    plugin.subscribe(&client, "sensors/#");
+
+    let mut delivery = Delivery::new("sensors/temp");
+    delivery.payload = b"{\"value\":20}";
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
 }
 
 #[test]
-fn retained_header_filter_true() {
+fn selectors_sharing_a_filter_are_combined() {
     let _guard = test_lock();
-    unsafe {
-        let key = CString::new("selector").unwrap();
-        let val = CString::new("/msg[retained=true]").unwrap();
-        let mut opt = mosquitto_opt {
-            key: key.as_ptr() as *mut c_char,
-            value: val.as_ptr() as *mut c_char,
-        };
-        let mut userdata: *mut c_void = std::ptr::null_mut();
-
-        assert_eq!(
-            mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, &mut opt, 1),
-            0
-        );
-        let (cb, ctx) = REGISTERED.expect("callback registered");
-
-        let topic = CString::new("").unwrap();
-        let mut msg = mosquitto_evt_message {
-            future: std::ptr::null_mut(),
-            client: std::ptr::null_mut(),
-            topic: topic.as_ptr() as *mut c_char,
-            payload: std::ptr::null_mut(),
-            properties: std::ptr::null_mut(),
-            reason_string: std::ptr::null_mut(),
-            payloadlen: 0,
-            qos: 0,
-            reason_code: 0,
-            retain: true,
-            future2: [std::ptr::null_mut(); 4],
-        };
-
-        assert_eq!(cb(7, &mut msg as *mut _ as *mut c_void, ctx), 0);
-
-        msg.retain = false;
-        assert_eq!(
-            cb(7, &mut msg as *mut _ as *mut c_void, ctx),
-            MOSQ_ERR_PLUGIN_DEFER
-        );
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+
+    plugin.subscribe(&client, "$moqtail//a//sensor");
+    assert_eq!(
+        plugin.subscribe(&client, "$moqtail//a//actuator"),
+        (MOSQ_ERR_SUCCESS, "a/#".to_string())
+    );
+
+    assert_eq!(
+        plugin.deliver(&client, Delivery::new("a/b/sensor")),
+        MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        plugin.deliver(&client, Delivery::new("a/b/actuator")),
+        MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(
+        plugin.deliver(&client, Delivery::new("a/b/other")),
+        MOSQ_ERR_ACL_DENIED
+    );
+}
 
-        mosquitto_plugin_cleanup(std::ptr::null_mut(), userdata, std::ptr::null_mut(), 0);
-        assert!(REGISTERED.is_none());
-    }
+#[test]
+fn publish_checks_are_allowed() {
+    let _guard = test_lock();
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+    plugin.subscribe(&client, "$moqtail//foo[json$.temp>30]");
+
+    let mut delivery = Delivery::new("foo");
+    delivery.access = MOSQ_ACL_WRITE;
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
 }
 
 #[test]
-fn retained_header_filter_false() {
+fn unsubscribe_rewrites_filter_and_forgets_selector() {
     let _guard = test_lock();
-    unsafe {
-        let key = CString::new("selector").unwrap();
-        let val = CString::new("/msg[retained=false]").unwrap();
-        let mut opt = mosquitto_opt {
-            key: key.as_ptr() as *mut c_char,
-            value: val.as_ptr() as *mut c_char,
-        };
-        let mut userdata: *mut c_void = std::ptr::null_mut();
-
-        assert_eq!(
-            mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, &mut opt, 1),
-            0
-        );
-        let (cb, ctx) = REGISTERED.expect("callback registered");
-
-        let topic = CString::new("").unwrap();
-        let mut msg = mosquitto_evt_message {
-            future: std::ptr::null_mut(),
-            client: std::ptr::null_mut(),
-            topic: topic.as_ptr() as *mut c_char,
-            payload: std::ptr::null_mut(),
-            properties: std::ptr::null_mut(),
-            reason_string: std::ptr::null_mut(),
-            payloadlen: 0,
-            qos: 0,
-            reason_code: 0,
-            retain: false,
-            future2: [std::ptr::null_mut(); 4],
-        };
-
-        assert_eq!(cb(7, &mut msg as *mut _ as *mut c_void, ctx), 0);
-
-        msg.retain = true;
-        assert_eq!(
-            cb(7, &mut msg as *mut _ as *mut c_void, ctx),
-            MOSQ_ERR_PLUGIN_DEFER
-        );
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+    plugin.subscribe(&client, "$moqtail//foo[json$.temp>30]");
+
+    assert_eq!(
+        plugin.unsubscribe(&client, "$moqtail//foo[json$.temp>30]"),
+        (MOSQ_ERR_SUCCESS, "foo".to_string())
+    );
+    let mut delivery = Delivery::new("foo");
+    delivery.payload = b"{\"temp\":25}";
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
+}
 
-        mosquitto_plugin_cleanup(std::ptr::null_mut(), userdata, std::ptr::null_mut(), 0);
-        assert!(REGISTERED.is_none());
-    }
+#[test]
+fn disconnect_forgets_selectors_of_ending_sessions() {
+    let _guard = test_lock();
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+    let mut delivery = Delivery::new("foo");
+    delivery.payload = b"{\"temp\":25}";
+
+    plugin.subscribe(&client, "$moqtail//foo[json$.temp>30]");
+    assert_eq!(plugin.disconnect(&client), MOSQ_ERR_SUCCESS);
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
 }
 
 #[test]
-fn payload_filter() {
+fn sessions_outliving_the_connection_keep_selectors_until_they_end() {
     let _guard = test_lock();
-    unsafe {
-        let key = CString::new("selector").unwrap();
-        let val = CString::new("/foo[json$.temp>30]").unwrap();
-        let mut opt = mosquitto_opt {
-            key: key.as_ptr() as *mut c_char,
-            value: val.as_ptr() as *mut c_char,
-        };
-        let mut userdata: *mut c_void = std::ptr::null_mut();
-
-        assert_eq!(
-            mosquitto_plugin_init(std::ptr::null_mut(), &mut userdata, &mut opt, 1),
-            0
-        );
-        let (cb, ctx) = REGISTERED.expect("callback registered");
-
-        let topic = CString::new("foo").unwrap();
-        let payload1 = CString::new("{\"temp\":35}").unwrap();
-        let mut msg = mosquitto_evt_message {
-            future: std::ptr::null_mut(),
-            client: std::ptr::null_mut(),
-            topic: topic.as_ptr() as *mut c_char,
-            payload: payload1.as_ptr() as *mut c_void,
-            properties: std::ptr::null_mut(),
-            reason_string: std::ptr::null_mut(),
-            payloadlen: payload1.as_bytes().len() as u32,
-            qos: 0,
-            reason_code: 0,
-            retain: false,
-            future2: [std::ptr::null_mut(); 4],
-        };
-
-        assert_eq!(cb(7, &mut msg as *mut _ as *mut c_void, ctx), 0);
-
-        let payload2 = CString::new("{\"temp\":25}").unwrap();
-        msg.payload = payload2.as_ptr() as *mut c_void;
-        msg.payloadlen = payload2.as_bytes().len() as u32;
-        assert_eq!(
-            cb(7, &mut msg as *mut _ as *mut c_void, ctx),
-            MOSQ_ERR_PLUGIN_DEFER
-        );
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+    let mut delivery = Delivery::new("foo");
+    delivery.payload = b"{\"temp\":25}";
+
+    // A clean start with a session expiry still keeps the session.
+    plugin.persist_client(&client, MOSQ_EVT_PERSIST_CLIENT_ADD, 3600);
+    plugin.subscribe(&client, "$moqtail//foo[json$.temp>30]");
+    plugin.unsubscribe(&client, "$moqtail//foo[json$.temp>30]");
+    plugin.subscribe(&client, "$moqtail//foo[json$.temp>30]");
+    assert_eq!(plugin.disconnect(&client), MOSQ_ERR_SUCCESS);
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_ACL_DENIED);
+
+    assert_eq!(
+        plugin.persist_client(&client, MOSQ_EVT_PERSIST_CLIENT_DELETE, 3600),
+        MOSQ_ERR_SUCCESS
+    );
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
+
+    // Dropping the expiry to zero ends the session with the connection.
+    plugin.persist_client(&client, MOSQ_EVT_PERSIST_CLIENT_ADD, 3600);
+    plugin.subscribe(&client, "$moqtail//foo[json$.temp>30]");
+    plugin.persist_client(&client, MOSQ_EVT_PERSIST_CLIENT_UPDATE, 0);
+    assert_eq!(plugin.disconnect(&client), MOSQ_ERR_SUCCESS);
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
+}
+
+#[test]
+fn invalid_selector_rejects_subscription() {
+    let _guard = test_lock();
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+
+    assert_eq!(
+        plugin.subscribe(&client, "$moqtail/foo/bar"),
+        (MOSQ_ERR_ACL_DENIED, "$moqtail/foo/bar".to_string())
+    );
+    // Selectors that can only match the empty topic cannot be routed.
+    assert_eq!(
+        plugin.subscribe(&client, "$moqtail//msg[qos<=1]"),
+        (MOSQ_ERR_ACL_DENIED, "$moqtail//msg[qos<=1]".to_string())
+    );
+}
+
+#[test]
+fn user_property_selector_filters_wildcard_level() {
+    let _guard = test_lock();
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+
+    // `sensors/+[json$.value>30]` is not a valid topic filter, so the
+    // predicate travels in a user property next to the plain filter.
+    assert_eq!(
+        plugin.subscribe_with_properties(
+            &client,
+            "sensors/+",
+            &[("moqtail-selector", "/sensors/+[json$.value>30]")],
+        ),
+        (MOSQ_ERR_SUCCESS, "sensors/+".to_string())
+    );
+
+    let mut delivery = Delivery::new("sensors/kitchen");
+    delivery.payload = b"{\"value\":42}";
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
+    delivery.payload = b"{\"value\":12}";
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_ACL_DENIED);
+
+    // Unsubscribing the filter drops the selector with it.
+    assert_eq!(
+        plugin.unsubscribe(&client, "sensors/+"),
+        (MOSQ_ERR_SUCCESS, "sensors/+".to_string())
+    );
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
+}
 
-        mosquitto_plugin_cleanup(std::ptr::null_mut(), userdata, std::ptr::null_mut(), 0);
-        assert!(REGISTERED.is_none());
+#[test]
+fn user_property_selector_must_match_filter() {
+    let _guard = test_lock();
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+
+    assert_eq!(
+        plugin.subscribe_with_properties(
+            &client,
+            "sensors/#",
+            &[
+                ("trace", "on"),
+                ("moqtail-selector", "/sensors/+[json$.value>30]"),
+            ],
+        ),
+        (MOSQ_ERR_ACL_DENIED, "sensors/#".to_string())
+    );
+    assert_eq!(
+        plugin.subscribe_with_properties(&client, "sensors/#", &[("trace", "on")]),
+        (MOSQ_ERR_SUCCESS, "sensors/#".to_string())
+    );
+}
+
+#[test]
+fn resubscribing_keeps_one_selector_with_its_state() {
+    let _guard = test_lock();
+    let plugin = Plugin::load();
+    let client = CString::new("sub-1").unwrap();
+    let mut delivery = Delivery::new("foo");
+    delivery.payload = b"{\"state\":\"on\"}";
+
+    for filter in [
+        "$moqtail//foo |> distinct(json$.state)",
+        "$moqtail//foo|>distinct(json$.state)",
+    ] {
+        plugin.subscribe(&client, filter);
+        plugin.subscribe_with_properties(
+            &client,
+            "bar",
+            &[("moqtail-selector", "/bar |> distinct(json$.state)")],
+        );
     }
+    // A second copy of the selector would not have seen the first message.
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
+    plugin.subscribe(&client, "$moqtail//foo |> distinct(json$.state)");
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_ACL_DENIED);
+
+    delivery.topic = "bar";
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_SUCCESS);
+    plugin.subscribe_with_properties(
+        &client,
+        "bar",
+        &[("moqtail-selector", "/bar |> distinct(json$.state)")],
+    );
+    assert_eq!(plugin.deliver(&client, delivery), MOSQ_ERR_ACL_DENIED);
 }